@@ -0,0 +1,206 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::events::RunSummary;
+
+/// One line of the --audit-log file
+///
+/// A run writes a "started" line once its record filter is known, then a "finished" or
+/// "failed" line, so a run that was killed is left with only its "started" line. A run
+/// that fails before its filter is known writes only the "failed" line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    /// started, finished, or failed
+    pub event: String,
+    pub run_id: String,
+    /// The account the tool ran as, from the OS rather than the environment
+    pub user: String,
+    pub host: String,
+    pub pid: u32,
+    /// The database read, password masked
+    pub source: String,
+    /// Fingerprint of the record filter; missing when the run failed before resolving it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    /// Files and tables the run writes to; STDOUT when it writes there
+    #[serde(default)]
+    pub outputs: Vec<String>,
+    /// How the run ended, as in the run summary
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub processed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rejected: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub errors: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elapsed_secs: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The --audit-log file, shared by every run on the host
+///
+/// Each entry is appended as one line while holding an exclusive advisory lock on the
+/// file, so lines from concurrent runs never interleave, and is synced before the run goes on.
+pub struct AuditLog {
+    path: PathBuf,
+    run_id: String,
+    user: String,
+    host: String,
+    source: String,
+    outputs: Vec<String>,
+    filter: Mutex<Option<String>>,
+}
+
+impl AuditLog {
+    /// Prepare to log run `run_id`; the file is created on the first entry if missing
+    pub fn new(path: &Path, run_id: &str, source: &str, outputs: Vec<String>) -> Result<Self> {
+        let user =
+            whoami::fallible::username().context("Failed to look up the user for the audit log")?;
+        let host = whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string());
+        Ok(Self {
+            path: path.to_path_buf(),
+            run_id: run_id.to_string(),
+            user,
+            host,
+            source: source.to_string(),
+            outputs,
+            filter: Mutex::new(None),
+        })
+    }
+
+    /// Log the start of the run, selecting records by the filter with this fingerprint
+    pub fn started(&self, filter: String) -> Result<()> {
+        *self.filter.lock().unwrap() = Some(filter);
+        self.append(&self.entry("started"))
+    }
+
+    /// Log how the run ended
+    pub fn finished(&self, summary: &RunSummary) -> Result<()> {
+        let event = if summary.status == "failed" {
+            "failed"
+        } else {
+            "finished"
+        };
+        let entry = AuditEntry {
+            status: Some(summary.status.to_string()),
+            processed: Some(summary.processed),
+            rejected: Some(summary.rejected),
+            errors: Some(summary.errors),
+            elapsed_secs: Some(summary.elapsed_secs),
+            error: summary.error.clone(),
+            ..self.entry(event)
+        };
+        self.append(&entry)
+    }
+
+    fn entry(&self, event: &str) -> AuditEntry {
+        AuditEntry {
+            timestamp: Utc::now(),
+            event: event.to_string(),
+            run_id: self.run_id.clone(),
+            user: self.user.clone(),
+            host: self.host.clone(),
+            pid: std::process::id(),
+            source: self.source.clone(),
+            filter: self.filter.lock().unwrap().clone(),
+            outputs: self.outputs.clone(),
+            status: None,
+            processed: None,
+            rejected: None,
+            errors: None,
+            elapsed_secs: None,
+            error: None,
+        }
+    }
+
+    fn append(&self, entry: &AuditEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        let context = || format!("Failed to append to audit log: {}", self.path.display());
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(context)?;
+        file.lock().with_context(context)?;
+        file.write_all(line.as_bytes()).with_context(context)?;
+        file.sync_data().with_context(context)?;
+        file.unlock().with_context(context)?;
+        Ok(())
+    }
+}
+
+/// Print the last `limit` runs in an audit log as a table, oldest first
+///
+/// The start and end lines of a run are shown as one row; a run with no end line either
+/// is still going or was killed.
+pub fn list(path: &Path, limit: usize) -> Result<()> {
+    let file = File::open(path).context(format!("Failed to open audit log: {}", path.display()))?;
+    file.lock_shared()
+        .context(format!("Failed to lock audit log: {}", path.display()))?;
+
+    // Runs in the order they started, with their first and last lines
+    let mut order: Vec<String> = Vec::new();
+    let mut runs: HashMap<String, (AuditEntry, AuditEntry)> = HashMap::new();
+    for (number, line) in BufReader::new(&file).lines().enumerate() {
+        let line = line.context(format!("Failed to read audit log: {}", path.display()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: AuditEntry = serde_json::from_str(&line).context(format!(
+            "Invalid entry on line {} of audit log {}",
+            number + 1,
+            path.display()
+        ))?;
+        match runs.get_mut(&entry.run_id) {
+            Some((_, last)) => *last = entry,
+            None => {
+                order.push(entry.run_id.clone());
+                runs.insert(entry.run_id.clone(), (entry.clone(), entry));
+            }
+        }
+    }
+
+    println!(
+        "{:<20}  {:<22}  {:<12}  {:<12}  {:<12}  {:>9}  {:>7}  {:>6}  OUTPUTS",
+        "STARTED", "RUN ID", "USER", "FILTER", "STATUS", "PROCESSED", "ERRORS", "SECS"
+    );
+    let count = |value: Option<u64>| {
+        value
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| "-".to_string())
+    };
+    for run_id in order.iter().skip(order.len().saturating_sub(limit)) {
+        let (start, last) = &runs[run_id];
+        let status = match last.event.as_str() {
+            "started" => "no end".to_string(),
+            event => last.status.clone().unwrap_or_else(|| event.to_string()),
+        };
+        println!(
+            "{:<20}  {:<22}  {:<12}  {:<12}  {:<12}  {:>9}  {:>7}  {:>6}  {}",
+            start.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            run_id,
+            start.user,
+            last.filter.as_deref().unwrap_or("-"),
+            status,
+            count(last.processed),
+            count(last.errors),
+            last.elapsed_secs
+                .map(|s| format!("{:.0}", s))
+                .unwrap_or_else(|| "-".to_string()),
+            last.outputs.join(", ")
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,308 @@
+use anyhow::{bail, Result};
+use sqlx::PgPool;
+use std::collections::BTreeSet;
+use tracing::warn;
+
+use crate::db::{OrderBy, RecordType};
+use crate::histogram::DateField;
+
+/// Something a run can be asked to do that reads from the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// Fetching the records themselves
+    Extract,
+    /// Leaving out deleted records
+    ActiveOnly,
+    /// --editor
+    Editors,
+    /// --editor given as a username rather than an id
+    EditorNames,
+    /// --import-queue
+    ImportQueues,
+    /// --source
+    Sources,
+    /// --org-unit
+    OrgUnits,
+    /// --histogram over this column
+    Histogram(DateField),
+    /// --since on this column
+    Since(DateField),
+    /// --enrich for this tag; its query is checked with EXPLAIN rather than a column list
+    Enrich(u16),
+    /// --embed-holdings
+    Holdings,
+    /// --with-display-fields
+    DisplayFields,
+    /// --order-by on a column other than id
+    OrderBy(OrderBy),
+    /// Fetching the fingerprint, quality, and edit date with each record
+    RecordMeta,
+    /// Fetching the TCN and source with each record, and the source names, for --add-901
+    Identifiers,
+    /// Cutting chunks along the partitions of the record table; never asked for, so
+    /// without it the chunks are planned over the whole table
+    PartitionPlan,
+}
+
+impl Feature {
+    pub fn name(self) -> String {
+        match self {
+            Feature::Extract => "extraction".to_string(),
+            Feature::ActiveOnly => "deleted-record filter".to_string(),
+            Feature::Editors => "--editor".to_string(),
+            Feature::EditorNames => "--editor by username".to_string(),
+            Feature::ImportQueues => "--import-queue".to_string(),
+            Feature::Sources => "--source".to_string(),
+            Feature::OrgUnits => "--org-unit".to_string(),
+            Feature::Histogram(field) => format!("--histogram on {}", field.column()),
+            Feature::Since(field) => format!("--since on {}", field.column()),
+            Feature::Enrich(tag) => format!("--enrich {:03}", tag),
+            Feature::Holdings => "--embed-holdings".to_string(),
+            Feature::DisplayFields => "--with-display-fields".to_string(),
+            Feature::OrderBy(order) => format!("--order-by {}", order.column()),
+            Feature::RecordMeta => "record fingerprint and quality".to_string(),
+            Feature::Identifiers => "--add-901".to_string(),
+            Feature::PartitionPlan => "partition-aligned chunk planning".to_string(),
+        }
+    }
+
+    /// Whether the run can go ahead without this feature; filters never can,
+    /// since dropping one would change which records are exported
+    pub fn degradable(self) -> bool {
+        matches!(
+            self,
+            Feature::Histogram(_) | Feature::Enrich(_) | Feature::Holdings | Feature::PartitionPlan
+        )
+    }
+
+    /// The (schema.table, column) pairs this feature reads when extracting `record_type`
+    pub fn dependencies(self, record_type: RecordType) -> Vec<(&'static str, &'static str)> {
+        let table = record_type.table();
+        match self {
+            Feature::Extract => vec![(table, "id"), (table, "marc")],
+            Feature::ActiveOnly => vec![(table, "deleted")],
+            Feature::Editors => vec![(table, "editor"), ("actor.usr", "id")],
+            Feature::EditorNames => vec![("actor.usr", "usrname")],
+            Feature::ImportQueues => vec![
+                (record_type.import_queue_table(), "id"),
+                (record_type.queued_record_table(), "queue"),
+                (record_type.queued_record_table(), "imported_as"),
+            ],
+            Feature::Sources => vec![
+                (table, "source"),
+                ("config.bib_source", "id"),
+                ("config.bib_source", "source"),
+            ],
+            Feature::OrgUnits => vec![
+                ("asset.call_number", "record"),
+                ("asset.call_number", "owning_lib"),
+                ("asset.call_number", "deleted"),
+                ("actor.org_unit", "id"),
+                ("actor.org_unit", "parent_ou"),
+                ("actor.org_unit", "shortname"),
+            ],
+            Feature::Histogram(field) | Feature::Since(field) => vec![(table, field.column())],
+            Feature::Enrich(_) => vec![],
+            Feature::Holdings => vec![
+                ("asset.call_number", "id"),
+                ("asset.call_number", "record"),
+                ("asset.call_number", "label"),
+                ("asset.call_number", "owning_lib"),
+                ("asset.call_number", "deleted"),
+                ("asset.copy", "call_number"),
+                ("asset.copy", "barcode"),
+                ("asset.copy", "circ_modifier"),
+                ("asset.copy", "status"),
+                ("asset.copy", "deleted"),
+                ("actor.org_unit", "id"),
+                ("actor.org_unit", "shortname"),
+                ("config.copy_status", "id"),
+                ("config.copy_status", "name"),
+            ],
+            Feature::DisplayFields => vec![
+                ("metabib.display_entry", "id"),
+                ("metabib.display_entry", "source"),
+                ("metabib.display_entry", "field"),
+                ("metabib.display_entry", "value"),
+                ("config.display_field_map", "name"),
+                ("config.display_field_map", "field"),
+            ],
+            Feature::OrderBy(order) => vec![(table, order.column())],
+            Feature::RecordMeta => vec![
+                (table, "fingerprint"),
+                (table, "quality"),
+                (table, "edit_date"),
+            ],
+            Feature::Identifiers => vec![
+                (table, "tcn_value"),
+                (table, "tcn_source"),
+                (table, "source"),
+                ("config.bib_source", "id"),
+                ("config.bib_source", "source"),
+            ],
+            // The system catalogs, which every role can read
+            Feature::PartitionPlan => vec![],
+        }
+    }
+}
+
+/// A feature whose dependencies the database role cannot read
+#[derive(Debug)]
+pub struct Unmet {
+    pub feature: Feature,
+    pub problems: Vec<String>,
+}
+
+/// Features switched off by --degrade-gracefully
+#[derive(Debug, Default)]
+pub struct Capabilities {
+    disabled: Vec<Unmet>,
+}
+
+impl Capabilities {
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        !self.disabled.iter().any(|unmet| unmet.feature == feature)
+    }
+
+    /// Switch off an optional feature found unusable outside the column probe
+    pub fn disable(&mut self, feature: Feature, problem: String) {
+        warn!("Disabling {}: {}", feature.name(), problem);
+        self.disabled.push(Unmet {
+            feature,
+            problems: vec![problem],
+        });
+    }
+
+    /// One line per disabled feature, for the run summary
+    pub fn summary(&self) -> Vec<String> {
+        self.disabled
+            .iter()
+            .map(|unmet| format!("{} ({})", unmet.feature.name(), unmet.problems.join("; ")))
+            .collect()
+    }
+}
+
+/// What the catalog says about one column and the current role
+#[derive(sqlx::FromRow)]
+struct ColumnAccess {
+    schema_exists: bool,
+    schema_usage: Option<bool>,
+    table_exists: bool,
+    column_exists: bool,
+    column_select: Option<bool>,
+    row_security: Option<bool>,
+}
+
+/// Check that the role can read everything `features` touch, before any of it runs
+///
+/// Every failure is gathered into one report. With `degrade`, features that the run can do
+/// without are disabled instead of failing it.
+pub async fn check(
+    pool: &PgPool,
+    features: &[Feature],
+    record_type: RecordType,
+    degrade: bool,
+) -> Result<Capabilities> {
+    let mut unmet = Vec::new();
+    let mut row_security = BTreeSet::new();
+
+    for &feature in features {
+        let mut problems = Vec::new();
+        for (table, column) in feature.dependencies(record_type) {
+            let (schema, relation) = table
+                .split_once('.')
+                .expect("dependencies are schema-qualified");
+            let access: ColumnAccess = sqlx::query_as(
+                "SELECT n.oid IS NOT NULL AS schema_exists,
+                        has_schema_privilege(n.oid, 'USAGE') AS schema_usage,
+                        c.oid IS NOT NULL AS table_exists,
+                        a.attnum IS NOT NULL AS column_exists,
+                        CASE WHEN a.attnum IS NOT NULL
+                             THEN has_column_privilege(c.oid, a.attnum, 'SELECT') END AS column_select,
+                        c.relrowsecurity
+                            AND (c.relforcerowsecurity OR c.relowner <> r.oid)
+                            AND NOT (r.rolsuper OR r.rolbypassrls) AS row_security
+                 FROM pg_roles r
+                 LEFT JOIN pg_namespace n ON n.nspname = $1
+                 LEFT JOIN pg_class c ON c.relnamespace = n.oid AND c.relname = $2
+                 LEFT JOIN pg_attribute a ON a.attrelid = c.oid AND a.attname = $3 AND NOT a.attisdropped
+                 WHERE r.rolname = current_user",
+            )
+            .bind(schema)
+            .bind(relation)
+            .bind(column)
+            .fetch_one(pool)
+            .await?;
+
+            let problem = if !access.schema_exists {
+                Some(format!("schema {} does not exist", schema))
+            } else if access.schema_usage != Some(true) {
+                Some(format!("no USAGE on schema {}", schema))
+            } else if !access.table_exists {
+                Some(format!("table {} does not exist", table))
+            } else if !access.column_exists {
+                Some(format!("column {}.{} does not exist", table, column))
+            } else if access.column_select != Some(true) {
+                Some(format!("no SELECT on {}.{}", table, column))
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                if !problems.contains(&problem) {
+                    problems.push(problem);
+                }
+            }
+            if access.row_security == Some(true) {
+                row_security.insert(table);
+            }
+        }
+        if !problems.is_empty() {
+            unmet.push(Unmet { feature, problems });
+        }
+    }
+
+    // Row-level security hides rows rather than failing, so it only merits a warning
+    for table in row_security {
+        warn!(
+            "Row-level security applies to {} for this role; rows hidden by its policies will not be counted or exported",
+            table
+        );
+    }
+
+    let fatal: Vec<&Unmet> = unmet
+        .iter()
+        .filter(|u| !(degrade && u.feature.degradable()))
+        .collect();
+    if !fatal.is_empty() {
+        let mut report = String::from("The database role cannot read what this run needs:");
+        for u in &fatal {
+            report.push_str(&format!(
+                "\n  {}: {}",
+                u.feature.name(),
+                u.problems.join("; ")
+            ));
+            if u.feature == Feature::DisplayFields {
+                report.push_str(
+                    "\n    (display fields need Evergreen 3.2 or later with the display field map configured)",
+                );
+            }
+        }
+        let optional: Vec<String> = fatal
+            .iter()
+            .filter(|u| u.feature.degradable())
+            .map(|u| u.feature.name())
+            .collect();
+        if !optional.is_empty() {
+            report.push_str(&format!(
+                "\n--degrade-gracefully would run without {} instead",
+                optional.join(", ")
+            ));
+        }
+        bail!(report);
+    }
+
+    for u in &unmet {
+        warn!("Disabling {}: {}", u.feature.name(), u.problems.join("; "));
+    }
+    Ok(Capabilities { disabled: unmet })
+}
@@ -0,0 +1,334 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::db::RecordType;
+use crate::filter::RecordFilter;
+use crate::plan::ChunkPlan;
+use crate::sink::OutputFormat;
+
+/// Checkpoint format version, bumped on incompatible changes
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// How often the writer saves a checkpoint; each save syncs the output to disk first
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How far an interrupted run got, for --resume
+///
+/// Everything it describes was on disk when it was saved: the output holds exactly the
+/// records up to `after_id` plus those in `handled`, in the first `point.offset` bytes
+/// of the file being written.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub version: u32,
+    /// Every selected record with an id at or below this has been dealt with; `None`
+    /// when the run had not finished any part of the selection from its start
+    pub after_id: Option<i64>,
+    /// Records above `after_id` dealt with already, by chunks that ran ahead
+    pub handled: Vec<i64>,
+    /// Records written to the output so far, across every resumed run
+    pub records_written: u64,
+    /// --output, or the template of a split output
+    pub output: PathBuf,
+    pub format: OutputFormat,
+    pub records_per_file: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_bytes: Option<u64>,
+    /// The output is written as NAME.part until the run succeeds, rather than in place
+    #[serde(default)]
+    pub atomic: bool,
+    pub point: OutputPoint,
+    /// Byte length of the --quarantine-file, when there is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantine_offset: Option<u64>,
+    /// Kind of record the run extracts; resuming with the other is refused
+    #[serde(default)]
+    pub record_type: RecordType,
+    /// Selection the run was started with; resuming with another one is refused
+    pub filter: RecordFilter,
+    /// The --fields of a --format csv run, whose header names them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub columns: Option<String>,
+}
+
+/// Where the writer had got to in its output
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct OutputPoint {
+    /// Number of the file being written, when the output is split
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<u64>,
+    /// Records in the file being written; only kept for a split output
+    pub records_in_file: u64,
+    /// Bytes of that file holding whole records
+    pub offset: u64,
+}
+
+impl Checkpoint {
+    /// The checkpoint of a run that has yet to write anything
+    pub fn start(
+        output: PathBuf,
+        format: OutputFormat,
+        records_per_file: Option<u64>,
+        max_file_bytes: Option<u64>,
+        atomic: bool,
+        record_type: RecordType,
+        filter: RecordFilter,
+    ) -> Self {
+        Self {
+            version: CHECKPOINT_VERSION,
+            after_id: None,
+            handled: Vec::new(),
+            records_written: 0,
+            output,
+            format,
+            records_per_file,
+            max_file_bytes,
+            atomic,
+            point: OutputPoint {
+                file: (records_per_file.is_some() || max_file_bytes.is_some()).then_some(1),
+                records_in_file: 0,
+                offset: 0,
+            },
+            quarantine_offset: None,
+            record_type,
+            filter,
+            columns: None,
+        }
+    }
+
+    /// Load a checkpoint, or `None` if there is none to resume
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).context(format!("Failed to read checkpoint: {}", path.display()))
+            }
+        };
+
+        let checkpoint: Self = serde_json::from_str(&text)
+            .context(format!("Invalid checkpoint: {}", path.display()))?;
+        if checkpoint.version != CHECKPOINT_VERSION {
+            bail!(
+                "Checkpoint {} has version {}, expected {}",
+                path.display(),
+                checkpoint.version,
+                CHECKPOINT_VERSION
+            );
+        }
+        Ok(Some(checkpoint))
+    }
+
+    /// Write the checkpoint via a temporary file, synced before it replaces the old one,
+    /// so a crash leaves either checkpoint whole
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        let context = || format!("Failed to write checkpoint: {}", tmp.display());
+        let mut file = std::fs::File::create(&tmp).with_context(context)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)
+            .with_context(context)?;
+        file.sync_all().with_context(context)?;
+        std::fs::rename(&tmp, path)
+            .context(format!("Failed to write checkpoint: {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Which records the writer has dealt with, reduced to the id below which all of them
+/// have been
+///
+/// Chunks finish out of order and their records reach the writer interleaved, so a
+/// chunk only counts once its worker has said how many records it sent and the writer
+/// has dealt with that many. The id advances over the leading run of such chunks. A
+/// stream delivers records in id order, so each one advances it.
+pub struct Progress {
+    state: Mutex<ProgressState>,
+}
+
+struct ProgressState {
+    /// Records come from one stream rather than chunks
+    streamed: bool,
+    /// Last id of each planned chunk, in chunk order, which is id order
+    last_ids: Vec<i64>,
+    /// Records each chunk's worker sent, once it has finished
+    sent: Vec<Option<u64>>,
+    /// Records of each chunk the writer has dealt with
+    handled: Vec<u64>,
+    /// Chunks before this one are all done
+    next: usize,
+    after_id: Option<i64>,
+    /// Ids above `after_id` the writer has dealt with
+    ahead: BTreeSet<i64>,
+    stream_ended: bool,
+}
+
+impl Progress {
+    /// Progress through the chunks of `plan`, which is cut after `after_id`
+    pub fn chunked(plan: &ChunkPlan, after_id: Option<i64>) -> Self {
+        let last_ids: Vec<i64> = plan
+            .chunks
+            .iter()
+            .map(|chunk| chunk.range.last.id)
+            .collect();
+        let chunks = last_ids.len();
+        Self::new(
+            false,
+            last_ids,
+            vec![None; chunks],
+            vec![0; chunks],
+            after_id,
+        )
+    }
+
+    /// Progress through one stream of records in id order, from after `after_id`
+    pub fn streamed(after_id: Option<i64>) -> Self {
+        Self::new(true, Vec::new(), Vec::new(), Vec::new(), after_id)
+    }
+
+    fn new(
+        streamed: bool,
+        last_ids: Vec<i64>,
+        sent: Vec<Option<u64>>,
+        handled: Vec<u64>,
+        after_id: Option<i64>,
+    ) -> Self {
+        Self {
+            state: Mutex::new(ProgressState {
+                streamed,
+                last_ids,
+                sent,
+                handled,
+                next: 0,
+                after_id,
+                ahead: BTreeSet::new(),
+                stream_ended: false,
+            }),
+        }
+    }
+
+    /// A chunk's worker has handed the writer all `sent` of its records
+    pub fn chunk_sent(&self, chunk: usize, sent: u64) {
+        self.state.lock().unwrap().sent[chunk] = Some(sent);
+    }
+
+    /// The stream has delivered every record it was going to
+    pub fn stream_ended(&self) {
+        self.state.lock().unwrap().stream_ended = true;
+    }
+
+    /// The writer has written, or rejected, record `id`
+    pub fn handled(&self, id: i64) {
+        let mut state = self.state.lock().unwrap();
+        if state.streamed {
+            state.after_id = Some(id);
+            return;
+        }
+        let chunk = state.last_ids.partition_point(|&last| last < id);
+        if let Some(handled) = state.handled.get_mut(chunk) {
+            *handled += 1;
+        }
+        state.ahead.insert(id);
+    }
+
+    /// The id every record at or below has been dealt with, and the ids above it that
+    /// have been too
+    pub fn point(&self) -> (Option<i64>, Vec<i64>) {
+        let mut state = self.state.lock().unwrap();
+        let mut advanced = false;
+        while state.next < state.last_ids.len()
+            && state.sent[state.next] == Some(state.handled[state.next])
+        {
+            state.next += 1;
+            advanced = true;
+        }
+        if advanced {
+            let after_id = state.last_ids[state.next - 1];
+            state.after_id = Some(after_id);
+            state.ahead = state.ahead.split_off(&(after_id + 1));
+        }
+        (state.after_id, state.ahead.iter().copied().collect())
+    }
+
+    /// Whether the whole selection has been dealt with, leaving nothing to resume
+    pub fn complete(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        if state.streamed {
+            return state.stream_ended;
+        }
+        (state.next..state.last_ids.len())
+            .all(|chunk| state.sent[chunk] == Some(state.handled[chunk]))
+    }
+}
+
+/// Saves the checkpoint for the writer task as records are dealt with
+pub struct Checkpointer {
+    path: PathBuf,
+    progress: Arc<Progress>,
+    /// What every save repeats: the output, format, and filter, and where the counts
+    /// and ids start from
+    base: Checkpoint,
+    /// Ids a resumed run has already written, to be passed over when fetched again
+    skip: HashSet<i64>,
+    last_saved: Instant,
+}
+
+impl Checkpointer {
+    /// Save to `path`, starting from `base`: the checkpoint being resumed, or an empty one
+    pub fn new(path: PathBuf, progress: Arc<Progress>, base: Checkpoint) -> Self {
+        Self {
+            path,
+            progress,
+            skip: base.handled.iter().copied().collect(),
+            base,
+            last_saved: Instant::now(),
+        }
+    }
+
+    /// Whether the run being resumed already wrote record `id`; it then counts as dealt with
+    pub fn already_handled(&mut self, id: i64) -> bool {
+        if !self.skip.remove(&id) {
+            return false;
+        }
+        self.progress.handled(id);
+        true
+    }
+
+    /// The writer has written, or rejected, record `id`
+    pub fn handled(&self, id: i64) {
+        self.progress.handled(id);
+    }
+
+    /// Whether it is time to sync the output and save
+    pub fn due(&self) -> bool {
+        self.last_saved.elapsed() >= CHECKPOINT_INTERVAL
+    }
+
+    /// Save how far the run has got; the output must have been synced to `point` first,
+    /// along with the --quarantine-file to `quarantine_offset`
+    pub fn save(
+        &mut self,
+        point: OutputPoint,
+        quarantine_offset: Option<u64>,
+        written: u64,
+    ) -> Result<()> {
+        let (after_id, mut handled) = self.progress.point();
+        // Records the resumed run wrote that have not been fetched again are still written
+        handled.extend(&self.skip);
+        handled.sort_unstable();
+        let checkpoint = Checkpoint {
+            after_id,
+            handled,
+            records_written: self.base.records_written + written,
+            point,
+            quarantine_offset,
+            ..self.base.clone()
+        };
+        checkpoint.save(&self.path)?;
+        self.last_saved = Instant::now();
+        Ok(())
+    }
+}
@@ -0,0 +1,320 @@
+use anyhow::{bail, Result};
+use sqlx::PgPool;
+use std::fmt;
+use std::str::FromStr;
+use tracing::{info, warn};
+
+use crate::capability::Feature;
+
+/// Evergreen releases member libraries run, and that the features are known to work on
+const SUPPORTED: (Version, Version) = (Version::new(3, 7), Version::new(3, 12));
+
+/// What a database was found, or assumed, to be running
+#[derive(Debug, Clone)]
+pub struct Versions {
+    /// As `server_version` reports it, e.g. "15.4 (Debian 15.4-1)"
+    pub postgres_label: String,
+    pub postgres: Version,
+    /// `None` when config.upgrade_log cannot be read or has no release entries
+    pub evergreen: Option<Version>,
+    /// Whether the Evergreen version came from --assume-version
+    pub assumed: bool,
+}
+
+/// A release, major and minor; patch levels make no difference to the schema
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    /// The major release, 3 in 3.9
+    pub major: u32,
+    /// The minor release, 9 in 3.9
+    pub minor: u32,
+}
+
+impl Version {
+    /// Release `major`.`minor`
+    pub const fn new(major: u32, minor: u32) -> Self {
+        Self { major, minor }
+    }
+
+    /// The version in `server_version_num`: 90624 is 9.6, 150004 is 15
+    fn from_server_version_num(num: u32) -> Self {
+        if num >= 100_000 {
+            Self::new(num / 10_000, 0)
+        } else {
+            Self::new(num / 10_000, num / 100 % 100)
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    /// "3.9", "3.9.2", and "3.9-beta" are all 3.9
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = |part: Option<&str>| {
+            let digits: String = part?.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse::<u32>().ok()
+        };
+        let mut parts = s.trim().split('.');
+        match (number(parts.next()), number(parts.next())) {
+            (Some(major), Some(minor)) => Ok(Self::new(major, minor)),
+            _ => Err(format!("expected a version such as 3.9, got {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// The software a requirement is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Component {
+    Evergreen,
+    Postgres,
+}
+
+impl Component {
+    /// A version as the component numbers its releases: PostgreSQL dropped the minor
+    /// number from the release name at 10
+    fn release(self, version: Version) -> String {
+        match self {
+            Component::Postgres if version.major >= 10 => version.major.to_string(),
+            _ => version.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Component {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Component::Evergreen => write!(f, "Evergreen"),
+            Component::Postgres => write!(f, "PostgreSQL"),
+        }
+    }
+}
+
+/// A feature that only exists from some release on
+struct Requirement {
+    applies: fn(Feature) -> bool,
+    component: Component,
+    since: Version,
+    /// What the release added, for the warning
+    added: &'static str,
+}
+
+/// What each feature needs beyond the columns the capability probe looks for
+///
+/// Knowledge about a new release goes here, and nowhere else.
+const REQUIREMENTS: &[Requirement] = &[
+    Requirement {
+        applies: |f| f == Feature::DisplayFields,
+        component: Component::Evergreen,
+        since: Version::new(3, 2),
+        added: "metabib.display_entry and the display field map",
+    },
+    Requirement {
+        applies: |f| f == Feature::PartitionPlan,
+        component: Component::Postgres,
+        since: Version::new(10, 0),
+        added: "declarative partitioning and pg_partitioned_table",
+    },
+];
+
+/// Find the PostgreSQL and Evergreen versions, and log them
+///
+/// Evergreen's version is the highest release recorded in config.upgrade_log, which
+/// also lists the numbered upgrade scripts applied between releases; those are skipped.
+/// `assume` stands in for it on installs where the log is missing or misleading.
+pub async fn detect(pool: &PgPool, assume: Option<Version>) -> Result<Versions> {
+    let (postgres_label, num): (String, String) = sqlx::query_as(
+        "SELECT current_setting('server_version'), current_setting('server_version_num')",
+    )
+    .fetch_one(pool)
+    .await?;
+    let postgres = Version::from_server_version_num(num.parse().unwrap_or(0));
+
+    let evergreen = match assume {
+        Some(version) => Some(version),
+        None => upgrade_log_version(pool).await,
+    };
+
+    let versions = Versions {
+        postgres_label,
+        postgres,
+        evergreen,
+        assumed: assume.is_some(),
+    };
+    match (versions.evergreen, versions.assumed) {
+        (Some(version), true) => info!("PostgreSQL {}; Evergreen {} (--assume-version)", versions.postgres_label, version),
+        (Some(version), false) => info!("PostgreSQL {}; Evergreen {}", versions.postgres_label, version),
+        (None, _) => warn!(
+            "PostgreSQL {}; the Evergreen version could not be read from config.upgrade_log, so features are not checked against it (--assume-version sets it)",
+            versions.postgres_label
+        ),
+    }
+    if let Some(version) = versions.evergreen {
+        if version < SUPPORTED.0 || version > SUPPORTED.1 {
+            warn!(
+                "Evergreen {} is outside the releases this tool is known to work with ({} to {}); features may fail partway through the run",
+                version, SUPPORTED.0, SUPPORTED.1
+            );
+        }
+    }
+    Ok(versions)
+}
+
+/// The highest release in config.upgrade_log, or `None` when it cannot be read
+async fn upgrade_log_version(pool: &PgPool) -> Option<Version> {
+    let versions: Vec<String> = match sqlx::query_scalar("SELECT version FROM config.upgrade_log")
+        .fetch_all(pool)
+        .await
+    {
+        Ok(versions) => versions,
+        Err(e) => {
+            info!("Could not read config.upgrade_log: {}", e);
+            return None;
+        }
+    };
+    // Upgrade scripts are bare numbers ("1326"); releases are dotted ("3.9.1")
+    versions
+        .iter()
+        .filter(|version| version.contains('.'))
+        .filter_map(|version| version.parse::<Version>().ok())
+        .max()
+}
+
+/// Take the features the database's versions lack out of `features`
+///
+/// Automatic features, and optional ones under `degrade`, are returned with the reason
+/// so the caller can disable them. A feature the run was asked for and cannot do without
+/// fails it now, rather than deep into the run.
+pub fn screen(
+    versions: &Versions,
+    features: &mut Vec<Feature>,
+    degrade: bool,
+) -> Result<Vec<(Feature, String)>> {
+    let mut missing = Vec::new();
+    let mut fatal = Vec::new();
+
+    features.retain(|&feature| {
+        let Some((requirement, found)) = REQUIREMENTS.iter().find_map(|r| {
+            let found = match r.component {
+                Component::Evergreen => versions.evergreen?,
+                Component::Postgres => versions.postgres,
+            };
+            ((r.applies)(feature) && found < r.since).then_some((r, found))
+        }) else {
+            return true;
+        };
+
+        let problem = format!(
+            "{} {} introduced {}, and the database is at {} {}",
+            requirement.component,
+            requirement.component.release(requirement.since),
+            requirement.added,
+            requirement.component,
+            requirement.component.release(found)
+        );
+        if feature == Feature::PartitionPlan || (degrade && feature.degradable()) {
+            missing.push((feature, problem));
+        } else {
+            fatal.push(format!("{}: {}", feature.name(), problem));
+        }
+        false
+    });
+
+    if !fatal.is_empty() {
+        let mut report = String::from("The database's versions lack what this run needs:");
+        for problem in &fatal {
+            report.push_str(&format!("\n  {}", problem));
+        }
+        if !versions.assumed {
+            report.push_str(
+                "\n--assume-version overrides the Evergreen version if it was detected wrongly",
+            );
+        }
+        bail!(report);
+    }
+    Ok(missing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions(postgres: Version, evergreen: Option<Version>) -> Versions {
+        Versions {
+            postgres_label: postgres.to_string(),
+            postgres,
+            evergreen,
+            assumed: false,
+        }
+    }
+
+    #[test]
+    fn versions_parse_whatever_the_patch_level() {
+        for text in ["3.9", "3.9.2", "3.9-beta", " 3.9 "] {
+            assert_eq!(
+                text.parse::<Version>(),
+                Ok(Version::new(3, 9)),
+                "{:?}",
+                text
+            );
+        }
+        for text in ["", "3", "three.nine", "3.x"] {
+            assert!(text.parse::<Version>().is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn server_version_num_drops_the_minor_from_10() {
+        assert_eq!(Version::from_server_version_num(90624), Version::new(9, 6));
+        assert_eq!(
+            Version::from_server_version_num(100023),
+            Version::new(10, 0)
+        );
+        assert_eq!(
+            Version::from_server_version_num(150004),
+            Version::new(15, 0)
+        );
+        assert_eq!(Component::Postgres.release(Version::new(9, 6)), "9.6");
+        assert_eq!(Component::Postgres.release(Version::new(15, 0)), "15");
+    }
+
+    #[test]
+    fn requirements_screen_out_what_older_releases_lack() {
+        let old = versions(Version::new(9, 6), Some(Version::new(3, 12)));
+        let mut features = vec![Feature::Extract, Feature::PartitionPlan];
+        let missing = screen(&old, &mut features, false).unwrap();
+        assert_eq!(features, vec![Feature::Extract]);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].0, Feature::PartitionPlan);
+        assert!(missing[0].1.contains("PostgreSQL 10"), "{}", missing[0].1);
+
+        let current = versions(Version::new(15, 0), Some(Version::new(3, 12)));
+        let mut features = vec![
+            Feature::Extract,
+            Feature::PartitionPlan,
+            Feature::DisplayFields,
+        ];
+        assert!(screen(&current, &mut features, false).unwrap().is_empty());
+        assert_eq!(features.len(), 3);
+
+        // Without a detected Evergreen version, only PostgreSQL requirements apply
+        let unknown = versions(Version::new(15, 0), None);
+        let mut features = vec![Feature::DisplayFields];
+        assert!(screen(&unknown, &mut features, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_required_feature_the_release_lacks_fails_the_run() {
+        let old = versions(Version::new(15, 0), Some(Version::new(3, 1)));
+        let mut features = vec![Feature::DisplayFields];
+        let error = screen(&old, &mut features, false).unwrap_err().to_string();
+        assert!(error.contains("Evergreen 3.2"), "{}", error);
+        assert!(error.contains("--assume-version"), "{}", error);
+    }
+}
@@ -0,0 +1,274 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use std::fmt;
+use std::io::{Read, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// How MARC values are compressed in the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MarcCompression {
+    /// Plain text column
+    None,
+    /// Gzip-compressed bytea
+    Gzip,
+    /// Zstandard-compressed bytea
+    Zstd,
+    /// Bytea holding a mix of gzip, zstd, and uncompressed values, told apart by magic bytes
+    Auto,
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Decode a stored MARC value into text
+pub fn decompress(data: &[u8], compression: MarcCompression) -> Result<String> {
+    let compression = match compression {
+        MarcCompression::Auto if data.starts_with(GZIP_MAGIC) => MarcCompression::Gzip,
+        MarcCompression::Auto if data.starts_with(ZSTD_MAGIC) => MarcCompression::Zstd,
+        MarcCompression::Auto => MarcCompression::None,
+        other => other,
+    };
+
+    let bytes = match compression {
+        MarcCompression::Gzip => {
+            let mut out = Vec::with_capacity(data.len() * 4);
+            flate2::read::MultiGzDecoder::new(data)
+                .read_to_end(&mut out)
+                .context("invalid gzip data")?;
+            out
+        }
+        MarcCompression::Zstd => zstd::decode_all(data).context("invalid zstd data")?,
+        _ => data.to_vec(),
+    };
+
+    String::from_utf8(bytes).context("decompressed MARC is not valid UTF-8")
+}
+
+/// How the output is compressed as it is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputCompression {
+    None,
+    Gzip,
+    /// Zstandard, with a checksum in the frame
+    Zstd,
+}
+
+impl OutputCompression {
+    /// What an output named `path` is compressed with when --compress is not given
+    pub fn for_path(path: Option<&Path>) -> Self {
+        match path.and_then(|p| p.extension()) {
+            Some(extension) if extension == "gz" => OutputCompression::Gzip,
+            Some(extension) if extension == "zst" => OutputCompression::Zstd,
+            _ => OutputCompression::None,
+        }
+    }
+
+    /// The method as --compress names it
+    fn name(self) -> &'static str {
+        match self {
+            OutputCompression::None => "none",
+            OutputCompression::Gzip => "gzip",
+            OutputCompression::Zstd => "zstd",
+        }
+    }
+
+    /// The levels the method has, and the one used when none is given
+    fn levels(self) -> Option<(RangeInclusive<i32>, i32)> {
+        match self {
+            OutputCompression::None => None,
+            OutputCompression::Gzip => Some((0..=9, 6)),
+            OutputCompression::Zstd => Some((1..=22, zstd::DEFAULT_COMPRESSION_LEVEL)),
+        }
+    }
+}
+
+/// How the output is compressed: the method, and the level for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub method: OutputCompression,
+    level: i32,
+}
+
+impl Compression {
+    pub const NONE: Self = Self {
+        method: OutputCompression::None,
+        level: 0,
+    };
+
+    /// `method` at `level`, or at its default level without one
+    pub fn new(method: OutputCompression, level: Option<i32>) -> Result<Self> {
+        let Some((levels, default)) = method.levels() else {
+            if level.is_some() {
+                bail!("--compress-level needs --compress, or an --output ending in .gz or .zst");
+            }
+            return Ok(Self::NONE);
+        };
+        let level = level.unwrap_or(default);
+        if !levels.contains(&level) {
+            bail!(
+                "--compress-level {} is out of range for {}, which takes {} to {}",
+                level,
+                method.name(),
+                levels.start(),
+                levels.end()
+            );
+        }
+        Ok(Self { method, level })
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.method {
+            OutputCompression::None => write!(f, "none"),
+            method => write!(f, "{} level {}", method.name(), self.level),
+        }
+    }
+}
+
+/// Compresses output in memory as it is written, for the writer to pass on
+pub enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    /// An encoder for `compression`, or `None` when the output is not compressed
+    pub fn new(compression: Compression) -> std::io::Result<Option<Self>> {
+        let level = compression.level;
+        match compression.method {
+            OutputCompression::None => Ok(None),
+            OutputCompression::Gzip => Ok(Some(Encoder::Gzip(GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(level as u32),
+            )))),
+            OutputCompression::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), level)?;
+                encoder.include_checksum(true)?;
+                Ok(Some(Encoder::Zstd(encoder)))
+            }
+        }
+    }
+
+    /// Compress `bytes`, returning the compressed bytes ready to be written so far
+    pub fn write(&mut self, bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(bytes)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(bytes)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    /// End the stream, returning what remains to be written: the gzip trailer, or the
+    /// end of the zstd frame with its checksum
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XML: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+        <collection xmlns=\"http://www.loc.gov/MARC21/slim\">\n\
+        <record><controlfield tag=\"001\">1</controlfield></record>\n\
+        <record><datafield tag=\"245\" ind1=\"0\" ind2=\"0\"><subfield code=\"a\">Caf\u{e9}</subfield></datafield></record>\n\
+        </collection>\n";
+
+    /// `XML` compressed as the writer would, a line at a time
+    fn encode(method: OutputCompression) -> Vec<u8> {
+        let mut encoder = Encoder::new(Compression::new(method, None).unwrap())
+            .unwrap()
+            .unwrap();
+        let mut out = Vec::new();
+        for line in XML.split_inclusive('\n') {
+            out.extend(encoder.write(line.as_bytes()).unwrap());
+        }
+        out.extend(encoder.finish().unwrap());
+        out
+    }
+
+    #[test]
+    fn gzip_output_decompresses_to_the_xml() {
+        let compressed = encode(OutputCompression::Gzip);
+        assert!(compressed.starts_with(GZIP_MAGIC));
+        assert_eq!(decompress(&compressed, MarcCompression::Gzip).unwrap(), XML);
+        assert_eq!(decompress(&compressed, MarcCompression::Auto).unwrap(), XML);
+    }
+
+    #[test]
+    fn zstd_output_decompresses_to_the_xml() {
+        let compressed = encode(OutputCompression::Zstd);
+        assert!(compressed.starts_with(ZSTD_MAGIC));
+        assert_eq!(decompress(&compressed, MarcCompression::Zstd).unwrap(), XML);
+        assert_eq!(decompress(&compressed, MarcCompression::Auto).unwrap(), XML);
+    }
+
+    #[test]
+    fn truncated_output_fails_to_decompress() {
+        for (method, stored) in [
+            (OutputCompression::Gzip, MarcCompression::Gzip),
+            (OutputCompression::Zstd, MarcCompression::Zstd),
+        ] {
+            let compressed = encode(method);
+            let truncated = &compressed[..compressed.len() - 4];
+            assert!(decompress(truncated, stored).is_err(), "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn uncompressed_values_pass_through() {
+        assert_eq!(
+            decompress(XML.as_bytes(), MarcCompression::None).unwrap(),
+            XML
+        );
+        assert_eq!(
+            decompress(XML.as_bytes(), MarcCompression::Auto).unwrap(),
+            XML
+        );
+        assert!(decompress(&[0xff, 0xfe], MarcCompression::None).is_err());
+    }
+
+    #[test]
+    fn levels_are_checked_against_the_method() {
+        assert_eq!(
+            Compression::new(OutputCompression::Zstd, None)
+                .unwrap()
+                .to_string(),
+            format!("zstd level {}", zstd::DEFAULT_COMPRESSION_LEVEL)
+        );
+        assert_eq!(
+            Compression::new(OutputCompression::Gzip, Some(9))
+                .unwrap()
+                .to_string(),
+            "gzip level 9"
+        );
+        assert!(Compression::new(OutputCompression::Gzip, Some(10)).is_err());
+        assert!(Compression::new(OutputCompression::Zstd, Some(0)).is_err());
+        assert!(Compression::new(OutputCompression::None, Some(3)).is_err());
+        assert_eq!(
+            Compression::new(OutputCompression::None, None).unwrap(),
+            Compression::NONE
+        );
+    }
+
+    #[test]
+    fn the_extension_picks_the_method() {
+        let method = |path: &str| OutputCompression::for_path(Some(Path::new(path)));
+        assert_eq!(method("out.xml.gz"), OutputCompression::Gzip);
+        assert_eq!(method("out.xml.zst"), OutputCompression::Zstd);
+        assert_eq!(method("out.xml"), OutputCompression::None);
+        assert_eq!(OutputCompression::for_path(None), OutputCompression::None);
+    }
+}
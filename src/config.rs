@@ -0,0 +1,213 @@
+use anyhow::{bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Options that only make sense on the command line, never in a --config file
+const COMMAND_LINE_ONLY: [&str; 4] = ["config", "print_config", "help", "version"];
+
+/// Options whose values are database URLs, masked when printed
+const URL_OPTIONS: [&str; 2] = ["db_url", "sink_url"];
+
+/// The command line with the options of a --config file added to it
+///
+/// Each key of the file is an option's name with underscores (`chunk_size`, `db_url`),
+/// and its value is given as if on the command line: a string or number for an option
+/// taking a value, an array for one that is repeatable, and true or false for a flag. A
+/// key is only used when its option was given neither on the command line nor through
+/// its environment variable, so that the command line wins over the environment, which
+/// wins over the file.
+pub struct Merged {
+    pub args: Vec<OsString>,
+    pub path: Option<PathBuf>,
+    /// Options whose value came from the file
+    pub from_file: HashSet<String>,
+    /// Keys of the file that are no option, to be warned of once logging is up
+    pub unknown: Vec<String>,
+}
+
+impl Merged {
+    /// Add the options of the --config file named on the command line, if there is one
+    pub fn load(command: Command, args: Vec<OsString>) -> Result<Self> {
+        let mut merged = Self {
+            args,
+            path: None,
+            from_file: HashSet::new(),
+            unknown: Vec::new(),
+        };
+
+        // A first, lenient pass finds --config and what the command line and environment
+        // set; anything it cannot parse is left to the real parse to report
+        let Ok(matches) = command
+            .clone()
+            .ignore_errors(true)
+            .try_get_matches_from(&merged.args)
+        else {
+            return Ok(merged);
+        };
+        let Some(path) = matches.get_one::<PathBuf>("config").cloned() else {
+            return Ok(merged);
+        };
+        let table = read(&path)?;
+
+        let mut added = Vec::new();
+        for (key, value) in &table {
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_id() == key.as_str() && arg.get_long().is_some());
+            let Some(arg) = arg.filter(|_| !COMMAND_LINE_ONLY.contains(&key.as_str())) else {
+                merged.unknown.push(key.clone());
+                continue;
+            };
+            if matches!(
+                matches.value_source(key),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            ) {
+                continue;
+            }
+
+            let long = arg.get_long().unwrap_or_default();
+            match (arg.get_action(), value) {
+                (ArgAction::SetTrue, toml::Value::Boolean(set)) => {
+                    if *set {
+                        added.push(OsString::from(format!("--{}", long)));
+                    }
+                }
+                (ArgAction::SetTrue, _) => {
+                    bail!("{} in {} must be true or false", key, path.display())
+                }
+                (_, toml::Value::Array(values)) => {
+                    for value in values {
+                        added.push(OsString::from(format!(
+                            "--{}={}",
+                            long,
+                            scalar(&path, key, value)?
+                        )));
+                    }
+                }
+                (_, value) => added.push(OsString::from(format!(
+                    "--{}={}",
+                    long,
+                    scalar(&path, key, value)?
+                ))),
+            }
+            merged.from_file.insert(key.clone());
+        }
+
+        // After the program name, so that the command line's own options still end it
+        let at = merged.args.len().min(1);
+        merged.args.splice(at..at, added);
+        merged.path = Some(path);
+        Ok(merged)
+    }
+
+    /// Every option a --config file can set, for the warning about keys that are not one
+    pub fn valid_keys(command: &Command) -> Vec<String> {
+        command
+            .get_arguments()
+            .filter(|arg| arg.get_long().is_some())
+            .map(|arg| arg.get_id().to_string())
+            .filter(|id| !COMMAND_LINE_ONLY.contains(&id.as_str()))
+            .collect()
+    }
+
+    /// Print the options the run would use as a --config file, each with where its value
+    /// came from, and database passwords masked
+    pub fn print(&self, command: &Command, matches: &ArgMatches) {
+        for (id, value, source) in self.effective(command, matches) {
+            println!("{} = {}  # {}", id, value, source);
+        }
+    }
+
+    /// The options the run would use as a --config file table, passwords masked
+    pub fn table(&self, command: &Command, matches: &ArgMatches) -> toml::Table {
+        self.effective(command, matches)
+            .into_iter()
+            .map(|(id, value, _)| (id, value))
+            .collect()
+    }
+
+    /// Every option with a value, as a --config file would give it, with where the value
+    /// came from
+    fn effective(
+        &self,
+        command: &Command,
+        matches: &ArgMatches,
+    ) -> Vec<(String, toml::Value, &'static str)> {
+        let mut options = Vec::new();
+        for arg in command
+            .get_arguments()
+            .filter(|arg| arg.get_long().is_some())
+        {
+            let id = arg.get_id().as_str();
+            if COMMAND_LINE_ONLY.contains(&id) {
+                continue;
+            }
+            let Some(raw) = matches.get_raw(id) else {
+                continue;
+            };
+            let mut values: Vec<toml::Value> = raw
+                .map(|value| {
+                    let value = value.to_string_lossy();
+                    if URL_OPTIONS.contains(&id) {
+                        toml::Value::String(marc_extractor_rs::mask_password(&value))
+                    } else {
+                        typed(&value)
+                    }
+                })
+                .collect();
+            let value = match arg.get_action() {
+                ArgAction::Append => toml::Value::Array(values),
+                _ if values.len() > 1 => toml::Value::Array(values),
+                _ => match values.pop() {
+                    Some(value) => value,
+                    None => continue,
+                },
+            };
+            let source = match matches.value_source(id) {
+                _ if self.from_file.contains(id) => "config file",
+                Some(ValueSource::CommandLine) => "command line",
+                Some(ValueSource::EnvVariable) => "environment",
+                _ => "default",
+            };
+            options.push((id.to_string(), value, source));
+        }
+        options
+    }
+}
+
+/// Read a --config file into its table of options
+fn read(path: &Path) -> Result<toml::Table> {
+    let text = std::fs::read_to_string(path)
+        .context(format!("Failed to read config file: {}", path.display()))?;
+    text.parse::<toml::Table>()
+        .context(format!("Invalid config file: {}", path.display()))
+}
+
+/// A value of the file as the command line would give it
+fn scalar(path: &Path, key: &str, value: &toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(text) => Ok(text.clone()),
+        toml::Value::Integer(number) => Ok(number.to_string()),
+        toml::Value::Float(number) => Ok(number.to_string()),
+        toml::Value::Boolean(set) => Ok(set.to_string()),
+        toml::Value::Datetime(time) => Ok(time.to_string()),
+        toml::Value::Array(_) | toml::Value::Table(_) => {
+            bail!("{} in {} must be a single value", key, path.display())
+        }
+    }
+}
+
+/// A value as the command line gave it, a number or boolean when it reads as one
+fn typed(value: &str) -> toml::Value {
+    if let Ok(number) = value.parse::<i64>() {
+        return toml::Value::Integer(number);
+    }
+    match value {
+        "true" => toml::Value::Boolean(true),
+        "false" => toml::Value::Boolean(false),
+        _ => toml::Value::String(value.to_string()),
+    }
+}
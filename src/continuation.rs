@@ -0,0 +1,101 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::db::RecordType;
+use crate::filter::RecordFilter;
+
+/// Token format version, bumped on incompatible changes
+const TOKEN_VERSION: u32 = 2;
+
+/// Where a chain of batched runs has got to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Token {
+    pub version: u32,
+    /// Every selected record with an id at or below this has been extracted
+    pub last_id: i64,
+    /// Number of batches completed so far
+    pub batch: u64,
+    /// Records written across all completed batches
+    pub records_written: u64,
+    /// Kind of record the chain extracts; continuing with the other is refused
+    #[serde(default)]
+    pub record_type: RecordType,
+    /// Selection the chain was started with; continuing with another one is refused
+    pub filter: RecordFilter,
+    /// Number the next batch's first file of a split output takes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_file: Option<u64>,
+    /// The --manifest each batch is added to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<PathBuf>,
+}
+
+impl Token {
+    /// Load a token, or `None` if the chain has not started yet
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).context(format!(
+                    "Failed to read continuation token: {}",
+                    path.display()
+                ))
+            }
+        };
+
+        let token: Self = serde_json::from_str(&text)
+            .context(format!("Invalid continuation token: {}", path.display()))?;
+        if token.version != TOKEN_VERSION {
+            bail!(
+                "Continuation token {} has version {}, expected {}",
+                path.display(),
+                token.version,
+                TOKEN_VERSION
+            );
+        }
+
+        Ok(Some(token))
+    }
+
+    /// Write the token via a temporary file so a crash never leaves it half-written
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?).context(format!(
+            "Failed to write continuation token: {}",
+            tmp.display()
+        ))?;
+        std::fs::rename(&tmp, path).context(format!(
+            "Failed to write continuation token: {}",
+            path.display()
+        ))?;
+        Ok(())
+    }
+
+    /// Number of the batch that continues from this token, or the first of a new chain
+    pub fn batch_after(token: Option<&Self>) -> u64 {
+        token.map_or(0, |t| t.batch) + 1
+    }
+
+    pub fn next(
+        previous: Option<&Self>,
+        last_id: i64,
+        written: u64,
+        record_type: RecordType,
+        filter: &RecordFilter,
+        next_file: Option<u64>,
+        manifest: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            version: TOKEN_VERSION,
+            last_id,
+            batch: Self::batch_after(previous),
+            records_written: previous.map_or(0, |t| t.records_written) + written,
+            record_type,
+            filter: filter.clone(),
+            next_file,
+            manifest,
+        }
+    }
+}
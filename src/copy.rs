@@ -0,0 +1,118 @@
+use anyhow::{bail, Result};
+
+/// A field of a COPY row, `None` for NULL
+pub type Field = Option<Vec<u8>>;
+
+/// Splits the text format of COPY ... TO STDOUT into rows as its data arrives
+///
+/// Each row is one line with its fields separated by tabs. A newline, tab, or backslash
+/// inside a value is escaped with a backslash, so a raw newline always ends a row
+/// however much MARC the row holds, and a row can be split off as soon as its newline
+/// has arrived, whatever chunk of data it came in.
+#[derive(Default)]
+pub struct CopyText {
+    data: Vec<u8>,
+    /// Where the first row not yet split off starts in `data`
+    start: usize,
+}
+
+impl CopyText {
+    /// Add the next chunk of data from the server
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.data.drain(..self.start);
+        self.start = 0;
+        self.data.extend_from_slice(chunk);
+    }
+
+    /// The next whole row of the data added so far
+    pub fn next_row(&mut self) -> Option<Vec<Field>> {
+        let end = self.start + self.data[self.start..].iter().position(|&b| b == b'\n')?;
+        let line = &self.data[self.start..end];
+        self.start = end + 1;
+        Some(line.split(|&b| b == b'\t').map(field).collect())
+    }
+
+    /// Check that the data ended with a whole row
+    pub fn finish(&self) -> Result<()> {
+        if self.start < self.data.len() {
+            bail!("COPY data ended partway through a row");
+        }
+        Ok(())
+    }
+}
+
+/// A field as the server wrote it, unescaped
+fn field(raw: &[u8]) -> Field {
+    if raw == b"\\N" {
+        return None;
+    }
+    if !raw.contains(&b'\\') {
+        return Some(raw.to_vec());
+    }
+
+    let mut value = Vec::with_capacity(raw.len());
+    let mut i = 0;
+    while i < raw.len() {
+        if raw[i] != b'\\' || i + 1 == raw.len() {
+            value.push(raw[i]);
+            i += 1;
+            continue;
+        }
+        let escaped = raw[i + 1];
+        i += 2;
+        match escaped {
+            b'b' => value.push(0x08),
+            b'f' => value.push(0x0c),
+            b'n' => value.push(b'\n'),
+            b'r' => value.push(b'\r'),
+            b't' => value.push(b'\t'),
+            b'v' => value.push(0x0b),
+            b'0'..=b'7' => {
+                // Up to three octal digits
+                let mut byte = (escaped - b'0') as u32;
+                let mut digits = 1;
+                while digits < 3 && i < raw.len() && matches!(raw[i], b'0'..=b'7') {
+                    byte = byte * 8 + (raw[i] - b'0') as u32;
+                    i += 1;
+                    digits += 1;
+                }
+                value.push(byte as u8);
+            }
+            b'x' if i < raw.len() && raw[i].is_ascii_hexdigit() => {
+                // One or two hex digits
+                let mut byte = hex_digit(raw[i]);
+                i += 1;
+                if i < raw.len() && raw[i].is_ascii_hexdigit() {
+                    byte = byte * 16 + hex_digit(raw[i]);
+                    i += 1;
+                }
+                value.push(byte);
+            }
+            other => value.push(other),
+        }
+    }
+    Some(value)
+}
+
+/// Decode a bytea value in the hex format the server writes it in: \x followed by
+/// two hex digits per byte
+pub fn bytea(value: &[u8]) -> Result<Vec<u8>> {
+    let Some(hex) = value.strip_prefix(b"\\x") else {
+        bail!("bytea value is not in hex format; set bytea_output = 'hex'");
+    };
+    if hex.len() % 2 != 0 || !hex.iter().all(u8::is_ascii_hexdigit) {
+        bail!("bytea value is not valid hex");
+    }
+    Ok(hex
+        .chunks(2)
+        .map(|pair| hex_digit(pair[0]) * 16 + hex_digit(pair[1]))
+        .collect())
+}
+
+fn hex_digit(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::Result;
+
+use crate::db::MarcRecord;
+use crate::fields::FieldSpec;
+use crate::marc;
+use crate::quarantine::Rejection;
+use crate::writer::{prepare_record, Encoded, Encoder};
+
+/// Encoder for --format csv: a header row, then a row per record with its id and the
+/// columns of a --fields spec
+///
+/// Rows follow RFC 4180: lines end in CRLF, and a value holding a comma, quote, or line
+/// break is quoted, its quotes doubled.
+pub struct CsvEncoder {
+    spec: FieldSpec,
+    /// What the values of repeated fields are joined with
+    separator: String,
+}
+
+impl CsvEncoder {
+    pub fn new(spec: FieldSpec, separator: String) -> Self {
+        Self { spec, separator }
+    }
+}
+
+impl Encoder for CsvEncoder {
+    fn header(&self) -> Vec<u8> {
+        let mut header = vec!["id".to_string()];
+        header.extend(self.spec.columns.iter().map(|column| column.label.clone()));
+        row(&header)
+    }
+
+    /// The record's row, line end included; one whose MARC does not parse is rejected
+    fn encode(&self, record: &MarcRecord) -> Result<Encoded> {
+        let cleaned_marc = match prepare_record(record) {
+            Ok(cleaned) => cleaned,
+            Err(rejection) => return Ok(Err(rejection)),
+        };
+        let Some(parsed) = marc::parse_record(&cleaned_marc) else {
+            let reason = "not parseable MARCXML, so no fields can be extracted";
+            return Ok(Err(Rejection::new("encode", reason)));
+        };
+        let mut values = vec![record.id.to_string()];
+        values.extend(self.spec.values(record, &parsed, &self.separator));
+        Ok(Ok(row(&values)))
+    }
+}
+
+/// One CSV line of `values`
+fn row(values: &[String]) -> Vec<u8> {
+    let mut line = values
+        .iter()
+        .map(|value| csv_field(value))
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str("\r\n");
+    line.into_bytes()
+}
+
+/// Quote a CSV field when it holds a comma, quote, or line break
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
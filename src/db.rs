@@ -1,30 +1,654 @@
-use anyhow::{Context, Result};
-use sqlx::{PgPool, Row};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgArguments, PgPoolCopyExt, PgRow};
+use sqlx::{Arguments, Encode, PgConnection, PgPool, Postgres, Row, Type};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{Interval, MissedTickBehavior};
 use tracing::debug;
 
+use crate::compression::{self, MarcCompression};
+use crate::copy::{self, CopyText, Field};
+use crate::display::DisplayEntry;
+use crate::filter::RecordFilter;
+use crate::gaps::IdSet;
+use crate::histogram::{BucketCount, Histogram, HistogramSpec};
+use crate::quarantine::Rejection;
+
 /// MARC record from database
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MarcRecord {
+    /// The record's id in its table
     pub id: i64,
+    /// The record as MARCXML
     pub marc: String,
+    /// Set when a worker has already rejected the record
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rejection: Option<Rejection>,
+    /// Display field values, when --with-display-fields asked for them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub display: Vec<DisplayEntry>,
+    /// Evergreen's own columns about the record, when the run asked for them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<RecordMeta>,
+    /// The TCN and source columns, when --add-901 asked for them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identifiers: Option<Identifiers>,
+    /// The edit date and deleted flag, when --envelope oai asked for them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<RecordStatus>,
+}
+
+/// Columns of biblio.record_entry carried alongside the MARC
+///
+/// Evergreen computes the fingerprint and quality when a record is ingested; records
+/// loaded before that, or never reingested, have them null, and they stay `None` here.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordMeta {
+    pub fingerprint: Option<String>,
+    pub quality: Option<i32>,
+    pub edit_date: DateTime<Utc>,
+}
+
+/// Columns of biblio.record_entry an --add-901 field is made from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identifiers {
+    pub tcn_value: Option<String>,
+    pub tcn_source: Option<String>,
+    /// The config.bib_source id
+    pub source: Option<i32>,
+}
+
+/// Columns of the record table an OAI-PMH record header is made from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordStatus {
+    pub edit_date: DateTime<Utc>,
+    pub deleted: bool,
 }
 
 /// Database configuration
 #[derive(Debug, Clone)]
 pub struct DatabaseConfig {
-    pub include_deleted: bool,
+    /// Which kind of record, and so which table, is extracted
+    pub record_type: RecordType,
+    pub filter: RecordFilter,
     pub chunk_size: i64,
+    /// Only select records with an id above this
+    pub after_id: Option<i64>,
+    /// Only select records with an id at or below this
+    pub max_id: Option<i64>,
+    /// How the marc column is compressed
+    pub marc_compression: MarcCompression,
+    /// Order records are chunked and fetched in
+    pub order_by: OrderBy,
+    /// Also fetch each record's fingerprint, quality, and edit date
+    pub with_meta: bool,
+    /// Also fetch each record's TCN value, TCN source, and bib source
+    pub with_identifiers: bool,
+    /// Also fetch each record's edit date and deleted flag
+    pub with_status: bool,
+    /// Fetch the ids alone, for --ids-only; records then come back with empty MARC
+    pub ids_only: bool,
+    /// Fetch no more of a chunk than it was planned to hold, so a --limit stays exact
+    /// when rows land in a chunk's range after it was planned
+    pub capped: bool,
+}
+
+impl DatabaseConfig {
+    /// The table records are extracted from
+    pub fn table(&self) -> &'static str {
+        self.record_type.table()
+    }
+
+    /// The select list of the queries that fetch records
+    fn record_select(&self) -> String {
+        self.record_select_from(self.table())
+    }
+
+    /// The same, reading one partition of the record table directly
+    fn record_select_from(&self, table: &str) -> String {
+        if self.ids_only {
+            return format!("SELECT id FROM {}", table);
+        }
+        let mut columns = String::from("id, marc");
+        if self.with_meta {
+            columns.push_str(", fingerprint, quality");
+        }
+        if self.with_meta || self.with_status {
+            columns.push_str(", edit_date");
+        }
+        if self.with_status {
+            columns.push_str(", deleted");
+        }
+        if self.with_identifiers {
+            columns.push_str(", tcn_value, tcn_source, source");
+        }
+        format!("SELECT {} FROM {}", columns, table)
+    }
+}
+
+/// Which of Evergreen's record tables a run extracts from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordType {
+    /// Bibliographic records, from biblio.record_entry
+    #[default]
+    Bib,
+    /// Authority records, from authority.record_entry
+    Authority,
+}
+
+impl RecordType {
+    /// The table records of this type are kept in
+    pub fn table(self) -> &'static str {
+        match self {
+            RecordType::Bib => "biblio.record_entry",
+            RecordType::Authority => "authority.record_entry",
+        }
+    }
+
+    /// The Vandelay queues records of this type are imported through
+    pub fn import_queue_table(self) -> &'static str {
+        match self {
+            RecordType::Bib => "vandelay.bib_queue",
+            RecordType::Authority => "vandelay.authority_queue",
+        }
+    }
+
+    /// The records queued in them, with the record each was imported as
+    pub fn queued_record_table(self) -> &'static str {
+        match self {
+            RecordType::Bib => "vandelay.queued_bib_record",
+            RecordType::Authority => "vandelay.queued_authority_record",
+        }
+    }
+
+    /// The name --record-type takes for this type
+    pub fn name(self) -> &'static str {
+        match self {
+            RecordType::Bib => "bib",
+            RecordType::Authority => "authority",
+        }
+    }
+}
+
+/// How the record table is partitioned, as far as planning chunks goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Partitioning {
+    /// A plain table
+    None,
+    /// Range partitioned on id at every level, so each leaf partition holds an id range
+    /// no other leaf overlaps
+    RangeById,
+    /// Partitioned some other way, with the partition keys of each level
+    Other(Vec<String>),
+}
+
+/// A leaf partition of the record table and how many selected records it holds
+#[derive(Debug, Clone)]
+pub struct Partition {
+    /// Qualified and quoted as regclass prints it, so it can go straight into SQL
+    pub table: String,
+    pub records: i64,
+}
+
+/// How the selected records are read from the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ReadMode {
+    /// Plan chunks by key range and fetch them in parallel
+    #[default]
+    Chunks,
+    /// One query over a single connection, its rows written as they arrive
+    Stream,
+    /// One COPY ... TO STDOUT over a single connection, parsed as it arrives
+    Copy,
+}
+
+/// Column the selection is ordered by when it is cut into chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum OrderBy {
+    #[default]
+    Id,
+    CreateDate,
+    TcnValue,
+}
+
+impl OrderBy {
+    /// The record table column this orders by
+    pub fn column(self) -> &'static str {
+        match self {
+            OrderBy::Id => "id",
+            OrderBy::CreateDate => "create_date",
+            OrderBy::TcnValue => "tcn_value",
+        }
+    }
+
+    /// ORDER BY list; ties are broken by id so that every record has its own place
+    fn sql(self) -> &'static str {
+        match self {
+            OrderBy::Id => "id",
+            OrderBy::CreateDate => "create_date, id",
+            OrderBy::TcnValue => "tcn_value, id",
+        }
+    }
+
+    /// The column's type, for casting a key read back as text
+    fn sql_type(self) -> &'static str {
+        match self {
+            OrderBy::Id => "bigint",
+            OrderBy::CreateDate => "timestamptz",
+            OrderBy::TcnValue => "text",
+        }
+    }
+}
+
+/// A record's place in the --order-by order: the column's value, as text, and its id
+///
+/// In id order the id is the whole key and `value` is `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortKey {
+    pub value: Option<String>,
+    pub id: i64,
+}
+
+/// The records of one chunk: every selected record from `first` to `last` in the
+/// --order-by order, both included
+#[derive(Debug, Clone)]
+pub struct ChunkRange {
+    pub order_by: OrderBy,
+    pub first: SortKey,
+    pub last: SortKey,
+    /// Position of `first` in its table's selection when the run was planned
+    pub offset: i64,
+    /// Records the range held when the run was planned
+    pub records: i64,
+}
+
+impl std::fmt::Display for ChunkRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.first.value, &self.last.value) {
+            (Some(first), Some(last)) => write!(
+                f,
+                "by {} from {:?} (id {}) to {:?} (id {})",
+                self.order_by.column(),
+                first,
+                self.first.id,
+                last,
+                self.last.id
+            ),
+            _ => write!(f, "ids {}..={}", self.first.id, self.last.id),
+        }
+    }
+}
+
+/// Rows buffered between the database and the consumer in streaming mode
+const STREAM_BUFFER: usize = 256;
+
+/// Paces database fetches so that at most one starts per interval, across all workers
+#[derive(Debug)]
+pub struct Throttle {
+    interval: Mutex<Interval>,
+}
+
+impl Throttle {
+    pub fn new(period: Duration) -> Self {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            interval: Mutex::new(interval),
+        }
+    }
+
+    /// Wait until the next fetch is allowed to start
+    pub async fn wait(&self) {
+        self.interval.lock().await.tick().await;
+    }
+}
+
+/// SQL text and bound arguments for a query over the selected records
+struct RecordQuery {
+    sql: String,
+    args: PgArguments,
+}
+
+impl RecordQuery {
+    /// Start `select` with a WHERE clause choosing the configured records
+    fn new(select: &str, config: &DatabaseConfig) -> Result<Self> {
+        let mut query = Self {
+            sql: format!("{} WHERE true", select),
+            args: PgArguments::default(),
+        };
+
+        if config.filter.deleted_only {
+            query.push(" AND deleted = true");
+        } else if !config.filter.include_deleted {
+            query.push(" AND deleted = false");
+        }
+        if !config.filter.editors.is_empty() {
+            query
+                .push(" AND editor = ANY(")
+                .push_bind(config.filter.editors.clone())?
+                .push(")");
+        }
+        if !config.filter.import_queues.is_empty() {
+            query
+                .push(&format!(
+                    " AND id IN (SELECT imported_as FROM {} WHERE queue = ANY(",
+                    config.record_type.queued_record_table()
+                ))
+                .push_bind(config.filter.import_queues.clone())?
+                .push(") AND imported_as IS NOT NULL)");
+        }
+        if !config.filter.sources.is_empty() {
+            query
+                .push(" AND source = ANY(")
+                .push_bind(config.filter.sources.clone())?
+                .push(")");
+        }
+        if !config.filter.org_units.is_empty() {
+            query
+                .push(" AND id IN (SELECT record FROM asset.call_number WHERE NOT deleted AND owning_lib = ANY(")
+                .push_bind(config.filter.org_units.clone())?
+                .push("))");
+        }
+        if let Some(id) = config.filter.min_id {
+            query.push(" AND id >= ").push_bind(id)?;
+        }
+        if let Some(id) = config.filter.max_id {
+            query.push(" AND id <= ").push_bind(id)?;
+        }
+        if let Some(since) = config.filter.since {
+            query
+                .push(&format!(" AND {} >= ", since.field.column()))
+                .push_bind(since.time)?;
+        }
+        if let Some(predicate) = &config.filter.predicate {
+            query.push(" AND (").push(predicate).push(")");
+        }
+        if let Some(id) = config.after_id {
+            query.push(" AND id > ").push_bind(id)?;
+        }
+        if let Some(id) = config.max_id {
+            query.push(" AND id <= ").push_bind(id)?;
+        }
+
+        Ok(query)
+    }
+
+    fn push(&mut self, sql: &str) -> &mut Self {
+        self.sql.push_str(sql);
+        self
+    }
+
+    /// Bind a value and append its placeholder
+    fn push_bind<T>(&mut self, value: T) -> Result<&mut Self>
+    where
+        T: Encode<'static, Postgres> + Type<Postgres> + Send + 'static,
+    {
+        self.args.add(value).map_err(|e| anyhow!(e))?;
+        self.sql.push_str(&format!("${}", self.args.len()));
+        Ok(self)
+    }
+
+    /// The SQL with the bound values written into it, for COPY, which takes no parameters
+    ///
+    /// The server's format() quotes each value as a literal from the same bound
+    /// arguments, so the text reads them as the placeholders would have.
+    async fn inline(self, pool: &PgPool) -> Result<String> {
+        let Self { sql, mut args } = self;
+        let bound = args.len();
+        // Highest first, so that $1 is not taken for the start of $12
+        let mut template = sql.replace('%', "%%");
+        for n in (1..=bound).rev() {
+            template = template.replace(&format!("${}", n), &format!("%{}$L", n));
+        }
+        args.add(template).map_err(|e| anyhow!(e))?;
+
+        let values: Vec<String> = (1..=bound).map(|n| format!("${}", n)).collect();
+        let call = if values.is_empty() {
+            format!("SELECT format(${})", bound + 1)
+        } else {
+            format!("SELECT format(${}, {})", bound + 1, values.join(", "))
+        };
+        sqlx::query_scalar_with(&call, args)
+            .fetch_one(pool)
+            .await
+            .context("Failed to write the selection's values into the COPY query")
+    }
+}
+
+/// Resolve --editor values, each a username or an actor.usr id, to user ids
+pub async fn resolve_editors(pool: &PgPool, editors: &[String]) -> Result<Vec<i64>> {
+    let mut ids = Vec::with_capacity(editors.len());
+
+    for editor in editors {
+        let id: Option<i64> = match editor.parse::<i64>() {
+            Ok(id) => Some(id),
+            Err(_) => sqlx::query_scalar("SELECT id::bigint FROM actor.usr WHERE usrname = $1")
+                .bind(editor)
+                .fetch_optional(pool)
+                .await
+                .context(format!("Failed to look up editor {:?}", editor))?,
+        };
+        match id {
+            Some(id) => ids.push(id),
+            None => bail!("Unknown editor: no user named {:?}", editor),
+        }
+    }
+
+    Ok(ids)
+}
+
+/// Resolve --source values, each a config.bib_source name or id, to source ids
+///
+/// Every value is checked against the sources the database has, and those are listed
+/// when one is not among them.
+pub async fn resolve_sources(pool: &PgPool, sources: &[String]) -> Result<Vec<i64>> {
+    if sources.is_empty() {
+        return Ok(Vec::new());
+    }
+    let known = bib_sources(pool).await?;
+
+    let mut ids = Vec::with_capacity(sources.len());
+    let mut unknown = Vec::new();
+    for source in sources {
+        let found = known
+            .iter()
+            .find(|(id, name)| name == source || source.parse::<i64>() == Ok(*id));
+        match found {
+            Some((id, _)) => ids.push(*id),
+            None => unknown.push(format!("{:?}", source)),
+        }
+    }
+    if !unknown.is_empty() {
+        let available: Vec<String> = known
+            .iter()
+            .map(|(id, name)| format!("{} ({})", name, id))
+            .collect();
+        bail!(
+            "Unknown source(s): {}; the database has: {}",
+            unknown.join(", "),
+            if available.is_empty() {
+                "none".to_string()
+            } else {
+                available.join(", ")
+            }
+        );
+    }
+
+    Ok(ids)
+}
+
+/// Every config.bib_source, as its id and name, in id order
+pub async fn bib_sources(pool: &PgPool) -> Result<Vec<(i64, String)>> {
+    sqlx::query_as("SELECT id::bigint, source FROM config.bib_source ORDER BY id")
+        .fetch_all(pool)
+        .await
+        .context("Failed to look up bib sources")
+}
+
+/// Resolve --org-unit values, each an actor.org_unit shortname or id, to org unit ids,
+/// with every unit below them too when `descendants` is set
+///
+/// Shortnames match whatever their case. A value that matches no org unit is reported
+/// with the shortnames closest to it.
+pub async fn resolve_org_units(
+    pool: &PgPool,
+    org_units: &[String],
+    descendants: bool,
+) -> Result<Vec<i64>> {
+    if org_units.is_empty() {
+        return Ok(Vec::new());
+    }
+    let known: Vec<(i64, Option<i64>, String)> = sqlx::query_as(
+        "SELECT id::bigint, parent_ou::bigint, shortname FROM actor.org_unit ORDER BY id",
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to look up org units")?;
+
+    let mut ids = Vec::with_capacity(org_units.len());
+    let mut unknown = Vec::new();
+    for org_unit in org_units {
+        let found = known.iter().find(|(id, _, name)| {
+            name.eq_ignore_ascii_case(org_unit) || org_unit.parse::<i64>() == Ok(*id)
+        });
+        match found {
+            Some((id, _, _)) => ids.push(*id),
+            None => {
+                let wanted = org_unit.to_ascii_uppercase();
+                let mut close: Vec<(usize, &str)> = known
+                    .iter()
+                    .map(|(_, _, name)| {
+                        (
+                            edit_distance(&wanted, &name.to_ascii_uppercase()),
+                            name.as_str(),
+                        )
+                    })
+                    .filter(|(distance, _)| *distance <= 2)
+                    .collect();
+                close.sort();
+                let names: Vec<&str> = close.iter().take(5).map(|(_, name)| *name).collect();
+                if names.is_empty() {
+                    unknown.push(format!("{:?}", org_unit));
+                } else {
+                    unknown.push(format!(
+                        "{:?} (did you mean {}?)",
+                        org_unit,
+                        names.join(", ")
+                    ));
+                }
+            }
+        }
+    }
+    if !unknown.is_empty() {
+        bail!("Unknown org unit(s): {}", unknown.join(", "));
+    }
+
+    if descendants {
+        // Walk down the tree a level at a time from the units given
+        let mut level = ids.clone();
+        while !level.is_empty() {
+            level = known
+                .iter()
+                .filter(|(id, parent, _)| {
+                    parent.is_some_and(|p| level.contains(&p)) && !ids.contains(id)
+                })
+                .map(|(id, _, _)| *id)
+                .collect();
+            ids.extend(&level);
+        }
+    }
+    ids.sort_unstable();
+    ids.dedup();
+    Ok(ids)
+}
+
+/// Edits (insertions, deletions, substitutions) that turn one string into another
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Check that the users, import queues, sources, and org units a filter refers to exist,
+/// and that its --where predicate is SQL the record table can be queried with
+pub async fn validate_filter(
+    pool: &PgPool,
+    filter: &RecordFilter,
+    record_type: RecordType,
+) -> Result<()> {
+    let lookups = [
+        ("user", "actor.usr", &filter.editors),
+        (
+            "import queue",
+            record_type.import_queue_table(),
+            &filter.import_queues,
+        ),
+        ("source", "config.bib_source", &filter.sources),
+        ("org unit", "actor.org_unit", &filter.org_units),
+    ];
+
+    for (kind, table, ids) in lookups {
+        if ids.is_empty() {
+            continue;
+        }
+        let found: Vec<i64> = sqlx::query_scalar(&format!(
+            "SELECT id::bigint FROM {} WHERE id = ANY($1)",
+            table
+        ))
+        .bind(ids)
+        .fetch_all(pool)
+        .await
+        .context(format!("Failed to look up {} ids in {}", kind, table))?;
+        let unknown: Vec<String> = ids
+            .iter()
+            .filter(|id| !found.contains(id))
+            .map(|id| id.to_string())
+            .collect();
+        if !unknown.is_empty() {
+            bail!("Unknown {} id(s): {}", kind, unknown.join(", "));
+        }
+    }
+
+    if let Some(predicate) = &filter.predicate {
+        // The queries bind their own values as $1, $2, ..., which the predicate would read
+        let mut chars = predicate.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '$' && chars.peek().is_some_and(char::is_ascii_digit) {
+                bail!("--where cannot use $1-style parameters; write the values in as literals");
+            }
+        }
+        // Planned but not run, so a mistake costs nothing to find
+        sqlx::query(&format!(
+            "EXPLAIN SELECT id FROM {} WHERE ({})",
+            record_type.table(),
+            predicate
+        ))
+        .execute(pool)
+        .await
+        .context(format!("Invalid --where predicate {:?}", predicate))?;
+    }
+
+    Ok(())
 }
 
 /// Get total count of records to process
-pub async fn get_record_count(pool: &PgPool, include_deleted: bool) -> Result<i64> {
-    let query = if include_deleted {
-        "SELECT COUNT(*) FROM biblio.record_entry"
-    } else {
-        "SELECT COUNT(*) FROM biblio.record_entry WHERE deleted = false"
-    };
+pub async fn get_record_count(pool: &PgPool, config: &DatabaseConfig) -> Result<i64> {
+    let query = RecordQuery::new(&format!("SELECT COUNT(*) FROM {}", config.table()), config)?;
 
-    let count: i64 = sqlx::query_scalar(query)
+    let count: i64 = sqlx::query_scalar_with(&query.sql, query.args)
         .fetch_one(pool)
         .await
         .context("Failed to count records")?;
@@ -32,95 +656,823 @@ pub async fn get_record_count(pool: &PgPool, include_deleted: bool) -> Result<i6
     Ok(count)
 }
 
-/// Fetch a chunk of MARC records
-pub async fn fetch_records(
+/// Count the selected records per date bucket with a grouped query
+pub async fn date_histogram(
     pool: &PgPool,
     config: &DatabaseConfig,
-    offset: i64,
-) -> Result<Vec<MarcRecord>> {
-    debug!("Fetching chunk at offset {}", offset);
-
-    let query = if config.include_deleted {
-        r#"
-        SELECT id, marc
-        FROM biblio.record_entry
-        ORDER BY id
-        LIMIT $1 OFFSET $2
-        "#
+    spec: HistogramSpec,
+) -> Result<Histogram> {
+    let select = format!(
+        "SELECT to_char(date_trunc('{}', {}), '{}') AS bucket, COUNT(*) FROM {}",
+        spec.bucket.unit(),
+        spec.field.column(),
+        spec.bucket.label_format(),
+        config.table()
+    );
+    let mut query = RecordQuery::new(&select, config)?;
+    query.push(" GROUP BY 1 ORDER BY 1");
+
+    let rows: Vec<(Option<String>, i64)> = sqlx::query_as_with(&query.sql, query.args)
+        .fetch_all(pool)
+        .await
+        .context(format!("Failed to count records by {}", spec))?;
+
+    Ok(Histogram {
+        field: spec.field,
+        bucket: spec.bucket,
+        counts: rows
+            .into_iter()
+            .map(|(bucket, records)| BucketCount { bucket, records })
+            .collect(),
+    })
+}
+
+/// Find where a batch of at most `batch_max` selected records ends
+///
+/// Returns the last id in the batch (if any records are selected at all) and whether
+/// more selected records remain after it. Without a cap the batch runs to the end.
+pub async fn batch_end(
+    pool: &PgPool,
+    config: &DatabaseConfig,
+    batch_max: Option<i64>,
+) -> Result<(Option<i64>, bool)> {
+    if let Some(batch_max) = batch_max {
+        let mut query = RecordQuery::new(&format!("SELECT id FROM {}", config.table()), config)?;
+        query
+            .push(" ORDER BY id OFFSET ")
+            .push_bind(batch_max - 1)?
+            .push(" LIMIT 2");
+
+        let ids: Vec<i64> = sqlx::query_scalar_with(&query.sql, query.args)
+            .fetch_all(pool)
+            .await
+            .context("Failed to find the end of the batch")?;
+
+        match ids[..] {
+            [last, _] => return Ok((Some(last), true)),
+            [last] => return Ok((Some(last), false)),
+            _ => {}
+        }
+    }
+
+    let query = RecordQuery::new(&format!("SELECT max(id) FROM {}", config.table()), config)?;
+    let last: Option<i64> = sqlx::query_scalar_with(&query.sql, query.args)
+        .fetch_one(pool)
+        .await
+        .context("Failed to find the highest record id")?;
+
+    Ok((last, false))
+}
+
+/// Find out whether and how `table` is declaratively partitioned
+///
+/// The partitioned tables of the tree are found by walking pg_inherits down from
+/// `table`; their keys come from pg_partitioned_table.
+pub async fn partitioning(pool: &PgPool, table: &str) -> Result<Partitioning> {
+    let keys: Vec<String> = sqlx::query_scalar(
+        "WITH RECURSIVE tree(relid) AS (
+             SELECT $1::regclass::oid
+             UNION ALL
+             SELECT i.inhrelid FROM pg_inherits i JOIN tree t ON i.inhparent = t.relid
+         )
+         SELECT pg_get_partkeydef(p.partrelid)
+         FROM tree JOIN pg_partitioned_table p ON p.partrelid = tree.relid",
+    )
+    .bind(table)
+    .fetch_all(pool)
+    .await
+    .context(format!("Failed to look up the partitions of {}", table))?;
+
+    Ok(if keys.is_empty() {
+        Partitioning::None
+    } else if keys.iter().all(|key| key == "RANGE (id)") {
+        Partitioning::RangeById
     } else {
-        r#"
-        SELECT id, marc
-        FROM biblio.record_entry
-        WHERE deleted = false
-        ORDER BY id
-        LIMIT $1 OFFSET $2
-        "#
-    };
+        Partitioning::Other(keys)
+    })
+}
 
-    let rows = sqlx::query(query)
-        .bind(config.chunk_size)
-        .bind(offset)
+/// Count the selected records in each leaf partition, in id order of the partitions
+///
+/// One grouped query, no dearer than the plain count it stands in for. Partitions with
+/// no selected records are left out.
+pub async fn partition_counts(pool: &PgPool, config: &DatabaseConfig) -> Result<Vec<Partition>> {
+    let mut query = RecordQuery::new(
+        &format!(
+            "SELECT tableoid::regclass::text, COUNT(*), min(id) FROM {}",
+            config.table()
+        ),
+        config,
+    )?;
+    query.push(" GROUP BY tableoid ORDER BY 3");
+
+    let rows: Vec<(String, i64, i64)> = sqlx::query_as_with(&query.sql, query.args)
         .fetch_all(pool)
         .await
-        .context(format!("Failed to fetch records at offset {}", offset))?;
+        .context("Failed to count records per partition")?;
 
-    let mut records = Vec::with_capacity(rows.len());
+    Ok(rows
+        .into_iter()
+        .map(|(table, records, _)| Partition { table, records })
+        .collect())
+}
 
-    for row in rows {
-        let id: i64 = row.try_get("id")?;
-        let marc: Option<String> = row.try_get("marc")?;
-
-        if let Some(marc_data) = marc {
-            // Only include records that have MARC data
-            if !marc_data.trim().is_empty() {
-                records.push(MarcRecord {
-                    id,
-                    marc: marc_data,
+/// Cut the first `limit` selected records of `table` into chunks of `chunk_size`, in order
+///
+/// One pass over the selection's keys, in the index order where there is one, reads
+/// the first and last key of every chunk; only those rows come back. Each chunk is then
+/// fetched as a range of keys, which the index finds directly, where an offset would
+/// make the database walk past every record before it.
+pub async fn chunk_ranges(
+    pool: &PgPool,
+    config: &DatabaseConfig,
+    table: &str,
+    limit: i64,
+) -> Result<Vec<ChunkRange>> {
+    let order_by = config.order_by;
+    let value = match order_by {
+        OrderBy::Id => "NULL::text".to_string(),
+        order => format!("{}::text", order.column()),
+    };
+    let select = format!(
+        "SELECT value, id, position, last_row FROM (\
+         SELECT {} AS value, id, row_number() OVER w - 1 AS position, lead(id) OVER w IS NULL AS last_row \
+         FROM {}",
+        value, table
+    );
+    let mut query = RecordQuery::new(&select, config)?;
+    let size = config.chunk_size;
+    query
+        .push(&format!(" WINDOW w AS (ORDER BY {}) ORDER BY {} LIMIT ", order_by.sql(), order_by.sql()))
+        .push_bind(limit)?
+        .push(&format!(
+            ") keys WHERE position % {size} = 0 OR position % {size} = {} OR last_row OR position = ",
+            size - 1
+        ))
+        .push_bind(limit - 1)?
+        .push(" ORDER BY position");
+
+    let rows: Vec<(Option<String>, i64, i64, bool)> = sqlx::query_as_with(&query.sql, query.args)
+        .fetch_all(pool)
+        .await
+        .context(format!("Failed to plan the chunks of {}", table))?;
+
+    let mut ranges = Vec::with_capacity(rows.len() / 2 + 1);
+    let mut open: Option<(SortKey, i64)> = None;
+    for (value, id, position, last_row) in rows {
+        if order_by != OrderBy::Id && value.is_none() {
+            bail!(
+                "Record {} has no {}, so --order-by {} cannot place it in a chunk",
+                id,
+                order_by.column(),
+                order_by.column()
+            );
+        }
+        let key = SortKey { value, id };
+        if position % size == 0 {
+            open = Some((key.clone(), position));
+        }
+        if position % size == size - 1 || last_row || position == limit - 1 {
+            if let Some((first, offset)) = open.take() {
+                ranges.push(ChunkRange {
+                    order_by,
+                    first,
+                    last: key,
+                    offset,
+                    records: position - offset + 1,
                 });
-            } else {
-                debug!("Skipping record {} - empty MARC data", id);
             }
-        } else {
-            debug!("Skipping record {} - NULL MARC data", id);
         }
     }
+    Ok(ranges)
+}
+
+/// Whether fetching a chunk needs a full sort of the selection, as it does when no
+/// index provides the --order-by order
+///
+/// Sorting is discouraged for the EXPLAIN, so that an index the planner would pass
+/// over on a small table still shows up.
+pub async fn chunk_query_sorts(pool: &PgPool, config: &DatabaseConfig) -> Result<bool> {
+    let mut query = RecordQuery::new(&format!("EXPLAIN {}", config.record_select()), config)?;
+    query
+        .push(" ORDER BY ")
+        .push(config.order_by.sql())
+        .push(" LIMIT ")
+        .push_bind(config.chunk_size)?;
+
+    // Set for the session and put back after, rather than in a transaction of its own,
+    // since under --consistent the connection is in the snapshot's transaction already
+    let mut conn = pool.acquire().await?;
+    let enable_sort: String = sqlx::query_scalar("SELECT current_setting('enable_sort')")
+        .fetch_one(&mut *conn)
+        .await?;
+    sqlx::query("SET enable_sort = off")
+        .execute(&mut *conn)
+        .await?;
+    let plan = sqlx::query_scalar_with(&query.sql, query.args)
+        .fetch_all(&mut *conn)
+        .await
+        .context("Failed to explain the chunk query");
+    sqlx::query("SELECT set_config('enable_sort', $1, false)")
+        .bind(&enable_sort)
+        .execute(&mut *conn)
+        .await?;
+    let plan: Vec<String> = plan?;
+
+    // Node lines read "Sort  (cost=..."; an index on the leading column alone leaves an
+    // Incremental Sort, which is cheap
+    Ok(plan.iter().any(|line| {
+        line.trim_start()
+            .trim_start_matches("->")
+            .trim_start()
+            .starts_with("Sort  (")
+    }))
+}
+
+/// Fetch a chunk of MARC records from `table`, the record table or one of its partitions
+pub async fn fetch_records(
+    conn: &mut PgConnection,
+    config: &DatabaseConfig,
+    table: &str,
+    range: &ChunkRange,
+) -> Result<Vec<MarcRecord>> {
+    debug!("Fetching chunk of {}, {}", table, range);
+
+    let mut query = RecordQuery::new(&config.record_select_from(table), config)?;
+    match (&range.first.value, &range.last.value) {
+        (Some(first), Some(last)) => {
+            // A row comparison, which an index on (column, id) or the column alone serves
+            let (column, kind) = (range.order_by.column(), range.order_by.sql_type());
+            query
+                .push(&format!(" AND ({}, id) >= (", column))
+                .push_bind(first.clone())?
+                .push(&format!("::{}, ", kind))
+                .push_bind(range.first.id)?
+                .push(&format!(") AND ({}, id) <= (", column))
+                .push_bind(last.clone())?
+                .push(&format!("::{}, ", kind))
+                .push_bind(range.last.id)?
+                .push(")");
+        }
+        _ => {
+            query
+                .push(" AND id BETWEEN ")
+                .push_bind(range.first.id)?
+                .push(" AND ")
+                .push_bind(range.last.id)?;
+        }
+    }
+    query.push(" ORDER BY ").push(config.order_by.sql());
+    if config.capped {
+        query.push(" LIMIT ").push_bind(range.records)?;
+    }
+
+    let started = std::time::Instant::now();
+    let rows = sqlx::query_with(&query.sql, query.args)
+        .fetch_all(conn)
+        .await
+        .context(format!("Failed to fetch records of {}, {}", table, range))?;
+    let elapsed = started.elapsed();
+
+    let mut records = Vec::with_capacity(rows.len());
+
+    for row in rows {
+        records.extend(record_from_row(&row, config)?);
+    }
 
-    debug!("Fetched {} records at offset {}", records.len(), offset);
+    debug!(
+        "Fetched {} records of {}, {} in {}ms",
+        records.len(),
+        table,
+        range,
+        elapsed.as_millis()
+    );
 
     Ok(records)
 }
 
-/// Optimized version using cursor for streaming (alternative approach)
-/// This can be used for even better memory efficiency
-#[allow(dead_code)]
-pub async fn stream_records(
+/// Fetch the first `limit` selected rows in the --order-by order, for --dry-run; rows
+/// without MARC data are left out, so fewer records can come back
+pub async fn sample_records(
+    pool: &PgPool,
+    config: &DatabaseConfig,
+    limit: i64,
+) -> Result<Vec<MarcRecord>> {
+    let mut query = RecordQuery::new(&config.record_select(), config)?;
+    query.push(" ORDER BY ").push(config.order_by.sql());
+    query.push(" LIMIT ").push_bind(limit)?;
+
+    let rows = sqlx::query_with(&query.sql, query.args)
+        .fetch_all(pool)
+        .await
+        .context("Failed to fetch sample records")?;
+
+    let mut records = Vec::with_capacity(rows.len());
+    for row in rows {
+        records.extend(record_from_row(&row, config)?);
+    }
+    Ok(records)
+}
+
+/// Stream records over a single connection instead of fetching chunks
+/// This trades parallelism for minimal memory and database load
+pub fn stream_records(
     pool: &PgPool,
     config: &DatabaseConfig,
 ) -> Result<impl futures::Stream<Item = Result<MarcRecord>>> {
     use futures::stream::TryStreamExt;
 
-    let query = if config.include_deleted {
-        "SELECT id, marc FROM biblio.record_entry ORDER BY id"
-    } else {
-        "SELECT id, marc FROM biblio.record_entry WHERE deleted = false ORDER BY id"
-    };
+    let mut query = RecordQuery::new(&config.record_select(), config)?;
+    query.push(" ORDER BY ").push(config.order_by.sql());
+
+    // The row stream borrows the query text, so it is driven from its own task
+    let (tx, rx) = tokio::sync::mpsc::channel(STREAM_BUFFER);
+    let pool = pool.clone();
+    let config = config.clone();
 
-    let stream = sqlx::query(query)
-        .fetch(pool)
-        .map_err(anyhow::Error::from)
-        .and_then(|row| async move {
-            let id: i64 = row.try_get("id")?;
-            let marc: Option<String> = row.try_get("marc")?;
+    tokio::spawn(async move {
+        let RecordQuery { sql, args } = query;
+        let mut rows = sqlx::query_with(&sql, args).fetch(&pool);
 
-            if let Some(marc_data) = marc {
-                if !marc_data.trim().is_empty() {
-                    return Ok(Some(MarcRecord { id, marc: marc_data }));
+        loop {
+            let item = match rows.try_next().await {
+                Ok(Some(row)) => match record_from_row(&row, &config) {
+                    Ok(Some(record)) => Ok(record),
+                    Ok(None) => continue,
+                    Err(e) => Err(e),
+                },
+                Ok(None) => break,
+                Err(e) => Err(anyhow::Error::from(e)),
+            };
+
+            let failed = item.is_err();
+            if tx.send(item).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    Ok(tokio_stream::wrappers::ReceiverStream::new(rx))
+}
+
+/// Check that the server lets this connection run COPY ... TO STDOUT, for --mode copy
+pub async fn check_copy(pool: &PgPool) -> Result<()> {
+    use futures::stream::TryStreamExt;
+
+    let mut data = pool
+        .copy_out_raw("COPY (SELECT 1) TO STDOUT")
+        .await
+        .context("The server does not allow COPY ... TO STDOUT on this connection; --mode stream reads the same records without it")?;
+    while data.try_next().await?.is_some() {}
+    Ok(())
+}
+
+/// Stream the first `limit` selected records with one COPY ... TO STDOUT, parsing its
+/// text output as it arrives
+///
+/// This is the stream's query run as a COPY, whose rows come back without the per-row
+/// protocol overhead of a query's. Only the id and MARC columns are read; the server
+/// is asked for no more than `limit` rows, since a COPY cannot be stopped partway
+/// except by closing its connection.
+pub async fn copy_records(
+    pool: &PgPool,
+    config: &DatabaseConfig,
+    limit: i64,
+) -> Result<impl futures::Stream<Item = Result<MarcRecord>>> {
+    use futures::stream::TryStreamExt;
+
+    if config.with_meta || config.with_identifiers || config.with_status {
+        bail!("A COPY reads only each record's id and MARC");
+    }
+    let mut query = RecordQuery::new(&config.record_select(), config)?;
+    query
+        .push(" ORDER BY ")
+        .push(config.order_by.sql())
+        .push(" LIMIT ")
+        .push_bind(limit)?;
+    let statement = format!("COPY ({}) TO STDOUT", query.inline(pool).await?);
+    debug!("Copying records: {}", statement);
+
+    let mut data = pool.copy_out_raw(&statement).await.context(
+        "Failed to start COPY ... TO STDOUT; --mode stream reads the same records without it",
+    )?;
+
+    let (tx, rx) = tokio::sync::mpsc::channel(STREAM_BUFFER);
+    let config = config.clone();
+
+    tokio::spawn(async move {
+        let mut text = CopyText::default();
+        loop {
+            let chunk = match data.try_next().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => {
+                    if let Err(e) = text.finish() {
+                        let _ = tx.send(Err(e)).await;
+                    }
+                    break;
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(anyhow::Error::from(e))).await;
+                    break;
+                }
+            };
+            text.push(&chunk);
+
+            while let Some(row) = text.next_row() {
+                let item = match record_from_copy(row, &config) {
+                    Ok(Some(record)) => Ok(record),
+                    Ok(None) => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = item.is_err();
+                if tx.send(item).await.is_err() || failed {
+                    return;
                 }
             }
+        }
+    });
+
+    Ok(tokio_stream::wrappers::ReceiverStream::new(rx))
+}
+
+/// Build a record from a row of a COPY of `DatabaseConfig::record_select`, as
+/// `record_from_row` does from a query's
+fn record_from_copy(row: Vec<Field>, config: &DatabaseConfig) -> Result<Option<MarcRecord>> {
+    let mut fields = row.into_iter();
+    let id = match fields.next().flatten() {
+        Some(id) => std::str::from_utf8(&id)?.parse::<i64>()?,
+        None => bail!("COPY row without an id"),
+    };
+    if config.ids_only {
+        return Ok(Some(MarcRecord {
+            id,
+            marc: String::new(),
+            rejection: None,
+            display: Vec::new(),
+            meta: None,
+            identifiers: None,
+            status: None,
+        }));
+    }
+
+    let Some(marc) = fields.next() else {
+        bail!("COPY row for record {} without a marc column", id);
+    };
+    let marc = match (marc, config.marc_compression) {
+        (None, _) => None,
+        (Some(text), MarcCompression::None) => Some(Ok(
+            String::from_utf8(text).context(format!("Record {} is not UTF-8", id))?
+        )),
+        (Some(data), compression) => {
+            Some(copy::bytea(&data).and_then(|data| compression::decompress(&data, compression)))
+        }
+    };
+    Ok(record_from_marc(id, marc, None, None, None))
+}
+
+/// Ids of extractable records that are not in `present`, in id order
+pub async fn missing_ids(
+    pool: &PgPool,
+    config: &DatabaseConfig,
+    present: &IdSet,
+) -> Result<Vec<i64>> {
+    use futures::stream::TryStreamExt;
+
+    let mut query = RecordQuery::new(&format!("SELECT id FROM {}", config.table()), config)?;
+    query.push(" AND marc IS NOT NULL AND btrim(marc) <> '' ORDER BY id");
+
+    let mut ids = sqlx::query_scalar_with::<_, i64, _>(&query.sql, query.args).fetch(pool);
+
+    let mut missing = Vec::new();
+    while let Some(id) = ids.try_next().await.context("Failed to list record ids")? {
+        if !present.contains(id) {
+            missing.push(id);
+        }
+    }
+
+    Ok(missing)
+}
+
+/// Fetch the MARC records with the given ids
+pub async fn fetch_records_by_id(
+    conn: &mut PgConnection,
+    config: &DatabaseConfig,
+    ids: &[i64],
+) -> Result<Vec<MarcRecord>> {
+    let (first, last) = (ids.first().copied(), ids.last().copied());
+    debug!(
+        "Fetching {} records by id ({:?}..{:?})",
+        ids.len(),
+        first,
+        last
+    );
 
-            Ok(None)
+    let mut query = RecordQuery::new(&config.record_select(), config)?;
+    query
+        .push(" AND id = ANY(")
+        .push_bind(ids.to_vec())?
+        .push(") ORDER BY id");
+
+    let rows = sqlx::query_with(&query.sql, query.args)
+        .fetch_all(conn)
+        .await
+        .context(format!(
+            "Failed to fetch records by id ({:?}..{:?})",
+            first, last
+        ))?;
+
+    let mut records = Vec::with_capacity(rows.len());
+    for row in rows {
+        records.extend(record_from_row(&row, config)?);
+    }
+
+    Ok(records)
+}
+
+/// Build a record from a row of `DatabaseConfig::record_select`, skipping rows without MARC
+/// data unless only the ids were fetched
+///
+/// Compressed values are decoded here, on the worker, so decompression runs in
+/// parallel. A value that fails to decode becomes a rejected record.
+fn record_from_row(row: &PgRow, config: &DatabaseConfig) -> Result<Option<MarcRecord>> {
+    let id: i64 = row.try_get("id")?;
+    let meta = if config.with_meta {
+        Some(RecordMeta {
+            fingerprint: row.try_get("fingerprint")?,
+            quality: row.try_get("quality")?,
+            edit_date: row.try_get("edit_date")?,
         })
-        .try_filter_map(|opt| async move { Ok(opt) });
+    } else {
+        None
+    };
+    let identifiers = if config.with_identifiers {
+        Some(Identifiers {
+            tcn_value: row.try_get("tcn_value")?,
+            tcn_source: row.try_get("tcn_source")?,
+            source: row.try_get("source")?,
+        })
+    } else {
+        None
+    };
+    let status = if config.with_status {
+        Some(RecordStatus {
+            edit_date: row.try_get("edit_date")?,
+            deleted: row.try_get("deleted")?,
+        })
+    } else {
+        None
+    };
+
+    if config.ids_only {
+        return Ok(Some(MarcRecord {
+            id,
+            marc: String::new(),
+            rejection: None,
+            display: Vec::new(),
+            meta,
+            identifiers,
+            status,
+        }));
+    }
+
+    let marc = match config.marc_compression {
+        MarcCompression::None => row.try_get::<Option<String>, _>("marc")?.map(Ok),
+        compression => row
+            .try_get::<Option<Vec<u8>>, _>("marc")?
+            .map(|data| compression::decompress(&data, compression)),
+    };
+    Ok(record_from_marc(id, marc, meta, identifiers, status))
+}
+
+/// Build a record from its MARC as read from a row, already decoded when it was stored
+/// compressed, skipping records without MARC data
+///
+/// A value that failed to decode becomes a rejected record.
+fn record_from_marc(
+    id: i64,
+    marc: Option<Result<String>>,
+    meta: Option<RecordMeta>,
+    identifiers: Option<Identifiers>,
+    status: Option<RecordStatus>,
+) -> Option<MarcRecord> {
+    match marc {
+        // Only include records that have MARC data
+        Some(Ok(marc_data)) if !marc_data.trim().is_empty() => Some(MarcRecord {
+            id,
+            marc: marc_data,
+            rejection: None,
+            display: Vec::new(),
+            meta,
+            identifiers,
+            status,
+        }),
+        Some(Ok(_)) => {
+            debug!("Skipping record {} - empty MARC data", id);
+            None
+        }
+        Some(Err(e)) => {
+            debug!("Failed to decompress record {}: {:#}", id, e);
+            Some(MarcRecord {
+                id,
+                marc: String::new(),
+                rejection: Some(Rejection::new("decompress", format!("{:#}", e))),
+                display: Vec::new(),
+                meta,
+                identifiers,
+                status,
+            })
+        }
+        None => {
+            debug!("Skipping record {} - NULL MARC data", id);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::ChunkPlan;
+    use sqlx::postgres::PgPoolOptions;
+    use xtask::Fixtures;
+
+    /// A pool on a scratch database named `name` holding `fixtures`, or `None` without a
+    /// test server
+    async fn fixture_pool(name: &str, fixtures: Fixtures) -> Option<PgPool> {
+        let url = xtask::test_database(name, &fixtures).await?;
+        Some(PgPoolOptions::new().connect(&url).await.unwrap())
+    }
+
+    /// Ids alone, so that rows with NULL marc count like any other
+    fn config(chunk_size: i64) -> DatabaseConfig {
+        DatabaseConfig {
+            record_type: RecordType::Bib,
+            filter: RecordFilter::default(),
+            chunk_size,
+            after_id: None,
+            max_id: None,
+            marc_compression: MarcCompression::None,
+            order_by: OrderBy::Id,
+            with_meta: false,
+            with_identifiers: false,
+            with_status: false,
+            ids_only: true,
+            capped: true,
+        }
+    }
 
-    Ok(stream)
+    /// The records fetched for each of `ranges`
+    async fn fetch_all(
+        pool: &PgPool,
+        config: &DatabaseConfig,
+        ranges: &[ChunkRange],
+    ) -> Vec<Vec<i64>> {
+        let mut conn = pool.acquire().await.unwrap();
+        let mut chunks = Vec::new();
+        for range in ranges {
+            let records = fetch_records(&mut conn, config, config.table(), range)
+                .await
+                .unwrap();
+            chunks.push(records.iter().map(|record| record.id).collect());
+        }
+        chunks
+    }
+
+    #[tokio::test]
+    async fn a_capped_fetch_returns_exactly_the_limit() {
+        let Some(pool) = fixture_pool("db_capped_fetch", Fixtures::new(500)).await else {
+            return;
+        };
+        let config = config(100);
+        let total = get_record_count(&pool, &config).await.unwrap();
+        assert!(total > 400, "only {} active fixtures", total);
+
+        // Below one chunk, not a multiple of the chunk size, and past the end
+        for (limit, chunks) in [(40, 1), (250, 3), (total + 100, (total + 99) / 100)] {
+            let ranges = chunk_ranges(&pool, &config, config.table(), limit)
+                .await
+                .unwrap();
+            assert_eq!(ranges.len() as i64, chunks, "limit {}", limit);
+            let fetched = fetch_all(&pool, &config, &ranges).await;
+            let ids: Vec<i64> = fetched.iter().flatten().copied().collect();
+            assert_eq!(ids.len() as i64, limit.min(total), "limit {}", limit);
+            assert!(
+                ids.windows(2).all(|pair| pair[0] < pair[1]),
+                "limit {}",
+                limit
+            );
+            for (range, ids) in ranges.iter().zip(&fetched) {
+                assert_eq!(
+                    ids.len() as i64,
+                    range.records,
+                    "limit {}, {}",
+                    limit,
+                    range
+                );
+                assert!(range.records <= 100, "limit {}, {}", limit, range);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_capped_fetch_leaves_out_rows_that_join_a_range_after_planning() {
+        let Some(pool) = fixture_pool("db_capped_growth", Fixtures::new(300)).await else {
+            return;
+        };
+        let mut config = config(100);
+        let ranges = chunk_ranges(&pool, &config, config.table(), 150)
+            .await
+            .unwrap();
+        let last = ranges.last().unwrap().clone();
+        assert_eq!(last.records, 50);
+
+        // Deleted rows inside the last range become selected once restored
+        let restored = sqlx::query(
+            "UPDATE biblio.record_entry SET deleted = false WHERE deleted AND id BETWEEN $1 AND $2",
+        )
+        .bind(last.first.id)
+        .bind(last.last.id)
+        .execute(&pool)
+        .await
+        .unwrap()
+        .rows_affected();
+        assert!(restored > 0, "no deleted fixtures in {}", last);
+
+        let capped: usize = fetch_all(&pool, &config, &ranges)
+            .await
+            .iter()
+            .map(Vec::len)
+            .sum();
+        assert_eq!(capped, 150);
+
+        config.capped = false;
+        let uncapped: usize = fetch_all(&pool, &config, &ranges)
+            .await
+            .iter()
+            .map(Vec::len)
+            .sum();
+        assert_eq!(uncapped as u64, 150 + restored);
+    }
+
+    #[tokio::test]
+    async fn partitions_are_found_counted_and_chunked_apart() {
+        let fixtures = Fixtures {
+            partition_size: Some(100),
+            ..Fixtures::new(450)
+        };
+        let Some(pool) = fixture_pool("db_partitioned", fixtures).await else {
+            return;
+        };
+        let config = config(30);
+        assert_eq!(
+            partitioning(&pool, config.table()).await.unwrap(),
+            Partitioning::RangeById
+        );
+
+        let partitions = partition_counts(&pool, &config).await.unwrap();
+        let tables: Vec<&str> = partitions.iter().map(|p| p.table.as_str()).collect();
+        assert_eq!(
+            tables,
+            [
+                "biblio.record_entry_p0000",
+                "biblio.record_entry_p0001",
+                "biblio.record_entry_p0002",
+                "biblio.record_entry_p0003",
+                "biblio.record_entry_p0004",
+            ]
+        );
+        let total = get_record_count(&pool, &config).await.unwrap();
+        assert_eq!(partitions.iter().map(|p| p.records).sum::<i64>(), total);
+
+        let plan = ChunkPlan::build(&pool, &config, Some(partitions.clone()), total)
+            .await
+            .unwrap();
+        assert_eq!(plan.chunks.iter().map(|c| c.records()).sum::<i64>(), total);
+        for chunk in &plan.chunks {
+            // Partition n holds ids 100n + 1 to 100n + 100
+            let index = chunk.partition.unwrap() as i64;
+            let ids = index * 100 + 1..=index * 100 + 100;
+            assert!(
+                ids.contains(&chunk.range.first.id) && ids.contains(&chunk.range.last.id),
+                "chunk {} crosses out of {}",
+                chunk.range,
+                partitions[index as usize].table
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_plain_table_has_no_partitions() {
+        let Some(pool) = fixture_pool("db_unpartitioned", Fixtures::new(50)).await else {
+            return;
+        };
+        let config = config(30);
+        assert_eq!(
+            partitioning(&pool, config.table()).await.unwrap(),
+            Partitioning::None
+        );
+        let partitions = partition_counts(&pool, &config).await.unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].table, "biblio.record_entry");
+    }
 }
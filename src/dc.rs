@@ -0,0 +1,168 @@
+use quick_xml::escape::escape;
+
+use crate::marc::{self, FieldData, ParsedRecord};
+
+/// Opening of a record's oai_dc:dc element, declaring the namespaces and schema it needs
+/// whether it stands in a collection or in OAI-PMH metadata
+const DC_START: &str = "<oai_dc:dc xmlns:oai_dc=\"http://www.openarchives.org/OAI/2.0/oai_dc/\" \
+     xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+     xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" \
+     xsi:schemaLocation=\"http://www.openarchives.org/OAI/2.0/oai_dc/ http://www.openarchives.org/OAI/2.0/oai_dc.xsd\">";
+
+/// Map a MARCXML record to simple Dublin Core, as an oai_dc:dc element
+///
+/// Follows LoC's MARC to oai_dc mapping for: dc:title (245), dc:creator (100/110/111),
+/// dc:contributor (700/710), dc:subject (650/651, subdivisions joined by --), dc:date
+/// (260 and 264 $c), dc:identifier (020 and 022 $a, 856 $u, and the record `id`),
+/// dc:language (008/35-37, then 041 $a), and dc:type (leader/06 and 07). Every element
+/// repeats as the fields it comes from do; other fields are left out.
+pub fn to_dc(xml: &str, id: i64) -> Result<String, String> {
+    let record = marc::parse_record(xml)
+        .ok_or("not parseable MARCXML, so it cannot be mapped to Dublin Core")?;
+    let mut out = String::from(DC_START);
+
+    for_each(&record, &["245"], |subfields| {
+        push(
+            &mut out,
+            "title",
+            &joined(subfields, &["a", "b", "f", "g", "k", "n", "p", "s"], " "),
+        );
+    });
+    for_each(&record, &["100", "110", "111"], |subfields| {
+        push(
+            &mut out,
+            "creator",
+            &joined(subfields, &["a", "b", "c", "d", "q"], " "),
+        );
+    });
+    for_each(&record, &["700", "710"], |subfields| {
+        push(
+            &mut out,
+            "contributor",
+            &joined(subfields, &["a", "b", "c", "d", "q"], " "),
+        );
+    });
+    for_each(&record, &["650", "651"], |subfields| {
+        push(
+            &mut out,
+            "subject",
+            &joined(subfields, &["a", "v", "x", "y", "z"], "--"),
+        );
+    });
+    for_each(&record, &["260", "264"], |subfields| {
+        for (_, date) in subfields.iter().filter(|(code, _)| code == "c") {
+            push(&mut out, "date", marc::chop_punctuation(date));
+        }
+    });
+    for kind in types(record.leader.as_deref().unwrap_or_default()) {
+        push(&mut out, "type", kind);
+    }
+    for_each(&record, &["020", "022"], |subfields| {
+        for (_, number) in subfields.iter().filter(|(code, _)| code == "a") {
+            push(&mut out, "identifier", number);
+        }
+    });
+    for_each(&record, &["856"], |subfields| {
+        for (_, url) in subfields.iter().filter(|(code, _)| code == "u") {
+            push(&mut out, "identifier", url);
+        }
+    });
+    push(&mut out, "identifier", &id.to_string());
+    for language in languages(&record) {
+        push(&mut out, "language", &language);
+    }
+
+    out.push_str("</oai_dc:dc>");
+    Ok(out)
+}
+
+/// Call `each` with the subfields of every data field with one of `tags`, in record order
+fn for_each(record: &ParsedRecord, tags: &[&str], mut each: impl FnMut(&[(String, String)])) {
+    for field in &record.fields {
+        if let (true, FieldData::Data { subfields, .. }) =
+            (tags.contains(&field.tag.as_str()), &field.data)
+        {
+            each(subfields);
+        }
+    }
+}
+
+/// The subfields with any of `codes`, in order, punctuation chopped, joined by `separator`
+fn joined(subfields: &[(String, String)], codes: &[&str], separator: &str) -> String {
+    let parts: Vec<&str> = subfields
+        .iter()
+        .filter(|(code, _)| codes.contains(&code.as_str()))
+        .map(|(_, value)| marc::chop_punctuation(value))
+        .filter(|value| !value.is_empty())
+        .collect();
+    parts.join(separator)
+}
+
+/// Append a dc element, unless its text is empty
+fn push(out: &mut String, name: &str, text: &str) {
+    let text = text.trim();
+    if text.is_empty() {
+        return;
+    }
+    out.push_str("<dc:");
+    out.push_str(name);
+    out.push('>');
+    out.push_str(&escape(text));
+    out.push_str("</dc:");
+    out.push_str(name);
+    out.push('>');
+}
+
+/// dc:type values for a leader, in LoC's wording: collection for leader/07 c, manuscript
+/// for the manuscript types, then the kind of material leader/06 gives
+fn types(leader: &str) -> Vec<&'static str> {
+    let bytes = leader.as_bytes();
+    let (Some(&kind), level) = (bytes.get(6), bytes.get(7)) else {
+        return Vec::new();
+    };
+    let mut types = Vec::new();
+    if level == Some(&b'c') {
+        types.push("collection");
+    }
+    if matches!(kind, b'd' | b'f' | b'p' | b't') {
+        types.push("manuscript");
+    }
+    types.extend(match kind {
+        b'a' | b't' => Some("text"),
+        b'e' | b'f' => Some("cartographic"),
+        b'c' | b'd' => Some("notated music"),
+        b'i' | b'j' => Some("sound recording"),
+        b'k' => Some("still image"),
+        b'g' => Some("moving image"),
+        b'r' => Some("three dimensional object"),
+        b'm' => Some("software, multimedia"),
+        b'p' => Some("mixed material"),
+        _ => None,
+    });
+    types
+}
+
+/// The language codes of 008/35-37, then those in 041 $a it does not give
+fn languages(record: &ParsedRecord) -> Vec<String> {
+    let mut languages: Vec<String> = Vec::new();
+    for field in &record.fields {
+        match (&field.data, field.tag.as_str()) {
+            (FieldData::Control(data), "008") => {
+                let code = data.get(35..38).unwrap_or_default().trim();
+                if code.len() == 3 && code.chars().all(|c| c.is_ascii_alphabetic()) {
+                    languages.push(code.to_string());
+                }
+            }
+            (FieldData::Data { subfields, .. }, "041") => {
+                for (_, code) in subfields.iter().filter(|(code, _)| code == "a") {
+                    let code = code.trim();
+                    if !code.is_empty() && !languages.iter().any(|known| known == code) {
+                        languages.push(code.to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    languages
+}
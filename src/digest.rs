@@ -0,0 +1,247 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::sync::Mutex;
+
+use crate::quarantine::Rejection;
+
+/// Groups shown in the end-of-run table; the summary JSON has them all
+const SHOWN_GROUPS: usize = 10;
+
+/// Example ids kept per group
+const EXAMPLES: usize = 3;
+
+/// Distinct groups kept before further fingerprints are folded into one
+const MAX_GROUPS: usize = 1000;
+
+/// Widest message shown in the table
+const MESSAGE_WIDTH: usize = 60;
+
+/// What kind of failure an error was
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    InvalidXml,
+    Encoding,
+    WriteFailure,
+    FetchFailure,
+    /// A worker that stopped the run
+    Worker,
+}
+
+impl Category {
+    /// The category of a record some stage rejected
+    pub fn of(rejection: &Rejection) -> Self {
+        match rejection.stage.as_str() {
+            "decompress" => Category::Encoding,
+            _ => Category::InvalidXml,
+        }
+    }
+
+    /// The category as a field of --log-format json
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::InvalidXml => "invalid_xml",
+            Category::Encoding => "encoding",
+            Category::WriteFailure => "write_failure",
+            Category::FetchFailure => "fetch_failure",
+            Category::Worker => "worker",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Category::InvalidXml => "invalid XML",
+            Category::Encoding => "encoding",
+            Category::WriteFailure => "write failure",
+            Category::FetchFailure => "fetch failure",
+            Category::Worker => "worker",
+        }
+    }
+}
+
+/// What an error happened to
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Subject {
+    Record(i64),
+    Chunk(i64),
+    Batch(i64),
+}
+
+impl Subject {
+    fn id(self) -> i64 {
+        match self {
+            Subject::Record(id) | Subject::Chunk(id) | Subject::Batch(id) => id,
+        }
+    }
+
+    fn unit(self) -> &'static str {
+        match self {
+            Subject::Record(_) => "records",
+            Subject::Chunk(_) => "chunks",
+            Subject::Batch(_) => "batches",
+        }
+    }
+}
+
+/// Errors that share a category and message fingerprint
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorGroup {
+    pub category: Category,
+    pub fingerprint: String,
+    /// The message with its numbers masked
+    pub message: String,
+    pub count: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<Subject>,
+}
+
+/// Every error of a run, grouped so that thousands of identical failures read as one line
+#[derive(Default)]
+pub struct ErrorDigest {
+    groups: Mutex<HashMap<(Category, String), ErrorGroup>>,
+}
+
+impl ErrorDigest {
+    /// Count an error; `message` should not name the subject, so that it groups
+    pub fn record(&self, category: Category, subject: Option<Subject>, message: &str) {
+        let mut message = mask(message);
+        let mut groups = self.groups.lock().unwrap();
+        if groups.len() >= MAX_GROUPS && !groups.contains_key(&(category, message.clone())) {
+            message = "(other messages)".to_string();
+        }
+
+        let group = groups
+            .entry((category, message.clone()))
+            .or_insert_with(|| ErrorGroup {
+                category,
+                fingerprint: fingerprint(category, &message),
+                message,
+                count: 0,
+                examples: Vec::new(),
+            });
+        group.count += 1;
+        if let Some(subject) = subject.filter(|_| group.examples.len() < EXAMPLES) {
+            group.examples.push(subject);
+        }
+    }
+
+    /// All groups, largest first
+    pub fn groups(&self) -> Vec<ErrorGroup> {
+        let mut groups: Vec<_> = self.groups.lock().unwrap().values().cloned().collect();
+        groups.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then(a.category.cmp(&b.category))
+                .then(a.message.cmp(&b.message))
+        });
+        groups
+    }
+
+    /// The table printed at the end of a run with errors; colored when stderr is a terminal
+    ///
+    /// `details` says where the groups that do not fit can be found.
+    pub fn table(&self, details: Option<&str>) -> Vec<String> {
+        let groups = self.groups();
+        if groups.is_empty() {
+            return Vec::new();
+        }
+        let color = std::io::stderr().is_terminal();
+        let paint = |code: &str, text: String| {
+            if color {
+                format!("\x1b[{}m{}\x1b[0m", code, text)
+            } else {
+                text
+            }
+        };
+
+        let total: u64 = groups.iter().map(|g| g.count).sum();
+        let plural = if groups.len() == 1 { "group" } else { "groups" };
+        let mut lines = vec![paint(
+            "1",
+            format!(
+                "Error digest: {} errors in {} {}",
+                total,
+                groups.len(),
+                plural
+            ),
+        )];
+        lines.push(format!(
+            "  {:>7}  {:<13}  {:<width$}  EXAMPLES",
+            "COUNT",
+            "CATEGORY",
+            "MESSAGE",
+            width = MESSAGE_WIDTH
+        ));
+        for group in groups.iter().take(SHOWN_GROUPS) {
+            let examples = match group.examples.first() {
+                Some(first) => {
+                    let ids: Vec<_> = group.examples.iter().map(|s| s.id().to_string()).collect();
+                    format!("{} {}", first.unit(), ids.join(", "))
+                }
+                None => String::new(),
+            };
+            lines.push(format!(
+                "  {}  {}  {:<width$}  {}",
+                paint("1;31", format!("{:>7}", group.count)),
+                paint("33", format!("{:<13}", group.category.label())),
+                truncate(&group.message, MESSAGE_WIDTH),
+                paint("2", examples),
+                width = MESSAGE_WIDTH
+            ));
+        }
+
+        if groups.len() > SHOWN_GROUPS {
+            let rest = &groups[SHOWN_GROUPS..];
+            let count: u64 = rest.iter().map(|g| g.count).sum();
+            let mut line = format!("  ...and {} more groups with {} errors", rest.len(), count);
+            match details {
+                Some(details) => line.push_str(&format!("; all groups are in {}", details)),
+                None => line.push_str("; --summary-json lists all groups"),
+            }
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+/// The message with digit runs masked and whitespace collapsed, so that errors differing
+/// only in ids, offsets, or lengths fall into one group
+fn mask(message: &str) -> String {
+    let mut masked = String::with_capacity(message.len());
+    let mut in_digits = false;
+    for c in message
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+    {
+        if c.is_ascii_digit() {
+            if !in_digits {
+                masked.push('#');
+            }
+            in_digits = true;
+        } else {
+            masked.push(c);
+            in_digits = false;
+        }
+    }
+    masked
+}
+
+/// A short, stable id for a group, the same from run to run
+fn fingerprint(category: Category, message: &str) -> String {
+    let hash = Sha256::digest(format!("{}\n{}", category.label(), message).as_bytes());
+    format!("{:x}", hash)[..12].to_string()
+}
+
+fn truncate(message: &str, width: usize) -> String {
+    if message.chars().count() <= width {
+        return message.to_string();
+    }
+    let mut cut: String = message.chars().take(width - 3).collect();
+    cut.push_str("...");
+    cut
+}
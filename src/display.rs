@@ -0,0 +1,112 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+use crate::csv::csv_field;
+use crate::db::MarcRecord;
+use crate::writer::sync_to_disk;
+
+/// One value of an Evergreen display field (metabib.display_entry)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DisplayEntry {
+    pub field: String,
+    pub value: String,
+}
+
+/// Attach the display entries of every record in a chunk, grouped by record
+///
+/// A multivalued field yields one entry per value, in display_entry order.
+pub async fn attach(conn: &mut PgConnection, records: &mut [MarcRecord]) -> Result<()> {
+    let ids: Vec<i64> = records
+        .iter()
+        .filter(|r| r.rejection.is_none())
+        .map(|r| r.id)
+        .collect();
+
+    let rows: Vec<(i64, String, String)> = sqlx::query_as(
+        "SELECT de.source, map.name, de.value FROM metabib.display_entry de \
+         JOIN config.display_field_map map ON map.field = de.field \
+         WHERE de.source = ANY($1) ORDER BY de.source, map.name, de.id",
+    )
+    .bind(&ids)
+    .fetch_all(conn)
+    .await
+    .context("Failed to fetch display fields")?;
+
+    let mut entries: HashMap<i64, Vec<DisplayEntry>> = HashMap::new();
+    for (id, field, value) in rows {
+        entries
+            .entry(id)
+            .or_default()
+            .push(DisplayEntry { field, value });
+    }
+    for record in records {
+        if let Some(display) = entries.remove(&record.id) {
+            record.display = display;
+        }
+    }
+
+    Ok(())
+}
+
+/// Companion CSV of the display fields of the records written (record_id, field, value)
+pub struct DisplayCsv {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    rows: u64,
+    durable: bool,
+}
+
+impl DisplayCsv {
+    pub async fn new(path: &Path, durable: bool) -> Result<Self> {
+        let file = File::create(path).await.context(format!(
+            "Failed to create display fields file: {}",
+            path.display()
+        ))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(b"record_id,field,value\n").await?;
+
+        Ok(Self {
+            writer,
+            path: path.to_path_buf(),
+            rows: 0,
+            durable,
+        })
+    }
+
+    pub async fn write(&mut self, record: &MarcRecord) -> Result<()> {
+        for entry in &record.display {
+            let line = format!(
+                "{},{},{}\n",
+                record.id,
+                csv_field(&entry.field),
+                csv_field(&entry.value)
+            );
+            self.writer
+                .write_all(line.as_bytes())
+                .await
+                .context(format!(
+                    "Failed to write display fields file: {}",
+                    self.path.display()
+                ))?;
+            self.rows += 1;
+        }
+        Ok(())
+    }
+
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    pub async fn finalize(mut self) -> Result<()> {
+        self.writer.flush().await?;
+        if self.durable {
+            sync_to_disk(self.writer.get_ref(), &self.path).await?;
+        }
+        Ok(())
+    }
+}
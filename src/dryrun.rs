@@ -0,0 +1,132 @@
+use anyhow::Result;
+use indicatif::HumanBytes;
+use sqlx::PgPool;
+
+use crate::compression::Compression;
+use crate::db::{self, DatabaseConfig, Partition};
+use crate::sink::{self, OutputOptions};
+use crate::writer::WriteOutcome;
+
+/// Records fetched to check that the MARC column is populated and to size the output
+const SAMPLE_RECORDS: usize = 5;
+
+/// How a run would read the selection
+pub enum Reading<'a> {
+    /// One stream over a single connection
+    Stream,
+    /// The listed ids, in batches of the chunk size
+    Listed(&'a [i64]),
+    /// Chunks cut partition by partition
+    Partitioned(&'a [Partition]),
+    /// Chunks over the whole table
+    Chunked,
+}
+
+/// Print what a run with these options would export, then leave without writing anything
+///
+/// Only the count the run makes anyway and a fetch of the first few records are run. The
+/// chunks are worked out from the counts rather than planned, which for a plain table
+/// gives the same number without the pass over the selection a plan takes.
+pub async fn report(
+    pool: &PgPool,
+    config: &DatabaseConfig,
+    reading: Reading<'_>,
+    records: i64,
+    workers: u32,
+    options: OutputOptions,
+) -> Result<()> {
+    println!("Dry run: nothing was written");
+    println!("  Records selected: {}", records);
+    if records == 0 {
+        return Ok(());
+    }
+
+    let chunk_size = config.chunk_size;
+    let chunks = match reading {
+        Reading::Stream => None,
+        Reading::Listed(_) | Reading::Chunked => Some((records + chunk_size - 1) / chunk_size),
+        Reading::Partitioned(partitions) => {
+            let mut left = records;
+            let mut chunks = 0;
+            for partition in partitions {
+                let taken = partition.records.min(left);
+                chunks += (taken + chunk_size - 1) / chunk_size;
+                left -= taken;
+            }
+            Some(chunks)
+        }
+    };
+    let unit = if matches!(reading, Reading::Listed(_)) {
+        "Batches"
+    } else {
+        "Chunks"
+    };
+    match chunks {
+        Some(chunks) => {
+            let workers = (workers as i64).min(chunks);
+            println!(
+                "  {}: {} of up to {} records, run by {} worker{}",
+                unit,
+                chunks,
+                chunk_size,
+                workers,
+                if workers == 1 { "" } else { "s" }
+            );
+        }
+        None => println!("  Chunks: none; streamed over one connection"),
+    }
+
+    let wanted = (records as usize).min(SAMPLE_RECORDS);
+    let sample = match reading {
+        Reading::Listed(ids) => {
+            let mut conn = pool.acquire().await?;
+            db::fetch_records_by_id(&mut conn, config, &ids[..wanted.min(ids.len())]).await?
+        }
+        _ => db::sample_records(pool, config, wanted as i64).await?,
+    };
+    if sample.is_empty() {
+        println!(
+            "  Sample: none of the first {} records selected has MARC data",
+            wanted
+        );
+        return Ok(());
+    }
+
+    // Measured as the writer for the format would write them, headers left out
+    let mut writer = sink::discard(&options).await?;
+    let start = writer.offset().unwrap_or_default();
+    let mut written = 0;
+    for record in &sample {
+        if matches!(
+            writer.write_record(record, None).await?,
+            WriteOutcome::Written
+        ) {
+            written += 1;
+        }
+    }
+    let bytes = writer.offset().unwrap_or_default() - start;
+    println!(
+        "  Sample: {} of the first {} records selected have MARC data; {} written as {}",
+        sample.len(),
+        wanted,
+        written,
+        format!("{:?}", options.format).to_lowercase()
+    );
+    if written == 0 {
+        return Ok(());
+    }
+
+    let average = bytes / written;
+    let compressed = if options.compression == Compression::NONE {
+        ""
+    } else {
+        ", before compression"
+    };
+    println!(
+        "  Estimated output: {} ({} per record on average{})",
+        HumanBytes(average * records as u64),
+        HumanBytes(average),
+        compressed
+    );
+    Ok(())
+}
@@ -0,0 +1,348 @@
+use anyhow::{bail, Context, Result};
+use sqlx::{Column, Executor, PgConnection, PgPool, Row};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::warn;
+
+use crate::capability::{Capabilities, Feature};
+use crate::db::MarcRecord;
+use crate::marc;
+use crate::trace::Tracer;
+
+/// Placeholder an enrichment query uses for the chunk's record ids
+const IDS_PLACEHOLDER: &str = "$ids";
+
+/// The --embed-holdings query: every copy that is not deleted, on a call number that is
+/// not deleted, in library and call number order within each record
+const HOLDINGS_QUERY: &str = "SELECT cn.record, ou.shortname AS library, cn.label AS call_number, \
+     cp.barcode, cp.circ_modifier, cs.name AS status \
+     FROM asset.call_number cn \
+     JOIN asset.copy cp ON cp.call_number = cn.id \
+     LEFT JOIN actor.org_unit ou ON ou.id = cn.owning_lib \
+     LEFT JOIN config.copy_status cs ON cs.id = cp.status \
+     WHERE cn.record = ANY($1::bigint[]) AND NOT cn.deleted AND NOT cp.deleted \
+     ORDER BY cn.record, ou.shortname, cn.label, cp.barcode";
+
+/// One --enrich argument: a query whose rows become data fields
+///
+/// Written as `tag=956;subfields=a:barcode,b:status;query=SELECT record, barcode, status
+/// FROM my_view WHERE record = ANY($ids)`, optionally with `ind1=` and `ind2=`. The first
+/// column of the query is the record id; `query` comes last and runs to the end of the
+/// argument, so it may contain semicolons.
+#[derive(Debug, Clone)]
+pub struct Enrichment {
+    /// The option the enrichment came from, for its errors
+    option: &'static str,
+    /// Tag of the field added to each record
+    pub tag: String,
+    indicators: (char, char),
+    /// Subfield code and the query column that fills it
+    subfields: Vec<(char, String)>,
+    query: String,
+    /// The query wrapped to return the id and each subfield column as text
+    sql: String,
+}
+
+impl FromStr for Enrichment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (settings, query) = match s.find("query=") {
+            Some(pos) => (&s[..pos], s[pos + "query=".len()..].trim()),
+            None => return Err("missing query=".to_string()),
+        };
+
+        let mut tag = None;
+        let mut indicators = (' ', ' ');
+        let mut subfields = Vec::new();
+        for setting in settings.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {:?}", setting))?;
+            match key.trim() {
+                "tag" => tag = Some(value.trim().to_string()),
+                "ind1" => indicators.0 = indicator(value)?,
+                "ind2" => indicators.1 = indicator(value)?,
+                "subfields" => {
+                    for mapping in value.split(',') {
+                        let (code, column) = mapping
+                            .split_once(':')
+                            .ok_or_else(|| format!("expected code:column, got {:?}", mapping))?;
+                        let mut chars = code.trim().chars();
+                        let code = match (chars.next(), chars.next()) {
+                            (Some(c), None) if c.is_ascii_alphanumeric() => c,
+                            _ => return Err(format!("invalid subfield code {:?}", code)),
+                        };
+                        subfields.push((code, column.trim().to_string()));
+                    }
+                }
+                other => return Err(format!("unknown setting {:?}", other)),
+            }
+        }
+
+        let tag = tag.ok_or("missing tag=")?;
+        if tag.len() != 3 || !tag.bytes().all(|b| b.is_ascii_digit()) || tag.as_str() < "010" {
+            return Err(format!(
+                "tag must be a data field tag 010-999, got {:?}",
+                tag
+            ));
+        }
+        if subfields.is_empty() {
+            return Err("missing subfields=".to_string());
+        }
+        if !query.contains(IDS_PLACEHOLDER) {
+            return Err(format!("query must select by {}", IDS_PLACEHOLDER));
+        }
+
+        Ok(Self {
+            option: "--enrich",
+            tag,
+            indicators,
+            subfields,
+            // Typed, so that the query may use the ids anywhere an array is accepted
+            query: query.replace(IDS_PLACEHOLDER, "$1::bigint[]"),
+            sql: String::new(),
+        })
+    }
+}
+
+impl Enrichment {
+    /// The --embed-holdings field: one per copy, with the owning library's shortname in $a,
+    /// the call number in $j, the barcode in $p, the circulation modifier in $g, and the
+    /// copy status in $x
+    pub fn holdings(tag: &str) -> Self {
+        let subfields = [
+            ('a', "library"),
+            ('j', "call_number"),
+            ('p', "barcode"),
+            ('g', "circ_modifier"),
+            ('x', "status"),
+        ];
+        Self {
+            option: "--embed-holdings",
+            tag: tag.to_string(),
+            indicators: ('4', ' '),
+            subfields: subfields
+                .iter()
+                .map(|(code, column)| (*code, column.to_string()))
+                .collect(),
+            query: HOLDINGS_QUERY.to_string(),
+            sql: String::new(),
+        }
+    }
+}
+
+fn indicator(value: &str) -> Result<char, String> {
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c == ' ' || c.is_ascii_alphanumeric() => Ok(c),
+        (None, None) => Ok(' '),
+        _ => Err(format!("invalid indicator {:?}", value)),
+    }
+}
+
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// The --enrich queries of a run, and the --embed-holdings one, checked against the database
+#[derive(Default)]
+pub struct Enrichments {
+    list: Vec<Enrichment>,
+    fields_added: AtomicU64,
+    stray_rows: AtomicU64,
+}
+
+impl Enrichments {
+    /// Check each query's columns and plan at startup, so a bad query fails before any chunk
+    ///
+    /// With `degrade`, a query the role lacks privileges for is dropped and recorded in
+    /// `capabilities` instead of failing the run.
+    pub async fn prepare(
+        pool: &PgPool,
+        specs: &[Enrichment],
+        capabilities: &mut Capabilities,
+        degrade: bool,
+    ) -> Result<Self> {
+        let mut list = Vec::new();
+
+        for spec in specs {
+            let feature = Feature::Enrich(spec.tag.parse().expect("tag checked when parsed"));
+            let describe = match pool.describe(&spec.query).await {
+                Ok(describe) => describe,
+                Err(e) if degrade && is_permission_denied(&e) => {
+                    capabilities.disable(feature, e.to_string());
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e).context(format!(
+                        "Invalid {} query for tag {}",
+                        spec.option, spec.tag
+                    ))
+                }
+            };
+
+            let columns: Vec<&str> = describe.columns().iter().map(|c| c.name()).collect();
+            let Some(id_column) = columns.first() else {
+                bail!(
+                    "{} query for tag {} returns no columns",
+                    spec.option,
+                    spec.tag
+                );
+            };
+            for (code, column) in &spec.subfields {
+                if !columns.contains(&column.as_str()) {
+                    bail!(
+                        "{} query for tag {} has no column {:?} for subfield {} (columns: {})",
+                        spec.option,
+                        spec.tag,
+                        column,
+                        code,
+                        columns.join(", ")
+                    );
+                }
+            }
+
+            let mut select = format!("SELECT q.{}::bigint", quote_ident(id_column));
+            for (_, column) in &spec.subfields {
+                select.push_str(&format!(", q.{}::text", quote_ident(column)));
+            }
+            let sql = format!("{} FROM ({}) q", select, spec.query);
+
+            // EXPLAIN checks privileges too, which describing the query does not
+            let explained = sqlx::query(&format!("EXPLAIN {}", sql))
+                .bind(Vec::<i64>::new())
+                .fetch_all(pool)
+                .await;
+            match explained {
+                Ok(_) => {}
+                Err(e) if degrade && is_permission_denied(&e) => {
+                    capabilities.disable(feature, e.to_string());
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e).context(format!(
+                        "Invalid {} query for tag {}",
+                        spec.option, spec.tag
+                    ))
+                }
+            }
+
+            list.push(Enrichment {
+                sql,
+                ..spec.clone()
+            });
+        }
+
+        Ok(Self {
+            list,
+            ..Default::default()
+        })
+    }
+
+    pub fn tags(&self) -> Vec<&str> {
+        self.list.iter().map(|e| e.tag.as_str()).collect()
+    }
+
+    /// Run every query for a chunk and add one field per returned row to its record
+    pub async fn apply(
+        &self,
+        conn: &mut PgConnection,
+        records: &mut [MarcRecord],
+        tracer: &Tracer,
+    ) -> Result<()> {
+        if self.list.is_empty() {
+            return Ok(());
+        }
+
+        let ids: Vec<i64> = records
+            .iter()
+            .filter(|r| r.rejection.is_none())
+            .map(|r| r.id)
+            .collect();
+        let chunk: HashSet<i64> = ids.iter().copied().collect();
+
+        for enrichment in &self.list {
+            let rows = sqlx::query(&enrichment.sql)
+                .bind(&ids)
+                .fetch_all(&mut *conn)
+                .await
+                .context(format!(
+                    "{} query for tag {} failed",
+                    enrichment.option, enrichment.tag
+                ))?;
+
+            // Rows keep the query's order within each record
+            let mut fields: HashMap<i64, Vec<Vec<(char, String)>>> = HashMap::new();
+            let mut stray = 0;
+            for row in rows {
+                let Some(id) = row.try_get::<Option<i64>, _>(0)? else {
+                    stray += 1;
+                    continue;
+                };
+                if !chunk.contains(&id) {
+                    stray += 1;
+                    continue;
+                }
+
+                let mut subfields = Vec::new();
+                for (i, (code, _)) in enrichment.subfields.iter().enumerate() {
+                    if let Some(value) = row.try_get::<Option<String>, _>(i + 1)? {
+                        subfields.push((*code, value));
+                    }
+                }
+                if !subfields.is_empty() {
+                    fields.entry(id).or_default().push(subfields);
+                }
+            }
+
+            if stray > 0 {
+                warn!(
+                    "{} query for tag {} returned {} rows for records outside the chunk",
+                    enrichment.option, enrichment.tag, stray
+                );
+                self.stray_rows.fetch_add(stray, Ordering::Relaxed);
+            }
+
+            for record in records.iter_mut() {
+                let Some(record_fields) = fields.get(&record.id) else {
+                    continue;
+                };
+                let mut added = 0;
+                for subfields in record_fields {
+                    let subfields: Vec<(char, &str)> =
+                        subfields.iter().map(|(c, v)| (*c, v.as_str())).collect();
+                    if marc::append_datafield(
+                        &mut record.marc,
+                        &enrichment.tag,
+                        enrichment.indicators,
+                        &subfields,
+                    ) {
+                        added += 1;
+                    }
+                }
+                tracer.note(record.id, "enriched", || {
+                    format!("{} {} field(s) added", added, enrichment.tag)
+                });
+                self.fields_added.fetch_add(added, Ordering::Relaxed);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fields added, and rows returned for records outside their chunk
+    pub fn summary(&self) -> (u64, u64) {
+        (
+            self.fields_added.load(Ordering::Relaxed),
+            self.stray_rows.load(Ordering::Relaxed),
+        )
+    }
+}
+
+fn is_permission_denied(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .and_then(|db| db.code())
+        .is_some_and(|code| code == "42501")
+}
@@ -0,0 +1,140 @@
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::quarantine::Rejection;
+use crate::writer;
+
+/// First line of every --error-file; `idfile::read_id_file` passes over it
+pub const HEADER: &str = "id,last_id,stage,error";
+
+/// Stage of a line covering every record a broken --stream had yet to return
+pub const STREAM_STAGE: &str = "stream";
+
+/// The --error-file: one CSV line per record the run failed on, with the stage that
+/// failed it and the error, for a retry pass with --id-file
+///
+/// A chunk that failed to fetch is one `fetch` line for its whole range, from `id` to
+/// `last_id`, as the records in it were never read. A stream that broke off is one
+/// `stream` line from the id after the last record it returned, with no `last_id`. Lines
+/// are written as failures happen, from every worker, so they are not in id order, and
+/// each is kept to one line so that the id is always the start of one. A failed write
+/// stops the file there and fails the run once it ends, as for the --review-file.
+/// Without an --error-file nothing is kept.
+#[derive(Default)]
+pub struct ErrorFile {
+    file: Option<Mutex<Open>>,
+    /// List the stubs --on-stub skip leaves out as well
+    stubs: bool,
+}
+
+struct Open {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    lines: u64,
+    /// The write that failed, after which nothing more is written
+    failed: Option<std::io::Error>,
+}
+
+impl ErrorFile {
+    /// Create the file with just its header, which is all it holds after a clean run; a
+    /// resumed run instead adds to the file the run it resumes left
+    pub fn open(path: &Path, stubs: bool, resume: bool) -> Result<Self> {
+        let context = || format!("Failed to create error file: {}", path.display());
+        let writer = if resume && path.exists() {
+            BufWriter::new(
+                OpenOptions::new()
+                    .append(true)
+                    .open(path)
+                    .with_context(context)?,
+            )
+        } else {
+            let mut writer = BufWriter::new(File::create(path).with_context(context)?);
+            writeln!(writer, "{}", HEADER).with_context(context)?;
+            writer
+        };
+        Ok(Self {
+            file: Some(Mutex::new(Open {
+                writer,
+                path: path.to_path_buf(),
+                lines: 0,
+                failed: None,
+            })),
+            stubs,
+        })
+    }
+
+    /// A record `stage` failed
+    pub fn record(&self, id: i64, stage: &str, error: &str) {
+        self.write(&id.to_string(), "", stage, error);
+    }
+
+    /// A record a stage rejected or dropped; stubs only count when asked for
+    pub fn rejected(&self, id: i64, rejection: &Rejection) {
+        if rejection.stage != "stub" || self.stubs {
+            self.record(id, &rejection.stage, &rejection.reason);
+        }
+    }
+
+    /// A chunk from `first` to `last` failed to fetch
+    pub fn range(&self, first: i64, last: i64, error: &str) {
+        self.write(&first.to_string(), &last.to_string(), "fetch", error);
+    }
+
+    /// The stream broke off after returning the record `after`, or before returning any
+    pub fn stream(&self, after: Option<i64>, error: &str) {
+        let from = after.map(|id| (id + 1).to_string()).unwrap_or_default();
+        self.write(&from, "", STREAM_STAGE, error);
+    }
+
+    fn write(&self, id: &str, last_id: &str, stage: &str, error: &str) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let error = error.split_whitespace().collect::<Vec<_>>().join(" ");
+        let mut file = file.lock().unwrap();
+        if file.failed.is_some() {
+            return;
+        }
+        match writeln!(
+            file.writer,
+            "{},{},{},{}",
+            id,
+            last_id,
+            stage,
+            csv_field(&error)
+        ) {
+            Ok(()) => file.lines += 1,
+            Err(e) => file.failed = Some(e),
+        }
+    }
+
+    /// Flush the file, and sync it to disk when `durable`; the lines written, if there is one
+    pub fn finish(&self, durable: bool) -> Result<Option<u64>> {
+        let Some(file) = &self.file else {
+            return Ok(None);
+        };
+        let mut file = file.lock().unwrap();
+        let context = format!("Failed to write error file: {}", file.path.display());
+        if let Some(e) = file.failed.take() {
+            return Err(e).context(context);
+        }
+        file.writer.flush().context(context.clone())?;
+        if durable {
+            file.writer.get_ref().sync_all().context(context)?;
+            writer::sync_directory_of(&file.path)?;
+        }
+        Ok(Some(file.lines))
+    }
+}
+
+/// Quote a CSV field when it holds a comma or quote
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
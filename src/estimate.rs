@@ -0,0 +1,366 @@
+use indicatif::HumanBytes;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Parts of the chunk plan sampled separately, since record sizes drift with the id
+const STRATA: [&str; 3] = ["start", "middle", "end"];
+
+/// Normal quantile for a two-sided 95% interval
+const Z_95: f64 = 1.96;
+
+/// Order to run a plan's chunks in for --estimate
+///
+/// The plan is cut into start, middle, and end thirds, and the run takes a chunk from each
+/// in turn, so all three are covered whenever it is stopped. Within a third, chunks go in
+/// bit-reversed order of their position (first, halfway, a quarter, three quarters, ...),
+/// which spreads a short sample across the third instead of bunching it at the front.
+pub fn sample_order(num_chunks: i64) -> Vec<i64> {
+    let strata: Vec<Vec<i64>> = (0..STRATA.len())
+        .map(|stratum| {
+            let (start, end) = stratum_bounds(stratum, num_chunks);
+            spread(start, end)
+        })
+        .collect();
+    let longest = strata.iter().map(Vec::len).max().unwrap_or(0);
+    (0..longest)
+        .flat_map(|i| {
+            strata
+                .iter()
+                .filter_map(move |chunks| chunks.get(i).copied())
+        })
+        .collect()
+}
+
+/// The chunks `start..end` of a stratum
+fn stratum_bounds(stratum: usize, num_chunks: i64) -> (i64, i64) {
+    let strata = STRATA.len() as i64;
+    let stratum = stratum as i64;
+    (
+        num_chunks * stratum / strata,
+        num_chunks * (stratum + 1) / strata,
+    )
+}
+
+/// The chunks `start..end` in bit-reversed order of their position
+fn spread(start: i64, end: i64) -> Vec<i64> {
+    let len = (end - start).max(0) as u64;
+    if len == 0 {
+        return Vec::new();
+    }
+    let bits = u64::BITS - (len - 1).leading_zeros();
+    (0..1u64 << bits)
+        .map(|i| {
+            if bits == 0 {
+                0
+            } else {
+                i.reverse_bits() >> (u64::BITS - bits)
+            }
+        })
+        .filter(|&position| position < len)
+        .map(|position| start + position as i64)
+        .collect()
+}
+
+/// What one sampled chunk cost and produced
+#[derive(Default)]
+struct ChunkSample {
+    started: Option<Instant>,
+    /// Time from admission to the last record handed on; set once the chunk is done
+    busy: Option<Duration>,
+    /// Bytes of output its records came to
+    bytes: u64,
+    /// Records rejected or failed, or all of them when the chunk could not be fetched
+    failed: u64,
+}
+
+#[derive(Default)]
+struct State {
+    chunks: HashMap<i64, ChunkSample>,
+    /// Chunk each record in flight came from, so the writer can charge its bytes
+    records: HashMap<i64, i64>,
+}
+
+/// Measurements of an --estimate run, extrapolated to the whole plan at the end
+pub struct Estimator {
+    budget: Duration,
+    /// Records the plan gives each chunk
+    chunk_records: Vec<u64>,
+    total_records: i64,
+    num_chunks: i64,
+    started: Instant,
+    state: Mutex<State>,
+}
+
+impl Estimator {
+    pub fn new(budget: Duration, chunk_records: Vec<u64>) -> Self {
+        Self {
+            budget,
+            total_records: chunk_records.iter().sum::<u64>() as i64,
+            num_chunks: chunk_records.len() as i64,
+            chunk_records,
+            started: Instant::now(),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// How long the sample runs for
+    pub fn budget(&self) -> Duration {
+        self.budget
+    }
+
+    /// A chunk got its connection and started fetching
+    pub fn begin(&self, chunk: i64) {
+        let mut state = self.state.lock().unwrap();
+        state.chunks.entry(chunk).or_default().started = Some(Instant::now());
+    }
+
+    /// A record of `chunk` is about to go to the writer
+    pub fn assign(&self, record_id: i64, chunk: i64) {
+        self.state.lock().unwrap().records.insert(record_id, chunk);
+    }
+
+    /// A chunk handed on all its records, or could not be fetched (`failed`)
+    pub fn finish(&self, chunk: i64, failed: bool) {
+        let records = self.chunk_records(chunk);
+        let mut state = self.state.lock().unwrap();
+        let sample = state.chunks.entry(chunk).or_default();
+        sample.busy = Some(
+            sample
+                .started
+                .map_or(Duration::ZERO, |started| started.elapsed()),
+        );
+        if failed {
+            sample.failed = records;
+        }
+    }
+
+    /// The writer wrote a record as `bytes` of output
+    pub fn written(&self, record_id: i64, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(chunk) = state.records.remove(&record_id) {
+            state.chunks.entry(chunk).or_default().bytes += bytes;
+        }
+    }
+
+    /// The writer rejected a record, or failed to write it
+    pub fn failed(&self, record_id: i64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(chunk) = state.records.remove(&record_id) {
+            state.chunks.entry(chunk).or_default().failed += 1;
+        }
+    }
+
+    /// Rows the plan gives a chunk
+    fn chunk_records(&self, chunk: i64) -> u64 {
+        self.chunk_records.get(chunk as usize).copied().unwrap_or(0)
+    }
+
+    /// Rows the plan gives a stratum
+    fn stratum_records(&self, stratum: usize) -> u64 {
+        let (start, end) = stratum_bounds(stratum, self.num_chunks);
+        (start..end).map(|chunk| self.chunk_records(chunk)).sum()
+    }
+
+    /// The extrapolated duration, output size, and error rate of the full run
+    ///
+    /// Each quantity is a stratified ratio estimate: a stratum's sampled chunks give a
+    /// per-record rate, which is scaled up by the records in the stratum, and the strata
+    /// are added up. The interval treats the sampled chunks of a stratum as a random
+    /// sample of its chunks, so it reflects how much chunks differed from each other, not
+    /// changes on the server while the full run goes on.
+    pub fn report(&self) -> Vec<String> {
+        let elapsed = self.started.elapsed();
+        let state = self.state.lock().unwrap();
+        let done: Vec<(usize, Sample)> = state
+            .chunks
+            .iter()
+            .filter_map(|(&chunk, sample)| {
+                let busy = sample.busy?;
+                let sample = Sample {
+                    records: self.chunk_records(chunk) as f64,
+                    busy: busy.as_secs_f64(),
+                    bytes: sample.bytes as f64,
+                    failed: sample.failed as f64,
+                };
+                Some((stratum_of(chunk, self.num_chunks), sample))
+            })
+            .collect();
+
+        let mut lines = Vec::new();
+        if done.is_empty() {
+            lines.push(format!(
+                "Estimate: no chunk finished within {}; give --estimate more time or use a smaller --chunk-size",
+                format_duration(self.budget.as_secs_f64())
+            ));
+            return lines;
+        }
+
+        // Chunks run side by side, and wait on the pool and --throttle in between, so the
+        // plan's busy time is spread the way the sample's was: over this many chunks in
+        // flight, on average
+        let busy_total: f64 = state
+            .chunks
+            .values()
+            .filter_map(|sample| match (sample.busy, sample.started) {
+                (Some(busy), _) => Some(busy.as_secs_f64()),
+                (None, Some(started)) => Some(started.elapsed().as_secs_f64()),
+                (None, None) => None,
+            })
+            .sum();
+        let concurrency = busy_total.max(f64::EPSILON) / elapsed.as_secs_f64().max(f64::EPSILON);
+
+        let sampled: f64 = done.iter().map(|(_, s)| s.records).sum();
+        let busy = self.stratified(&done, |s| s.busy);
+        let bytes = self.stratified(&done, |s| s.bytes);
+        let failed = self.stratified(&done, |s| s.failed);
+        let total = self.total_records as f64;
+
+        lines.push(format!(
+            "Estimate from {} of {} chunks ({} of {} records, {:.1}%) sampled in {}:",
+            done.len(),
+            self.num_chunks,
+            sampled as u64,
+            self.total_records,
+            100.0 * sampled / total,
+            format_duration(elapsed.as_secs_f64())
+        ));
+        lines.push(format!(
+            "  Duration:    {}{}",
+            format_duration(busy.value / concurrency),
+            interval(&busy, |v| format_duration(v / concurrency))
+        ));
+        lines.push(format!(
+            "  Output size: {}{}",
+            HumanBytes(bytes.value.max(0.0) as u64),
+            interval(&bytes, |v| HumanBytes(v as u64).to_string())
+        ));
+        lines.push(format!(
+            "  Error rate:  {:.2}%{}, about {} records",
+            100.0 * failed.value / total,
+            interval(&failed, |v| format!("{:.2}%", 100.0 * v / total)),
+            failed.value.round() as u64
+        ));
+
+        let sizes: Vec<String> = STRATA
+            .iter()
+            .enumerate()
+            .filter_map(|(stratum, name)| {
+                let (records, bytes) = done
+                    .iter()
+                    .filter(|(s, _)| *s == stratum)
+                    .fold((0.0, 0.0), |(r, b), (_, sample)| {
+                        (r + sample.records, b + sample.bytes)
+                    });
+                (records > 0.0)
+                    .then(|| format!("{} {}", name, HumanBytes((bytes / records) as u64)))
+            })
+            .collect();
+        lines.push(format!("  Output per record: {}", sizes.join(", ")));
+        lines.push(format!(
+            "  The duration assumes {:.2} chunks in flight on average, as in the sample; nothing was written, so a slow output target is not accounted for",
+            concurrency
+        ));
+        lines
+    }
+
+    /// Stratified ratio estimate of the plan's total of `value`
+    fn stratified(&self, done: &[(usize, Sample)], value: impl Fn(&Sample) -> f64) -> Total {
+        let overall = {
+            let records: f64 = done.iter().map(|(_, s)| s.records).sum();
+            done.iter().map(|(_, s)| value(s)).sum::<f64>() / records
+        };
+
+        let mut total = Total {
+            value: 0.0,
+            variance: Some(0.0),
+        };
+        for stratum in 0..STRATA.len() {
+            let population = self.stratum_records(stratum) as f64;
+            if population == 0.0 {
+                continue;
+            }
+            let samples: Vec<&Sample> = done
+                .iter()
+                .filter(|(s, _)| *s == stratum)
+                .map(|(_, sample)| sample)
+                .collect();
+            let chunks = samples.len() as f64;
+            let records: f64 = samples.iter().map(|s| s.records).sum();
+
+            // An unsampled stratum borrows the rate of the rest, with no interval
+            if samples.is_empty() || records == 0.0 {
+                total.value += population * overall;
+                total.variance = None;
+                continue;
+            }
+            let rate = samples.iter().map(|s| value(s)).sum::<f64>() / records;
+            total.value += population * rate;
+
+            let coverage = (records / population).min(1.0);
+            if coverage >= 1.0 {
+                continue;
+            }
+            if samples.len() < 2 {
+                total.variance = None;
+                continue;
+            }
+            let mean_records = records / chunks;
+            let spread = samples
+                .iter()
+                .map(|s| (value(s) - rate * s.records).powi(2))
+                .sum::<f64>()
+                / (chunks - 1.0);
+            if let Some(variance) = &mut total.variance {
+                *variance += population.powi(2) * (1.0 - coverage) * spread
+                    / (chunks * mean_records.powi(2));
+            }
+        }
+        total
+    }
+}
+
+/// One finished chunk, in the units the estimates are made in
+struct Sample {
+    records: f64,
+    busy: f64,
+    bytes: f64,
+    failed: f64,
+}
+
+/// An estimated total, with its variance when the sample allows one
+struct Total {
+    value: f64,
+    variance: Option<f64>,
+}
+
+fn stratum_of(chunk: i64, num_chunks: i64) -> usize {
+    (0..STRATA.len())
+        .find(|&stratum| chunk < stratum_bounds(stratum, num_chunks).1)
+        .unwrap_or(STRATA.len() - 1)
+}
+
+/// " (95% interval low - high)", or why there is none
+fn interval(total: &Total, format: impl Fn(f64) -> String) -> String {
+    match total.variance {
+        Some(variance) => {
+            let margin = Z_95 * variance.sqrt();
+            format!(
+                " (95% interval {} - {})",
+                format((total.value - margin).max(0.0)),
+                format(total.value + margin)
+            )
+        }
+        None => " (too few chunks sampled for an interval)".to_string(),
+    }
+}
+
+/// Seconds as e.g. "1h 05m", "4m 10s", or "12s"
+fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {:02}s", m, s),
+        (h, m, _) => format!("{}h {:02}m", h, m),
+    }
+}
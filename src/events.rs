@@ -0,0 +1,185 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::FromRawFd;
+use std::path::Path;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::db::RecordType;
+use crate::digest::ErrorGroup;
+use crate::pool::PoolSummary;
+
+/// Events queued for a slow reader before droppable ones are discarded
+const QUEUE_CAPACITY: usize = 256;
+
+/// Minimum gap between progress events
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One line of the --progress-fd protocol
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Started {
+        total: i64,
+        workers: u32,
+        chunk_size: i64,
+    },
+    ChunkCompleted {
+        chunk: i64,
+        records: usize,
+    },
+    Progress {
+        processed: u64,
+        total: u64,
+    },
+    Warning {
+        message: String,
+    },
+    /// `--workers auto` changed how many chunks run at once
+    Workers {
+        workers: u32,
+        reason: String,
+    },
+    /// The pool's connections and acquire waits over the last report window
+    Pool {
+        connections: u32,
+        idle: u32,
+        waiting: u64,
+        acquires: u64,
+        avg_wait_ms: u64,
+    },
+    Finished(RunSummary),
+}
+
+/// How a run ended; the finished event, and the --summary-json file
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    pub run_id: String,
+    pub status: &'static str,
+    /// Kind of record the run extracted
+    pub record_type: RecordType,
+    pub processed: u64,
+    pub rejected: u64,
+    pub errors: u64,
+    /// Records --validate found not well-formed
+    pub invalid: u64,
+    pub elapsed_secs: f64,
+    /// Why the run failed, when status is "failed"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Errors grouped by category and message, largest group first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub error_digest: Vec<ErrorGroup>,
+    /// Connections acquired from the pool and how long they took to come
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool: Option<PoolSummary>,
+}
+
+impl RunSummary {
+    pub fn save_json(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)
+            .context(format!("Failed to write run summary: {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Newline-delimited JSON events written to an inherited file descriptor
+///
+/// Events go through a bounded queue to a dedicated thread, so a slow reader never
+/// holds up the pipeline: progress, chunk, and warning events are dropped when the
+/// queue is full, while started and finished always wait for room.
+#[derive(Default)]
+pub struct ProgressEvents {
+    tx: Mutex<Option<SyncSender<Event>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    last_progress: Mutex<Option<Instant>>,
+}
+
+impl ProgressEvents {
+    /// Events that go nowhere, for runs without --progress-fd
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Start writing events to `fd`, which must be open in this process
+    pub fn open(fd: i32) -> Result<Self> {
+        if fd <= 2 {
+            bail!(
+                "--progress-fd {} would mix events into stdin/stdout/stderr",
+                fd
+            );
+        }
+        if !Path::new(&format!("/dev/fd/{}", fd)).exists() {
+            bail!("--progress-fd {} is not an open file descriptor", fd);
+        }
+
+        // SAFETY: the descriptor was checked to be open and is owned by this handle from now on
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        let (tx, rx) = mpsc::sync_channel::<Event>(QUEUE_CAPACITY);
+
+        let thread = std::thread::spawn(move || {
+            for event in rx {
+                let mut line = serde_json::to_vec(&event).expect("events always serialize");
+                line.push(b'\n');
+                if let Err(e) = file.write_all(&line).and_then(|_| file.flush()) {
+                    warn!("Stopped writing progress events: {}", e);
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            tx: Mutex::new(Some(tx)),
+            thread: Mutex::new(Some(thread)),
+            last_progress: Mutex::new(None),
+        })
+    }
+
+    /// Queue an event, waiting for room; only for the rare events that must arrive
+    pub fn send(&self, event: Event) {
+        let tx = self.tx.lock().unwrap().clone();
+        if let Some(tx) = tx {
+            let _ = tx.send(event);
+        }
+    }
+
+    /// Queue an event unless the reader has fallen behind
+    pub fn emit(&self, event: Event) {
+        if let Some(tx) = self.tx.lock().unwrap().as_ref() {
+            let _ = tx.try_send(event);
+        }
+    }
+
+    pub fn warning(&self, message: impl Into<String>) {
+        self.emit(Event::Warning {
+            message: message.into(),
+        });
+    }
+
+    /// Report progress, at most once per PROGRESS_INTERVAL
+    pub fn progress(&self, processed: u64, total: u64) {
+        let now = Instant::now();
+        {
+            let mut last = self.last_progress.lock().unwrap();
+            if last.is_some_and(|t| now.duration_since(t) < PROGRESS_INTERVAL) {
+                return;
+            }
+            *last = Some(now);
+        }
+        self.emit(Event::Progress { processed, total });
+    }
+
+    /// Send the finished event and wait until everything queued has been written
+    pub fn finish(&self, event: Event) {
+        self.send(event);
+        self.tx.lock().unwrap().take();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
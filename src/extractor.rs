@@ -0,0 +1,583 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use futures::future::BoxFuture;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::audit::AuditLog;
+use crate::connect::mask_password;
+use crate::continuation::Token;
+use crate::db::MarcRecord;
+use crate::events::{Event, ProgressEvents, RunSummary};
+use crate::hooks::{HookEnv, HookPoint, Hooks};
+use crate::manifest::{Manifest, Parent};
+use crate::options::ExtractorConfig;
+use crate::pipeline::{self, Caller, Counters};
+use crate::provenance::Provenance;
+use crate::sink::{self, SinkKind};
+use crate::watchdog::Watchdog;
+use crate::writer::{self, WriteOutcome};
+use crate::{gaps, split};
+
+/// Where [`Extractor::run`] writes the records it reads, in place of the outputs the
+/// options name
+pub trait RecordSink {
+    /// Write one record, or turn it away as [`WriteOutcome::Rejected`] when the sink
+    /// cannot hold it; an error stops the run
+    fn write_record(
+        &mut self,
+        record: &MarcRecord,
+    ) -> impl Future<Output = Result<WriteOutcome>> + Send;
+
+    /// Complete the output once every record has been written
+    fn finish(self) -> impl Future<Output = Result<()>> + Send
+    where
+        Self: Sized;
+}
+
+/// A [`RecordSink`] the writer task can hold without knowing its type
+pub(crate) trait DynSink: Send {
+    fn write_record<'a>(
+        &'a mut self,
+        record: &'a MarcRecord,
+    ) -> BoxFuture<'a, Result<WriteOutcome>>;
+
+    fn finish(self: Box<Self>) -> BoxFuture<'static, Result<()>>;
+}
+
+impl<S: RecordSink + Send + 'static> DynSink for S {
+    fn write_record<'a>(
+        &'a mut self,
+        record: &'a MarcRecord,
+    ) -> BoxFuture<'a, Result<WriteOutcome>> {
+        Box::pin(RecordSink::write_record(self, record))
+    }
+
+    fn finish(self: Box<Self>) -> BoxFuture<'static, Result<()>> {
+        Box::pin(RecordSink::finish(*self))
+    }
+}
+
+/// How far a run has got, given to the [`Extractor::on_progress`] callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ExtractionProgress {
+    /// Fetching is about to start
+    Started {
+        /// Records the run expects to write
+        records: u64,
+        /// Whether [`ExtractionProgress::Written`] will say how many bytes were written
+        counts_bytes: bool,
+    },
+    /// More records have been written
+    Written {
+        /// Records written so far
+        records: u64,
+        /// Bytes written so far, after any compression, to a file or stdout
+        bytes: Option<u64>,
+    },
+    /// A split output has moved on to this file number
+    File(u64),
+    /// Every record fetched has been written; `stopped` when Ctrl-C, a
+    /// [`StopHandle`], or the memory limit cut the run short
+    Finished {
+        /// The run stopped before every record was fetched
+        stopped: bool,
+    },
+}
+
+/// How a run that did not fail outright ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Every record was written
+    Complete,
+    /// Some records or chunks failed
+    Errors,
+    /// A batched run stopped with records remaining
+    MoreRemains,
+    /// The memory watchdog stopped the run before it finished
+    MemoryLimit,
+    /// A [`StopHandle`] stopped the run before it finished
+    Interrupted,
+    /// A post- or failure hook failed, with `hooks_strict` set
+    HookFailed,
+}
+
+impl Outcome {
+    /// The run's status, as the summary, manifest, and hooks give it
+    pub fn status(&self) -> &'static str {
+        match self {
+            Outcome::Complete => "complete",
+            Outcome::Errors => "errors",
+            Outcome::MoreRemains => "more_remains",
+            Outcome::MemoryLimit => "memory_limit",
+            Outcome::Interrupted => "interrupted",
+            Outcome::HookFailed => "hook_failed",
+        }
+    }
+}
+
+/// What a finished run did
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtractionSummary {
+    /// How the run ended
+    pub outcome: Outcome,
+    /// The run's id in the summary, manifest, audit log, and provenance database
+    pub run_id: String,
+    /// Records written
+    pub records_written: u64,
+    /// Records turned away by an output
+    pub records_rejected: u64,
+    /// Records `validate` found not well-formed
+    pub records_invalid: u64,
+    /// Records and chunks that failed
+    pub errors: u64,
+    /// How long the run took
+    pub elapsed: Duration,
+}
+
+/// Stops a running extraction as Ctrl-C does on the command line: no new chunks start,
+/// and the running ones finish before the output is closed
+///
+/// A stop asked for before the run starts fetching takes effect once it does.
+#[derive(Clone, Default)]
+pub struct StopHandle(Arc<Mutex<Stop>>);
+
+#[derive(Default)]
+struct Stop {
+    requested: bool,
+    watchdog: Option<Arc<Watchdog>>,
+}
+
+impl StopHandle {
+    /// Stop the run
+    pub fn stop(&self) {
+        let mut stop = self.0.lock().unwrap();
+        stop.requested = true;
+        if let Some(watchdog) = &stop.watchdog {
+            watchdog.interrupt();
+        }
+    }
+
+    /// Stop the run through `watchdog` once it starts fetching
+    pub(crate) fn watch(&self, watchdog: Arc<Watchdog>) {
+        let mut stop = self.0.lock().unwrap();
+        if stop.requested {
+            watchdog.interrupt();
+        }
+        stop.watchdog = Some(watchdog);
+    }
+}
+
+type ProgressCallback = Arc<dyn Fn(ExtractionProgress) + Send + Sync>;
+
+/// Hands progress to the [`Extractor::on_progress`] callback, if there is one
+#[derive(Clone, Default)]
+pub(crate) struct Reporter(Option<ProgressCallback>);
+
+impl Reporter {
+    pub fn send(&self, progress: ExtractionProgress) {
+        if let Some(callback) = &self.0 {
+            callback(progress);
+        }
+    }
+}
+
+/// Extracts the records an [`ExtractorConfig`] selects from an Evergreen database
+///
+/// The run is the command line's: it connects as the options say, cuts the selection
+/// into chunks fetched by the workers, screens, cleans, and transforms each record, and
+/// writes it to the outputs, with the hooks, checkpoint, summary, manifest, audit log,
+/// and provenance database the options ask for.
+pub struct Extractor {
+    config: ExtractorConfig,
+    reporter: Reporter,
+    stop: StopHandle,
+    manifest_config: toml::Table,
+}
+
+impl Extractor {
+    /// An extractor for `config`, reporting no progress until given a callback
+    pub fn new(mut config: ExtractorConfig) -> Self {
+        config.apply_presets();
+        Self {
+            config,
+            reporter: Reporter::default(),
+            stop: StopHandle::default(),
+            manifest_config: toml::Table::new(),
+        }
+    }
+
+    /// Call `callback` as the run starts, writes records, and finishes, e.g. to update a
+    /// progress display or send the numbers on a channel
+    pub fn on_progress(
+        mut self,
+        callback: impl Fn(ExtractionProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.reporter = Reporter(Some(Arc::new(callback)));
+        self
+    }
+
+    /// Record `options` as the run's configuration in its `manifest`
+    pub fn manifest_config(mut self, options: toml::Table) -> Self {
+        self.manifest_config = options;
+        self
+    }
+
+    /// A handle that stops the run from another task or thread
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Extract every selected record to the outputs the options name: `output` (or
+    /// stdout), or the `sink_table`, and each `also_output`
+    ///
+    /// A run that fails still writes its summary and manifest and runs the failure hook
+    /// before the error is returned; a [`crate::OutputFull`] error means the output's
+    /// filesystem filled up.
+    pub async fn export(self) -> Result<ExtractionSummary> {
+        self.extract(None).await
+    }
+
+    /// Extract every selected record into `sink`, then finish it
+    ///
+    /// The records are cleaned and transformed as for a file output; each `also_output`
+    /// is still written.
+    pub async fn run<S: RecordSink + Send + 'static>(self, sink: S) -> Result<ExtractionSummary> {
+        if self.config.output.is_some() || self.config.sink == SinkKind::Postgres {
+            anyhow::bail!("Records go to the sink given to Extractor::run, so the options cannot name an output or --sink postgres too");
+        }
+        self.extract(Some(Box::new(sink))).await
+    }
+
+    async fn extract(self, sink: Option<Box<dyn DynSink>>) -> Result<ExtractionSummary> {
+        let Extractor {
+            mut config,
+            reporter,
+            stop,
+            manifest_config,
+        } = self;
+
+        // Loaded first, as a batch carries on the chain's file numbering and manifest
+        let token = match &config.continue_from {
+            Some(path) => Token::load(path)?,
+            None => None,
+        };
+        if let (Some(path), Some(manifest)) = (
+            &config.continue_from,
+            token.as_ref().and_then(|token| token.manifest.as_ref()),
+        ) {
+            match &config.manifest {
+                Some(given) if given != manifest => anyhow::bail!(
+                    "Continuation token {} adds each batch to manifest {}; pass the same --manifest, or none",
+                    path.display(),
+                    manifest.display()
+                ),
+                _ => config.manifest = Some(manifest.clone()),
+            }
+        }
+        let first_file = token
+            .as_ref()
+            .and_then(|token| token.next_file)
+            .unwrap_or(1);
+
+        // Second resolution; the pid tells apart runs started in the same second
+        let run_id = format!(
+            "{}-{}",
+            chrono::Utc::now().format("%Y%m%dT%H%M%SZ"),
+            std::process::id()
+        );
+        // A dry run or a histogram has no output for the hooks to act on
+        let hooks = Hooks {
+            pre: config.pre_hook.clone().filter(|_| !config.counts_only()),
+            post: config.post_hook.clone().filter(|_| !config.counts_only()),
+            failure: config
+                .failure_hook
+                .clone()
+                .filter(|_| !config.counts_only()),
+            timeout: Duration::from_secs(config.hook_timeout),
+        };
+        // An estimate or a dry run writes none of its files
+        let output_paths: Vec<PathBuf> = [
+            &config.output,
+            &config.quarantine_file,
+            &config.review_file,
+            &config.error_file,
+            &config.scrub_report,
+            &config.with_display_fields,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(config.also_output.iter().map(|also| &also.path))
+        .filter(|_| config.estimate.is_none() && !config.counts_only())
+        .cloned()
+        .collect();
+        // Hooks after the run always get a summary, in a temporary file if none was asked for
+        let temporary_summary =
+            config.summary_json.is_none() && (hooks.post.is_some() || hooks.failure.is_some());
+        let summary_json = match &config.summary_json {
+            Some(path) => Some(path.clone()),
+            None if temporary_summary => Some(
+                std::env::temp_dir().join(format!("marc_extractor_rs-{}-summary.json", run_id)),
+            ),
+            None => None,
+        };
+
+        let pre_env = HookEnv {
+            run_id: &run_id,
+            output_paths: &output_paths,
+            records_written: 0,
+            status: "starting",
+            summary: None,
+        };
+        hooks
+            .run(HookPoint::Pre, &pre_env)
+            .await
+            .context("Not starting the run")?;
+
+        let provenance = match &config.provenance_db {
+            Some(path) => {
+                let source = mask_password(&config.database_url().unwrap_or_default());
+                let provenance = Provenance::open(path, &source, &run_id).await?;
+                info!(
+                    "Provenance: run {} in {}",
+                    provenance.run_id(),
+                    path.display()
+                );
+                Some(provenance)
+            }
+            None => None,
+        };
+
+        let audit = match &config.audit_log {
+            Some(path) => {
+                let source = mask_password(&config.database_url().unwrap_or_default());
+                let mut outputs: Vec<String> = output_paths
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect();
+                match (&config.sink_table, &config.output) {
+                    _ if config.estimate.is_some() || config.counts_only() => {}
+                    (Some(table), _) if config.sink == SinkKind::Postgres => {
+                        outputs.insert(0, format!("table {}", table))
+                    }
+                    _ if sink.is_some() => outputs.insert(0, sink::CUSTOM_TARGET.to_string()),
+                    (_, None) => outputs.insert(0, "STDOUT".to_string()),
+                    _ => {}
+                }
+                Some(AuditLog::new(path, &run_id, &source, outputs)?)
+            }
+            None => None,
+        };
+
+        let events = Arc::new(match config.progress_fd {
+            Some(fd) => ProgressEvents::open(fd)?,
+            None => ProgressEvents::disabled(),
+        });
+        let counters = Counters::default();
+        let started = Instant::now();
+        let hooks_strict = config.hooks_strict;
+        let record_type = config.record_type;
+        let atomic_output = config
+            .output
+            .clone()
+            .filter(|_| !config.no_atomic && config.estimate.is_none())
+            .map(|output| {
+                (
+                    output,
+                    config.records_per_file.is_some() || config.max_file_bytes.is_some(),
+                )
+            });
+        // Described from what the run left on disk, so that a failed run still has one
+        let manifest = config
+            .manifest
+            .clone()
+            .filter(|_| config.estimate.is_none() && !config.counts_only())
+            .map(|path| {
+                let output = config
+                    .output
+                    .clone()
+                    .filter(|_| config.sink != SinkKind::Postgres);
+                let split = config.records_per_file.is_some() || config.max_file_bytes.is_some();
+                let also: Vec<PathBuf> = config
+                    .also_output
+                    .iter()
+                    .map(|also| also.path.clone())
+                    .collect();
+                // A chain's manifest is added to; the batch's own token says where it is
+                let chain = config.continue_from.as_ref().map(|_| {
+                    let previous = token.as_ref().filter(|token| token.manifest.is_some());
+                    (Token::batch_after(token.as_ref()), previous.is_some())
+                });
+                // A supplement refers back to the export whose gaps it filled
+                let parent = config
+                    .fill_gaps_against
+                    .clone()
+                    .filter(|path| gaps::is_manifest(path));
+                (path, output, split, also, chain, parent)
+            });
+        let also_parts: Vec<PathBuf> = config
+            .also_output
+            .iter()
+            .filter(|_| !config.no_atomic)
+            .map(|also| writer::part_path(&also.path))
+            .collect();
+        let durable = config.durable;
+        let started_at = Utc::now();
+
+        let caller = Caller {
+            sink,
+            on_progress: &reporter,
+            stop: &stop,
+        };
+        let result = pipeline::run(
+            config,
+            token,
+            Arc::clone(&events),
+            &counters,
+            provenance.as_ref(),
+            audit.as_ref(),
+            caller,
+        )
+        .await;
+        let status = result.as_ref().map_or("failed", Outcome::status);
+        if let Some((output, split)) = &atomic_output {
+            let parts: Vec<PathBuf> = if *split {
+                (first_file..)
+                    .map(|number| writer::part_path(&split::numbered(output, number)))
+                    .take_while(|part| part.exists())
+                    .collect()
+            } else {
+                Some(writer::part_path(output))
+                    .filter(|part| part.exists())
+                    .into_iter()
+                    .collect()
+            };
+            for part in parts {
+                warn!("Output left unfinished as {}", part.display());
+            }
+        }
+        for part in also_parts.iter().filter(|part| part.exists()) {
+            warn!("Output left unfinished as {}", part.display());
+        }
+
+        if let Some(provenance) = &provenance {
+            if let Err(e) = provenance
+                .finish(status, counters.processed.load(Ordering::Relaxed))
+                .await
+            {
+                error!("{:#}", e);
+            }
+        }
+
+        let summary = RunSummary {
+            run_id: run_id.clone(),
+            status,
+            record_type,
+            processed: counters.processed.load(Ordering::Relaxed),
+            rejected: counters.rejected.load(Ordering::Relaxed),
+            errors: counters.errors.load(Ordering::Relaxed),
+            invalid: counters.invalid.load(Ordering::Relaxed),
+            elapsed_secs: started.elapsed().as_secs_f64(),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            error_digest: counters.digest.groups(),
+            pool: counters.pool.summary(),
+        };
+        if let Some(path) = &summary_json {
+            if let Err(e) = summary.save_json(path) {
+                error!("{:#}", e);
+            }
+        }
+        if let Some((path, output, split, also, chain, parent)) = manifest {
+            let ranges = &counters.ranges;
+            let mut files = match &output {
+                Some(output) if split => {
+                    let numbers = first_file..=counters.files.load(Ordering::Relaxed);
+                    let paths = pipeline::output_files(output, Some(numbers.clone()));
+                    paths
+                        .into_iter()
+                        .zip(numbers.map(|number| ranges.file(number)))
+                        .collect()
+                }
+                Some(output) => vec![(output.clone(), ranges.file(1))],
+                None => Vec::new(),
+            };
+            // Every --also-output holds what --output took
+            files.extend(also.into_iter().map(|path| (path, ranges.all())));
+            let written = Manifest::new(
+                &summary,
+                started_at,
+                &counters.emitted,
+                files,
+                manifest_config,
+            )
+            .and_then(|mut manifest| {
+                if let Some(parent) = &parent {
+                    manifest.parent = Some(Parent::read(parent)?);
+                }
+                match chain {
+                    Some((batch, true)) => Ok(manifest.chain(batch, Manifest::load(&path)?)),
+                    Some((batch, false)) => Ok(manifest.chain(batch, None)),
+                    None => Ok(manifest),
+                }
+            })
+            .and_then(|manifest| manifest.save(&path, durable));
+            match written {
+                Ok(()) => info!("Manifest written to {}", path.display()),
+                Err(e) => error!("{:#}", e),
+            }
+        }
+        if let Some(audit) = &audit {
+            if let Err(e) = audit.finished(&summary) {
+                error!("{:#}", e);
+            }
+        }
+        let records_written = summary.processed;
+        let records_rejected = summary.rejected;
+        let records_invalid = summary.invalid;
+        let errors = summary.errors;
+        events.finish(Event::Finished(summary));
+
+        let point = match &result {
+            Ok(Outcome::Complete | Outcome::MoreRemains) => HookPoint::Post,
+            _ => HookPoint::Failure,
+        };
+        let hook_env = HookEnv {
+            run_id: &run_id,
+            output_paths: &output_paths,
+            records_written,
+            status,
+            summary: summary_json.as_ref(),
+        };
+        let hook_result = hooks.run(point, &hook_env).await;
+        if temporary_summary {
+            if let Some(path) = &summary_json {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        let mut outcome = result;
+        if let Err(e) = hook_result {
+            error!("{:#}", e);
+            if hooks_strict {
+                if let Err(e) = &outcome {
+                    error!("{:#}", e);
+                }
+                outcome = Ok(Outcome::HookFailed);
+            }
+        }
+
+        Ok(ExtractionSummary {
+            outcome: outcome?,
+            run_id,
+            records_written,
+            records_rejected,
+            records_invalid,
+            errors,
+            elapsed: started.elapsed(),
+        })
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::compression::{Compression, OutputCompression};
+use crate::db::MarcRecord;
+use crate::sink::{self, OutputFormat, OutputOptions};
+use crate::writer::{FormatWriter, WriteOutcome};
+
+/// Records each --also-output writer may have waiting before the run waits for it
+const BRANCH_CAPACITY: usize = 1000;
+
+/// Another file the run writes its records to, in a format of its own (--also-output)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlsoOutput {
+    /// The file written
+    pub path: PathBuf,
+    /// How records are encoded in it
+    pub format: OutputFormat,
+}
+
+impl AlsoOutput {
+    /// Read `PATH=FORMAT`; the path may hold `=` itself, the format never does
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (path, format) = value.rsplit_once('=').ok_or_else(|| {
+            format!(
+                "expected PATH=FORMAT (e.g. records.mrc=marc21), got {:?}",
+                value
+            )
+        })?;
+        if path.is_empty() {
+            return Err(format!("no path before ={}", format));
+        }
+        let format = OutputFormat::from_str(format, true).map_err(|_| {
+            let known: Vec<String> = OutputFormat::value_variants()
+                .iter()
+                .filter_map(|format| format.to_possible_value())
+                .map(|value| value.get_name().to_string())
+                .collect();
+            format!(
+                "unknown format {:?}; known are {}",
+                format,
+                known.join(", ")
+            )
+        })?;
+        Ok(Self {
+            path: PathBuf::from(path),
+            format,
+        })
+    }
+}
+
+/// What became of one --also-output
+#[derive(Debug)]
+pub struct Report {
+    pub output: AlsoOutput,
+    pub written: u64,
+    /// Records its format could not encode
+    pub rejected: u64,
+    /// Records whose write failed, the output carrying on after them
+    pub failed: u64,
+    /// Bytes written, before any compression
+    pub bytes: u64,
+    /// Why the output stopped part way, leaving it incomplete
+    pub error: Option<String>,
+}
+
+/// Hands the records the writer gets to the --also-output writers, each writing on a task
+/// of its own, so one pass over the database fills every output
+///
+/// An output that fails for good (its disk full, say) stops there and is reported at the
+/// end, while the run and the other outputs go on; with `fail_fast` its failure stops the
+/// run instead.
+pub struct Fanout {
+    branches: Vec<Branch>,
+    fail_fast: bool,
+}
+
+struct Branch {
+    /// `None` once the branch has stopped taking records
+    tx: Option<mpsc::Sender<Arc<MarcRecord>>>,
+    handle: JoinHandle<Result<Report>>,
+}
+
+impl Fanout {
+    /// Open every output, each with `options` but its own format, and compressed as its
+    /// name says
+    pub async fn open(
+        outputs: &[AlsoOutput],
+        options: &OutputOptions,
+        fail_fast: bool,
+    ) -> Result<Self> {
+        let mut branches = Vec::with_capacity(outputs.len());
+        for output in outputs {
+            let options = OutputOptions {
+                format: output.format,
+                compression: Compression::new(
+                    OutputCompression::for_path(Some(&output.path)),
+                    None,
+                )?,
+                records_per_file: None,
+                max_file_bytes: None,
+                written: None,
+                ..options.clone()
+            };
+            let writer = sink::open_writer(Some(output.path.clone()), options)
+                .await
+                .with_context(|| {
+                    format!("Failed to open --also-output {}", output.path.display())
+                })?;
+            info!(
+                "Also writing {:?} to {}",
+                output.format,
+                output.path.display()
+            );
+            let (tx, rx) = mpsc::channel(BRANCH_CAPACITY);
+            let handle = tokio::spawn(write_branch(output.clone(), writer, rx, fail_fast));
+            branches.push(Branch {
+                tx: Some(tx),
+                handle,
+            });
+        }
+        Ok(Self {
+            branches,
+            fail_fast,
+        })
+    }
+
+    /// Give `record` to every output still taking records
+    pub async fn send(&mut self, record: &MarcRecord) -> Result<()> {
+        if self.branches.is_empty() {
+            return Ok(());
+        }
+        let record = Arc::new(record.clone());
+        for branch in &mut self.branches {
+            let Some(tx) = &branch.tx else {
+                continue;
+            };
+            if tx.send(Arc::clone(&record)).await.is_err() {
+                // A branch only stops early when --fail-fast has it give up
+                branch.tx = None;
+                if self.fail_fast {
+                    return Err(match (&mut branch.handle).await {
+                        Ok(Err(e)) => e,
+                        Ok(Ok(_)) => anyhow!("An --also-output writer stopped early"),
+                        Err(e) => anyhow!(e).context("An --also-output writer panicked"),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Close every output and report on each
+    pub async fn finish(self) -> Result<Vec<Report>> {
+        let mut reports = Vec::with_capacity(self.branches.len());
+        for Branch { tx, handle } in self.branches {
+            drop(tx);
+            reports.push(handle.await.context("An --also-output writer panicked")??);
+        }
+        Ok(reports)
+    }
+}
+
+/// Write every record `rx` brings to one output, until the records run out
+async fn write_branch(
+    output: AlsoOutput,
+    mut writer: FormatWriter,
+    mut rx: mpsc::Receiver<Arc<MarcRecord>>,
+    fail_fast: bool,
+) -> Result<Report> {
+    let path = output.path.display().to_string();
+    let mut report = Report {
+        output,
+        written: 0,
+        rejected: 0,
+        failed: 0,
+        bytes: 0,
+        error: None,
+    };
+    while let Some(record) = rx.recv().await {
+        match writer.write_record(&record).await {
+            Ok(WriteOutcome::Written) => report.written += 1,
+            Ok(WriteOutcome::Rejected(rejection)) => {
+                warn!(
+                    record_id = record.id,
+                    "Skipped record ID {} in {} ({})", record.id, path, rejection
+                );
+                report.rejected += 1;
+            }
+            Err(e) if sink::is_fatal(&e) => {
+                let e = e.context(format!("--also-output {} failed", path));
+                if fail_fast {
+                    return Err(e);
+                }
+                error!("{:#}; carrying on without it", e);
+                report.bytes = writer.offset();
+                report.error = Some(format!("{:#}", e));
+                // Taken and dropped, so the other outputs are never kept waiting
+                while rx.recv().await.is_some() {}
+                return Ok(report);
+            }
+            Err(e) => {
+                error!(
+                    record_id = record.id,
+                    "Failed to write record ID {} to {}: {:#}", record.id, path, e
+                );
+                report.failed += 1;
+            }
+        }
+    }
+    report.bytes = writer.offset();
+    if let Err(e) = writer.finalize().await {
+        let e = e.context(format!("Failed to finish --also-output {}", path));
+        if fail_fast {
+            return Err(e);
+        }
+        error!("{:#}", e);
+        report.error = Some(format!("{:#}", e));
+    }
+    Ok(report)
+}
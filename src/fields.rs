@@ -0,0 +1,899 @@
+use chrono::SecondsFormat;
+use std::fmt;
+
+use crate::db::MarcRecord;
+use crate::marc::{Field, FieldData, ParsedRecord};
+
+/// A parsed --fields spec: the columns to pull out of each record, in order
+///
+/// ```text
+/// spec       = column ("," column)*
+/// column     = "@" name | tag occurrence? condition? subfields? function*
+/// occurrence = "[" number "]"                 the nth matching field, from 1
+/// condition  = "{" test ("," test)* "}"       i1=x, i2=x (# or _ for blank), sfC=value
+/// subfields  = code+                          a-z and 0-9
+/// function   = ":" name                       trim, firstword, normalize-isbn
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldSpec {
+    /// The columns, in order
+    pub columns: Vec<Column>,
+}
+
+/// One column, with the spec text it came from, which is also its header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub label: String,
+    pub source: Source,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// A column of biblio.record_entry rather than of the MARC
+    Pseudo(Pseudo),
+    Field(FieldSelector),
+}
+
+/// Record columns a spec can name with `@`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pseudo {
+    Id,
+    Fingerprint,
+    Quality,
+    EditDate,
+}
+
+impl Pseudo {
+    const ALL: [Pseudo; 4] = [
+        Pseudo::Id,
+        Pseudo::Fingerprint,
+        Pseudo::Quality,
+        Pseudo::EditDate,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Pseudo::Id => "id",
+            Pseudo::Fingerprint => "fingerprint",
+            Pseudo::Quality => "quality",
+            Pseudo::EditDate => "edit_date",
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Pseudo::Id => "the record id",
+            Pseudo::Fingerprint => "Evergreen's match fingerprint (empty when never computed)",
+            Pseudo::Quality => "Evergreen's quality score (empty when never computed)",
+            Pseudo::EditDate => "when the record was last edited, in RFC 3339",
+        }
+    }
+}
+
+/// Which fields of a tag, and which of their subfields, make up a column
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldSelector {
+    pub tag: String,
+    /// Only the nth field (from 1) among those passing the conditions
+    pub occurrence: Option<usize>,
+    pub conditions: Vec<Condition>,
+    /// Empty for every subfield, or for a control field's data
+    pub subfields: Vec<char>,
+    /// Applied in order to each value
+    pub functions: Vec<Function>,
+}
+
+impl FieldSelector {
+    /// Tags 001-009 hold data rather than indicators and subfields
+    pub fn is_control(&self) -> bool {
+        self.tag.starts_with("00")
+    }
+}
+
+/// A test a field must pass to count for its column
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    /// The first (1) or second (2) indicator is this character
+    Indicator(u8, char),
+    /// Some subfield with this code has exactly this value
+    Subfield(char, String),
+}
+
+/// Post-processing of each value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    Trim,
+    FirstWord,
+    NormalizeIsbn,
+}
+
+impl Function {
+    const ALL: [Function; 3] = [Function::Trim, Function::FirstWord, Function::NormalizeIsbn];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Function::Trim => "trim",
+            Function::FirstWord => "firstword",
+            Function::NormalizeIsbn => "normalize-isbn",
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Function::Trim => "whitespace removed from both ends",
+            Function::FirstWord => "cut at the first whitespace",
+            Function::NormalizeIsbn => {
+                "reduced to the ISBN's digits and check character; values that are not a 10- or 13-character ISBN are kept as they are"
+            }
+        }
+    }
+
+    /// A value that shows what the function does
+    fn example(self) -> &'static str {
+        match self {
+            Function::Trim => "  Moby Dick ",
+            Function::FirstWord => "0306406152 (pbk.)",
+            Function::NormalizeIsbn => "0-306-40615-2 (pbk.)",
+        }
+    }
+
+    /// Apply the function to one value
+    pub fn apply(self, value: &str) -> String {
+        match self {
+            Function::Trim => value.trim().to_string(),
+            Function::FirstWord => value.split_whitespace().next().unwrap_or("").to_string(),
+            Function::NormalizeIsbn => {
+                let first = value.split_whitespace().next().unwrap_or("");
+                let isbn: String = first
+                    .chars()
+                    .filter(|c| *c != '-')
+                    .map(|c| c.to_ascii_uppercase())
+                    .collect();
+                let (body, check) = isbn.split_at(isbn.len().saturating_sub(1));
+                let valid = match isbn.len() {
+                    10 => {
+                        body.chars().all(|c| c.is_ascii_digit())
+                            && (check == "X" || check.chars().all(|c| c.is_ascii_digit()))
+                    }
+                    13 => isbn.chars().all(|c| c.is_ascii_digit()),
+                    _ => false,
+                };
+                if valid {
+                    isbn
+                } else {
+                    value.to_string()
+                }
+            }
+        }
+    }
+}
+
+/// Where and why a spec failed to parse
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpecError {
+    pub spec: String,
+    /// Character offset of the problem, from 0
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "invalid field spec at character {}: {}",
+            self.position + 1,
+            self.message
+        )?;
+        writeln!(f, "  {}", self.spec)?;
+        write!(f, "  {}^", " ".repeat(self.position))
+    }
+}
+
+impl std::error::Error for SpecError {}
+
+impl FieldSpec {
+    /// Read a spec such as `"001,245a,020a:normalize-isbn"`
+    pub fn parse(spec: &str) -> Result<Self, SpecError> {
+        let mut parser = Parser {
+            spec,
+            chars: spec.chars().collect(),
+            pos: 0,
+        };
+        let mut columns = Vec::new();
+        loop {
+            parser.skip_spaces();
+            columns.push(parser.column()?);
+            parser.skip_spaces();
+            match parser.peek() {
+                None => break,
+                Some(',') => parser.pos += 1,
+                Some(c) => {
+                    return Err(
+                        parser.error(format!("unexpected {:?}; columns are separated by ','", c))
+                    )
+                }
+            }
+        }
+        Ok(Self { columns })
+    }
+
+    /// The spec as it was given, column by column
+    pub fn text(&self) -> String {
+        self.columns
+            .iter()
+            .map(|column| column.label.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Whether a column needs Evergreen's fingerprint, quality, or edit date, which are
+    /// only fetched when asked for
+    pub fn wants_meta(&self) -> bool {
+        self.columns
+            .iter()
+            .any(|column| matches!(column.source, Source::Pseudo(pseudo) if pseudo != Pseudo::Id))
+    }
+
+    /// Each column's value for `record`, whose MARC `parsed` is; the values of repeated
+    /// fields are joined with `separator`, and a column with none is empty
+    pub fn values(
+        &self,
+        record: &MarcRecord,
+        parsed: &ParsedRecord,
+        separator: &str,
+    ) -> Vec<String> {
+        self.columns
+            .iter()
+            .map(|column| match &column.source {
+                Source::Pseudo(pseudo) => pseudo_value(*pseudo, record),
+                Source::Field(selector) => field_values(selector, parsed).join(separator),
+            })
+            .collect()
+    }
+
+    /// How each column was understood, one line per column
+    pub fn explain(&self) -> Vec<String> {
+        self.columns
+            .iter()
+            .enumerate()
+            .map(|(index, column)| {
+                let meaning = match &column.source {
+                    Source::Pseudo(pseudo) => pseudo.describe().to_string(),
+                    Source::Field(selector) => explain_selector(selector),
+                };
+                format!("column {} ({}): {}", index + 1, column.label, meaning)
+            })
+            .collect()
+    }
+}
+
+fn pseudo_value(pseudo: Pseudo, record: &MarcRecord) -> String {
+    let meta = record.meta.as_ref();
+    match pseudo {
+        Pseudo::Id => record.id.to_string(),
+        Pseudo::Fingerprint => meta
+            .and_then(|meta| meta.fingerprint.clone())
+            .unwrap_or_default(),
+        Pseudo::Quality => meta
+            .and_then(|meta| meta.quality)
+            .map(|q| q.to_string())
+            .unwrap_or_default(),
+        Pseudo::EditDate => meta
+            .map(|meta| meta.edit_date.to_rfc3339_opts(SecondsFormat::Secs, true))
+            .unwrap_or_default(),
+    }
+}
+
+/// The value of each field `selector` picks out of a record, functions applied; empty
+/// values are left out
+fn field_values(selector: &FieldSelector, parsed: &ParsedRecord) -> Vec<String> {
+    let mut matching = parsed
+        .fields
+        .iter()
+        .filter(|field| field.tag == selector.tag && passes(selector, field));
+    let fields: Vec<&Field> = match selector.occurrence {
+        Some(n) => matching.nth(n - 1).into_iter().collect(),
+        None => matching.collect(),
+    };
+    fields
+        .into_iter()
+        .map(|field| {
+            let value = match &field.data {
+                FieldData::Control(data) => data.clone(),
+                FieldData::Data { subfields, .. } => subfields
+                    .iter()
+                    .filter(|(code, _)| {
+                        selector.subfields.is_empty()
+                            || selector.subfields.iter().any(|c| code.chars().eq([*c]))
+                    })
+                    .map(|(_, value)| value.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            };
+            selector
+                .functions
+                .iter()
+                .fold(value, |value, function| function.apply(&value))
+        })
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+/// Whether a field meets every condition of `selector`
+fn passes(selector: &FieldSelector, field: &Field) -> bool {
+    let FieldData::Data {
+        indicators,
+        subfields,
+    } = &field.data
+    else {
+        return selector.conditions.is_empty();
+    };
+    selector.conditions.iter().all(|condition| match condition {
+        Condition::Indicator(which, value) => {
+            let indicator = if *which == 1 {
+                &indicators.0
+            } else {
+                &indicators.1
+            };
+            indicator.chars().next().unwrap_or(' ') == *value
+        }
+        Condition::Subfield(code, value) => subfields
+            .iter()
+            .any(|(c, v)| c.chars().eq([*code]) && v == value),
+    })
+}
+
+fn explain_selector(selector: &FieldSelector) -> String {
+    let mut fields = match selector.occurrence {
+        Some(n) => format!("the {} {} field", ordinal(n), selector.tag),
+        None => format!("every {} field", selector.tag),
+    };
+    let tests: Vec<String> = selector
+        .conditions
+        .iter()
+        .map(|condition| match condition {
+            Condition::Indicator(which, ' ') => format!("indicator {} blank", which),
+            Condition::Indicator(which, value) => format!("indicator {} = '{}'", which, value),
+            Condition::Subfield(code, value) => format!("a ${} of {:?}", code, value),
+        })
+        .collect();
+    if !tests.is_empty() {
+        fields.push_str(&format!(" with {}", tests.join(" and ")));
+    }
+
+    let mut text = if selector.is_control() {
+        format!("the data of {}", fields)
+    } else if selector.subfields.is_empty() {
+        format!("all subfields of {}, joined with spaces", fields)
+    } else {
+        let codes: Vec<String> = selector
+            .subfields
+            .iter()
+            .map(|c| format!("${}", c))
+            .collect();
+        let joined = if codes.len() > 1 {
+            ", in field order, joined with spaces"
+        } else {
+            ""
+        };
+        format!("{} of {}{}", codes.join(" "), fields, joined)
+    };
+    if selector.occurrence.is_none() {
+        text.push_str("; repeated fields are joined with the separator");
+    }
+    for function in &selector.functions {
+        let example = function.example();
+        text.push_str(&format!(
+            "; then {}: {} ({:?} becomes {:?})",
+            function.name(),
+            function.describe(),
+            example,
+            function.apply(example)
+        ));
+    }
+    text
+}
+
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
+
+struct Parser<'a> {
+    spec: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> SpecError {
+        self.error_at(self.pos, message)
+    }
+
+    fn error_at(&self, position: usize, message: impl Into<String>) -> SpecError {
+        SpecError {
+            spec: self.spec.to_string(),
+            position,
+            message: message.into(),
+        }
+    }
+
+    /// Consume characters while `pred` holds
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(&pred) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn expect(&mut self, expected: char, context: &str) -> Result<(), SpecError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(c) => Err(self.error(format!(
+                "expected '{}' {}, found {:?}",
+                expected, context, c
+            ))),
+            None => Err(self.error(format!(
+                "expected '{}' {}, but the spec ends",
+                expected, context
+            ))),
+        }
+    }
+
+    fn column(&mut self) -> Result<Column, SpecError> {
+        let start = self.pos;
+        let source = match self.peek() {
+            Some('@') => Source::Pseudo(self.pseudo()?),
+            Some(c) if c.is_ascii_alphanumeric() => Source::Field(self.field()?),
+            Some(c) => {
+                return Err(self.error(format!("expected a tag or an @ field, found {:?}", c)))
+            }
+            None => return Err(self.error("expected a tag or an @ field, but the spec ends")),
+        };
+        let label = self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+        Ok(Column { label, source })
+    }
+
+    fn pseudo(&mut self) -> Result<Pseudo, SpecError> {
+        let start = self.pos;
+        self.pos += 1;
+        let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        Pseudo::ALL
+            .into_iter()
+            .find(|p| p.name() == name)
+            .ok_or_else(|| {
+                let known: Vec<String> = Pseudo::ALL
+                    .iter()
+                    .map(|p| format!("@{}", p.name()))
+                    .collect();
+                self.error_at(
+                    start,
+                    format!("unknown field @{}; known are {}", name, known.join(", ")),
+                )
+            })
+    }
+
+    fn field(&mut self) -> Result<FieldSelector, SpecError> {
+        let start = self.pos;
+        let tag: String = self.chars[start..].iter().take(3).collect();
+        if tag.len() < 3 || !tag.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(self.error_at(start, "expected a three-character tag such as 245"));
+        }
+        self.pos += 3;
+        let mut selector = FieldSelector {
+            tag,
+            occurrence: None,
+            conditions: Vec::new(),
+            subfields: Vec::new(),
+            functions: Vec::new(),
+        };
+
+        if self.peek() == Some('[') {
+            selector.occurrence = Some(self.occurrence()?);
+        }
+        if self.peek() == Some('{') {
+            let position = self.pos;
+            selector.conditions = self.conditions()?;
+            if selector.is_control() {
+                return Err(self.error_at(
+                    position,
+                    format!(
+                        "control field {} has no indicators or subfields to test",
+                        selector.tag
+                    ),
+                ));
+            }
+        }
+        while let Some(code) = self
+            .peek()
+            .filter(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        {
+            if selector.is_control() {
+                return Err(self.error(format!("control field {} has no subfields", selector.tag)));
+            }
+            if selector.subfields.contains(&code) {
+                return Err(self.error(format!("subfield ${} is given twice", code)));
+            }
+            selector.subfields.push(code);
+            self.pos += 1;
+        }
+        while self.peek() == Some(':') {
+            selector.functions.push(self.function()?);
+        }
+
+        match self.peek() {
+            None | Some(',') => Ok(selector),
+            Some(c) if c.is_whitespace() => Ok(selector),
+            Some('[') => Err(self.error("the occurrence goes straight after the tag")),
+            Some('{') => {
+                Err(self.error("conditions go after the occurrence and before the subfield codes"))
+            }
+            Some(c) if c.is_ascii_uppercase() => Err(self.error(format!(
+                "subfield codes are lowercase letters and digits; did you mean '{}'?",
+                c.to_ascii_lowercase()
+            ))),
+            Some(c) => {
+                Err(self.error(format!("unexpected {:?} in the {} column", c, selector.tag)))
+            }
+        }
+    }
+
+    fn occurrence(&mut self) -> Result<usize, SpecError> {
+        self.pos += 1;
+        let start = self.pos;
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        let n = match digits.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            Ok(_) => return Err(self.error_at(start, "occurrences count from 1")),
+            Err(_) => return Err(self.error_at(start, "expected an occurrence number such as [1]")),
+        };
+        self.expect(']', "to close the occurrence")?;
+        Ok(n)
+    }
+
+    fn conditions(&mut self) -> Result<Vec<Condition>, SpecError> {
+        self.pos += 1;
+        let mut conditions = Vec::new();
+        loop {
+            self.skip_spaces();
+            conditions.push(self.condition()?);
+            self.skip_spaces();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(conditions);
+                }
+                Some(c) => {
+                    return Err(self.error(format!(
+                        "expected ',' or '}}' after a condition, found {:?}",
+                        c
+                    )))
+                }
+                None => {
+                    return Err(
+                        self.error("expected '}' to close the conditions, but the spec ends")
+                    )
+                }
+            }
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, SpecError> {
+        let start = self.pos;
+        let name = self.take_while(|c| c.is_ascii_alphanumeric());
+        let condition = if name == "i1" || name == "i2" {
+            self.expect('=', &format!("after {}", name))?;
+            let value = match self.peek() {
+                Some('#') | Some('_') => ' ',
+                Some(c) if c.is_ascii_alphanumeric() => c,
+                _ => {
+                    return Err(self.error(
+                        "expected an indicator value (a letter, a digit, or # or _ for blank)",
+                    ))
+                }
+            };
+            self.pos += 1;
+            Condition::Indicator(if name == "i1" { 1 } else { 2 }, value)
+        } else if let Some(code) = name.strip_prefix("sf").filter(|code| code.len() == 1) {
+            let code = code.chars().next().expect("one character");
+            if !(code.is_ascii_lowercase() || code.is_ascii_digit()) {
+                return Err(
+                    self.error_at(start + 2, "subfield codes are lowercase letters and digits")
+                );
+            }
+            self.expect('=', &format!("after {}", name))?;
+            Condition::Subfield(code, self.value()?)
+        } else {
+            return Err(self.error_at(
+                start,
+                "expected a condition: i1=x, i2=x, or sfC=value such as sf2=fast",
+            ));
+        };
+        Ok(condition)
+    }
+
+    /// A condition's value: up to the next ',' or '}', or in double quotes
+    fn value(&mut self) -> Result<String, SpecError> {
+        if self.peek() != Some('"') {
+            let value = self.take_while(|c| c != ',' && c != '}');
+            if value.is_empty() {
+                return Err(self.error("expected a value; quote an empty one as \"\""));
+            }
+            return Ok(value);
+        }
+        let open = self.pos;
+        self.pos += 1;
+        let value = self.take_while(|c| c != '"');
+        if self.peek() != Some('"') {
+            return Err(self.error_at(open, "this quote is never closed"));
+        }
+        self.pos += 1;
+        Ok(value)
+    }
+
+    fn function(&mut self) -> Result<Function, SpecError> {
+        self.pos += 1;
+        let start = self.pos;
+        let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == '-');
+        Function::ALL
+            .into_iter()
+            .find(|f| f.name() == name)
+            .ok_or_else(|| {
+                let known: Vec<&str> = Function::ALL.iter().map(|f| f.name()).collect();
+                self.error_at(
+                    start,
+                    format!(
+                        "unknown function {:?}; known are {}",
+                        name,
+                        known.join(", ")
+                    ),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::marc::parse_record;
+
+    fn selector(spec: &str) -> FieldSelector {
+        match FieldSpec::parse(spec).unwrap().columns.remove(0).source {
+            Source::Field(selector) => selector,
+            source => panic!("{:?} is not a field: {:?}", spec, source),
+        }
+    }
+
+    /// The character offset and message of a spec that must not parse
+    fn error(spec: &str) -> (usize, String) {
+        let error = FieldSpec::parse(spec).unwrap_err();
+        (error.position, error.message)
+    }
+
+    fn values(spec: &str, xml: &str) -> Vec<String> {
+        let record = MarcRecord {
+            id: 7,
+            marc: xml.to_string(),
+            rejection: None,
+            display: Vec::new(),
+            meta: None,
+            identifiers: None,
+            status: None,
+        };
+        let parsed = parse_record(xml).unwrap();
+        FieldSpec::parse(spec)
+            .unwrap()
+            .values(&record, &parsed, "|")
+    }
+
+    const RECORD: &str = "<record xmlns=\"http://www.loc.gov/MARC21/slim\">\
+        <leader>00000nam a2200000 a 4500</leader>\
+        <controlfield tag=\"001\">ocm123</controlfield>\
+        <datafield tag=\"020\" ind1=\" \" ind2=\" \"><subfield code=\"a\">0-306-40615-2 (pbk.)</subfield></datafield>\
+        <datafield tag=\"245\" ind1=\"1\" ind2=\"0\"><subfield code=\"a\">Title :</subfield><subfield code=\"b\">subtitle</subfield><subfield code=\"c\">Author</subfield></datafield>\
+        <datafield tag=\"650\" ind1=\" \" ind2=\"0\"><subfield code=\"a\">Whales</subfield></datafield>\
+        <datafield tag=\"650\" ind1=\" \" ind2=\"7\"><subfield code=\"a\">Ships</subfield><subfield code=\"2\">fast</subfield></datafield>\
+        <datafield tag=\"856\" ind1=\"4\" ind2=\"1\"><subfield code=\"u\">http://a</subfield></datafield>\
+        <datafield tag=\"856\" ind1=\"4\" ind2=\"0\"><subfield code=\"u\">http://b</subfield></datafield>\
+        </record>";
+
+    #[test]
+    fn specs_parse_into_their_selectors() {
+        assert_eq!(
+            selector("245ab"),
+            FieldSelector {
+                tag: "245".to_string(),
+                occurrence: None,
+                conditions: Vec::new(),
+                subfields: vec!['a', 'b'],
+                functions: Vec::new(),
+            }
+        );
+        assert_eq!(selector("245[1]ab").occurrence, Some(1));
+        assert_eq!(
+            selector("856{i2=0}u").conditions,
+            vec![Condition::Indicator(2, '0')]
+        );
+        assert_eq!(
+            selector("856{i1=#, i2=_}u").conditions,
+            vec![Condition::Indicator(1, ' '), Condition::Indicator(2, ' ')]
+        );
+        assert_eq!(
+            selector("650{sf2=fast}a").conditions,
+            vec![Condition::Subfield('2', "fast".to_string())]
+        );
+        assert_eq!(
+            selector("650{sf2=\"a,b}\"}a").conditions,
+            vec![Condition::Subfield('2', "a,b}".to_string())]
+        );
+        assert_eq!(
+            selector("020a:firstword:normalize-isbn").functions,
+            vec![Function::FirstWord, Function::NormalizeIsbn]
+        );
+        assert_eq!(
+            selector("245[2]{i1=1,sfc=x}ab:trim"),
+            FieldSelector {
+                tag: "245".to_string(),
+                occurrence: Some(2),
+                conditions: vec![
+                    Condition::Indicator(1, '1'),
+                    Condition::Subfield('c', "x".to_string())
+                ],
+                subfields: vec!['a', 'b'],
+                functions: vec![Function::Trim],
+            }
+        );
+        assert!(selector("001").is_control());
+        assert!(selector("245").subfields.is_empty());
+    }
+
+    #[test]
+    fn columns_keep_their_text_as_labels() {
+        let spec = FieldSpec::parse(" @id, 245[1]ab ,650{sf2=fast}a:trim").unwrap();
+        let labels: Vec<&str> = spec.columns.iter().map(|c| c.label.as_str()).collect();
+        assert_eq!(labels, ["@id", "245[1]ab", "650{sf2=fast}a:trim"]);
+        assert_eq!(spec.text(), "@id,245[1]ab,650{sf2=fast}a:trim");
+        assert_eq!(spec.columns[0].source, Source::Pseudo(Pseudo::Id));
+        assert!(!spec.wants_meta());
+        assert!(FieldSpec::parse("@id,@edit_date").unwrap().wants_meta());
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        let cases: &[(&str, usize, &str)] = &[
+            ("", 0, "expected a tag"),
+            ("245a,", 5, "expected a tag"),
+            ("24", 0, "three-character tag"),
+            ("@title", 0, "unknown field @title"),
+            ("245[0]a", 4, "count from 1"),
+            ("245[x]a", 4, "occurrence number"),
+            ("245[1a", 5, "expected ']'"),
+            ("245a[1]", 4, "straight after the tag"),
+            ("856{i1=4}[1]u", 9, "straight after the tag"),
+            ("245a{i1=0}", 4, "conditions go after"),
+            ("245A", 3, "did you mean 'a'"),
+            ("245aa", 4, "given twice"),
+            ("001a", 3, "has no subfields"),
+            ("001{i1=0}", 3, "control field 001"),
+            ("856{i3=0}u", 4, "expected a condition"),
+            ("856{i2=?}u", 7, "indicator value"),
+            ("856{i2=0 u", 9, "expected ',' or '}'"),
+            ("856{i2=0", 8, "spec ends"),
+            ("650{sfA=x}a", 6, "lowercase"),
+            ("650{sf2=}a", 8, "expected a value"),
+            ("650{sf2=\"fast}a", 8, "never closed"),
+            ("020a:upper", 5, "unknown function \"upper\""),
+            ("245a;246a", 4, "unexpected ';'"),
+            ("245a 246a", 5, "columns are separated by ','"),
+        ];
+        for (spec, position, message) in cases {
+            let (found_position, found_message) = error(spec);
+            assert!(
+                found_message.contains(message),
+                "{:?}: {:?} lacks {:?}",
+                spec,
+                found_message,
+                message
+            );
+            assert_eq!(found_position, *position, "{:?}: {}", spec, found_message);
+        }
+    }
+
+    #[test]
+    fn error_display_marks_the_position() {
+        let error = FieldSpec::parse("245[0]a").unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "invalid field spec at character 5: occurrences count from 1\n  245[0]a\n      ^"
+        );
+    }
+
+    #[test]
+    fn functions_apply_to_values() {
+        assert_eq!(Function::Trim.apply("  Moby Dick "), "Moby Dick");
+        assert_eq!(Function::FirstWord.apply("0306406152 (pbk.)"), "0306406152");
+        assert_eq!(Function::FirstWord.apply("   "), "");
+        assert_eq!(
+            Function::NormalizeIsbn.apply("0-306-40615-2 (pbk.)"),
+            "0306406152"
+        );
+        assert_eq!(Function::NormalizeIsbn.apply("080442957x"), "080442957X");
+        assert_eq!(
+            Function::NormalizeIsbn.apply("978-0-306-40615-7"),
+            "9780306406157"
+        );
+        assert_eq!(Function::NormalizeIsbn.apply("not an isbn"), "not an isbn");
+        assert_eq!(Function::NormalizeIsbn.apply("12345X7890"), "12345X7890");
+    }
+
+    #[test]
+    fn selectors_pick_values_out_of_a_record() {
+        let cases: &[(&str, &str)] = &[
+            ("@id", "7"),
+            ("001", "ocm123"),
+            ("245", "Title : subtitle Author"),
+            ("245ab", "Title : subtitle"),
+            ("245ba", "Title : subtitle"),
+            ("245c", "Author"),
+            ("650a", "Whales|Ships"),
+            ("650[2]a", "Ships"),
+            ("650[3]a", ""),
+            ("650{sf2=fast}a", "Ships"),
+            ("650{i2=0}a", "Whales"),
+            ("856{i2=0}u", "http://b"),
+            ("856[1]{i1=4}u", "http://a"),
+            ("856{i1=#}u", ""),
+            ("020a:normalize-isbn", "0306406152"),
+            ("020a:firstword", "0-306-40615-2"),
+            ("500a", ""),
+        ];
+        for (spec, expected) in cases {
+            assert_eq!(values(spec, RECORD).join(""), *expected, "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn explain_describes_each_column() {
+        let lines = FieldSpec::parse("@id,245[1]ab,856{i2=#}u")
+            .unwrap()
+            .explain();
+        assert_eq!(lines[0], "column 1 (@id): the record id");
+        assert_eq!(
+            lines[1],
+            "column 2 (245[1]ab): $a $b of the 1st 245 field, in field order, joined with spaces"
+        );
+        assert_eq!(
+            lines[2],
+            "column 3 (856{i2=#}u): $u of every 856 field with indicator 2 blank; repeated fields are joined with the separator"
+        );
+        assert_eq!(ordinal(2), "2nd");
+        assert_eq!(ordinal(11), "11th");
+        assert_eq!(ordinal(23), "23rd");
+    }
+}
@@ -0,0 +1,245 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::histogram::DateField;
+
+/// Saved filter format version, bumped on incompatible changes
+const FILTER_VERSION: u32 = 1;
+
+/// Which records a run selects, independent of how they are fetched or written
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordFilter {
+    /// Select deleted records as well as active ones
+    #[serde(default)]
+    pub include_deleted: bool,
+    /// Select deleted records and no others
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted_only: bool,
+    /// Only select records last edited by these users (actor.usr ids)
+    #[serde(default)]
+    pub editors: Vec<i64>,
+    /// Only select records imported through these Vandelay bib queues
+    #[serde(default)]
+    pub import_queues: Vec<i64>,
+    /// Only select records from these bibliographic sources (config.bib_source ids)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<i64>,
+    /// Only select records with a call number owned by one of these org units
+    /// (actor.org_unit ids, any descendants asked for already among them)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub org_units: Vec<i64>,
+    /// Only select records with an id at or above this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_id: Option<i64>,
+    /// Only select records with an id at or below this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_id: Option<i64>,
+    /// Only select records whose date is at or after a cutoff
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<Since>,
+    /// Only select records this SQL predicate from --where holds for, taken as trusted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub predicate: Option<String>,
+    /// Only select the records the --id-file lists, as it read when the filter was made
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_file: Option<IdFile>,
+}
+
+/// An --id-file, and the sha256 of its contents
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdFile {
+    pub path: PathBuf,
+    pub sha256: String,
+}
+
+/// How a filter saved by an earlier run differs from the one a run now uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drift {
+    None,
+    /// The same selection, but the id file's contents changed since
+    IdFile,
+    /// A different selection
+    Selection,
+}
+
+/// A --since cutoff on one of the record's dates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Since {
+    pub field: DateField,
+    pub time: DateTime<Utc>,
+}
+
+/// On-disk form of a saved filter
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SavedFilter {
+    version: u32,
+    filter: RecordFilter,
+}
+
+impl IdFile {
+    /// Hash the id file at `path`
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents =
+            std::fs::read(path).context(format!("Failed to read id file: {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            sha256: format!("{:x}", Sha256::digest(&contents)),
+        })
+    }
+}
+
+impl RecordFilter {
+    /// Load a filter written by --save-filter, refusing one whose id file has changed
+    /// since unless `allow_drift`
+    pub fn load(path: &Path, allow_drift: bool) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .context(format!("Failed to read filter file: {}", path.display()))?;
+        let saved: SavedFilter =
+            toml::from_str(&text).context(format!("Invalid filter file: {}", path.display()))?;
+
+        if saved.version != FILTER_VERSION {
+            bail!(
+                "Filter file {} has version {}, expected {}",
+                path.display(),
+                saved.version,
+                FILTER_VERSION
+            );
+        }
+
+        if saved.filter.include_deleted && saved.filter.deleted_only {
+            bail!(
+                "Filter file {} both includes deleted records and selects only them",
+                path.display()
+            );
+        }
+
+        let mut filter = saved.filter;
+        if let Some(saved) = &mut filter.id_file {
+            let current = IdFile::read(&saved.path)?;
+            if current.sha256 != saved.sha256 {
+                if !allow_drift {
+                    bail!(
+                        "Id file {} has changed since filter file {} was saved; refusing to apply it without --allow-filter-drift",
+                        saved.path.display(),
+                        path.display()
+                    );
+                }
+                warn!(
+                    "Id file {} has changed since filter file {} was saved; applying it anyway",
+                    saved.path.display(),
+                    path.display()
+                );
+                *saved = current;
+            }
+        }
+        Ok(filter)
+    }
+
+    /// How this filter differs from one `saved` by an earlier run
+    pub fn drift_from(&self, saved: &RecordFilter) -> Drift {
+        if self == saved {
+            return Drift::None;
+        }
+        let same_file = match (&self.id_file, &saved.id_file) {
+            (Some(current), Some(saved)) => current.path == saved.path,
+            _ => false,
+        };
+        let unhashed = RecordFilter {
+            id_file: self.id_file.clone(),
+            ..saved.clone()
+        };
+        if same_file && *self == unhashed {
+            Drift::IdFile
+        } else {
+            Drift::Selection
+        }
+    }
+
+    /// A short id for the selection, the same for the same filter however it was given
+    pub fn fingerprint(&self) -> String {
+        let text = toml::to_string(self).unwrap_or_default();
+        format!("{:x}", Sha256::digest(text.as_bytes()))[..12].to_string()
+    }
+
+    /// Write the filter with every field spelled out, so it reads the same however it was built
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let saved = SavedFilter {
+            version: FILTER_VERSION,
+            filter: self.clone(),
+        };
+        std::fs::write(path, toml::to_string(&saved)?)
+            .context(format!("Failed to write filter file: {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("filter-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn a_saved_filter_refuses_an_id_file_changed_since() {
+        let dir = scratch("drift");
+        let (ids, saved) = (dir.join("ids.txt"), dir.join("saved.toml"));
+        std::fs::write(&ids, "1\n2\n").unwrap();
+        let filter = RecordFilter {
+            id_file: Some(IdFile::read(&ids).unwrap()),
+            ..RecordFilter::default()
+        };
+        filter.save(&saved).unwrap();
+        assert_eq!(RecordFilter::load(&saved, false).unwrap(), filter);
+
+        std::fs::write(&ids, "1\n2\n3\n").unwrap();
+        let error = RecordFilter::load(&saved, false).unwrap_err();
+        let drifted = RecordFilter::load(&saved, true).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(error.to_string().contains("--allow-filter-drift"));
+        assert_eq!(drifted.drift_from(&filter), Drift::IdFile);
+    }
+
+    #[test]
+    fn only_a_changed_id_file_is_drift() {
+        let id_file = |path: &str, sha256: &str| IdFile {
+            path: PathBuf::from(path),
+            sha256: sha256.to_string(),
+        };
+        let saved = RecordFilter {
+            id_file: Some(id_file("ids.txt", "aa")),
+            ..RecordFilter::default()
+        };
+        let drifted = RecordFilter {
+            id_file: Some(id_file("ids.txt", "bb")),
+            ..saved.clone()
+        };
+        let moved = RecordFilter {
+            id_file: Some(id_file("other.txt", "aa")),
+            ..saved.clone()
+        };
+        let deleted = RecordFilter {
+            include_deleted: true,
+            ..drifted.clone()
+        };
+
+        assert_eq!(saved.drift_from(&saved), Drift::None);
+        assert_eq!(drifted.drift_from(&saved), Drift::IdFile);
+        assert_eq!(moved.drift_from(&saved), Drift::Selection);
+        assert_eq!(deleted.drift_from(&saved), Drift::Selection);
+        assert_eq!(RecordFilter::default().drift_from(&saved), Drift::Selection);
+    }
+}
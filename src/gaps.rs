@@ -0,0 +1,273 @@
+use anyhow::{bail, Context, Result};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use tracing::{debug, info, warn};
+
+use crate::manifest::Manifest;
+
+/// Most supplements a chain of manifests is followed back through
+const MAX_PARENTS: usize = 64;
+
+/// Compact set of record ids backed by a bitmap
+#[derive(Debug, Default)]
+pub struct IdSet {
+    words: Vec<u64>,
+    len: u64,
+}
+
+impl IdSet {
+    pub fn insert(&mut self, id: i64) {
+        let Ok(id) = u64::try_from(id) else {
+            return;
+        };
+        let (word, bit) = ((id / 64) as usize, id % 64);
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        if self.words[word] & (1 << bit) == 0 {
+            self.words[word] |= 1 << bit;
+            self.len += 1;
+        }
+    }
+
+    pub fn insert_range(&mut self, first: i64, last: i64) {
+        for id in first.max(0)..=last {
+            self.insert(id);
+        }
+    }
+
+    pub fn contains(&self, id: i64) -> bool {
+        let Ok(id) = u64::try_from(id) else {
+            return false;
+        };
+        self.words
+            .get((id / 64) as usize)
+            .is_some_and(|word| word & (1 << (id % 64)) != 0)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+/// Whether `path` is a --manifest rather than an export; a JSON object, not XML
+pub fn is_manifest(path: &Path) -> bool {
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    let mut reader = BufReader::new(file);
+    loop {
+        let Ok(buf) = reader.fill_buf() else {
+            return false;
+        };
+        let Some(&byte) = buf.first() else {
+            return false;
+        };
+        if !byte.is_ascii_whitespace() {
+            return byte == b'{';
+        }
+        reader.consume(1);
+    }
+}
+
+/// Collect the record ids a previous export holds, from its --manifest or a MARCXML file
+pub fn read_present_ids(path: &Path) -> Result<IdSet> {
+    let mut ids = IdSet::default();
+    if is_manifest(path) {
+        read_manifest_ids(path, &mut ids)?;
+    } else {
+        scan_export(path, &mut ids)?;
+    }
+    Ok(ids)
+}
+
+/// Add the ids of every file a manifest lists, and those of the export it supplements
+///
+/// A file listed with id ranges holds every selected record in them. One without, from
+/// a run that could not keep them, is scanned instead, so it has to be on disk.
+fn read_manifest_ids(path: &Path, ids: &mut IdSet) -> Result<()> {
+    let mut next = Some(path.to_path_buf());
+    let mut followed = 0;
+    while let Some(path) = next.take() {
+        let manifest = Manifest::load(&path)?
+            .with_context(|| format!("Manifest not found: {}", path.display()))?;
+        for output in &manifest.outputs {
+            match &output.id_ranges {
+                Some(ranges) => {
+                    for &[first, last] in ranges {
+                        ids.insert_range(first, last);
+                    }
+                }
+                None if output.path.exists() => scan_export(&output.path, ids)?,
+                None => bail!(
+                    "Manifest {} lists {} without id ranges, and the file is not there to scan",
+                    path.display(),
+                    output.path.display()
+                ),
+            }
+        }
+        info!(
+            "Manifest {} lists {} files of run {}",
+            path.display(),
+            manifest.outputs.len(),
+            manifest.run_id
+        );
+
+        if let Some(parent) = manifest.parent {
+            followed += 1;
+            if followed > MAX_PARENTS {
+                bail!(
+                    "Manifest {} is more than {} supplements from the original export",
+                    path.display(),
+                    MAX_PARENTS
+                );
+            }
+            next = Some(parent.path);
+        }
+    }
+    Ok(())
+}
+
+/// Add the record ids present in a previous MARCXML export
+///
+/// Evergreen keeps the record id in the 001 control field, so that is what identifies
+/// each exported record. The file is scanned as a stream, so its size does not matter.
+fn scan_export(path: &Path, ids: &mut IdSet) -> Result<()> {
+    let file = File::open(path).context(format!(
+        "Failed to open previous export: {}",
+        path.display()
+    ))?;
+    let mut reader = Reader::from_reader(BufReader::new(file));
+
+    let found = ids.len();
+    let mut buf = Vec::new();
+    let mut in_001 = false;
+    let mut record_has_id = false;
+    let mut unreadable = 0u64;
+
+    loop {
+        match reader.read_event_into(&mut buf).context(format!(
+            "Failed to parse previous export: {}",
+            path.display()
+        ))? {
+            Event::Start(e) if e.local_name().as_ref() == b"record" => record_has_id = false,
+            Event::End(e) if e.local_name().as_ref() == b"record" => {
+                if !record_has_id {
+                    unreadable += 1;
+                }
+                in_001 = false;
+            }
+            Event::Start(e) if e.local_name().as_ref() == b"controlfield" => {
+                in_001 = e
+                    .try_get_attribute("tag")?
+                    .is_some_and(|tag| tag.value.as_ref() == b"001");
+            }
+            Event::Text(text) if in_001 => {
+                if let Ok(id) = text.unescape()?.trim().parse::<i64>() {
+                    ids.insert(id);
+                    record_has_id = true;
+                }
+                in_001 = false;
+            }
+            Event::End(_) => in_001 = false,
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    if unreadable > 0 {
+        warn!(
+            "{} records in {} have no numeric 001 and cannot be matched",
+            unreadable,
+            path.display()
+        );
+    }
+    debug!(
+        "Found {} record ids in {}",
+        ids.len() - found,
+        path.display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A manifest listing one file with `ranges`, or without any, written into `dir`
+    fn manifest(dir: &Path, name: &str, file: &Path, ranges: Option<&str>, parent: Option<&Path>) {
+        let ranges = ranges.map_or(String::new(), |ranges| {
+            format!(r#", "id_ranges": {}"#, ranges)
+        });
+        let parent = parent.map_or(String::new(), |parent| {
+            format!(
+                r#", "parent": {{"path": {:?}, "run_id": "first", "sha256": "00"}}"#,
+                parent
+            )
+        });
+        let json = format!(
+            r#"{{"run_id": "{name}", "status": "complete", "outcome": "complete",
+                "record_type": "bib", "started": "2026-10-01T00:00:00Z",
+                "finished": "2026-10-01T00:01:00Z", "elapsed_secs": 60.0,
+                "records_written": 3, "records_rejected": 0, "errors": 0,
+                "records_invalid": 0,
+                "outputs": [{{"path": {file:?}, "bytes": 1, "sha256": "00"{ranges}}}]{parent}}}"#
+        );
+        std::fs::write(dir.join(name), json).unwrap();
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gaps-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn a_manifest_gives_its_ranges_and_those_up_its_chain() {
+        let dir = scratch("chain");
+        let lost = dir.join("not-on-disk.xml");
+        manifest(&dir, "first.json", &lost, Some("[[1, 3], [7, 8]]"), None);
+        manifest(
+            &dir,
+            "second.json",
+            &lost,
+            Some("[[5, 5]]"),
+            Some(&dir.join("first.json")),
+        );
+
+        assert!(is_manifest(&dir.join("second.json")));
+        let ids = read_present_ids(&dir.join("second.json")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let present: Vec<i64> = (0..10).filter(|&id| ids.contains(id)).collect();
+        assert_eq!(present, vec![1, 2, 3, 5, 7, 8]);
+    }
+
+    #[test]
+    fn a_file_listed_without_ranges_is_scanned() {
+        let dir = scratch("scan");
+        let export = dir.join("export.xml");
+        std::fs::write(
+            &export,
+            r#"<collection><record><controlfield tag="001">42</controlfield></record></collection>"#,
+        )
+        .unwrap();
+        manifest(&dir, "manifest.json", &export, None, None);
+        manifest(&dir, "gone.json", &dir.join("gone.xml"), None, None);
+
+        assert!(!is_manifest(&export));
+        let ids = read_present_ids(&dir.join("manifest.json")).unwrap();
+        assert!(ids.contains(42));
+        assert_eq!(ids.len(), 1);
+        let error = read_present_ids(&dir.join("gone.json")).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(error.to_string().contains("not there to scan"));
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::{bail, Context, Result};
+use indicatif::HumanBytes;
+use sqlx::postgres::PgConnectOptions;
+use sqlx::PgPool;
+use tracing::{info, warn};
+
+use crate::connect::ConnectRetry;
+use crate::db::{self, DatabaseConfig, OrderBy};
+
+/// Most chunks a run may be split into without --force; each chunk is a task and a query
+pub const MAX_CHUNKS: i64 = 100_000;
+
+/// Decoded chunk size above which a warning is given
+const CHUNK_MEMORY_WARNING: u64 = 256 * 1024 * 1024;
+
+/// Rows sampled to estimate the average record size
+const SIZE_SAMPLE: i64 = 1000;
+
+/// Memory the records waiting for the writer may take above which a warning is given
+const CHANNEL_MEMORY_WARNING: u64 = 1024 * 1024 * 1024;
+
+/// Memory the output buffers may take above which a warning is given
+const WRITE_BUFFER_WARNING: u64 = 1024 * 1024 * 1024;
+
+/// Connection limit and usage as reported by the server
+struct Headroom {
+    max_connections: i64,
+    reserved: i64,
+    in_use: i64,
+}
+
+/// Cap the pool's `connections` at those the server has free; `option` names the flag
+/// they came from, for the warning
+///
+/// Checked over a short-lived connection before the pool exists, since the pool's
+/// size cannot change once it is built. As the run's first connection, it is the one
+/// that waits out a database still starting up.
+pub async fn cap_connections(
+    options: &PgConnectOptions,
+    retry: &ConnectRetry,
+    connections: u32,
+    option: &str,
+) -> Result<u32> {
+    let mut conn = retry.connect(options).await?;
+
+    let setting = |name: &'static str| format!("SELECT current_setting('{}')::bigint", name);
+    let headroom = Headroom {
+        max_connections: sqlx::query_scalar(&setting("max_connections"))
+            .fetch_one(&mut conn)
+            .await?,
+        reserved: sqlx::query_scalar(&setting("superuser_reserved_connections"))
+            .fetch_one(&mut conn)
+            .await?,
+        // Includes this probe, whose slot the pool's first connection takes over
+        in_use: sqlx::query_scalar(
+            "SELECT count(*) FROM pg_stat_activity WHERE backend_type = 'client backend'",
+        )
+        .fetch_one(&mut conn)
+        .await?,
+    };
+    drop(conn);
+
+    let free = headroom.max_connections - headroom.reserved - headroom.in_use + 1;
+    if free < 1 {
+        bail!(
+            "The database has no free connections (max_connections {}, {} reserved for superusers, {} in use)",
+            headroom.max_connections,
+            headroom.reserved,
+            headroom.in_use
+        );
+    }
+    if i64::from(connections) > free {
+        warn!(
+            "{} {} exceeds the {} connections the database has free (max_connections {}, {} reserved for superusers, {} in use); using {}",
+            option,
+            connections,
+            free,
+            headroom.max_connections,
+            headroom.reserved,
+            headroom.in_use,
+            free
+        );
+        return Ok(free as u32);
+    }
+
+    Ok(connections)
+}
+
+/// Refuse to split a run into more than MAX_CHUNKS chunks unless forced
+pub fn check_chunk_count(records: i64, chunk_size: i64, force: bool) -> Result<()> {
+    let chunks = (records + chunk_size - 1) / chunk_size;
+    if chunks <= MAX_CHUNKS {
+        return Ok(());
+    }
+    if force {
+        warn!(
+            "Splitting {} records into {} chunks (--force)",
+            records, chunks
+        );
+        return Ok(());
+    }
+
+    let suggested = (records + MAX_CHUNKS - 1) / MAX_CHUNKS;
+    bail!(
+        "--chunk-size {} splits {} records into {} chunks (limit {}); use --chunk-size {} or more, or --force",
+        chunk_size,
+        records,
+        chunks,
+        MAX_CHUNKS,
+        suggested
+    );
+}
+
+/// Average size of the MARC in a sample of `table`'s rows, or `None` for an empty table
+pub async fn average_record_size(pool: &PgPool, table: &str) -> Result<Option<u64>> {
+    let average: Option<i64> = sqlx::query_scalar(&format!(
+        "SELECT avg(octet_length(marc))::bigint FROM (SELECT marc FROM {} LIMIT $1) sample",
+        table
+    ))
+    .bind(SIZE_SAMPLE)
+    .fetch_one(pool)
+    .await
+    .context("Failed to sample record sizes")?;
+    Ok(average.map(|average| average as u64))
+}
+
+/// Warn when a chunk of average-sized records would take a lot of memory
+pub fn check_chunk_memory(average: u64, chunk_size: i64, workers: u32) {
+    let chunk_bytes = average * chunk_size as u64;
+    info!(
+        "Average record size: {} (about {} per chunk)",
+        HumanBytes(average),
+        HumanBytes(chunk_bytes)
+    );
+
+    if chunk_bytes > CHUNK_MEMORY_WARNING {
+        warn!(
+            "Chunks of {} records average {} each, and {} workers can hold {} at once; consider a smaller --chunk-size",
+            chunk_size,
+            HumanBytes(chunk_bytes),
+            workers,
+            HumanBytes(chunk_bytes * u64::from(workers))
+        );
+    }
+}
+
+/// Warn when `queued` average-sized records waiting for the writer would take a lot of memory
+pub fn check_channel_memory(average: u64, queued: usize) {
+    let queued_bytes = average * queued as u64;
+    if queued_bytes > CHANNEL_MEMORY_WARNING {
+        warn!(
+            "{} records of {} on average can wait for the writer, holding {}; consider a smaller --channel-capacity",
+            queued,
+            HumanBytes(average),
+            HumanBytes(queued_bytes)
+        );
+    }
+}
+
+/// Warn when a buffer of `size` bytes for each of `outputs` output files would take a lot
+/// of memory
+pub fn check_write_buffer(size: u64, outputs: usize) {
+    let total = size.saturating_mul(outputs as u64);
+    if total > WRITE_BUFFER_WARNING {
+        warn!(
+            "--write-buffer-size {} for {} output file(s) holds {} in memory; consider a smaller buffer",
+            HumanBytes(size),
+            outputs,
+            HumanBytes(total)
+        );
+    }
+}
+
+/// Warn when no index serves --order-by, since every chunk query then sorts the whole selection
+pub async fn check_order_index(pool: &PgPool, config: &DatabaseConfig) -> Result<()> {
+    if config.order_by == OrderBy::Id || !db::chunk_query_sorts(pool, config).await? {
+        return Ok(());
+    }
+
+    let column = config.order_by.column();
+    warn!(
+        "No index provides --order-by {} order, so each chunk sorts every selected record; \
+         consider CREATE INDEX ON {} ({}, id)",
+        column,
+        config.table(),
+        column
+    );
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Date column a histogram or --since is taken over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DateField {
+    /// When the record was last edited
+    EditDate,
+    /// When the record was created
+    CreateDate,
+}
+
+impl DateField {
+    /// The record table column
+    pub fn column(self) -> &'static str {
+        match self {
+            DateField::EditDate => "edit_date",
+            DateField::CreateDate => "create_date",
+        }
+    }
+}
+
+impl FromStr for DateField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "edit_date" => Ok(DateField::EditDate),
+            "create_date" => Ok(DateField::CreateDate),
+            other => Err(format!(
+                "unknown field {:?} (edit_date, create_date)",
+                other
+            )),
+        }
+    }
+}
+
+/// Width of a histogram bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    Day,
+    Month,
+    Year,
+}
+
+impl Bucket {
+    /// Unit passed to date_trunc
+    pub fn unit(self) -> &'static str {
+        match self {
+            Bucket::Day => "day",
+            Bucket::Month => "month",
+            Bucket::Year => "year",
+        }
+    }
+
+    /// to_char pattern labelling a bucket
+    pub fn label_format(self) -> &'static str {
+        match self {
+            Bucket::Day => "YYYY-MM-DD",
+            Bucket::Month => "YYYY-MM",
+            Bucket::Year => "YYYY",
+        }
+    }
+}
+
+/// A --histogram argument such as `edit_date:month`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct HistogramSpec {
+    /// The date records are counted by
+    pub field: DateField,
+    /// The span each count covers
+    pub bucket: Bucket,
+}
+
+impl FromStr for HistogramSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (field, bucket) = s.split_once(':').ok_or_else(|| {
+            format!(
+                "expected <field>:<bucket>, e.g. edit_date:month, got {:?}",
+                s
+            )
+        })?;
+
+        let field = field.parse::<DateField>()?;
+        let bucket = match bucket {
+            "day" => Bucket::Day,
+            "month" => Bucket::Month,
+            "year" => Bucket::Year,
+            other => return Err(format!("unknown bucket {:?} (day, month, year)", other)),
+        };
+
+        Ok(Self { field, bucket })
+    }
+}
+
+impl std::fmt::Display for HistogramSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.field.column(), self.bucket.unit())
+    }
+}
+
+/// Record count for one bucket; `bucket` is `None` for records without a date
+#[derive(Debug, Serialize)]
+pub struct BucketCount {
+    pub bucket: Option<String>,
+    pub records: i64,
+}
+
+/// Counts per bucket, in bucket order
+#[derive(Debug, Serialize)]
+pub struct Histogram {
+    pub field: DateField,
+    pub bucket: Bucket,
+    pub counts: Vec<BucketCount>,
+}
+
+impl Histogram {
+    /// Lines of a two-column table of bucket labels and counts
+    pub fn table(&self) -> Vec<String> {
+        let label = |c: &BucketCount| c.bucket.clone().unwrap_or_else(|| "(none)".to_string());
+        let width = self
+            .counts
+            .iter()
+            .map(|c| label(c).len())
+            .max()
+            .unwrap_or(0);
+
+        self.counts
+            .iter()
+            .map(|c| format!("{:<width$}  {:>10}", label(c), c.records, width = width))
+            .collect()
+    }
+
+    pub fn save_json(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)
+            .context(format!("Failed to write histogram: {}", path.display()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+/// How long a finished hook's output is still read
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+/// When a hook runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookPoint {
+    /// Before anything is read; a failure aborts the run
+    Pre,
+    /// After a run that completed (or stopped at --batch-max)
+    Post,
+    /// After a run that failed, stopped, or had errors
+    Failure,
+}
+
+impl HookPoint {
+    fn name(self) -> &'static str {
+        match self {
+            HookPoint::Pre => "pre-hook",
+            HookPoint::Post => "post-hook",
+            HookPoint::Failure => "failure-hook",
+        }
+    }
+}
+
+/// What a hook is told about the run, through its environment and first argument
+pub struct HookEnv<'a> {
+    pub run_id: &'a str,
+    pub output_paths: &'a [PathBuf],
+    pub records_written: u64,
+    pub status: &'a str,
+    /// The run summary JSON, passed as $1 once it exists
+    pub summary: Option<&'a PathBuf>,
+}
+
+/// The --pre-hook, --post-hook, and --failure-hook commands of a run
+pub struct Hooks {
+    pub pre: Option<String>,
+    pub post: Option<String>,
+    pub failure: Option<String>,
+    pub timeout: Duration,
+}
+
+impl Hooks {
+    pub fn command(&self, point: HookPoint) -> Option<&str> {
+        match point {
+            HookPoint::Pre => self.pre.as_deref(),
+            HookPoint::Post => self.post.as_deref(),
+            HookPoint::Failure => self.failure.as_deref(),
+        }
+    }
+
+    /// Run the hook for `point`, if there is one, with `sh -c`
+    ///
+    /// Its stdout and stderr go to the log line by line, prefixed with the hook's name.
+    /// A non-zero exit, a signal, or running past the timeout is an error.
+    pub async fn run(&self, point: HookPoint, env: &HookEnv<'_>) -> Result<()> {
+        let Some(command) = self.command(point) else {
+            return Ok(());
+        };
+        let name = point.name();
+        info!("Running {}: {}", name, command);
+
+        let paths: Vec<String> = env
+            .output_paths
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect();
+        let mut child = Command::new("sh");
+        // $0 is the hook's name, $1 the summary path
+        child.arg("-c").arg(command).arg(name);
+        if let Some(summary) = env.summary {
+            child.arg(summary);
+        }
+        let mut child = child
+            .env("RUN_ID", env.run_id)
+            .env("OUTPUT_PATHS", paths.join("\n"))
+            .env("RECORDS_WRITTEN", env.records_written.to_string())
+            .env("STATUS", env.status)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context(format!("Failed to start {}", name))?;
+
+        let stdout = tokio::spawn(log_lines(child.stdout.take(), name, false));
+        let stderr = tokio::spawn(log_lines(child.stderr.take(), name, true));
+
+        let status = match tokio::time::timeout(self.timeout, child.wait()).await {
+            Ok(status) => status.context(format!("Failed to wait for {}", name))?,
+            Err(_) => {
+                let _ = child.kill().await;
+                bail!(
+                    "{} timed out after {}s: {}",
+                    name,
+                    self.timeout.as_secs(),
+                    command
+                );
+            }
+        };
+        // A hook that left a background process holding its output is not waited for
+        let _ = tokio::time::timeout(OUTPUT_GRACE, async { tokio::join!(stdout, stderr) }).await;
+
+        if !status.success() {
+            match status.code() {
+                Some(code) => bail!("{} exited with {}: {}", name, code, command),
+                None => bail!("{} was killed by a signal: {}", name, command),
+            }
+        }
+        info!("{} finished", name);
+        Ok(())
+    }
+}
+
+async fn log_lines(output: Option<impl AsyncRead + Unpin>, name: &'static str, stderr: bool) {
+    let Some(output) = output else {
+        return;
+    };
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if stderr {
+            warn!("{}: {}", name, line);
+        } else {
+            info!("{}: {}", name, line);
+        }
+    }
+}
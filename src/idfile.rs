@@ -0,0 +1,84 @@
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use tracing::warn;
+
+use crate::db::MarcRecord;
+use crate::errorfile;
+use crate::writer::{Encoded, Encoder};
+
+/// Read an --id-file: one record id per line, with blank lines and `#` comments ignored
+///
+/// An --error-file reads as one too: its header is passed over and each line gives the
+/// id before its first comma. The lines for chunks or a stream that failed to fetch
+/// stand for ranges of records, not one, so they are left out with a warning.
+///
+/// The ids come back sorted with duplicates removed, so each is fetched once and the
+/// batches run in id order.
+pub fn read_id_file(path: &Path) -> Result<Vec<i64>> {
+    let text = std::fs::read_to_string(path)
+        .context(format!("Failed to read id file: {}", path.display()))?;
+
+    let mut ids = Vec::new();
+    let mut ranges = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        if line == errorfile::HEADER {
+            continue;
+        }
+        let mut fields = line.splitn(4, ',');
+        let line = fields.next().unwrap_or_default();
+        if let (false, Some(last_id), Some(stage)) =
+            (line.contains('#'), fields.next(), fields.next())
+        {
+            if !last_id.is_empty() || stage == errorfile::STREAM_STAGE {
+                ranges.push(format!("{}..{}", line, last_id));
+                continue;
+            }
+        }
+        let line = match line.split_once('#') {
+            Some((before, _)) => before.trim(),
+            None => line.trim(),
+        };
+        if line.is_empty() {
+            continue;
+        }
+        match line.parse::<i64>() {
+            Ok(id) => ids.push(id),
+            Err(_) => bail!(
+                "Invalid record id {:?} on line {} of id file {}",
+                line,
+                number + 1,
+                path.display()
+            ),
+        }
+    }
+
+    if let Some(first) = ranges.first() {
+        warn!(
+            "Id file {} lists {} ranges of records that failed to fetch (e.g. {}), which are not \
+             retried; rerun them with --min-id and --max-id",
+            path.display(),
+            ranges.len(),
+            first
+        );
+    }
+
+    ids.sort_unstable();
+    ids.dedup();
+    Ok(ids)
+}
+
+/// Encoder for --ids-only: each record's id on a line of its own, as --id-file reads them
+///
+/// The MARC is never fetched for it, so there is nothing to reject but what an earlier
+/// stage already has.
+pub struct IdEncoder;
+
+impl Encoder for IdEncoder {
+    /// The record's line
+    fn encode(&self, record: &MarcRecord) -> Result<Encoded> {
+        match &record.rejection {
+            Some(rejection) => Ok(Err(rejection.clone())),
+            None => Ok(Ok(format!("{}\n", record.id).into_bytes())),
+        }
+    }
+}
@@ -0,0 +1,20 @@
+use anyhow::Result;
+
+use crate::db::MarcRecord;
+use crate::marc;
+use crate::quarantine::Rejection;
+use crate::writer::{prepare_record, Encoded, Encoder};
+
+/// Encoder for binary MARC 21 (ISO 2709), one record after another with no wrapper
+pub struct Iso2709Encoder;
+
+impl Encoder for Iso2709Encoder {
+    /// The record as ISO 2709
+    fn encode(&self, record: &MarcRecord) -> Result<Encoded> {
+        let cleaned_marc = match prepare_record(record) {
+            Ok(cleaned) => cleaned,
+            Err(rejection) => return Ok(Err(rejection)),
+        };
+        Ok(marc::to_iso2709(&cleaned_marc).map_err(|reason| Rejection::new("encode", reason)))
+    }
+}
@@ -0,0 +1,195 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+use crate::db::MarcRecord;
+use crate::errorfile::ErrorFile;
+use crate::marc;
+use crate::quarantine::Rejection;
+use crate::review::Review;
+use crate::trace::Tracer;
+
+/// Size limits of a system records are headed for, measured on the ISO 2709 encoding
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetLimits {
+    /// Name used in reports; defaults to the file name
+    #[serde(default)]
+    pub name: String,
+    pub max_record_bytes: Option<usize>,
+    pub max_field_bytes: Option<usize>,
+    pub max_fields: Option<usize>,
+    pub max_subfields: Option<usize>,
+}
+
+impl TargetLimits {
+    /// A built-in target name, or a TOML file of limits
+    pub fn resolve(value: &str) -> Result<Self> {
+        match value {
+            // Five digits of record length, four of field length
+            "iso2709" => Ok(Self {
+                name: "iso2709".to_string(),
+                max_record_bytes: Some(99_999),
+                max_field_bytes: Some(9_999),
+                ..Default::default()
+            }),
+            _ => {
+                let path = Path::new(value);
+                let text = std::fs::read_to_string(path).context(format!(
+                    "Failed to read target limits file: {}",
+                    path.display()
+                ))?;
+                let mut limits: Self = toml::from_str(&text)
+                    .context(format!("Invalid target limits file: {}", path.display()))?;
+                if limits.name.is_empty() {
+                    limits.name = path.display().to_string();
+                }
+                Ok(limits)
+            }
+        }
+    }
+
+    /// Every limit the record breaks; `None` when it cannot be measured
+    fn violations(&self, xml: &str) -> Option<Vec<String>> {
+        let lengths = marc::iso2709_lengths(xml)?;
+        let mut violations = Vec::new();
+
+        if let Some(max) = self.max_record_bytes.filter(|&max| lengths.record > max) {
+            violations.push(format!(
+                "record is {} bytes (limit {})",
+                lengths.record, max
+            ));
+        }
+        if let Some(max) = self.max_fields.filter(|&max| lengths.fields.len() > max) {
+            violations.push(format!("{} fields (limit {})", lengths.fields.len(), max));
+        }
+        for field in &lengths.fields {
+            if let Some(max) = self.max_field_bytes.filter(|&max| field.bytes > max) {
+                violations.push(format!(
+                    "field {} is {} bytes (limit {})",
+                    field.tag, field.bytes, max
+                ));
+            }
+            if let Some(max) = self.max_subfields.filter(|&max| field.subfields > max) {
+                violations.push(format!(
+                    "field {} has {} subfields (limit {})",
+                    field.tag, field.subfields, max
+                ));
+            }
+        }
+
+        Some(violations)
+    }
+}
+
+/// What to do with a record that breaks the --target-limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OnLimitViolation {
+    /// Write the record and report it
+    Warn,
+    /// Leave the record out (or quarantine it) and report it
+    Skip,
+    /// Abort the run
+    Fail,
+}
+
+/// Checks records against --target-limits in the workers and remembers the offenders
+pub struct LimitCheck {
+    limits: Option<TargetLimits>,
+    policy: OnLimitViolation,
+    quarantine: bool,
+    pub ids: Mutex<Vec<i64>>,
+    tracer: Arc<Tracer>,
+    review: Arc<Review>,
+    error_file: Arc<ErrorFile>,
+}
+
+impl LimitCheck {
+    pub fn new(
+        limits: Option<TargetLimits>,
+        policy: OnLimitViolation,
+        quarantine: bool,
+        tracer: Arc<Tracer>,
+        review: Arc<Review>,
+        error_file: Arc<ErrorFile>,
+    ) -> Self {
+        Self {
+            limits,
+            policy,
+            quarantine,
+            ids: Mutex::new(Vec::new()),
+            tracer,
+            review,
+            error_file,
+        }
+    }
+
+    /// Decide what happens to a record; `None` means it is dropped
+    pub fn screen(&self, mut record: MarcRecord) -> Result<Option<MarcRecord>> {
+        let Some(limits) = &self.limits else {
+            return Ok(Some(record));
+        };
+        if record.rejection.is_some() {
+            return Ok(Some(record));
+        }
+        let violations = match limits.violations(&record.marc) {
+            Some(violations) if !violations.is_empty() => violations,
+            _ => return Ok(Some(record)),
+        };
+
+        let reason = violations.join("; ");
+        self.tracer.note(record.id, "limits", || {
+            let policy = format!("{:?}", self.policy).to_lowercase();
+            format!(
+                "exceeds {} limits: {} (--on-limit-violation {})",
+                limits.name, reason, policy
+            )
+        });
+        self.ids.lock().unwrap().push(record.id);
+
+        match self.policy {
+            OnLimitViolation::Warn => {
+                warn!(
+                    record_id = record.id,
+                    error_kind = "limits",
+                    "Record {} exceeds {} limits: {}",
+                    record.id,
+                    limits.name,
+                    reason
+                );
+                Ok(Some(record))
+            }
+            OnLimitViolation::Fail => bail!(
+                "Record {} exceeds {} limits: {} (--on-limit-violation fail)",
+                record.id,
+                limits.name,
+                reason
+            ),
+            OnLimitViolation::Skip if self.quarantine => {
+                record.rejection = Some(Rejection::new("limits", reason));
+                Ok(Some(record))
+            }
+            OnLimitViolation::Skip => {
+                warn!(
+                    record_id = record.id,
+                    error_kind = "limits",
+                    "Skipping record {}: exceeds {} limits: {}",
+                    record.id,
+                    limits.name,
+                    reason
+                );
+                let rejection = Rejection::new("limits", reason);
+                self.review.capture(&record, &rejection);
+                self.error_file.rejected(record.id, &rejection);
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn target(&self) -> Option<&str> {
+        self.limits.as_ref().map(|l| l.name.as_str())
+    }
+}
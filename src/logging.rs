@@ -0,0 +1,95 @@
+use clap::ValueEnum;
+use std::fmt;
+use std::io::IsTerminal;
+use tracing::field::{Field, Visit};
+use tracing_subscriber::field::{RecordFields, VisitOutput};
+use tracing_subscriber::fmt::format::{DefaultVisitor, Writer};
+use tracing_subscriber::fmt::FormatFields;
+
+/// Fields events carry for --log-format json, whose values their messages already give
+///
+/// Plain lines leave them out so that they read as they always have.
+const STRUCTURED_FIELDS: [&str; 12] = [
+    "database",
+    "workers",
+    "chunk_size",
+    "record_id",
+    "chunk_id",
+    "batch_id",
+    "offset",
+    "error_kind",
+    "records_processed",
+    "records_rejected",
+    "records_quarantined",
+    "errors",
+];
+
+/// How log lines are written to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum LogFormat {
+    /// Lines for people: time, level, and message
+    #[default]
+    Plain,
+    /// One JSON object per line, with each event's fields (record_id, chunk_id, offset,
+    /// error_kind, ...) alongside its message, for Loki and other log stores
+    Json,
+}
+
+/// Start logging at info with --verbose, else warn, unless RUST_LOG says otherwise
+pub fn init(format: LogFormat, verbose: bool) {
+    let log_level = if verbose { "info" } else { "warn" };
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(log_level));
+
+    match format {
+        // Colored only on a terminal, like the progress bar
+        LogFormat::Plain => tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_writer(std::io::stderr)
+            .with_ansi(std::io::stderr().is_terminal())
+            .fmt_fields(PlainFields)
+            .init(),
+        // The event's fields at the top level, with the chunk or batch span it ran in
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_env_filter(filter)
+            .with_writer(std::io::stderr)
+            .init(),
+    }
+}
+
+/// The default field formatting, less the structured fields
+struct PlainFields;
+
+impl<'writer> FormatFields<'writer> for PlainFields {
+    fn format_fields<R: RecordFields>(&self, writer: Writer<'writer>, fields: R) -> fmt::Result {
+        let mut visitor = PlainVisitor(DefaultVisitor::new(writer, true));
+        fields.record(&mut visitor);
+        visitor.0.finish()
+    }
+}
+
+struct PlainVisitor<'a>(DefaultVisitor<'a>);
+
+impl Visit for PlainVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if !STRUCTURED_FIELDS.contains(&field.name()) {
+            self.0.record_str(field, value);
+        }
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        if !STRUCTURED_FIELDS.contains(&field.name()) {
+            self.0.record_error(field, value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !STRUCTURED_FIELDS.contains(&field.name()) {
+            self.0.record_debug(field, value);
+        }
+    }
+}
@@ -0,0 +1,467 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+
+use crate::db::RecordType;
+use crate::events::RunSummary;
+use crate::writer;
+
+/// Lowest and highest id of the records written, kept by the writer as it goes
+pub struct IdRange {
+    min: AtomicI64,
+    max: AtomicI64,
+}
+
+impl Default for IdRange {
+    fn default() -> Self {
+        Self {
+            min: AtomicI64::new(i64::MAX),
+            max: AtomicI64::new(i64::MIN),
+        }
+    }
+}
+
+impl IdRange {
+    pub fn note(&self, id: i64) {
+        self.min.fetch_min(id, Ordering::Relaxed);
+        self.max.fetch_max(id, Ordering::Relaxed);
+    }
+
+    /// `None` until a record has been written
+    pub fn get(&self) -> Option<(i64, i64)> {
+        let (min, max) = (
+            self.min.load(Ordering::Relaxed),
+            self.max.load(Ordering::Relaxed),
+        );
+        (min <= max).then_some((min, max))
+    }
+}
+
+/// Ids written to each file of the output, kept by the writer as ranges that hold every
+/// selected record between their ends
+///
+/// Records of one chunk arrive in id order, so while a chunk keeps writing to the same
+/// file its records there run from the first to the last without a gap. A record that
+/// is not written ends its chunk's range. Nothing is kept until the run says how its
+/// selection is chunked.
+#[derive(Default)]
+pub struct FileRanges {
+    state: Mutex<RangesState>,
+}
+
+#[derive(Default)]
+struct RangesState {
+    /// Last id of each chunk, in id order; `None` while ranges are not kept
+    last_ids: Option<Vec<i64>>,
+    /// The selection is a list of ids, so only ids one apart share a range
+    listed: bool,
+    /// File being written
+    file: u64,
+    /// Range each chunk has open in that file
+    open: HashMap<usize, [i64; 2]>,
+    /// Ranges of every file, by number
+    closed: BTreeMap<u64, Vec<[i64; 2]>>,
+}
+
+impl RangesState {
+    fn close(&mut self, chunk: usize) {
+        if let Some(range) = self.open.remove(&chunk) {
+            self.closed.entry(self.file).or_default().push(range);
+        }
+    }
+
+    fn close_all(&mut self) {
+        let file = self.file;
+        for (_, range) in self.open.drain() {
+            self.closed.entry(file).or_default().push(range);
+        }
+    }
+}
+
+impl FileRanges {
+    /// Keep ranges for a selection fetched in chunks ending at `last_ids` (none for a
+    /// single stream), each in id order; `listed` when it is a list of ids
+    pub fn planned(&self, last_ids: Vec<i64>, listed: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.last_ids = Some(last_ids);
+        state.listed = listed;
+    }
+
+    /// Record `id` was written to file `file`
+    pub fn written(&self, id: i64, file: u64) {
+        let mut state = self.state.lock().unwrap();
+        let Some(last_ids) = &state.last_ids else {
+            return;
+        };
+        let chunk = last_ids.partition_point(|&last| last < id);
+        if file != state.file {
+            state.close_all();
+            state.file = file;
+        }
+        let listed = state.listed;
+        match state.open.get_mut(&chunk) {
+            Some(range) if id > range[1] && (!listed || id == range[1] + 1) => range[1] = id,
+            Some(_) => {
+                state.close(chunk);
+                state.open.insert(chunk, [id, id]);
+            }
+            None => {
+                state.open.insert(chunk, [id, id]);
+            }
+        }
+    }
+
+    /// Record `id` was dealt with but not written
+    pub fn missed(&self, id: i64) {
+        let mut state = self.state.lock().unwrap();
+        let Some(last_ids) = &state.last_ids else {
+            return;
+        };
+        let chunk = last_ids.partition_point(|&last| last < id);
+        state.close(chunk);
+    }
+
+    /// Ranges of file `file` in id order; `None` when they were not kept
+    pub fn file(&self, file: u64) -> Option<Vec<[i64; 2]>> {
+        let mut state = self.state.lock().unwrap();
+        state.last_ids.as_ref()?;
+        state.close_all();
+        let mut ranges = state.closed.get(&file).cloned().unwrap_or_default();
+        ranges.sort_unstable();
+        Some(ranges)
+    }
+
+    /// Ranges across every file, in id order
+    pub fn all(&self) -> Option<Vec<[i64; 2]>> {
+        let mut state = self.state.lock().unwrap();
+        state.last_ids.as_ref()?;
+        state.close_all();
+        let mut ranges: Vec<[i64; 2]> = state.closed.values().flatten().copied().collect();
+        ranges.sort_unstable();
+        Some(ranges)
+    }
+}
+
+/// One file the records were written to, as it was left on disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputFile {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub sha256: String,
+    /// Ids the file holds, as ranges with every selected record between their ends;
+    /// absent when the run did not keep them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_ranges: Option<Vec<[i64; 2]>>,
+}
+
+impl OutputFile {
+    /// Size and hash `path`, or its `.part` file if the run left it unfinished; `None`
+    /// when neither is there
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        let part = writer::part_path(path);
+        let path = match (path.exists(), part.exists()) {
+            (true, _) => path.to_path_buf(),
+            (false, true) => part,
+            (false, false) => return Ok(None),
+        };
+        let mut file =
+            File::open(&path).context(format!("Failed to open {} to hash it", path.display()))?;
+        let mut hasher = Sha256::new();
+        let bytes = std::io::copy(&mut file, &mut hasher)
+            .context(format!("Failed to hash {}", path.display()))?;
+        Ok(Some(Self {
+            path,
+            bytes,
+            sha256: format!("{:x}", hasher.finalize()),
+            id_ranges: None,
+        }))
+    }
+}
+
+/// The manifest of an earlier export, as one filling its gaps refers to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Parent {
+    /// Where the manifest was, made absolute
+    pub path: PathBuf,
+    pub run_id: String,
+    /// Hash of the manifest file, to tell it from a later one written over it
+    pub sha256: String,
+}
+
+impl Parent {
+    pub fn read(path: &Path) -> Result<Self> {
+        let text =
+            std::fs::read(path).context(format!("Failed to read manifest: {}", path.display()))?;
+        let manifest: Manifest = serde_json::from_slice(&text)
+            .context(format!("Invalid manifest: {}", path.display()))?;
+        Ok(Self {
+            path: std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()),
+            run_id: manifest.run_id,
+            sha256: format!("{:x}", Sha256::digest(&text)),
+        })
+    }
+}
+
+/// What a run exported, for the system receiving it; the --manifest file
+///
+/// Written at the end of every run but a dry run or an estimate, including one that
+/// failed or was stopped, which is marked partial. The batches of a --continue-from
+/// chain share one, which adds up the batches it lists.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub run_id: String,
+    /// complete, or partial for a run that failed, stopped, or had errors
+    pub status: String,
+    /// How the run ended, as the status of --summary-json gives it
+    pub outcome: String,
+    pub record_type: RecordType,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub elapsed_secs: f64,
+    pub records_written: u64,
+    pub records_rejected: u64,
+    pub errors: u64,
+    /// Records --validate found not well-formed
+    pub records_invalid: u64,
+    /// Lowest and highest id written; absent when nothing was
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_id: Option<i64>,
+    /// Files written, none for stdout or --sink postgres
+    pub outputs: Vec<OutputFile>,
+    /// The options the run used, as --print-config gives them, passwords masked; only
+    /// the chain as a whole has them
+    #[serde(default, skip_serializing_if = "toml::Table::is_empty")]
+    pub config: toml::Table,
+    /// The export a --fill-gaps-against run filled the gaps of, so the two read as one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<Parent>,
+    /// Number of the batch in its --continue-from chain, on each entry of `batches`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch: Option<u64>,
+    /// Each batch of a --continue-from chain so far
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub batches: Vec<Manifest>,
+}
+
+impl Manifest {
+    /// Describe the run `summary` reports, which wrote `files` with the id ranges kept
+    /// for them (those not on disk are left out), started at `started`
+    pub fn new(
+        summary: &RunSummary,
+        started: DateTime<Utc>,
+        emitted: &IdRange,
+        files: Vec<(PathBuf, Option<Vec<[i64; 2]>>)>,
+        config: toml::Table,
+    ) -> Result<Self> {
+        let mut outputs = Vec::new();
+        for (file, id_ranges) in files {
+            if let Some(output) = OutputFile::read(&file)? {
+                outputs.push(OutputFile {
+                    id_ranges,
+                    ..output
+                });
+            }
+        }
+        let range = emitted.get();
+        Ok(Self {
+            run_id: summary.run_id.clone(),
+            // A batch that stopped with records remaining wrote all it was meant to
+            status: match summary.status {
+                "complete" | "more_remains" => "complete",
+                _ => "partial",
+            }
+            .to_string(),
+            outcome: summary.status.to_string(),
+            record_type: summary.record_type,
+            started,
+            finished: Utc::now(),
+            elapsed_secs: summary.elapsed_secs,
+            records_written: summary.processed,
+            records_rejected: summary.rejected,
+            errors: summary.errors,
+            records_invalid: summary.invalid,
+            min_id: range.map(|(min, _)| min),
+            max_id: range.map(|(_, max)| max),
+            outputs,
+            config,
+            parent: None,
+            batch: None,
+            batches: Vec::new(),
+        })
+    }
+
+    /// Load the manifest at `path`, or `None` if there is none
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).context(format!("Failed to read manifest: {}", path.display()))
+            }
+        };
+        let manifest =
+            serde_json::from_str(&text).context(format!("Invalid manifest: {}", path.display()))?;
+        Ok(Some(manifest))
+    }
+
+    /// Add this run to the manifest of its chain as batch `batch`, replacing an earlier
+    /// attempt at the same batch, and add up the batches
+    pub fn chain(mut self, batch: u64, previous: Option<Manifest>) -> Self {
+        let config = std::mem::take(&mut self.config);
+        self.batch = Some(batch);
+        let mut batches = previous.map_or_else(Vec::new, |previous| previous.batches);
+        batches.retain(|earlier| earlier.batch != Some(batch));
+        batches.push(self);
+        batches.sort_by_key(|batch| batch.batch);
+
+        let first = &batches[0];
+        let last = &batches[batches.len() - 1];
+        let complete = batches.iter().all(|batch| batch.status == "complete");
+        Self {
+            run_id: last.run_id.clone(),
+            status: if complete { "complete" } else { "partial" }.to_string(),
+            outcome: last.outcome.clone(),
+            record_type: last.record_type,
+            started: first.started,
+            finished: last.finished,
+            elapsed_secs: batches.iter().map(|batch| batch.elapsed_secs).sum(),
+            records_written: batches.iter().map(|batch| batch.records_written).sum(),
+            records_rejected: batches.iter().map(|batch| batch.records_rejected).sum(),
+            errors: batches.iter().map(|batch| batch.errors).sum(),
+            records_invalid: batches.iter().map(|batch| batch.records_invalid).sum(),
+            min_id: batches.iter().filter_map(|batch| batch.min_id).min(),
+            max_id: batches.iter().filter_map(|batch| batch.max_id).max(),
+            outputs: batches
+                .iter()
+                .flat_map(|batch| batch.outputs.iter().cloned())
+                .collect(),
+            config,
+            parent: None,
+            batch: None,
+            batches,
+        }
+    }
+
+    /// Write the manifest as NAME.part, then rename it, so that a receiving system
+    /// watching for it never reads half of one
+    pub fn save(&self, path: &Path, durable: bool) -> Result<()> {
+        let part = writer::part_path(path);
+        let written = File::create(&part).and_then(|mut file| {
+            file.write_all(&serde_json::to_vec_pretty(self)?)?;
+            if durable {
+                file.sync_all()?;
+            }
+            Ok(())
+        });
+        written.context(format!("Failed to write manifest: {}", part.display()))?;
+        writer::publish(path, durable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(run_id: &str, status: &str, written: u64, ids: (i64, i64), file: &str) -> Manifest {
+        serde_json::from_value(serde_json::json!({
+            "run_id": run_id,
+            "status": status,
+            "outcome": if status == "complete" { "more_remains" } else { "failed" },
+            "record_type": "bib",
+            "started": "2026-10-01T00:00:00Z",
+            "finished": "2026-10-01T00:01:00Z",
+            "elapsed_secs": 60.0,
+            "records_written": written,
+            "records_rejected": 0,
+            "errors": 0,
+            "records_invalid": 0,
+            "min_id": ids.0,
+            "max_id": ids.1,
+            "outputs": [{"path": file, "bytes": 10, "sha256": "00"}],
+            "config": {"batch_max": 100},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn each_file_gets_the_ranges_of_the_chunks_written_to_it() {
+        let ranges = FileRanges::default();
+        ranges.planned(vec![100, 200], false);
+        // Two chunks interleave in file 1, then the second moves on to file 2
+        for (id, file) in [
+            (1, 1),
+            (101, 1),
+            (5, 1),
+            (150, 1),
+            (9, 1),
+            (160, 2),
+            (199, 2),
+        ] {
+            ranges.written(id, file);
+        }
+        assert_eq!(ranges.file(1), Some(vec![[1, 9], [101, 150]]));
+        assert_eq!(ranges.file(2), Some(vec![[160, 199]]));
+        assert_eq!(ranges.file(3), Some(Vec::new()));
+        assert_eq!(ranges.all(), Some(vec![[1, 9], [101, 150], [160, 199]]));
+    }
+
+    #[test]
+    fn a_record_not_written_ends_its_range() {
+        let ranges = FileRanges::default();
+        ranges.planned(Vec::new(), false);
+        for id in [1, 2, 3] {
+            ranges.written(id, 1);
+        }
+        ranges.missed(4);
+        for id in [5, 6] {
+            ranges.written(id, 1);
+        }
+        assert_eq!(ranges.file(1), Some(vec![[1, 3], [5, 6]]));
+    }
+
+    #[test]
+    fn listed_ids_only_share_a_range_one_apart() {
+        let ranges = FileRanges::default();
+        ranges.planned(vec![50], true);
+        for id in [3, 4, 5, 9, 10, 40] {
+            ranges.written(id, 1);
+        }
+        assert_eq!(ranges.file(1), Some(vec![[3, 5], [9, 10], [40, 40]]));
+    }
+
+    #[test]
+    fn nothing_is_kept_before_the_run_is_planned() {
+        let ranges = FileRanges::default();
+        ranges.written(1, 1);
+        assert_eq!(ranges.file(1), None);
+        assert_eq!(ranges.all(), None);
+    }
+
+    #[test]
+    fn a_batch_run_again_replaces_its_failed_attempt() {
+        let chain = batch("a", "complete", 100, (1, 100), "out_00001.xml").chain(1, None);
+        let chain = batch("b", "partial", 40, (101, 140), "out_00002.xml").chain(2, Some(chain));
+        assert_eq!(chain.status, "partial");
+
+        let chain = batch("c", "complete", 100, (101, 200), "out_00002.xml").chain(2, Some(chain));
+        assert_eq!(chain.status, "complete");
+        assert_eq!(chain.run_id, "c");
+        assert_eq!(chain.records_written, 200);
+        assert_eq!((chain.min_id, chain.max_id), (Some(1), Some(200)));
+        assert_eq!(chain.outputs.len(), 2);
+        let numbers: Vec<_> = chain.batches.iter().map(|batch| batch.batch).collect();
+        assert_eq!(numbers, vec![Some(1), Some(2)]);
+        // The options are given once, for the chain
+        assert!(!chain.config.is_empty());
+        assert!(chain.batches.iter().all(|batch| batch.config.is_empty()));
+    }
+}
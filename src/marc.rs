@@ -0,0 +1,1215 @@
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::name::PrefixDeclaration;
+use quick_xml::Reader;
+use serde::Serialize;
+use serde_json::json;
+
+/// Whether a MARCXML record has no leader and no control or data fields
+///
+/// Healthy records are recognised by a substring probe; only records that fail the
+/// probe are parsed to confirm they are really empty.
+pub fn is_stub(xml: &str) -> bool {
+    if xml.contains("leader>") || xml.contains("controlfield ") || xml.contains("datafield ") {
+        return false;
+    }
+
+    let mut reader = Reader::from_str(xml);
+    let mut in_leader = false;
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => match e.local_name().as_ref() {
+                b"leader" => in_leader = true,
+                b"controlfield" | b"datafield" => return false,
+                _ => {}
+            },
+            Ok(Event::Empty(e)) => {
+                if matches!(e.local_name().as_ref(), b"controlfield" | b"datafield") {
+                    return false;
+                }
+            }
+            Ok(Event::Text(text)) if in_leader => {
+                if !text.iter().all(u8::is_ascii_whitespace) {
+                    return false;
+                }
+            }
+            Ok(Event::End(_)) => in_leader = false,
+            Ok(Event::Eof) => return true,
+            // Malformed records are not stubs; later stages deal with them
+            Err(_) => return false,
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Whether a MARCXML record parses as XML, with every element closed, and has a `record` element
+pub fn is_well_formed(xml: &str) -> bool {
+    well_formedness_error(xml).is_none()
+}
+
+/// Why a MARCXML record is not well-formed XML, or `None` when it is
+///
+/// Besides what the parser catches, this reports elements left open, text that does not
+/// unescape (a raw `&`), control characters XML 1.0 does not allow anywhere, and a
+/// missing `record` element.
+pub fn well_formedness_error(xml: &str) -> Option<String> {
+    if let Some(at) = xml
+        .bytes()
+        .position(|b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r'))
+    {
+        return Some(format!(
+            "control character 0x{:02X} at byte {}",
+            xml.as_bytes()[at],
+            at
+        ));
+    }
+
+    let mut reader = Reader::from_str(xml);
+    let mut open: Vec<String> = Vec::new();
+    let mut has_record = false;
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => {
+                has_record |= e.local_name().as_ref() == b"record";
+                open.push(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+            }
+            Ok(Event::Empty(e)) => has_record |= e.local_name().as_ref() == b"record",
+            Ok(Event::End(e)) => {
+                if open.pop().is_none() {
+                    let name = String::from_utf8_lossy(e.name().as_ref()).into_owned();
+                    return Some(format!(
+                        "</{}> at byte {} closes nothing",
+                        name,
+                        reader.buffer_position()
+                    ));
+                }
+            }
+            Ok(Event::Text(text)) => {
+                if let Err(e) = text.unescape() {
+                    return Some(format!(
+                        "{} in text ending at byte {}",
+                        e,
+                        reader.buffer_position()
+                    ));
+                }
+            }
+            Ok(Event::Eof) => {
+                return match open.last() {
+                    Some(name) => Some(format!("<{}> is never closed", name)),
+                    None if !has_record => Some("no <record> element".to_string()),
+                    None => None,
+                };
+            }
+            Err(e) => return Some(format!("{} at byte {}", e, reader.error_position())),
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Namespace of MARCXML records
+const MARC_NAMESPACE: &str = "http://www.loc.gov/MARC21/slim";
+
+/// The `record` element of stored MARCXML, alone, with the MARC21/slim namespace as its
+/// default
+///
+/// The record is found whatever its namespace prefix, bare or inside a `collection`, and
+/// everything around it is dropped: XML declarations, processing instructions, comments,
+/// and the wrapper. Comments and processing instructions inside it go too, its elements
+/// lose their prefix, and namespaces the wrapper declared for its attributes are declared
+/// on it instead. Only the first record of a collection is kept; [`record_elements`]
+/// gives them all. A bare record that already declares the default namespace is returned
+/// as it is. Returns `None` when the text does not parse as far as the end of a record.
+pub fn record_element(xml: &str) -> Option<String> {
+    let xml = xml.trim();
+    if is_bare_record(xml) {
+        return Some(xml.to_string());
+    }
+    records_up_to(xml, 1)?.pop()
+}
+
+/// Every `record` element of stored MARCXML, each alone as [`record_element`] gives the
+/// first
+///
+/// Some migrated rows hold a whole `collection` of records. Records after one that does
+/// not parse are left out; returns `None` when not even the first parses.
+pub fn record_elements(xml: &str) -> Option<Vec<String>> {
+    let xml = xml.trim();
+    if is_bare_record(xml) {
+        return Some(vec![xml.to_string()]);
+    }
+    records_up_to(xml, usize::MAX)
+}
+
+/// The first `max` record elements of `xml`, cleaned
+fn records_up_to(xml: &str, max: usize) -> Option<Vec<String>> {
+    let mut reader = Reader::from_str(xml);
+    let mut records = Vec::new();
+    let mut out = String::with_capacity(xml.len());
+    // Prefixes declared by each element around the record, outermost first
+    let mut declared: Vec<Vec<(String, String)>> = Vec::new();
+    // Prefix of the record's own name, which its elements are taken to share
+    let mut prefix: Option<String> = None;
+    // Elements open inside the record, the record itself included
+    let mut depth = 0usize;
+
+    loop {
+        let start = reader.buffer_position() as usize;
+        let Ok(event) = reader.read_event() else {
+            return (!records.is_empty()).then_some(records);
+        };
+        let raw = &xml[start..reader.buffer_position() as usize];
+        let mut closed = false;
+        match &event {
+            Event::Start(e) | Event::Empty(e) if depth == 0 => {
+                let empty = matches!(event, Event::Empty(_));
+                if e.local_name().as_ref() != b"record" {
+                    if !empty {
+                        declared.push(prefix_declarations(e)?);
+                    }
+                    continue;
+                }
+                prefix = e
+                    .name()
+                    .prefix()
+                    .map(|p| String::from_utf8_lossy(p.as_ref()).into_owned());
+                push_record_start(&mut out, e, raw, prefix.as_deref(), &declared)?;
+                if empty {
+                    closed = true;
+                } else {
+                    depth = 1;
+                }
+            }
+            Event::End(_) if depth == 0 => {
+                declared.pop();
+            }
+            Event::Start(e) | Event::Empty(e) => {
+                if matches!(event, Event::Start(_)) {
+                    depth += 1;
+                }
+                push_unprefixed(&mut out, raw, e.name().as_ref(), prefix.as_deref());
+            }
+            Event::End(e) => {
+                push_unprefixed(&mut out, raw, e.name().as_ref(), prefix.as_deref());
+                depth -= 1;
+                closed = depth == 0;
+            }
+            Event::Text(_) | Event::CData(_) if depth > 0 => out.push_str(raw),
+            Event::Eof => return (!records.is_empty()).then_some(records),
+            _ => {}
+        }
+        if closed {
+            records.push(std::mem::take(&mut out));
+            if records.len() == max {
+                return Some(records);
+            }
+        }
+    }
+}
+
+/// Whether stored MARCXML is a lone record declaring the MARC21/slim namespace as its
+/// default, with no prefix, declaration, comment, or CDATA to deal with; what cleaning
+/// leaves of a record that parses
+pub fn is_bare_record(xml: &str) -> bool {
+    let Some(tag_end) = xml.find('>') else {
+        return false;
+    };
+    let tag = &xml[..tag_end];
+    tag.starts_with("<record")
+        && tag[7..].starts_with(char::is_whitespace)
+        && tag.contains(&format!("xmlns=\"{}\"", MARC_NAMESPACE))
+        && xml.find("</record>") == Some(xml.len() - "</record>".len())
+        && !xml.contains("<?")
+        && !xml.contains("<!")
+}
+
+/// Namespace prefixes a start tag declares, with their raw values
+fn prefix_declarations(e: &BytesStart) -> Option<Vec<(String, String)>> {
+    let mut prefixes = Vec::new();
+    for attribute in e.attributes() {
+        let attribute = attribute.ok()?;
+        if let Some(PrefixDeclaration::Named(name)) = attribute.key.as_namespace_binding() {
+            prefixes.push((
+                String::from_utf8_lossy(name).into_owned(),
+                String::from_utf8_lossy(&attribute.value).into_owned(),
+            ));
+        }
+    }
+    Some(prefixes)
+}
+
+/// Append a record's start tag, unprefixed and declaring the MARC21/slim namespace
+/// as the default plus the prefixes declared around it
+///
+/// A tag with nothing to change is appended as it is.
+fn push_record_start(
+    out: &mut String,
+    e: &BytesStart,
+    raw: &str,
+    prefix: Option<&str>,
+    declared: &[Vec<(String, String)>],
+) -> Option<()> {
+    let own = prefix_declarations(e)?;
+    let mut carried: Vec<&(String, String)> = Vec::new();
+    for declaration in declared.iter().flatten() {
+        if Some(declaration.0.as_str()) == prefix
+            || own.iter().any(|(name, _)| *name == declaration.0)
+        {
+            continue;
+        }
+        carried.retain(|(name, _)| *name != declaration.0);
+        carried.push(declaration);
+    }
+
+    let mut has_default = false;
+    let mut attributes = Vec::new();
+    for attribute in e.attributes() {
+        let attribute = attribute.ok()?;
+        let value = String::from_utf8_lossy(&attribute.value).into_owned();
+        match attribute.key.as_namespace_binding() {
+            Some(PrefixDeclaration::Default) => has_default = value == MARC_NAMESPACE,
+            Some(PrefixDeclaration::Named(name)) if Some(name) == prefix.map(str::as_bytes) => {}
+            _ => attributes.push((
+                String::from_utf8_lossy(attribute.key.as_ref()).into_owned(),
+                value,
+            )),
+        }
+    }
+
+    let empty = raw.ends_with("/>");
+    if prefix.is_none() && has_default && carried.is_empty() {
+        out.push_str(raw);
+        return Some(());
+    }
+
+    out.push_str("<record");
+    push_attribute(out, "xmlns", MARC_NAMESPACE);
+    for (name, value) in carried {
+        push_attribute(out, &format!("xmlns:{}", name), value);
+    }
+    for (name, value) in &attributes {
+        push_attribute(out, name, value);
+    }
+    out.push_str(if empty { "/>" } else { ">" });
+    Some(())
+}
+
+/// Append an attribute, in whichever quotes its raw value does not hold
+fn push_attribute(out: &mut String, name: &str, value: &str) {
+    let quote = if value.contains('"') { '\'' } else { '"' };
+    out.push_str(&format!(" {}={}{}{}", name, quote, value, quote));
+}
+
+/// Append a tag taken from inside a record, without the record's prefix on its name
+fn push_unprefixed(out: &mut String, raw: &str, name: &[u8], prefix: Option<&str>) {
+    let Some(prefix) =
+        prefix.filter(|p| name.starts_with(p.as_bytes()) && name.get(p.len()) == Some(&b':'))
+    else {
+        out.push_str(raw);
+        return;
+    };
+    let lead = if raw.starts_with("</") { 2 } else { 1 };
+    out.push_str(&raw[..lead]);
+    out.push_str(&raw[lead + prefix.len() + 1..]);
+}
+
+/// Add a data field just before a record's closing tag, using the record's namespace prefix
+///
+/// Returns false, leaving the record alone, when it has no closing record tag.
+pub fn append_datafield(
+    xml: &mut String,
+    tag: &str,
+    indicators: (char, char),
+    subfields: &[(char, &str)],
+) -> bool {
+    let Some(end) = xml.rfind("record>") else {
+        return false;
+    };
+    let Some(open) = xml[..end].rfind("</") else {
+        return false;
+    };
+    let prefix = &xml[open + 2..end];
+    if !(prefix.is_empty() || prefix.ends_with(':')) {
+        return false;
+    }
+
+    let mut field = format!(
+        "<{p}datafield tag=\"{}\" ind1=\"{}\" ind2=\"{}\">",
+        tag,
+        indicators.0,
+        indicators.1,
+        p = prefix
+    );
+    for (code, value) in subfields {
+        field.push_str(&format!(
+            "<{p}subfield code=\"{}\">{}</{p}subfield>",
+            code,
+            quick_xml::escape::escape(value),
+            p = prefix
+        ));
+    }
+    field.push_str(&format!("</{}datafield>", prefix));
+
+    xml.insert_str(open, &field);
+    true
+}
+
+/// Replace every `tag` data field of a record with one new field at its end
+///
+/// Returns `None` when the record cannot be parsed, leaving it to be rejected rather
+/// than given a second field.
+pub fn replace_datafield(
+    xml: &str,
+    tag: &str,
+    indicators: (char, char),
+    subfields: &[(char, &str)],
+) -> Option<String> {
+    let (mut out, _) = remove_fields(xml, |field_tag, data| data && field_tag == tag)?;
+    append_datafield(&mut out, tag, indicators, subfields).then_some(out)
+}
+
+/// Cut the fields `remove` picks out of a record, given each field's tag and whether it
+/// is a data field
+///
+/// The record is parsed to find the fields, which are cut out with their markup; the
+/// whitespace around them stays. Returns the record with the number of fields cut, or
+/// `None` when it cannot be parsed.
+pub fn remove_fields(xml: &str, remove: impl Fn(&str, bool) -> bool) -> Option<(String, usize)> {
+    let mut reader = Reader::from_str(xml);
+    let mut depth = 0;
+    let mut record_depth = None;
+    // Byte range of each field being removed
+    let mut removed: Vec<(usize, usize)> = Vec::new();
+    let mut open = None;
+
+    loop {
+        let start = reader.buffer_position() as usize;
+        let event = reader.read_event().ok()?;
+        match &event {
+            Event::Start(e) | Event::Empty(e) => {
+                let empty = matches!(event, Event::Empty(_));
+                let name = e.local_name();
+                if record_depth.is_none() && name.as_ref() == b"record" && !empty {
+                    record_depth = Some(depth);
+                } else if record_depth.is_some_and(|d| depth == d + 1) {
+                    if let name @ (b"controlfield" | b"datafield") = name.as_ref() {
+                        let field_tag = e.try_get_attribute("tag").ok()??.unescape_value().ok()?;
+                        if remove(&field_tag, name == b"datafield") {
+                            if empty {
+                                removed.push((start, reader.buffer_position() as usize));
+                            } else {
+                                open = Some(start);
+                            }
+                        }
+                    }
+                }
+                if !empty {
+                    depth += 1;
+                }
+            }
+            Event::End(_) => {
+                depth -= 1;
+                if record_depth == Some(depth) {
+                    break;
+                }
+                if record_depth.is_some_and(|d| depth == d + 1) {
+                    if let Some(start) = open.take() {
+                        removed.push((start, reader.buffer_position() as usize));
+                    }
+                }
+            }
+            // The record never closed
+            Event::Eof => return None,
+            _ => {}
+        }
+    }
+
+    let mut out = String::with_capacity(xml.len());
+    let mut copied = 0;
+    for &(start, end) in &removed {
+        out.push_str(&xml[copied..start]);
+        copied = end;
+    }
+    out.push_str(&xml[copied..]);
+    Some((out, removed.len()))
+}
+
+/// Replace every non-ASCII character in a record's text and attribute values with a
+/// numeric character reference (`&#xE9;`), leaving the markup as it is
+///
+/// The record is parsed, and only the text between tags and the quoted attribute values
+/// inside them are rewritten; everything else is copied over unchanged. CDATA sections
+/// cannot hold references, so they become escaped text. Returns `None` for records that
+/// cannot be parsed, or whose element names are not ASCII.
+pub fn escape_non_ascii(xml: &str) -> Option<String> {
+    if xml.is_ascii() {
+        return Some(xml.to_string());
+    }
+
+    let mut reader = Reader::from_str(xml);
+    let mut out = String::with_capacity(xml.len() + xml.len() / 8);
+
+    loop {
+        let start = reader.buffer_position() as usize;
+        let event = reader.read_event().ok()?;
+        let raw = &xml[start..reader.buffer_position() as usize];
+        match &event {
+            Event::Text(_) => push_escaped(&mut out, raw),
+            Event::Start(e) | Event::Empty(e) => {
+                if !e.name().as_ref().is_ascii() {
+                    return None;
+                }
+                if raw.is_ascii() {
+                    out.push_str(raw);
+                } else {
+                    push_tag_escaped(&mut out, raw);
+                }
+            }
+            Event::CData(data) => {
+                let text = std::str::from_utf8(data).ok()?;
+                push_escaped(&mut out, &quick_xml::escape::escape(text));
+            }
+            Event::Eof => break,
+            _ => out.push_str(raw),
+        }
+    }
+    Some(out)
+}
+
+/// Whether XML 1.0 allows a character: tab, newline, carriage return, and everything from
+/// space up but the surrogates, U+FFFE, and U+FFFF
+fn is_xml_char(c: u32) -> bool {
+    matches!(c, 0x9 | 0xA | 0xD | 0x20..=0xD7FF | 0xE000..=0xFFFD | 0x10000..=0x10FFFF)
+}
+
+/// Remove the characters XML 1.0 does not allow from MARCXML, whether raw or written as
+/// character references (`&#x1F;`), which are just as invalid
+///
+/// Works on the text rather than a parse, as those characters are what stops most records
+/// with them from parsing. Returns the scrubbed text and the code point of each character
+/// removed, or `None` when there were none.
+pub fn scrub_illegal_chars(xml: &str) -> Option<(String, Vec<u32>)> {
+    let has_raw = xml.chars().any(|c| !is_xml_char(c as u32));
+    if !has_raw && !xml.contains("&#") {
+        return None;
+    }
+
+    let mut out = String::with_capacity(xml.len());
+    let mut removed = Vec::new();
+    let mut rest = xml;
+    while let Some(c) = rest.chars().next() {
+        if !is_xml_char(c as u32) {
+            removed.push(c as u32);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        if let Some((code, len)) = character_reference(rest).filter(|&(code, _)| !is_xml_char(code))
+        {
+            removed.push(code);
+            rest = &rest[len..];
+            continue;
+        }
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    (!removed.is_empty()).then_some((out, removed))
+}
+
+/// The code point of the numeric character reference `text` starts with, and its length
+fn character_reference(text: &str) -> Option<(u32, usize)> {
+    let body = text.strip_prefix("&#")?;
+    let end = body.find(';')?;
+    let code = match body[..end].strip_prefix('x') {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => body[..end].parse().ok()?,
+    };
+    Some((code, end + 3))
+}
+
+/// Drop the ISBD punctuation cataloging leaves at the end of a subfield
+///
+/// A final period stays when it ends an initial or abbreviation (Brian W., N.J.).
+pub fn chop_punctuation(text: &str) -> &str {
+    let text = text.trim().trim_end_matches([' ', ',', '/', ':', ';', '=']);
+    let Some(stem) = text.strip_suffix('.') else {
+        return text;
+    };
+    let word = stem.rsplit([' ', '.']).next().unwrap_or_default();
+    if word.chars().count() == 1 || stem.ends_with('.') {
+        text
+    } else {
+        stem.trim_end()
+    }
+}
+
+/// Spaces each level of nesting is indented by in pretty-printed output
+const INDENT: usize = 2;
+
+/// Re-serialize an XML fragment with each element on a line of its own, indented
+/// [`INDENT`] spaces a level, starting `depth` levels in
+///
+/// The whitespace between elements is replaced; the text of an element holding no other
+/// element is copied as it stands, references and CDATA included, so a subfield's content
+/// keeps every byte, leading and trailing spaces too. Tags are copied as they are. Returns
+/// `None` for fragments that cannot be parsed, or that mix text with elements, whose
+/// text could not be indented without changing it.
+pub fn pretty(xml: &str, depth: usize) -> Option<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut out = String::with_capacity(xml.len() + xml.len() / 4);
+    // For each open element, whether it has held an element yet
+    let mut open: Vec<bool> = Vec::new();
+    // Text read since the last tag, as it stands in the fragment
+    let mut text = String::new();
+
+    loop {
+        let start = reader.buffer_position() as usize;
+        let event = reader.read_event().ok()?;
+        let raw = &xml[start..reader.buffer_position() as usize];
+        match &event {
+            Event::Text(_) | Event::CData(_) => text.push_str(raw),
+            Event::End(_) => {
+                if open.pop()? {
+                    if !text.trim().is_empty() {
+                        return None;
+                    }
+                    push_line_start(&mut out, depth + open.len());
+                } else {
+                    out.push_str(&text);
+                }
+                text.clear();
+                out.push_str(raw);
+            }
+            Event::Eof => break,
+            _ => {
+                if !text.trim().is_empty() {
+                    return None;
+                }
+                text.clear();
+                if let Some(nested) = open.last_mut() {
+                    *nested = true;
+                }
+                push_line_start(&mut out, depth + open.len());
+                out.push_str(raw);
+                if matches!(event, Event::Start(_)) {
+                    open.push(false);
+                }
+            }
+        }
+    }
+    (open.is_empty() && text.trim().is_empty()).then_some(out)
+}
+
+/// Start a line of pretty-printed output `depth` levels in
+fn push_line_start(out: &mut String, depth: usize) {
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out.extend(std::iter::repeat_n(' ', depth * INDENT));
+}
+
+/// Append `text` with its non-ASCII characters as references
+fn push_escaped(out: &mut String, text: &str) {
+    for c in text.chars() {
+        if c.is_ascii() {
+            out.push(c);
+        } else {
+            out.push_str(&format!("&#x{:X};", u32::from(c)));
+        }
+    }
+}
+
+/// Append a start tag with the non-ASCII characters of its quoted attribute values as references
+fn push_tag_escaped(out: &mut String, tag: &str) {
+    let mut quote = None;
+    for c in tag.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            None if c == '"' || c == '\'' => quote = Some(c),
+            _ => {}
+        }
+        if quote.is_some() && !c.is_ascii() {
+            out.push_str(&format!("&#x{:X};", u32::from(c)));
+        } else {
+            out.push(c);
+        }
+    }
+}
+
+/// A record's leader and fields, as read from MARCXML
+pub struct ParsedRecord {
+    pub leader: Option<String>,
+    pub fields: Vec<Field>,
+}
+
+pub struct Field {
+    pub tag: String,
+    pub data: FieldData,
+}
+
+pub enum FieldData {
+    Control(String),
+    /// Indicators as the attributes give them, and (code, value) for each subfield
+    Data {
+        indicators: (String, String),
+        subfields: Vec<(String, String)>,
+    },
+}
+
+impl Field {
+    /// Bytes the field takes in ISO 2709, terminator included: control fields are their
+    /// data; data fields are two indicators, a delimiter and code before each subfield
+    fn encoded_len(&self) -> usize {
+        match &self.data {
+            FieldData::Control(text) => text.len() + 1,
+            FieldData::Data { subfields, .. } => {
+                2 + subfields
+                    .iter()
+                    .map(|(_, value)| 2 + value.len())
+                    .sum::<usize>()
+                    + 1
+            }
+        }
+    }
+}
+
+/// What text read from the record belongs to
+enum Reading {
+    Nothing,
+    Leader,
+    Control,
+    Subfield,
+}
+
+/// Read the leader, control fields, and data fields of a MARCXML record
+///
+/// Returns `None` for records that cannot be parsed, or with a field that has no tag.
+pub fn parse_record(xml: &str) -> Option<ParsedRecord> {
+    let mut reader = Reader::from_str(xml);
+    let mut leader: Option<String> = None;
+    let mut fields: Vec<Field> = Vec::new();
+    let mut reading = Reading::Nothing;
+
+    loop {
+        let event = reader.read_event().ok()?;
+        match &event {
+            Event::Start(e) | Event::Empty(e) => {
+                let has_content = matches!(event, Event::Start(_));
+                let attribute = |name: &str| -> Option<String> {
+                    match e.try_get_attribute(name).ok()? {
+                        Some(value) => Some(value.unescape_value().ok()?.into_owned()),
+                        None => Some(String::new()),
+                    }
+                };
+                match e.local_name().as_ref() {
+                    b"leader" => {
+                        leader = Some(String::new());
+                        reading = if has_content {
+                            Reading::Leader
+                        } else {
+                            Reading::Nothing
+                        };
+                    }
+                    b"controlfield" => {
+                        let tag = e.try_get_attribute("tag").ok()??.unescape_value().ok()?;
+                        fields.push(Field {
+                            tag: tag.into_owned(),
+                            data: FieldData::Control(String::new()),
+                        });
+                        reading = if has_content {
+                            Reading::Control
+                        } else {
+                            Reading::Nothing
+                        };
+                    }
+                    b"datafield" => {
+                        let tag = e.try_get_attribute("tag").ok()??.unescape_value().ok()?;
+                        fields.push(Field {
+                            tag: tag.into_owned(),
+                            data: FieldData::Data {
+                                indicators: (attribute("ind1")?, attribute("ind2")?),
+                                subfields: Vec::new(),
+                            },
+                        });
+                        reading = Reading::Nothing;
+                    }
+                    b"subfield" => {
+                        if let Some(FieldData::Data { subfields, .. }) =
+                            fields.last_mut().map(|f| &mut f.data)
+                        {
+                            subfields.push((attribute("code")?, String::new()));
+                            reading = if has_content {
+                                Reading::Subfield
+                            } else {
+                                Reading::Nothing
+                            };
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Event::Text(_) | Event::CData(_) => {
+                let text = match &event {
+                    Event::Text(text) => text.unescape().ok()?.into_owned(),
+                    Event::CData(data) => std::str::from_utf8(data).ok()?.to_string(),
+                    _ => unreachable!(),
+                };
+                let target = match (&reading, fields.last_mut().map(|f| &mut f.data)) {
+                    (Reading::Leader, _) => leader.as_mut(),
+                    (Reading::Control, Some(FieldData::Control(data))) => Some(data),
+                    (Reading::Subfield, Some(FieldData::Data { subfields, .. })) => {
+                        subfields.last_mut().map(|(_, value)| value)
+                    }
+                    _ => None,
+                };
+                if let Some(target) = target {
+                    target.push_str(&text);
+                }
+            }
+            Event::End(_) => reading = Reading::Nothing,
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Some(ParsedRecord { leader, fields })
+}
+
+/// Encoded size of one field in an ISO 2709 record, terminator included
+pub struct FieldLength {
+    pub tag: String,
+    pub bytes: usize,
+    pub subfields: usize,
+}
+
+/// Sizes a MARCXML record would have once encoded as ISO 2709
+pub struct Iso2709Lengths {
+    pub record: usize,
+    pub fields: Vec<FieldLength>,
+}
+
+/// Leader and directory entry sizes fixed by ISO 2709
+const LEADER_BYTES: usize = 24;
+const DIRECTORY_ENTRY_BYTES: usize = 12;
+
+/// ISO 2709 delimiters
+const SUBFIELD_DELIMITER: u8 = 0x1F;
+const FIELD_TERMINATOR: u8 = 0x1E;
+const RECORD_TERMINATOR: u8 = 0x1D;
+
+/// Largest record length and field length the leader and directory have digits for
+const MAX_RECORD_BYTES: usize = 99_999;
+const MAX_FIELD_BYTES: usize = 9_999;
+
+/// Measure a record as the ISO 2709 encoding would lay it out, without encoding it
+///
+/// Returns `None` for records that cannot be parsed.
+pub fn iso2709_lengths(xml: &str) -> Option<Iso2709Lengths> {
+    let parsed = parse_record(xml)?;
+    let fields: Vec<FieldLength> = parsed
+        .fields
+        .iter()
+        .map(|field| FieldLength {
+            tag: field.tag.clone(),
+            bytes: field.encoded_len(),
+            subfields: match &field.data {
+                FieldData::Control(_) => 0,
+                FieldData::Data { subfields, .. } => subfields.len(),
+            },
+        })
+        .collect();
+
+    let record = LEADER_BYTES
+        + DIRECTORY_ENTRY_BYTES * fields.len()
+        + 1
+        + fields.iter().map(|f| f.bytes).sum::<usize>()
+        + 1;
+    Some(Iso2709Lengths { record, fields })
+}
+
+/// Encode a MARCXML record as an ISO 2709 (binary MARC 21) record
+///
+/// The leader is the record's own, with the record length, base address of data, and
+/// the fixed entry map written from the encoding, and position 9 set to `a` since the
+/// data is UTF-8. Returns why when the record cannot be encoded: it does not parse, has
+/// no 24 character leader, or does not fit the lengths the leader and directory can hold.
+pub fn to_iso2709(xml: &str) -> Result<Vec<u8>, String> {
+    let parsed = parse_record(xml).ok_or("not well-formed MARCXML")?;
+    let leader = parsed
+        .leader
+        .filter(|leader| leader.len() == LEADER_BYTES && leader.is_ascii())
+        .ok_or("no 24 character leader")?;
+
+    // A single byte, blank when the attribute is empty
+    let byte = |value: &str, what: &str, tag: &str| -> Result<u8, String> {
+        match value.as_bytes() {
+            [] => Ok(b' '),
+            [b] if b.is_ascii() && !b.is_ascii_control() => Ok(*b),
+            _ => Err(format!(
+                "{} {:?} of field {} is not a single character",
+                what, value, tag
+            )),
+        }
+    };
+    let delimited = |value: &str, tag: &str| -> Result<(), String> {
+        if value
+            .bytes()
+            .any(|b| matches!(b, SUBFIELD_DELIMITER | FIELD_TERMINATOR | RECORD_TERMINATOR))
+        {
+            return Err(format!(
+                "field {} holds an ISO 2709 delimiter character",
+                tag
+            ));
+        }
+        Ok(())
+    };
+
+    let mut directory = Vec::with_capacity(DIRECTORY_ENTRY_BYTES * parsed.fields.len() + 1);
+    let mut data = Vec::new();
+    for field in &parsed.fields {
+        let tag = &field.tag;
+        if tag.len() != 3 || !tag.is_ascii() {
+            return Err(format!("field tag {:?} is not 3 characters", tag));
+        }
+        let start = data.len();
+        match &field.data {
+            FieldData::Control(text) => {
+                delimited(text, tag)?;
+                data.extend_from_slice(text.as_bytes());
+            }
+            FieldData::Data {
+                indicators,
+                subfields,
+            } => {
+                data.push(byte(&indicators.0, "indicator 1", tag)?);
+                data.push(byte(&indicators.1, "indicator 2", tag)?);
+                for (code, value) in subfields {
+                    delimited(value, tag)?;
+                    data.push(SUBFIELD_DELIMITER);
+                    data.push(byte(code, "subfield code", tag)?);
+                    data.extend_from_slice(value.as_bytes());
+                }
+            }
+        }
+        data.push(FIELD_TERMINATOR);
+
+        let length = data.len() - start;
+        if length > MAX_FIELD_BYTES {
+            return Err(format!(
+                "field {} is {} bytes, over the {} ISO 2709 allows",
+                tag, length, MAX_FIELD_BYTES
+            ));
+        }
+        directory.extend_from_slice(format!("{}{:04}{:05}", tag, length, start).as_bytes());
+    }
+    directory.push(FIELD_TERMINATOR);
+
+    let base = LEADER_BYTES + directory.len();
+    let total = base + data.len() + 1;
+    if total > MAX_RECORD_BYTES {
+        return Err(format!(
+            "record is {} bytes, over the {} ISO 2709 allows",
+            total, MAX_RECORD_BYTES
+        ));
+    }
+
+    let mut leader = leader.into_bytes();
+    leader[0..5].copy_from_slice(format!("{:05}", total).as_bytes());
+    leader[9] = b'a';
+    leader[10..12].copy_from_slice(b"22");
+    leader[12..17].copy_from_slice(format!("{:05}", base).as_bytes());
+    leader[20..24].copy_from_slice(b"4500");
+
+    let mut record = Vec::with_capacity(total);
+    record.extend_from_slice(&leader);
+    record.extend_from_slice(&directory);
+    record.extend_from_slice(&data);
+    record.push(RECORD_TERMINATOR);
+    Ok(record)
+}
+
+/// A record in MARC-in-JSON, with the leader ahead of the fields as the convention has it
+#[derive(Serialize)]
+struct MarcJson {
+    leader: String,
+    fields: Vec<serde_json::Value>,
+}
+
+/// Convert a MARCXML record to MARC-in-JSON, the convention marc4j and pymarc read
+///
+/// Each field is an object keyed by its tag: a control field's value is its data, and a
+/// data field's is its two indicators and its subfields in order, each an object keyed
+/// by its code, so repeated subfields survive. An empty indicator is written as a blank.
+/// Returns why when the record does not parse or has no leader.
+pub fn to_marc_json(xml: &str) -> Result<String, String> {
+    let parsed = parse_record(xml).ok_or("not well-formed MARCXML")?;
+    let leader = parsed.leader.ok_or("no leader")?;
+    let blank = |indicator: String| {
+        if indicator.is_empty() {
+            " ".to_string()
+        } else {
+            indicator
+        }
+    };
+
+    let fields = parsed
+        .fields
+        .into_iter()
+        .map(|field| match field.data {
+            FieldData::Control(text) => json!({ field.tag: text }),
+            FieldData::Data {
+                indicators,
+                subfields,
+            } => {
+                let subfields: Vec<_> = subfields
+                    .into_iter()
+                    .map(|(code, value)| json!({ code: value }))
+                    .collect();
+                json!({ field.tag: {
+                    "ind1": blank(indicators.0),
+                    "ind2": blank(indicators.1),
+                    "subfields": subfields,
+                } })
+            }
+        })
+        .collect();
+    serde_json::to_string(&MarcJson { leader, fields }).map_err(|e| e.to_string())
+}
+
+/// Put a record's fields in canonical order: control fields by tag, then data fields by tag
+///
+/// The sort is stable, so repeated fields (several 505s, say) keep their order among
+/// themselves. Fields only trade places: the leader, anything else inside the record,
+/// and the whitespace between fields stay where they are. Returns `None` when the
+/// order is already canonical or the record cannot be parsed.
+pub fn sort_fields(xml: &str) -> Option<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut depth = 0;
+    let mut record_depth = None;
+    // Byte range of each field, with whether it is a data field and its tag
+    let mut fields: Vec<(usize, usize, bool, String)> = Vec::new();
+    let mut open: Option<(usize, bool, String)> = None;
+
+    loop {
+        let start = reader.buffer_position() as usize;
+        let event = reader.read_event().ok()?;
+        match &event {
+            Event::Start(e) | Event::Empty(e) => {
+                let empty = matches!(event, Event::Empty(_));
+                let name = e.local_name();
+                if record_depth.is_none() && name.as_ref() == b"record" && !empty {
+                    record_depth = Some(depth);
+                } else if record_depth.is_some_and(|d| depth == d + 1) {
+                    if let name @ (b"controlfield" | b"datafield") = name.as_ref() {
+                        let tag = e.try_get_attribute("tag").ok()??.unescape_value().ok()?;
+                        let field = (start, name == b"datafield", tag.into_owned());
+                        if empty {
+                            let end = reader.buffer_position() as usize;
+                            fields.push((field.0, end, field.1, field.2));
+                        } else {
+                            open = Some(field);
+                        }
+                    }
+                }
+                if !empty {
+                    depth += 1;
+                }
+            }
+            Event::End(_) => {
+                depth -= 1;
+                if record_depth == Some(depth) {
+                    break;
+                }
+                if record_depth.is_some_and(|d| depth == d + 1) {
+                    if let Some((start, data, tag)) = open.take() {
+                        fields.push((start, reader.buffer_position() as usize, data, tag));
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    let mut sorted: Vec<usize> = (0..fields.len()).collect();
+    sorted.sort_by(|&a, &b| (fields[a].2, &fields[a].3).cmp(&(fields[b].2, &fields[b].3)));
+    if sorted
+        .iter()
+        .enumerate()
+        .all(|(slot, &field)| slot == field)
+    {
+        return None;
+    }
+
+    // Each slot gets the field sorted into it; the text between slots is kept
+    let mut out = String::with_capacity(xml.len());
+    let mut copied = 0;
+    for (slot, &field) in sorted.iter().enumerate() {
+        out.push_str(&xml[copied..fields[slot].0]);
+        out.push_str(&xml[fields[field].0..fields[field].1]);
+        copied = fields[slot].1;
+    }
+    out.push_str(&xml[copied..]);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The tag and first subfield of each field, in order
+    fn fields(xml: &str) -> Vec<(String, String)> {
+        parse_record(xml)
+            .unwrap()
+            .fields
+            .iter()
+            .map(|field| {
+                let value = match &field.data {
+                    FieldData::Control(value) => value.clone(),
+                    FieldData::Data { subfields, .. } => subfields[0].1.clone(),
+                };
+                (field.tag.clone(), value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sort_fields_keeps_repeated_tags_in_order() {
+        let xml = "<record xmlns=\"http://www.loc.gov/MARC21/slim\">\
+                   <leader>00000nam a2200000 a 4500</leader>\
+                   <datafield tag=\"505\" ind1=\"0\" ind2=\" \"><subfield code=\"a\">first</subfield></datafield>\
+                   <datafield tag=\"245\" ind1=\"0\" ind2=\"0\"><subfield code=\"a\">Title</subfield></datafield>\
+                   <controlfield tag=\"008\">data</controlfield>\
+                   <datafield tag=\"505\" ind1=\"0\" ind2=\" \"><subfield code=\"a\">second</subfield></datafield>\
+                   <datafield tag=\"100\" ind1=\"1\" ind2=\" \"><subfield code=\"a\">Author</subfield></datafield>\
+                   <datafield tag=\"505\" ind1=\"0\" ind2=\" \"><subfield code=\"a\">third</subfield></datafield>\
+                   <controlfield tag=\"001\">42</controlfield>\
+                   </record>";
+        let sorted = sort_fields(xml).unwrap();
+        assert!(sorted.starts_with("<record xmlns=\"http://www.loc.gov/MARC21/slim\"><leader>"));
+        let tags: Vec<String> = fields(&sorted).into_iter().map(|(tag, _)| tag).collect();
+        assert_eq!(tags, ["001", "008", "100", "245", "505", "505", "505"]);
+        let notes: Vec<String> = fields(&sorted)
+            .into_iter()
+            .filter(|(tag, _)| tag == "505")
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(notes, ["first", "second", "third"]);
+        assert_eq!(sort_fields(&sorted), None);
+    }
+
+    /// Every text and attribute value of a document, decoded, in document order
+    fn decoded(xml: &str) -> Vec<String> {
+        let mut reader = Reader::from_str(xml);
+        let mut values = Vec::new();
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Start(e) | Event::Empty(e) => {
+                    for attribute in e.attributes() {
+                        values.push(attribute.unwrap().unescape_value().unwrap().into_owned());
+                    }
+                }
+                Event::Text(t) => values.push(t.unescape().unwrap().into_owned()),
+                Event::CData(data) => values.push(String::from_utf8(data.to_vec()).unwrap()),
+                Event::Eof => return values.into_iter().filter(|v| !v.is_empty()).collect(),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn escape_non_ascii_round_trips() {
+        let xml = "<record xmlns=\"http://www.loc.gov/MARC21/slim\" type=\"Bibliogr\u{e1}fico\">\
+                   <leader>00000nam a2200000 a 4500</leader>\
+                   <datafield tag=\"245\" ind1=\"0\" ind2=\"0\">\
+                   <subfield code=\"a\">Caf\u{e9} &amp; na\u{ef}ve \u{65e5}\u{672c} \u{1f4da}</subfield>\
+                   <subfield code=\"b\"><![CDATA[\u{c5}ngstr\u{f6}m <raw>]]></subfield>\
+                   </datafield>\
+                   </record>";
+        let escaped = escape_non_ascii(xml).unwrap();
+        assert!(escaped.is_ascii(), "{}", escaped);
+        assert!(escaped.contains("&#x1F4DA;"), "{}", escaped);
+        assert!(escaped.contains("<subfield code=\"a\">"), "{}", escaped);
+        assert_eq!(decoded(&escaped), decoded(xml));
+        assert_eq!(
+            escape_non_ascii(&escaped).as_deref(),
+            Some(escaped.as_str())
+        );
+    }
+
+    const BARE: &str = "<record xmlns=\"http://www.loc.gov/MARC21/slim\">\
+                        <leader>00000nam a2200000 a 4500</leader>\
+                        <controlfield tag=\"001\">1</controlfield>\
+                        </record>";
+
+    #[test]
+    fn a_bare_record_is_kept_as_it_is() {
+        assert_eq!(record_element(BARE).as_deref(), Some(BARE));
+        let padded = format!("\n  {}\n", BARE);
+        assert_eq!(record_element(&padded).as_deref(), Some(BARE));
+    }
+
+    #[test]
+    fn a_prefixed_record_loses_its_prefix() {
+        let xml = "<?xml version=\"1.0\"?>\
+                   <marc:record xmlns:marc='http://www.loc.gov/MARC21/slim'>\
+                   <marc:leader>00000nam a2200000 a 4500</marc:leader>\
+                   <marc:controlfield tag=\"001\">1</marc:controlfield>\
+                   </marc:record>";
+        assert_eq!(record_element(xml).as_deref(), Some(BARE));
+    }
+
+    #[test]
+    fn a_collection_is_unwrapped() {
+        let xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!-- exported -->\n\
+             <collection xmlns=\"http://www.loc.gov/MARC21/slim\">\n{}\n</collection>",
+            BARE
+        );
+        assert_eq!(record_element(&xml).as_deref(), Some(BARE));
+
+        let prefixed = "<marc:collection xmlns:marc=\"http://www.loc.gov/MARC21/slim\">\
+                        <marc:record><marc:leader>00000nam a2200000 a 4500</marc:leader>\
+                        <marc:controlfield tag=\"001\">1</marc:controlfield></marc:record>\
+                        </marc:collection>";
+        assert_eq!(record_element(prefixed).as_deref(), Some(BARE));
+    }
+
+    #[test]
+    fn a_collection_with_attributes_is_unwrapped() {
+        let xml = "<collection xmlns=\"http://www.loc.gov/MARC21/slim\" \
+                   xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" \
+                   xsi:schemaLocation=\"http://www.loc.gov/MARC21/slim http://www.loc.gov/standards/marcxml/schema/MARC21slim.xsd\">\
+                   <record type=\"Bibliographic\"><?pi here?><leader>00000nam a2200000 a 4500</leader></record>\
+                   </collection>";
+        assert_eq!(
+            record_element(xml).as_deref(),
+            Some(
+                "<record xmlns=\"http://www.loc.gov/MARC21/slim\" \
+                 xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" type=\"Bibliographic\">\
+                 <leader>00000nam a2200000 a 4500</leader></record>"
+            )
+        );
+    }
+
+    #[test]
+    fn every_record_of_a_collection_is_found() {
+        let xml = format!("<collection>{}<record/>{}</collection>", BARE, BARE);
+        let records = record_elements(&xml).unwrap();
+        assert_eq!(
+            records,
+            [
+                BARE,
+                "<record xmlns=\"http://www.loc.gov/MARC21/slim\"/>",
+                BARE
+            ]
+        );
+    }
+
+    #[test]
+    fn text_that_does_not_parse_has_no_record() {
+        assert_eq!(record_element("not xml"), None);
+        assert_eq!(record_element("<collection><record><leader>"), None);
+        assert_eq!(record_element("<collection></collection>"), None);
+    }
+}
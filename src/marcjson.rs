@@ -0,0 +1,44 @@
+use anyhow::Result;
+
+use crate::db::MarcRecord;
+use crate::marc;
+use crate::quarantine::Rejection;
+use crate::writer::{prepare_record, Encoded, Encoder};
+
+/// Encoder for MARC-in-JSON: a top-level array holding one object per record, each on
+/// its own line
+pub struct MarcJsonEncoder;
+
+impl Encoder for MarcJsonEncoder {
+    fn header(&self) -> Vec<u8> {
+        b"[".to_vec()
+    }
+
+    /// The record as a MARC-in-JSON object, without the separator written before it
+    fn encode(&self, record: &MarcRecord) -> Result<Encoded> {
+        let cleaned_marc = match prepare_record(record) {
+            Ok(cleaned) => cleaned,
+            Err(rejection) => return Ok(Err(rejection)),
+        };
+        match marc::to_marc_json(&cleaned_marc) {
+            Ok(json) => Ok(Ok(json.into_bytes())),
+            Err(reason) => Ok(Err(Rejection::new("encode", reason))),
+        }
+    }
+
+    fn separator(&self, first: bool) -> &'static [u8] {
+        if first {
+            b"\n"
+        } else {
+            b",\n"
+        }
+    }
+
+    fn footer(&self, empty: bool) -> &'static [u8] {
+        if empty {
+            b"]\n"
+        } else {
+            b"\n]\n"
+        }
+    }
+}
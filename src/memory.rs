@@ -0,0 +1,32 @@
+/// Peak resident set size of this process in bytes, where the platform reports it
+pub fn peak_rss() -> Option<u64> {
+    // VmHWM is the RSS high-water mark
+    proc_status_kb("VmHWM:").map(|kb| kb * 1024)
+}
+
+/// Current resident set size of this process in bytes
+///
+/// Read from /proc on Linux; elsewhere from ps(1), which BSD and macOS provide.
+pub async fn current_rss() -> Option<u64> {
+    if let Some(kb) = proc_status_kb("VmRSS:") {
+        return Some(kb * 1024);
+    }
+
+    let output = tokio::process::Command::new("ps")
+        .args(["-o", "rss=", "-p", &std::process::id().to_string()])
+        .output()
+        .await
+        .ok()?;
+    let kb: u64 = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+/// A line of /proc/self/status, reported in kB
+fn proc_status_kb(key: &str) -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with(key))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
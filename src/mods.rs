@@ -0,0 +1,413 @@
+use anyhow::Result;
+use quick_xml::escape::escape;
+
+use crate::db::MarcRecord;
+use crate::marc::{self, Field, FieldData};
+use crate::quarantine::Rejection;
+use crate::writer::{prepare_record, Encoded, Encoder};
+
+/// Opening of a MODS 3.7 collection, declaring the schema it validates against
+const COLLECTION_START: &str = "<modsCollection xmlns=\"http://www.loc.gov/mods/v3\" \
+     xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" \
+     xsi:schemaLocation=\"http://www.loc.gov/mods/v3 http://www.loc.gov/standards/mods/v3/mods-3-7.xsd\">\n";
+
+const COLLECTION_END: &[u8] = b"</modsCollection>\n";
+
+/// Encoder for MODS 3.7: a modsCollection holding one mods element per record, each on
+/// its own line
+pub struct ModsEncoder {
+    pretty: bool,
+}
+
+impl ModsEncoder {
+    pub fn new() -> Self {
+        Self { pretty: false }
+    }
+
+    /// Write records one element per line, indented
+    pub fn pretty(mut self, pretty: bool) -> Self {
+        self.pretty = pretty;
+        self
+    }
+}
+
+impl Encoder for ModsEncoder {
+    fn header(&self) -> Vec<u8> {
+        let mut header = b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n".to_vec();
+        header.extend_from_slice(COLLECTION_START.as_bytes());
+        header
+    }
+
+    /// The record's mods element, as the line or lines it is written as
+    fn encode(&self, record: &MarcRecord) -> Result<Encoded> {
+        let cleaned_marc = match prepare_record(record) {
+            Ok(cleaned) => cleaned,
+            Err(rejection) => return Ok(Err(rejection)),
+        };
+        let mut mods = match to_mods(&cleaned_marc, record.id) {
+            Ok(mods) => mods,
+            Err(reason) => return Ok(Err(Rejection::new("encode", reason))),
+        };
+        if self.pretty {
+            mods = marc::pretty(&mods, 1).unwrap_or(mods);
+        }
+        mods.push('\n');
+        Ok(Ok(mods.into_bytes()))
+    }
+
+    fn footer(&self, _empty: bool) -> &'static [u8] {
+        COLLECTION_END
+    }
+}
+
+/// Map a MARCXML record to a MODS 3.7 mods element, identified by `id` in its recordInfo
+///
+/// Not a full crosswalk: 245 becomes titleInfo; 100/110/111 and 700/710/711 name (the 1xx
+/// marked primary); 260 and 264 originInfo; 600/610/611, 630, 648, 650, 651, 653, and 655
+/// subject, with their $v $x $y $z subdivisions; 020, 022, and 035 identifier. Other
+/// fields and subfields are left out.
+pub fn to_mods(xml: &str, id: i64) -> Result<String, String> {
+    let record =
+        marc::parse_record(xml).ok_or("not parseable MARCXML, so it cannot be mapped to MODS")?;
+    let mut out = String::from("<mods version=\"3.7\">");
+
+    for field in &record.fields {
+        let FieldData::Data {
+            indicators,
+            subfields,
+        } = &field.data
+        else {
+            continue;
+        };
+        let data = DataField {
+            ind2: &indicators.1,
+            subfields,
+        };
+        match field.tag.as_str() {
+            "245" => push_title(&mut out, &data),
+            "100" | "110" | "111" => push_name(&mut out, &field.tag[1..], &data, true),
+            "700" | "710" | "711" => push_name(&mut out, &field.tag[1..], &data, false),
+            "260" | "264" => push_origin(&mut out, field, &data),
+            tag if tag.starts_with('6') => push_subject(&mut out, tag, &data),
+            "020" => push_identifiers(&mut out, "isbn", &data, &["a"], &["z"]),
+            "022" => push_identifiers(&mut out, "issn", &data, &["a"], &["y", "z"]),
+            "035" => push_system_numbers(&mut out, &data),
+            _ => {}
+        }
+    }
+
+    out.push_str("<recordInfo>");
+    push_element(&mut out, "recordIdentifier", "", &id.to_string());
+    push_element(
+        &mut out,
+        "recordOrigin",
+        "",
+        "Converted from MARCXML to MODS version 3.7 by marc_extractor_rs",
+    );
+    out.push_str("</recordInfo></mods>");
+    Ok(out)
+}
+
+/// The parts of a data field the crosswalk reads
+struct DataField<'a> {
+    ind2: &'a str,
+    subfields: &'a [(String, String)],
+}
+
+impl<'a> DataField<'a> {
+    fn first(&self, code: &str) -> Option<&'a str> {
+        self.subfields
+            .iter()
+            .find(|(c, _)| c == code)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn all<'b>(&'b self, code: &'b str) -> impl Iterator<Item = &'a str> + 'b {
+        self.subfields
+            .iter()
+            .filter(move |(c, _)| c == code)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The subfields with any of `codes`, in order, joined by spaces
+    fn joined(&self, codes: &[&str]) -> String {
+        let parts: Vec<&str> = self
+            .subfields
+            .iter()
+            .filter(|(code, _)| codes.contains(&code.as_str()))
+            .map(|(_, value)| value.trim())
+            .collect();
+        parts.join(" ")
+    }
+}
+
+fn push_element(out: &mut String, name: &str, attributes: &str, text: &str) {
+    out.push('<');
+    out.push_str(name);
+    out.push_str(attributes);
+    out.push('>');
+    out.push_str(&escape(text));
+    out.push_str("</");
+    out.push_str(name);
+    out.push('>');
+}
+
+/// An attribute to put in a start tag, escaped, with the space before it
+fn attribute(name: &str, value: &str) -> String {
+    format!(" {}=\"{}\"", name, escape(value))
+}
+
+/// 245: the title, with the leading article the second indicator counts as nonSort
+fn push_title(out: &mut String, field: &DataField) {
+    let Some(title) = field.first("a") else {
+        return;
+    };
+    let mut title = title.to_string();
+    out.push_str("<titleInfo>");
+    let skip = field.ind2.parse::<usize>().unwrap_or(0);
+    if skip > 0 && skip < title.chars().count() {
+        let split = title
+            .char_indices()
+            .nth(skip)
+            .map_or(title.len(), |(at, _)| at);
+        let rest = title.split_off(split);
+        out.push_str("<nonSort xml:space=\"preserve\">");
+        out.push_str(&escape(&title));
+        out.push_str("</nonSort>");
+        title = rest;
+    }
+    push_element(out, "title", "", marc::chop_punctuation(&title));
+    for (code, value) in field.subfields {
+        let name = match code.as_str() {
+            "b" => "subTitle",
+            "n" => "partNumber",
+            "p" => "partName",
+            _ => continue,
+        };
+        push_element(out, name, "", marc::chop_punctuation(value));
+    }
+    out.push_str("</titleInfo>");
+}
+
+/// The name type for the last two digits of a X00, X10, or X11 tag
+fn name_type(suffix: &str) -> &'static str {
+    match suffix {
+        "00" => "personal",
+        "10" => "corporate",
+        _ => "conference",
+    }
+}
+
+/// The namePart elements of a X00, X10, or X11 field; empty without a $a
+fn name_parts(suffix: &str, field: &DataField) -> String {
+    let mut parts = String::new();
+    if field.first("a").is_none() {
+        return parts;
+    }
+    match suffix {
+        "00" => {
+            push_element(
+                &mut parts,
+                "namePart",
+                "",
+                marc::chop_punctuation(&field.joined(&["a", "b", "c", "q"])),
+            );
+            if let Some(dates) = field.first("d") {
+                push_element(
+                    &mut parts,
+                    "namePart",
+                    " type=\"date\"",
+                    marc::chop_punctuation(dates),
+                );
+            }
+        }
+        "10" => {
+            for (code, value) in field.subfields {
+                if code == "a" || code == "b" {
+                    push_element(&mut parts, "namePart", "", marc::chop_punctuation(value));
+                }
+            }
+        }
+        _ => push_element(
+            &mut parts,
+            "namePart",
+            "",
+            marc::chop_punctuation(&field.joined(&["a", "c", "d", "n", "q"])),
+        ),
+    }
+    parts
+}
+
+/// 1xx and 7xx: a name, with its relator terms and codes as roles
+fn push_name(out: &mut String, suffix: &str, field: &DataField, primary: bool) {
+    let parts = name_parts(suffix, field);
+    if parts.is_empty() {
+        return;
+    }
+    let usage = if primary { " usage=\"primary\"" } else { "" };
+    out.push_str(&format!("<name type=\"{}\"{}>", name_type(suffix), usage));
+    out.push_str(&parts);
+    let relator = if suffix == "11" { "j" } else { "e" };
+    for term in field.all(relator) {
+        out.push_str("<role>");
+        push_element(
+            out,
+            "roleTerm",
+            " type=\"text\" authority=\"marcrelator\"",
+            marc::chop_punctuation(term),
+        );
+        out.push_str("</role>");
+    }
+    for code in field.all("4") {
+        out.push_str("<role>");
+        push_element(
+            out,
+            "roleTerm",
+            " type=\"code\" authority=\"marcrelator\"",
+            marc::chop_punctuation(code),
+        );
+        out.push_str("</role>");
+    }
+    out.push_str("</name>");
+}
+
+/// 260, and 264 by the event its second indicator names: place, publisher, and date
+fn push_origin(out: &mut String, field: &Field, data: &DataField) {
+    let (event, date) = match (field.tag.as_str(), data.ind2) {
+        ("264", "0") => (Some("production"), "dateCreated"),
+        ("264", "1") => (Some("publication"), "dateIssued"),
+        ("264", "2") => (Some("distribution"), "dateIssued"),
+        ("264", "3") => (Some("manufacture"), "dateIssued"),
+        ("264", "4") => (None, "copyrightDate"),
+        _ => (None, "dateIssued"),
+    };
+    let mut inner = String::new();
+    for (code, value) in data.subfields {
+        match code.as_str() {
+            "a" => {
+                inner.push_str("<place>");
+                push_element(
+                    &mut inner,
+                    "placeTerm",
+                    " type=\"text\"",
+                    marc::chop_punctuation(value),
+                );
+                inner.push_str("</place>");
+            }
+            "b" => push_element(&mut inner, "publisher", "", marc::chop_punctuation(value)),
+            "c" => push_element(&mut inner, date, "", marc::chop_punctuation(value)),
+            _ => {}
+        }
+    }
+    if inner.is_empty() {
+        return;
+    }
+    match event {
+        Some(event) => out.push_str(&format!("<originInfo{}>", attribute("eventType", event))),
+        None => out.push_str("<originInfo>"),
+    }
+    out.push_str(&inner);
+    out.push_str("</originInfo>");
+}
+
+/// The subject thesaurus a 6xx second indicator names, or its $2 for 7
+fn subject_authority<'a>(field: &'a DataField) -> Option<&'a str> {
+    match field.ind2 {
+        "0" => Some("lcsh"),
+        "1" => Some("lcshac"),
+        "2" => Some("mesh"),
+        "3" => Some("nal"),
+        "5" => Some("csh"),
+        "6" => Some("rvm"),
+        "7" => field.first("2"),
+        _ => None,
+    }
+}
+
+/// 6xx: a subject headed by a name, title, or term, then its subdivisions in order
+fn push_subject(out: &mut String, tag: &str, field: &DataField) {
+    let mut inner = String::new();
+    match tag {
+        "600" | "610" | "611" => {
+            let suffix = &tag[1..];
+            let parts = name_parts(suffix, field);
+            if !parts.is_empty() {
+                inner.push_str(&format!(
+                    "<name type=\"{}\">{}</name>",
+                    name_type(suffix),
+                    parts
+                ));
+            }
+        }
+        "630" => {
+            if let Some(title) = field.first("a") {
+                inner.push_str("<titleInfo>");
+                push_element(&mut inner, "title", "", marc::chop_punctuation(title));
+                inner.push_str("</titleInfo>");
+            }
+        }
+        "648" | "650" | "651" | "653" | "655" => {
+            let name = match tag {
+                "648" => "temporal",
+                "651" => "geographic",
+                "655" => "genre",
+                _ => "topic",
+            };
+            for term in field.all("a") {
+                push_element(&mut inner, name, "", marc::chop_punctuation(term));
+            }
+        }
+        _ => return,
+    }
+    if inner.is_empty() {
+        return;
+    }
+    for (code, value) in field.subfields {
+        let name = match code.as_str() {
+            "v" => "genre",
+            "x" => "topic",
+            "y" => "temporal",
+            "z" => "geographic",
+            _ => continue,
+        };
+        push_element(&mut inner, name, "", marc::chop_punctuation(value));
+    }
+
+    // 653 is an uncontrolled term, whatever its second indicator says
+    match subject_authority(field).filter(|_| tag != "653") {
+        Some(authority) => out.push_str(&format!("<subject{}>", attribute("authority", authority))),
+        None => out.push_str("<subject>"),
+    }
+    out.push_str(&inner);
+    out.push_str("</subject>");
+}
+
+/// 020 and 022: the number in `valid` subfields, and those in `invalid` marked so
+fn push_identifiers(
+    out: &mut String,
+    kind: &str,
+    field: &DataField,
+    valid: &[&str],
+    invalid: &[&str],
+) {
+    for (code, value) in field.subfields {
+        let attributes = if valid.contains(&code.as_str()) {
+            attribute("type", kind)
+        } else if invalid.contains(&code.as_str()) {
+            format!("{} invalid=\"yes\"", attribute("type", kind))
+        } else {
+            continue;
+        };
+        push_element(out, "identifier", &attributes, value.trim());
+    }
+}
+
+/// 035: an OCLC number without its prefix, or another system number as it is
+fn push_system_numbers(out: &mut String, field: &DataField) {
+    for number in field.all("a") {
+        match number.trim().strip_prefix("(OCoLC)") {
+            Some(oclc) => push_element(out, "identifier", " type=\"oclc\"", oclc.trim()),
+            None => push_element(out, "identifier", " type=\"local\"", number.trim()),
+        }
+    }
+}
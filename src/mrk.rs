@@ -0,0 +1,105 @@
+use anyhow::Result;
+
+use crate::db::MarcRecord;
+use crate::marc::{self, FieldData};
+use crate::quarantine::Rejection;
+use crate::writer::{prepare_record, Encoded, Encoder};
+
+/// Encoder for MarcEdit's mnemonic text (.mrk): a line per field, a blank line after
+/// each record, lines ending in CRLF as MarcEdit writes them
+pub struct MrkEncoder;
+
+impl Encoder for MrkEncoder {
+    /// The record's lines, with the blank line that ends it
+    fn encode(&self, record: &MarcRecord) -> Result<Encoded> {
+        let cleaned_marc = match prepare_record(record) {
+            Ok(cleaned) => cleaned,
+            Err(rejection) => return Ok(Err(rejection)),
+        };
+        let mut mrk = match to_mrk(&cleaned_marc) {
+            Ok(mrk) => mrk,
+            Err(reason) => return Ok(Err(Rejection::new("encode", reason))),
+        };
+        mrk.push_str("\r\n");
+        Ok(Ok(mrk.into_bytes()))
+    }
+}
+
+/// Write a MARCXML record as MarcEdit's MarcBreaker would: `=LDR  ` and the leader, then
+/// `=TAG  ` and each field, each line ending in CRLF
+///
+/// Blanks in the leader, control fields, and indicators are written `\`. Characters the
+/// format gives a meaning to are written as the mnemonics MarcMaker reads back: `$` as
+/// {dollar}, `\` as {bsol}, and `{` `}` as {lcub} {rcub}. An `=` is only read as the
+/// start of a field at the start of a line, so it stays as it is. A line break in the
+/// data has no mnemonic, and a record holding one is refused.
+pub fn to_mrk(xml: &str) -> Result<String, String> {
+    let record = marc::parse_record(xml).ok_or("not well-formed MARCXML")?;
+    let leader = record.leader.ok_or("no leader")?;
+    let mut out = String::new();
+    line(&mut out, "LDR", &fixed(&leader));
+
+    for field in &record.fields {
+        if field.tag.len() != 3 {
+            return Err(format!("tag {:?} is not three characters", field.tag));
+        }
+        let text = match &field.data {
+            FieldData::Control(data) => fixed(data),
+            FieldData::Data {
+                indicators,
+                subfields,
+            } => {
+                let mut text = format!("{}{}", indicator(&indicators.0), indicator(&indicators.1));
+                for (code, value) in subfields {
+                    text.push('$');
+                    text.push_str(&escape(code));
+                    text.push_str(&escape(value));
+                }
+                text
+            }
+        };
+        if text.contains(['\r', '\n']) {
+            return Err(format!(
+                "a line break in {}, which .mrk cannot hold",
+                field.tag
+            ));
+        }
+        line(&mut out, &field.tag, &text);
+    }
+    Ok(out)
+}
+
+fn line(out: &mut String, tag: &str, text: &str) {
+    out.push('=');
+    out.push_str(tag);
+    out.push_str("  ");
+    out.push_str(text);
+    out.push_str("\r\n");
+}
+
+/// The leader or a control field, its blanks written `\`
+fn fixed(data: &str) -> String {
+    escape(data).replace(' ', "\\")
+}
+
+fn indicator(value: &str) -> String {
+    match value {
+        "" | " " => "\\".to_string(),
+        other => escape(other),
+    }
+}
+
+/// Characters .mrk gives a meaning to, as MarcEdit's mnemonics
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '$' => escaped.push_str("{dollar}"),
+            '\\' => escaped.push_str("{bsol}"),
+            '{' => escaped.push_str("{lcub}"),
+            '}' => escaped.push_str("{rcub}"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,194 @@
+use clap::ValueEnum;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+use crate::db::MarcRecord;
+use crate::errorfile::ErrorFile;
+use crate::marc;
+use crate::quarantine::Rejection;
+use crate::review::Review;
+use crate::trace::Tracer;
+
+/// What to do with a row whose MARC is a collection of several records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MultiRecordRows {
+    /// Write each of them, all under the row's id
+    EmitAll,
+    /// Write only the first
+    First,
+    /// Leave the row out (quarantined if --quarantine-file is set)
+    Skip,
+}
+
+/// Finds the rows in the workers whose MARC holds several records, as some migrations
+/// left them, and remembers their ids
+///
+/// Without it the writer would keep only the first record of such a row, unannounced.
+/// Each record split out is a record of its own from here on: screened, counted, and
+/// written apart.
+pub struct MultiRecordCheck {
+    pub policy: MultiRecordRows,
+    pub quarantine: bool,
+    /// Only ids were fetched, for --ids-only, so there is no MARC to look in
+    pub ids_only: bool,
+    pub ids: Mutex<Vec<i64>>,
+    pub tracer: Arc<Tracer>,
+    pub review: Arc<Review>,
+    pub error_file: Arc<ErrorFile>,
+}
+
+impl MultiRecordCheck {
+    /// The records a fetched row is written as: itself, its records split apart, or none
+    pub fn split(&self, mut record: MarcRecord) -> Vec<MarcRecord> {
+        // Nearly every row closes one record, so only those closing more are parsed
+        if record.rejection.is_some() || self.ids_only || record.marc.matches("record>").count() < 2
+        {
+            return vec![record];
+        }
+        let mut records = match marc::record_elements(&record.marc) {
+            Some(records) if records.len() > 1 => records,
+            _ => return vec![record],
+        };
+
+        let count = records.len();
+        self.ids.lock().unwrap().push(record.id);
+        self.tracer.note(record.id, "split", || {
+            let policy = format!("{:?}", self.policy).to_lowercase();
+            format!(
+                "row holds {} records (--multi-record-rows {})",
+                count, policy
+            )
+        });
+        match self.policy {
+            MultiRecordRows::EmitAll => {
+                warn!(
+                    record_id = record.id,
+                    "Record {} holds {} records; writing each", record.id, count
+                );
+                records
+                    .into_iter()
+                    .map(|marc| MarcRecord {
+                        marc,
+                        ..record.clone()
+                    })
+                    .collect()
+            }
+            MultiRecordRows::First => {
+                warn!(
+                    record_id = record.id,
+                    "Record {} holds {} records; writing only the first", record.id, count
+                );
+                record.marc = records.swap_remove(0);
+                vec![record]
+            }
+            MultiRecordRows::Skip => {
+                let rejection = Rejection::new("split", format!("row holds {} records", count));
+                if self.quarantine {
+                    record.rejection = Some(rejection);
+                    return vec![record];
+                }
+                warn!(
+                    record_id = record.id,
+                    "Skipping record {}: row holds {} records", record.id, count
+                );
+                self.review.capture(&record, &rejection);
+                self.error_file.rejected(record.id, &rejection);
+                Vec::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROW: &str = "<collection xmlns=\"http://www.loc.gov/MARC21/slim\">\
+        <record><controlfield tag=\"001\">a</controlfield></record>\
+        <record><controlfield tag=\"001\">b</controlfield></record>\
+        <record><controlfield tag=\"001\">c</controlfield></record>\
+        </collection>";
+
+    fn check(policy: MultiRecordRows, quarantine: bool) -> MultiRecordCheck {
+        MultiRecordCheck {
+            policy,
+            quarantine,
+            ids_only: false,
+            ids: Mutex::new(Vec::new()),
+            tracer: Arc::new(Tracer::new(&[])),
+            review: Arc::new(Review::default()),
+            error_file: Arc::new(ErrorFile::default()),
+        }
+    }
+
+    fn row(marc: &str) -> MarcRecord {
+        MarcRecord {
+            id: 42,
+            marc: marc.to_string(),
+            rejection: None,
+            display: Vec::new(),
+            meta: None,
+            identifiers: None,
+            status: None,
+        }
+    }
+
+    /// The 001 of each record
+    fn control_numbers(records: &[MarcRecord]) -> Vec<String> {
+        records
+            .iter()
+            .map(|record| {
+                let start = record.marc.find("001\">").unwrap() + 5;
+                let end = record.marc.find("</controlfield>").unwrap();
+                record.marc[start..end].to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn emit_all_writes_each_record_under_the_row_id() {
+        let check = check(MultiRecordRows::EmitAll, false);
+        let records = check.split(row(ROW));
+        assert_eq!(control_numbers(&records), ["a", "b", "c"]);
+        assert!(records.iter().all(|record| record.id == 42));
+        assert!(records
+            .iter()
+            .all(|record| record.marc.starts_with("<record")));
+        assert_eq!(*check.ids.lock().unwrap(), [42]);
+    }
+
+    #[test]
+    fn first_writes_only_the_first_record() {
+        let check = check(MultiRecordRows::First, false);
+        let records = check.split(row(ROW));
+        assert_eq!(control_numbers(&records), ["a"]);
+        assert_eq!(*check.ids.lock().unwrap(), [42]);
+    }
+
+    #[test]
+    fn skip_leaves_the_row_out_or_quarantines_it() {
+        let check_skip = check(MultiRecordRows::Skip, false);
+        assert!(check_skip.split(row(ROW)).is_empty());
+        assert_eq!(*check_skip.ids.lock().unwrap(), [42]);
+
+        let records = check(MultiRecordRows::Skip, true).split(row(ROW));
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].marc, ROW);
+        let rejection = records[0].rejection.as_ref().unwrap().to_string();
+        assert!(rejection.contains("row holds 3 records"), "{}", rejection);
+    }
+
+    #[test]
+    fn a_row_with_one_record_is_left_alone() {
+        let check = check(MultiRecordRows::Skip, false);
+        for marc in [
+            "<record xmlns=\"http://www.loc.gov/MARC21/slim\"><leader>x</leader></record>",
+            "<collection><record><leader>x</leader></record></collection>",
+        ] {
+            let records = check.split(row(marc));
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[0].marc, marc);
+        }
+        assert!(check.ids.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,66 @@
+use anyhow::Result;
+use chrono::SecondsFormat;
+use clap::ValueEnum;
+
+use crate::db::MarcRecord;
+use crate::marc;
+use crate::quarantine::Rejection;
+use crate::writer::{prepare_record, Encoded, Encoder};
+
+/// What each --format ndjson line carries the record as
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NdjsonBody {
+    /// The MARCXML, as a string under "marcxml"
+    Xml,
+    /// The MARC-in-JSON object --format json writes, under "marc"
+    Json,
+}
+
+/// Encoder for newline-delimited JSON: one object per record and line, with no enclosing
+/// array, so the file can be split and streamed
+///
+/// A line is never left half written without the run stopping; see [`Destination::flush`].
+///
+/// [`Destination::flush`]: crate::writer::Destination::flush
+pub struct NdjsonEncoder {
+    body: NdjsonBody,
+}
+
+impl NdjsonEncoder {
+    pub fn new(body: NdjsonBody) -> Self {
+        Self { body }
+    }
+}
+
+impl Encoder for NdjsonEncoder {
+    /// The record's line: its id, edit date, and MARC as one JSON object
+    fn encode(&self, record: &MarcRecord) -> Result<Encoded> {
+        let cleaned_marc = match prepare_record(record) {
+            Ok(cleaned) => cleaned,
+            Err(rejection) => return Ok(Err(rejection)),
+        };
+        let edit_date = match &record.status {
+            Some(status) => {
+                json_string(&status.edit_date.to_rfc3339_opts(SecondsFormat::Secs, true))
+            }
+            None => "null".to_string(),
+        };
+        let body = match self.body {
+            NdjsonBody::Xml => format!("\"marcxml\":{}", json_string(&cleaned_marc)),
+            NdjsonBody::Json => match marc::to_marc_json(&cleaned_marc) {
+                Ok(json) => format!("\"marc\":{}", json),
+                Err(reason) => return Ok(Err(Rejection::new("encode", reason))),
+            },
+        };
+        Ok(Ok(format!(
+            "{{\"id\":{},\"edit_date\":{},{}}}\n",
+            record.id, edit_date, body
+        )
+        .into_bytes()))
+    }
+}
+
+/// `text` as a JSON string, quotes included
+fn json_string(text: &str) -> String {
+    serde_json::to_string(text).expect("a string always serializes")
+}
@@ -0,0 +1,102 @@
+use anyhow::{bail, Context, Result};
+use console::Term;
+use sqlx::postgres::PgConnectOptions;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// Where the database password comes from, in the order they are tried
+#[derive(Debug, Clone)]
+pub enum PasswordSource {
+    /// The first line of a file (--password-file)
+    File(PathBuf),
+    /// Typed at the terminal (--password-prompt)
+    Prompt,
+    /// The password in --db-url
+    Url,
+    /// The PGPASSWORD environment variable
+    Environment,
+    /// A matching line of ~/.pgpass (or PGPASSFILE), if there is one
+    Pgpass,
+}
+
+impl PasswordSource {
+    /// Which source applies, given the options and the URL
+    pub fn pick(file: Option<&Path>, prompt: bool, url: &str) -> Self {
+        match (file, prompt) {
+            (Some(path), _) => PasswordSource::File(path.to_path_buf()),
+            (None, true) => PasswordSource::Prompt,
+            _ if has_password(url) => PasswordSource::Url,
+            _ if std::env::var_os("PGPASSWORD").is_some() => PasswordSource::Environment,
+            _ => PasswordSource::Pgpass,
+        }
+    }
+
+    /// Set the password on `options` from a file or the terminal; the other sources are
+    /// already in them
+    pub fn apply(&self, options: PgConnectOptions) -> Result<PgConnectOptions> {
+        let password = match self {
+            PasswordSource::File(path) => read_file(path)?,
+            PasswordSource::Prompt => prompt(&options)?,
+            _ => return Ok(options),
+        };
+        Ok(options.password(&password))
+    }
+
+    /// How the startup log names the source
+    pub fn describe(&self) -> String {
+        match self {
+            PasswordSource::File(path) => format!("from --password-file {}", path.display()),
+            PasswordSource::Prompt => "typed at the prompt".to_string(),
+            PasswordSource::Url => "from --db-url".to_string(),
+            PasswordSource::Environment => "from PGPASSWORD".to_string(),
+            PasswordSource::Pgpass => {
+                "from ~/.pgpass if it has a matching line, else none".to_string()
+            }
+        }
+    }
+}
+
+/// The first line of `path`, trimmed; neither errors nor logs ever show it
+fn read_file(path: &Path) -> Result<String> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read --password-file {}", path.display()))?;
+    let password = contents.lines().next().unwrap_or_default().trim();
+    if password.is_empty() {
+        bail!(
+            "--password-file {} has no password on its first line",
+            path.display()
+        );
+    }
+    Ok(password.to_string())
+}
+
+/// Ask for the password on the terminal without echoing it
+fn prompt(options: &PgConnectOptions) -> Result<String> {
+    let term = Term::stderr();
+    if !term.is_term() {
+        bail!("--password-prompt needs a terminal; use --password-file when running unattended");
+    }
+    let host = match options.get_socket() {
+        Some(socket) => socket.display().to_string(),
+        None => options.get_host().to_string(),
+    };
+    term.write_str(&format!(
+        "Password for {}@{}: ",
+        options.get_username(),
+        host
+    ))?;
+    let password = term
+        .read_secure_line()
+        .context("Failed to read the password")?;
+    if password.is_empty() {
+        bail!("No password was typed");
+    }
+    Ok(password)
+}
+
+/// Whether `url` carries a password, in its user info or as a password parameter
+fn has_password(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| {
+        url.password().is_some() || url.query_pairs().any(|(key, _)| key == "password")
+    })
+}
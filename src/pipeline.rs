@@ -0,0 +1,3691 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use indicatif::HumanBytes;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::PgPool;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::{JoinError, JoinHandle};
+use tracing::{debug, error, info, warn, Instrument};
+
+use crate::audit::AuditLog;
+use crate::capability::{self, Capabilities, Feature};
+use crate::checkpoint::{Checkpoint, Checkpointer, Progress};
+use crate::compression::{Compression, MarcCompression, OutputCompression};
+use crate::connect::{mask_password, ConnectRetry};
+use crate::continuation::Token;
+use crate::db::{
+    self, DatabaseConfig, MarcRecord, OrderBy, Partition, Partitioning, ReadMode, RecordType,
+    Throttle,
+};
+use crate::digest::{Category, ErrorDigest, Subject};
+use crate::display::DisplayCsv;
+use crate::enrich::{Enrichment, Enrichments};
+use crate::errorfile::ErrorFile;
+use crate::estimate::Estimator;
+use crate::events::{Event, ProgressEvents};
+use crate::extractor::{DynSink, ExtractionProgress, Outcome, Reporter, StopHandle};
+use crate::fanout::{self, AlsoOutput, Fanout};
+use crate::fields::FieldSpec;
+use crate::filter::{Drift, IdFile, RecordFilter, Since};
+use crate::histogram::{Histogram, HistogramSpec};
+use crate::limits::{LimitCheck, OnLimitViolation, TargetLimits};
+use crate::manifest::{FileRanges, IdRange};
+use crate::multirecord::{MultiRecordCheck, MultiRecordRows};
+use crate::options::{ExtractorConfig, OnStub, LOW_IMPACT_CHANNEL_CAPACITY};
+use crate::password::PasswordSource;
+use crate::plan::ChunkPlan;
+use crate::pool::PoolMonitor;
+use crate::profile::{Profile, ProfileBuilder};
+use crate::provenance::{Entry, Provenance};
+use crate::quarantine::{Quarantine, Rejection};
+use crate::reorder::Reorder;
+use crate::review::Review;
+use crate::scaling::{Scaler, Workers};
+use crate::schema::SchemaCheck;
+use crate::scrub::{self, Scrubber};
+use crate::session::SessionSetup;
+use crate::sink::{self, Envelope, OutputFormat, OutputOptions, PostgresSinkConfig, SinkKind};
+use crate::snapshot::Snapshot;
+use crate::spill::{self, Arrival, SpillConfig, SpillStats};
+use crate::tls::{self, TlsOptions};
+use crate::trace::Tracer;
+use crate::transform::{FieldOrder, FieldSelection, TagPattern, Transforms};
+use crate::tunnel::{Tunnel, TunnelConfig};
+use crate::validate::{Validation, Validator};
+use crate::watchdog::Watchdog;
+use crate::writer::{self, ByteCounter, EncodedRecord, Encoder, WriteOutcome, WRITE_BATCH};
+use crate::{compat, display, dryrun, estimate, gaps, guards, idfile, marc, memory, plan, split};
+
+/// Scrubs records, cleans their MARCXML down to the record element, and encodes them for
+/// a file output in the workers, spreading the work over them, so the writer finds
+/// records ready to write
+struct Cleaner {
+    scrubber: Arc<Scrubber>,
+    /// Only ids were fetched, for --ids-only, so there is no MARC to clean
+    ids_only: bool,
+    /// What a file output is written as; the postgres and the application's sinks take
+    /// the records as they are
+    encoder: Option<Arc<dyn Encoder>>,
+    tracer: Arc<Tracer>,
+}
+
+impl Cleaner {
+    /// The record scrubbed, cleaned, and encoded, as it goes to the writer
+    fn prepare(&self, mut record: MarcRecord) -> EncodedRecord {
+        self.clean(&mut record);
+        // A record that fails to encode is left to the writer, which reports it
+        let encoded = self
+            .encoder
+            .as_ref()
+            .and_then(|encoder| encoder.encode(&record).ok());
+        EncodedRecord { record, encoded }
+    }
+
+    fn clean(&self, record: &mut MarcRecord) {
+        if let Some(removed) = self.scrubber.scrub(record) {
+            self.tracer.note(record.id, "scrubbed", || {
+                format!(
+                    "removed {} characters XML does not allow: {}",
+                    removed.len(),
+                    scrub::codepoints(&removed)
+                )
+            });
+        }
+        if self.ids_only || record.rejection.is_some() {
+            return;
+        }
+        let cleaned = writer::clean_marc_xml(&record.marc);
+        self.tracer.note(record.id, "cleaned", || {
+            format!(
+                "{} -> {} bytes ({} removed)",
+                record.marc.len(),
+                cleaned.len(),
+                record.marc.len() as i64 - cleaned.len() as i64
+            )
+        });
+        record.marc = cleaned;
+    }
+}
+
+/// Classifies stub records in the workers and remembers their ids
+struct StubCheck {
+    policy: OnStub,
+    quarantine: bool,
+    /// Only ids were fetched, for --ids-only, so there is no MARC to find stubs in
+    ids_only: bool,
+    /// Deleted records are written as OAI-PMH headers alone, for --envelope oai, so
+    /// their MARC is not looked at either
+    oai: bool,
+    ids: Mutex<Vec<i64>>,
+    tracer: Arc<Tracer>,
+    review: Arc<Review>,
+    error_file: Arc<ErrorFile>,
+}
+
+impl StubCheck {
+    /// Decide what happens to a fetched record; `None` means it is dropped
+    fn screen(&self, mut record: MarcRecord) -> Result<Option<MarcRecord>> {
+        self.tracer.fetched(&record);
+        let deletion = self.oai && record.status.as_ref().is_some_and(|status| status.deleted);
+        if record.rejection.is_some() || self.ids_only || deletion || !marc::is_stub(&record.marc) {
+            return Ok(Some(record));
+        }
+
+        self.tracer.note(record.id, "filtered", || {
+            format!(
+                "stub with no leader or fields (--on-stub {:?})",
+                self.policy
+            )
+            .to_lowercase()
+        });
+        self.ids.lock().unwrap().push(record.id);
+        match self.policy {
+            OnStub::Keep => Ok(Some(record)),
+            OnStub::Fail => anyhow::bail!(
+                "Record {} is a stub with no leader or fields (--on-stub fail)",
+                record.id
+            ),
+            OnStub::Skip if self.quarantine => {
+                record.rejection = Some(Rejection::new("stub", "no leader or fields"));
+                Ok(Some(record))
+            }
+            OnStub::Skip => {
+                let rejection = Rejection::new("stub", "no leader or fields");
+                self.review.capture(&record, &rejection);
+                self.error_file.rejected(record.id, &rejection);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Records the fetchers may have waiting for the writer, unless --channel-capacity says otherwise
+const CHANNEL_CAPACITY: usize = 1000;
+/// What the chunk and batch workers share, cloned for each chunk as it is dispatched
+#[derive(Clone)]
+struct WorkerContext {
+    pool: PgPool,
+    tx: mpsc::Sender<EncodedRecord>,
+    db_config: DatabaseConfig,
+    errors: Arc<AtomicU64>,
+    digest: Arc<ErrorDigest>,
+    throttle: Option<Arc<Throttle>>,
+    stages: Stages,
+    monitor: Arc<PoolMonitor>,
+    events: Arc<ProgressEvents>,
+    enrichments: Arc<Enrichments>,
+    cleaner: Arc<Cleaner>,
+    watchdog: Arc<Watchdog>,
+    scaler: Option<Arc<Scaler>>,
+    /// Puts chunks back in order for --ordered
+    reorder: Option<Arc<Reorder>>,
+    error_file: Arc<ErrorFile>,
+    /// Fetch the --with-display-fields values along with the records
+    display_fields: bool,
+}
+
+/// Record counts shared by the workers, the writer, and the final report
+#[derive(Clone, Default)]
+pub(crate) struct Counters {
+    pub(crate) processed: Arc<AtomicU64>,
+    pub(crate) errors: Arc<AtomicU64>,
+    pub(crate) rejected: Arc<AtomicU64>,
+    /// Records --validate found not well-formed
+    pub(crate) invalid: Arc<AtomicU64>,
+    pub(crate) digest: Arc<ErrorDigest>,
+    /// Ids of the records written, for --manifest
+    pub(crate) emitted: Arc<IdRange>,
+    /// Files a split output has been written to so far, for --manifest
+    pub(crate) files: Arc<AtomicU64>,
+    /// Ids each output file holds, for --manifest
+    pub(crate) ranges: Arc<FileRanges>,
+    /// Times the workers' pool acquisitions, for the pool report
+    pub(crate) pool: Arc<PoolMonitor>,
+}
+
+/// What the application running an extraction gave its [`Extractor`]
+pub(crate) struct Caller<'a> {
+    /// Takes the records in place of the outputs the options name
+    pub sink: Option<Box<dyn DynSink>>,
+    pub on_progress: &'a Reporter,
+    pub stop: &'a StopHandle,
+}
+
+/// Extract the records `config` selects, writing them where it says
+pub(crate) async fn run(
+    mut config: ExtractorConfig,
+    token: Option<Token>,
+    events: Arc<ProgressEvents>,
+    counters: &Counters,
+    provenance: Option<&Provenance>,
+    audit: Option<&AuditLog>,
+    caller: Caller<'_>,
+) -> Result<Outcome> {
+    let Caller {
+        sink: custom_sink,
+        on_progress,
+        stop,
+    } = caller;
+    let Settings {
+        connect_options,
+        first_file,
+        sink_config,
+        output_options,
+        target_limits,
+    } = settings(&mut config, token.as_ref())?;
+    // Held for the whole run; dropping the tunnel kills ssh
+    let Database {
+        pool,
+        tunnel: _tunnel,
+        snapshot,
+    } = connect(&mut config, connect_options).await?;
+    let mut selection = select(&config, token.as_ref(), &pool, audit).await?;
+    let (error_file, scrubber) = failure_reports(&config, selection.resumed.is_some())?;
+    let mut work = count(
+        &config,
+        &pool,
+        &selection.capabilities,
+        &mut selection.db_config,
+    )
+    .await?;
+    if let Some(outcome) =
+        without_extracting(&config, &pool, &selection, &work, &output_options).await?
+    {
+        return Ok(outcome);
+    }
+    let file_output = sink_config.is_none() && custom_sink.is_none();
+    let plan = plan_run(&config, &pool, &selection, &mut work, file_output, counters).await?;
+
+    // Bytes written out so far, after any compression; an estimate writes none
+    let written = output_options
+        .written
+        .clone()
+        .filter(|_| plan.estimator.is_none());
+
+    // What the workers encode records as for a file output, which an estimate measures
+    let encoder = match (&plan.estimator, file_output) {
+        (None, false) => None,
+        _ => Some(sink::encoder(&output_options)?),
+    };
+
+    events.send(Event::Started {
+        total: plan.expected_records,
+        workers: config.workers.max(),
+        chunk_size: config.chunk_size,
+    });
+
+    // Channel for passing records from fetchers to writer
+    debug!(
+        "Channel capacity: {} records; write buffer: {}",
+        plan.channel_capacity,
+        HumanBytes(config.write_buffer_size)
+    );
+    let (tx, rx) = mpsc::channel::<EncodedRecord>(plan.channel_capacity);
+
+    let watchdog = Arc::new(Watchdog::new(config.max_memory, config.spill_dir.is_some()));
+    let watchdog_handle = watchdog.spawn();
+
+    stop.watch(Arc::clone(&watchdog));
+    let writing_started = Instant::now();
+    on_progress.send(ExtractionProgress::Started {
+        records: plan.expected_records as u64,
+        counts_bytes: written.is_some(),
+    });
+
+    // Optionally put a spooler between the fetchers and the writer
+    let (rx, spill_handle) = spool(
+        &config,
+        rx,
+        plan.channel_capacity,
+        &watchdog,
+        selection.resumed.as_ref(),
+    );
+
+    let tracer = Arc::new(Tracer::new(&config.trace_record));
+
+    let (provenance_tx, recorder_handle) = match provenance {
+        Some(provenance) => {
+            let (tx, handle) = provenance.recorder();
+            (Some(tx), Some(handle))
+        }
+        None => (None, None),
+    };
+
+    // Records the stages leave out, for catalogers; an estimate writes none
+    let (review, review_handle) = match config
+        .review_file
+        .as_ref()
+        .filter(|_| plan.estimator.is_none())
+    {
+        Some(path) => {
+            let (review, handle) = Review::open(path.clone(), config.review_max).await?;
+            (Arc::new(review), Some(handle))
+        }
+        None => (Arc::new(Review::default()), None),
+    };
+
+    let mut writer_handle = WriterTask {
+        output: config.output.clone(),
+        options: output_options,
+        postgres: sink_config,
+        custom: custom_sink,
+        resumed: selection.resumed.clone(),
+        also_output: config.also_output.clone(),
+        fail_fast: config.fail_fast,
+        quarantine_file: config
+            .quarantine_file
+            .clone()
+            .filter(|_| plan.estimator.is_none()),
+        display_file: config
+            .with_display_fields
+            .clone()
+            .filter(|_| plan.estimator.is_none()),
+        durable: config.durable,
+        checkpointer: checkpointer(&config, &selection, plan.progress.as_ref()),
+        provenance_tx: provenance_tx.clone(),
+        estimator: plan.estimator.clone(),
+        tracer: Arc::clone(&tracer),
+        review: Arc::clone(&review),
+        error_file: Arc::clone(&error_file),
+        events: Arc::clone(&events),
+        on_progress: on_progress.clone(),
+        written: written.clone(),
+        total: plan.expected_records as u64,
+        counters: counters.clone(),
+    }
+    .spawn(rx);
+
+    let throttle = config
+        .throttle
+        .map(|ms| Arc::new(Throttle::new(Duration::from_millis(ms))));
+
+    // Listed ids the fetches did not return, for the summary
+    let not_found: Arc<Mutex<Vec<i64>>> = Arc::default();
+
+    let cleaner = Arc::new(Cleaner {
+        scrubber: Arc::clone(&scrubber),
+        ids_only: config.ids_only,
+        encoder,
+        tracer: Arc::clone(&tracer),
+    });
+    let stages = Stages::build(
+        &config,
+        &pool,
+        target_limits,
+        &tracer,
+        &review,
+        &error_file,
+        counters,
+    )
+    .await?;
+
+    // Time pool acquisitions; the streaming worker holds one connection throughout,
+    // so the pool is only watched when chunks or batches compete for connections
+    let reporter = (!config.stream).then(|| {
+        Arc::clone(&counters.pool).spawn_reporter(
+            pool.clone(),
+            config.workers.max(),
+            config.throttle.is_none(),
+            Arc::clone(&events),
+        )
+    });
+
+    // With --workers auto the pool holds the most connections allowed, and the controller
+    // decides how many chunks use them at once
+    let scaler = match config.workers {
+        Workers::Auto { max } if !config.stream => {
+            Some(Arc::new(Scaler::new(max, Arc::clone(&events))))
+        }
+        _ => None,
+    };
+    let controller = scaler.as_ref().map(|scaler| scaler.spawn());
+
+    // A stream, or a single chunk, is in order already
+    let reorder = (config.ordered && !config.stream && config.only_chunk.is_none()).then(|| {
+        Arc::new(Reorder::new(
+            config.ordered_window as usize,
+            tx.clone(),
+            Arc::clone(&watchdog),
+        ))
+    });
+    // The workers hold the only senders, so the writer knows when they are done
+    let context = WorkerContext {
+        pool: pool.clone(),
+        tx,
+        db_config: selection.db_config.clone(),
+        errors: Arc::clone(&counters.errors),
+        digest: Arc::clone(&counters.digest),
+        throttle,
+        stages: stages.clone(),
+        monitor: Arc::clone(&counters.pool),
+        events: Arc::clone(&events),
+        enrichments: Arc::clone(&selection.enrichments),
+        cleaner,
+        watchdog: Arc::clone(&watchdog),
+        scaler: scaler.clone(),
+        reorder,
+        error_file: Arc::clone(&error_file),
+        display_fields: config.with_display_fields.is_some(),
+    };
+
+    let listed = work.listed_ids.is_some();
+    let handles = if config.stream {
+        vec![spawn_stream(
+            context,
+            plan.records,
+            config.mode,
+            plan.progress.clone(),
+        )]
+    } else if let Some(ids) = work.listed_ids.take() {
+        spawn_batches(
+            context,
+            &config,
+            ids,
+            plan.records,
+            &not_found,
+            &counters.ranges,
+        )
+    } else {
+        let chunks = plan.chunks.clone().expect("chunked runs have a plan");
+        spawn_chunks(
+            context,
+            &config,
+            chunks,
+            plan.records,
+            plan.estimator.clone(),
+            plan.progress.clone(),
+        )
+    };
+
+    let (fatal, writer_result) = wait(
+        handles,
+        &mut writer_handle,
+        plan.estimator.as_deref(),
+        counters,
+    )
+    .await;
+
+    if let Some(reporter) = reporter {
+        reporter.abort();
+    }
+    if let Some(controller) = controller {
+        controller.abort();
+    }
+    if let Some(handle) = watchdog_handle {
+        handle.abort();
+    }
+
+    // Records the workers dropped never reach the writer, so they are recorded here
+    if let Some(tx) = provenance_tx {
+        for (record_id, reason) in stages.dropped(&config) {
+            let entry = Entry {
+                record_id,
+                disposition: "skipped",
+                reason: Some(reason),
+                marc: None,
+                output_file: None,
+                byte_offset: None,
+            };
+            // A recorder that stopped reports its own error below
+            if tx.send(entry).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    // Wait for the spooler to replay everything it buffered
+    let spill_result = match spill_handle {
+        Some(handle) => Some(handle.await.context("Spill task panicked")?),
+        None => None,
+    };
+
+    // Wait for writer to finish; its error explains a spooler that could not hand over records
+    let writer_result = match writer_result {
+        Some(result) => result,
+        None => writer_handle.await,
+    };
+    // Nothing is captured once the writer is done; the file is closed even if the run failed
+    review.close();
+    let review_result = match review_handle {
+        Some(handle) => Some(handle.await.context("Review task panicked")?),
+        None => None,
+    };
+    // Flushed whether or not the run failed, so the failures so far can be retried
+    let error_lines = error_file.finish(config.durable);
+    let scrubbed = scrubber.finish(config.durable);
+    // The recorder finishes once the writer drops its sender; its error explains a writer
+    // that stopped because the recorder had
+    let recorded = match recorder_handle {
+        Some(handle) => Some(handle.await.context("Provenance task panicked")??),
+        None => None,
+    };
+    let writer = writer_result.context("Writer task panicked")??;
+    let reviewed = review_result.transpose()?;
+    let error_lines = error_lines?;
+    scrubbed?;
+    let spill_stats = spill_result.transpose()?;
+
+    on_progress.send(ExtractionProgress::Finished {
+        stopped: watchdog.stopped(),
+    });
+    let snapshot = match snapshot {
+        Some(snapshot) => {
+            let used = (snapshot.id().to_string(), snapshot.taken());
+            snapshot.release().await?;
+            Some(used)
+        }
+        None => None,
+    };
+
+    let mut not_found = std::mem::take(&mut *not_found.lock().unwrap());
+    not_found.sort_unstable();
+    let summary = Summary {
+        selection: &selection,
+        work: &work,
+        plan: &plan,
+        stages: &stages,
+        counters,
+        writer,
+        processed: counters.processed.load(Ordering::Relaxed),
+        rejected: counters.rejected.load(Ordering::Relaxed),
+        errors: counters.errors.load(Ordering::Relaxed),
+        written,
+        writing_started,
+        snapshot,
+        review: &review,
+        reviewed,
+        error_lines,
+        scrubber: &scrubber,
+        not_found,
+        spill: spill_stats,
+        scaler: scaler.as_deref(),
+        provenance,
+        recorded,
+        watchdog: &watchdog,
+        listed,
+        tracer: &tracer,
+        first_file,
+        fatal,
+    };
+    report(&config, &summary);
+    conclude(&config, token.as_ref(), summary)
+}
+
+/// What the options come to once checked against each other
+struct Settings {
+    connect_options: PgConnectOptions,
+    /// The number of a split output's first file
+    first_file: u64,
+    sink_config: Option<PostgresSinkConfig>,
+    output_options: OutputOptions,
+    target_limits: Option<TargetLimits>,
+}
+
+/// Check the options, logging the settings the run goes ahead with
+fn settings(config: &mut ExtractorConfig, token: Option<&Token>) -> Result<Settings> {
+    let db_url = config.database_url().ok_or_else(|| {
+        anyhow!(
+            "No database given: set --db-url, or --db-host, --db-port, --db-name, and --db-user"
+        )
+    })?;
+    info!("Starting MARC extraction");
+    let masked_url = mask_password(&db_url);
+    let tls = TlsOptions {
+        mode: config.ssl_mode,
+        root_cert: config.ssl_root_cert.clone(),
+        client_cert: config.ssl_client_cert.clone(),
+        client_key: config.ssl_client_key.clone(),
+    };
+    let mut connect_options = tls.apply(db_url.parse().context("Invalid database URL")?)?;
+    let password = PasswordSource::pick(
+        config.password_file.as_deref(),
+        config.password_prompt,
+        &db_url,
+    );
+    connect_options = password.apply(connect_options)?;
+    let ssl_mode = tls::mode_name(connect_options.get_ssl_mode());
+    info!(database = %masked_url, "Database: {} (SSL mode {})", masked_url, ssl_mode);
+    info!("Password: {}", password.describe());
+    info!(workers = %config.workers, "Workers: {}", config.workers);
+    info!(
+        chunk_size = config.chunk_size,
+        "Chunk size: {}", config.chunk_size
+    );
+
+    if config.record_type != RecordType::Bib {
+        info!(
+            "Record type: {} ({})",
+            config.record_type.name(),
+            config.record_type.table()
+        );
+        // Each draws on bib tables or columns authority records do not have
+        let bib_only = [
+            (config.profile.is_some(), "--profile"),
+            (
+                config.with_display_fields.is_some(),
+                "--with-display-fields",
+            ),
+            (config.embed_holdings.is_some(), "--embed-holdings"),
+            (
+                config.add_fingerprint_field.is_some(),
+                "--add-fingerprint-field",
+            ),
+            (config.add_901, "--add-901"),
+            (!config.org_unit.is_empty(), "--org-unit"),
+            (config.order_by == OrderBy::TcnValue, "--order-by tcn_value"),
+        ];
+        if let Some((_, option)) = bib_only.iter().find(|(given, _)| *given) {
+            anyhow::bail!(
+                "{} only applies to bib records, not --record-type {}",
+                option,
+                config.record_type.name()
+            );
+        }
+    }
+    if config.marc_compression != MarcCompression::None {
+        info!("MARC compression: {:?}", config.marc_compression);
+    }
+    let compression = Compression::new(
+        config
+            .compress
+            .unwrap_or_else(|| OutputCompression::for_path(config.output.as_deref())),
+        config.compress_level,
+    )?;
+    if compression != Compression::NONE {
+        info!("Output compression: {}", compression);
+    }
+    if config.max_file_bytes == Some(0) {
+        anyhow::bail!("--max-file-bytes must be more than 0");
+    }
+    if config.write_buffer_size == 0 {
+        anyhow::bail!("--write-buffer-size must be more than 0");
+    }
+    guards::check_write_buffer(config.write_buffer_size, 1 + config.also_output.len());
+    // A batch's split output carries on the chain's numbering
+    let first_file = token.and_then(|token| token.next_file).unwrap_or(1);
+    let limits = [
+        config
+            .records_per_file
+            .map(|per_file| format!("{} records", per_file)),
+        config
+            .max_file_bytes
+            .map(|bytes| HumanBytes(bytes).to_string()),
+    ];
+    let limits: Vec<String> = limits.into_iter().flatten().collect();
+    if !limits.is_empty() {
+        let Some(output) = &config.output else {
+            let option = if config.records_per_file.is_some() {
+                "--records-per-file"
+            } else {
+                "--max-file-bytes"
+            };
+            anyhow::bail!(
+                "{} needs --output to name the files after; stdout cannot be split",
+                option
+            );
+        };
+        info!(
+            "Splitting output: at most {} per file, starting with {}",
+            limits.join(" or "),
+            split::numbered(output, first_file).display()
+        );
+    }
+    if let Some(path) = &config.checkpoint {
+        if config.output.is_none() {
+            anyhow::bail!("--checkpoint needs --output: a resumed run appends to the file, which stdout cannot be");
+        }
+        if compression != Compression::NONE {
+            anyhow::bail!("--checkpoint cannot be used with a compressed output, which cannot be cut back to a checkpoint and appended to");
+        }
+        if config.order_by != OrderBy::Id {
+            anyhow::bail!("--checkpoint needs --order-by id: a resumed run starts after the highest id it can vouch for");
+        }
+        info!("Checkpoint: {}", path.display());
+    }
+    if config.mode == ReadMode::Copy {
+        info!("Mode: streaming with COPY");
+    } else if config.stream {
+        info!("Mode: streaming");
+    }
+    if let Some(ms) = config.throttle {
+        info!("Throttle: {}ms between fetches", ms);
+    }
+    if let Some(path) = &config.quarantine_file {
+        info!("Quarantine file: {}", path.display());
+    }
+    if let Some(dir) = &config.spill_dir {
+        info!(
+            "Spill directory: {} (max {})",
+            dir.display(),
+            HumanBytes(config.spill_max)
+        );
+    }
+    if let Some(limit) = config.max_memory {
+        info!("Memory limit: {}", HumanBytes(limit));
+    }
+    if let (Some(Profile::OclcHoldings), Some(action)) = (config.profile, config.holdings_action) {
+        info!(
+            "Profile: oclc-holdings (holdings action: {})",
+            action.code()
+        );
+    }
+
+    if config.ids_only {
+        info!("Output: record ids only");
+        config.format = OutputFormat::Ids;
+    }
+    for (index, also) in config.also_output.iter().enumerate() {
+        let taken = config.output.as_ref() == Some(&also.path)
+            || config.also_output[..index]
+                .iter()
+                .any(|o| o.path == also.path);
+        if taken {
+            anyhow::bail!(
+                "--also-output {} names a file the run already writes",
+                also.path.display()
+            );
+        }
+    }
+    let formats = config.formats();
+    if config.escape_non_ascii
+        && !formats
+            .iter()
+            .any(|format| matches!(format, OutputFormat::Xml | OutputFormat::Dc))
+    {
+        anyhow::bail!("--escape-non-ascii only applies to --format xml and dc");
+    }
+    let pretty_formats = [OutputFormat::Xml, OutputFormat::Mods, OutputFormat::Dc];
+    if config.pretty && !formats.iter().any(|format| pretty_formats.contains(format)) {
+        anyhow::bail!("--pretty only applies to --format xml, mods, and dc");
+    }
+    match (&config.fields, formats.contains(&OutputFormat::Csv)) {
+        (Some(spec), true) => info!("Columns: id,{}", spec.text()),
+        (Some(_), false) => anyhow::bail!("--fields only applies to --format csv"),
+        (None, true) => anyhow::bail!("--format csv needs --fields"),
+        (None, false) => {}
+    }
+    let oai_prefix = if config.envelope == Envelope::Oai {
+        if !formats
+            .iter()
+            .any(|format| matches!(format, OutputFormat::Xml | OutputFormat::Dc))
+        {
+            anyhow::bail!("--envelope only applies to --format xml and dc");
+        }
+        info!(
+            "Envelope: OAI-PMH ListRecords (identifiers {}<id>)",
+            config.oai_prefix.as_deref().unwrap_or_default()
+        );
+        config.oai_prefix.clone()
+    } else {
+        if config.oai_prefix.is_some() {
+            anyhow::bail!("--oai-prefix only applies to --envelope oai");
+        }
+        None
+    };
+    let sink_config = match (config.sink, &config.sink_url, &config.sink_table) {
+        (SinkKind::Postgres, Some(url), Some(table)) => {
+            if config.output.is_some() {
+                anyhow::bail!("--output cannot be used with --sink postgres");
+            }
+            if config.format != OutputFormat::Xml {
+                anyhow::bail!("--format applies to --output and stdout, not --sink postgres");
+            }
+            info!(
+                "Sink: table {} on {} (on conflict: {:?}, batches of {})",
+                table,
+                mask_password(url),
+                config.sink_on_conflict,
+                config.sink_batch_size
+            );
+            Some(PostgresSinkConfig {
+                url: url.clone(),
+                table: table.clone(),
+                on_conflict: config.sink_on_conflict,
+                batch_size: config.sink_batch_size as usize,
+            })
+        }
+        _ => None,
+    };
+
+    let output_options = OutputOptions {
+        format: config.format,
+        compression,
+        escape_non_ascii: config.escape_non_ascii,
+        pretty: config.pretty,
+        durable: config.durable,
+        atomic: !config.no_atomic,
+        buffer_size: config.write_buffer_size as usize,
+        records_per_file: config.records_per_file,
+        max_file_bytes: config.max_file_bytes,
+        first_file,
+        oai_prefix,
+        fields: config.fields.clone(),
+        field_separator: config.field_separator.clone(),
+        ndjson_body: config.ndjson_body,
+        written: (config.sink != SinkKind::Postgres).then(ByteCounter::default),
+    };
+
+    let target_limits = match &config.target_limits {
+        Some(value) => {
+            let limits = TargetLimits::resolve(value)?;
+            info!(
+                "Target limits: {} (on violation: {:?})",
+                limits.name, config.on_limit_violation
+            );
+            Some(limits)
+        }
+        None => None,
+    };
+
+    Ok(Settings {
+        connect_options,
+        first_file,
+        sink_config,
+        output_options,
+        target_limits,
+    })
+}
+
+/// The database the run reads from, and what has to stay open while it does
+struct Database {
+    pool: PgPool,
+    /// Held for the whole run; dropping it kills ssh
+    tunnel: Option<Tunnel>,
+    /// Held open for the whole run, as connections import the snapshot as they are opened
+    snapshot: Option<Snapshot>,
+}
+
+/// Open the pool the workers share; without --connections, the workers are capped to
+/// what the server will accept
+async fn connect(
+    config: &mut ExtractorConfig,
+    mut connect_options: PgConnectOptions,
+) -> Result<Database> {
+    // Create database connection pool
+    info!("Creating database connection pool...");
+    if let Some(socket) = &config.db_socket {
+        info!("Connecting via Unix socket in {}", socket.display());
+        connect_options = connect_options.socket(socket);
+    }
+    let tunnel = match &config.ssh_tunnel {
+        Some(target) => {
+            let tunnel_config = TunnelConfig {
+                target: target.clone(),
+                key: config.ssh_key.clone(),
+                accept_new_host_key: config.ssh_accept_new_host_key,
+            };
+            let host = connect_options.get_host().to_string();
+            let tunnel = Tunnel::open(tunnel_config, &host, connect_options.get_port()).await?;
+            connect_options = connect_options.host("127.0.0.1").port(tunnel.local_port());
+            Some(tunnel)
+        }
+        None => None,
+    };
+
+    let retry = ConnectRetry {
+        retries: config.connect_retries,
+        delay: config.connect_retry_delay,
+        timeout: config.connect_timeout,
+    };
+    // The pool cannot grow past what the server will accept, so size it to fit; without
+    // --connections there is a connection per worker, so the workers are capped with it
+    let connections = match config.connections {
+        Some(connections) => {
+            guards::cap_connections(&connect_options, &retry, connections, "--connections").await?
+        }
+        None => {
+            let free = guards::cap_connections(
+                &connect_options,
+                &retry,
+                config.workers.max(),
+                "--workers",
+            )
+            .await?;
+            config.workers = config.workers.capped(free);
+            free
+        }
+    };
+    if connections < config.workers.max() {
+        info!(
+            "{} workers sharing {} connections",
+            config.workers.max(),
+            connections
+        );
+    }
+
+    let mut session = config
+        .session_sql
+        .iter()
+        .fold(SessionSetup::default(), |session, sql| session.sql(sql));
+    if let Some(seconds) = config.statement_timeout {
+        info!("Statement timeout: {}s per query", seconds);
+        session = session.sql(&format!("SET statement_timeout = '{}s'", seconds));
+    }
+    for description in session.descriptions() {
+        info!("Session setup: {}", description);
+    }
+    let snapshot = if config.consistent {
+        let snapshot = Snapshot::export(&connect_options).await?;
+        info!(
+            "Snapshot: {} (the database as of {})",
+            snapshot.id(),
+            snapshot.taken().to_rfc3339()
+        );
+        Some(snapshot)
+    } else {
+        None
+    };
+    let pool_options = PgPoolOptions::new()
+        .max_connections(connections)
+        .acquire_timeout(Duration::from_secs(config.acquire_timeout));
+    let (session, pool_options) = match &snapshot {
+        Some(snapshot) => (snapshot.session(session), snapshot.apply(pool_options)),
+        None => (session, pool_options),
+    };
+    session.check(&connect_options).await?;
+
+    let pool_options = session.apply(pool_options);
+    let pool = retry
+        .retry(|| pool_options.clone().connect_with(connect_options.clone()))
+        .await?;
+    if let Some(tunnel) = &tunnel {
+        tunnel.attach(&pool);
+    }
+
+    info!("Database connection established");
+    // Before anything is counted, so that a server refusing COPY fails the run at once
+    if config.mode == ReadMode::Copy {
+        db::check_copy(&pool).await?;
+    }
+
+    Ok(Database {
+        pool,
+        tunnel,
+        snapshot,
+    })
+}
+
+/// The records the run extracts, and how they are read
+struct Selection {
+    filter: RecordFilter,
+    capabilities: Capabilities,
+    enrichments: Arc<Enrichments>,
+    db_config: DatabaseConfig,
+    /// The checkpoint of the interrupted run being resumed
+    resumed: Option<Checkpoint>,
+}
+
+/// Gather the record selection, checking what the server supports and where a chain of
+/// batches or an interrupted run left off
+async fn select(
+    config: &ExtractorConfig,
+    token: Option<&Token>,
+    pool: &PgPool,
+    audit: Option<&AuditLog>,
+) -> Result<Selection> {
+    let saved_filter = match &config.filter {
+        Some(path) => {
+            info!("Filter: {}", path.display());
+            Some(RecordFilter::load(path, config.allow_filter_drift)?)
+        }
+        None => None,
+    };
+
+    // Probe everything the run will read before any of it is queried
+    let mut features = vec![Feature::Extract];
+    let (include_deleted, editors, import_queues, sources, org_units) = match &saved_filter {
+        Some(f) => (
+            f.include_deleted,
+            !f.editors.is_empty(),
+            !f.import_queues.is_empty(),
+            !f.sources.is_empty(),
+            !f.org_units.is_empty(),
+        ),
+        None => (
+            config.include_deleted,
+            !config.editor.is_empty(),
+            !config.import_queue.is_empty(),
+            !config.source.is_empty(),
+            !config.org_unit.is_empty(),
+        ),
+    };
+    if !include_deleted {
+        features.push(Feature::ActiveOnly);
+    }
+    if editors {
+        features.push(Feature::Editors);
+    }
+    if saved_filter.is_none() && config.editor.iter().any(|e| e.parse::<i64>().is_err()) {
+        features.push(Feature::EditorNames);
+    }
+    if import_queues {
+        features.push(Feature::ImportQueues);
+    }
+    if sources {
+        features.push(Feature::Sources);
+    }
+    if org_units {
+        features.push(Feature::OrgUnits);
+    }
+    let since = match &saved_filter {
+        Some(f) => f.since,
+        None => config.since.map(|time| Since {
+            field: config.since_field,
+            time,
+        }),
+    };
+    if let Some(since) = since {
+        features.push(Feature::Since(since.field));
+    }
+    if let Some(spec) = config.histogram {
+        features.push(Feature::Histogram(spec.field));
+    }
+    if config.with_display_fields.is_some() {
+        features.push(Feature::DisplayFields);
+    }
+    if config.embed_holdings.is_some() {
+        features.push(Feature::Holdings);
+    }
+    if config.order_by != OrderBy::Id {
+        features.push(Feature::OrderBy(config.order_by));
+    }
+    let with_meta = config.add_fingerprint_field.is_some()
+        || config.fields.as_ref().is_some_and(FieldSpec::wants_meta);
+    if with_meta {
+        features.push(Feature::RecordMeta);
+    }
+    if config.add_901 {
+        features.push(Feature::Identifiers);
+    }
+    if !config.stream && config.fill_gaps_against.is_none() && config.id_file.is_none() {
+        features.push(Feature::PartitionPlan);
+    }
+    // Features the server's release lacks are known before any column is probed
+    let versions = compat::detect(pool, config.assume_version).await?;
+    let unsupported = compat::screen(&versions, &mut features, config.degrade_gracefully)?;
+    let mut capabilities = capability::check(
+        pool,
+        &features,
+        config.record_type,
+        config.degrade_gracefully,
+    )
+    .await?;
+    for (feature, problem) in unsupported {
+        capabilities.disable(feature, problem);
+    }
+    let mut enrich = config.enrich.clone();
+    if let Some(tag) = &config.embed_holdings {
+        if capabilities.is_enabled(Feature::Holdings) {
+            info!("Embedding holdings as {} fields", tag);
+            enrich.push(Enrichment::holdings(tag));
+        }
+    }
+    let enrichments = Arc::new(
+        Enrichments::prepare(pool, &enrich, &mut capabilities, config.degrade_gracefully).await?,
+    );
+    if !enrichments.tags().is_empty() {
+        info!("Enriching with fields: {}", enrichments.tags().join(", "));
+    }
+
+    // Gather the record selection into one place
+    let mut filter = match saved_filter {
+        Some(filter) => filter,
+        None => RecordFilter {
+            include_deleted: config.include_deleted,
+            deleted_only: config.deleted_only,
+            editors: db::resolve_editors(pool, &config.editor).await?,
+            import_queues: config.import_queue.clone(),
+            sources: db::resolve_sources(pool, &config.source).await?,
+            org_units: db::resolve_org_units(pool, &config.org_unit, config.descendants).await?,
+            min_id: config.min_id,
+            max_id: config.max_id,
+            since,
+            predicate: config.predicate.clone(),
+            id_file: None,
+        },
+    };
+    // A saved filter names its id file, which must also be given so that the options it
+    // rules out are checked
+    match (&filter.id_file, &config.id_file, &config.filter) {
+        (Some(saved), None, Some(path)) => anyhow::bail!(
+            "Filter file {} selects the records listed in {}; give it as --id-file too",
+            path.display(),
+            saved.path.display()
+        ),
+        (Some(saved), Some(id_file), Some(path)) if saved.path != *id_file => anyhow::bail!(
+            "Filter file {} selects the records listed in {}, not --id-file {}",
+            path.display(),
+            saved.path.display(),
+            id_file.display()
+        ),
+        (None, Some(id_file), _) => filter.id_file = Some(IdFile::read(id_file)?),
+        _ => {}
+    }
+    db::validate_filter(pool, &filter, config.record_type).await?;
+    if let Some(path) = &config.save_filter {
+        filter.save(path)?;
+        info!("Filter saved to {}", path.display());
+    }
+    if filter.deleted_only {
+        info!("Deleted records only");
+    } else {
+        info!("Include deleted: {}", filter.include_deleted);
+    }
+    if !filter.editors.is_empty() {
+        info!("Editors: {:?}", filter.editors);
+    }
+    if !filter.import_queues.is_empty() {
+        info!("Import queues: {:?}", filter.import_queues);
+    }
+    if !filter.sources.is_empty() {
+        info!("Sources: {:?}", filter.sources);
+    }
+    if !filter.org_units.is_empty() {
+        info!("Org units: {:?}", filter.org_units);
+    }
+    match (filter.min_id, filter.max_id) {
+        (Some(min), Some(max)) => info!("Ids: {} to {}", min, max),
+        (Some(min), None) => info!("Ids: {} and above", min),
+        (None, Some(max)) => info!("Ids: up to {}", max),
+        (None, None) => {}
+    }
+    if let Some(since) = filter.since {
+        info!(
+            "Since: {} ({})",
+            since.time.to_rfc3339(),
+            since.field.column()
+        );
+    }
+    if let Some(predicate) = &filter.predicate {
+        info!("Where: {}", predicate);
+    }
+    if let Some(audit) = audit {
+        audit.started(filter.fingerprint())?;
+    }
+
+    // Create database config
+    let mut db_config = DatabaseConfig {
+        record_type: config.record_type,
+        filter: filter.clone(),
+        chunk_size: config.chunk_size,
+        after_id: None,
+        max_id: None,
+        marc_compression: config.marc_compression,
+        order_by: config.order_by,
+        with_meta,
+        with_identifiers: config.add_901,
+        // An OAI header and an NDJSON line both give the edit date
+        with_status: config.envelope == Envelope::Oai
+            || config.formats().contains(&OutputFormat::Ndjson),
+        ids_only: config.ids_only,
+        capped: config.limit.is_some(),
+    };
+
+    // Pick up a chain of batched runs where the last one stopped
+    if let Some(path) = &config.continue_from {
+        match token {
+            Some(token) => {
+                if token.record_type != config.record_type {
+                    anyhow::bail!(
+                        "Continuation token {} is for a chain of {} records; refusing to continue it with --record-type {}",
+                        path.display(),
+                        token.record_type.name(),
+                        config.record_type.name()
+                    );
+                }
+                match filter.drift_from(&token.filter) {
+                    Drift::None => {}
+                    Drift::IdFile if config.allow_filter_drift => warn!(
+                        "Id file {} has changed since continuation token {} was created; continuing anyway",
+                        config.id_file.as_ref().expect("in the filter").display(),
+                        path.display()
+                    ),
+                    Drift::IdFile => anyhow::bail!(
+                        "Id file {} has changed since continuation token {} was created; refusing to continue without --allow-filter-drift",
+                        config.id_file.as_ref().expect("in the filter").display(),
+                        path.display()
+                    ),
+                    Drift::Selection => anyhow::bail!(
+                        "Continuation token {} was created with a different record filter ({:?}); refusing to continue with {:?}",
+                        path.display(),
+                        token.filter,
+                        filter
+                    ),
+                }
+                info!(
+                    "Continuing after record {} (batch {}, {} records so far)",
+                    token.last_id,
+                    token.batch + 1,
+                    token.records_written
+                );
+                db_config.after_id = Some(token.last_id);
+            }
+            None => info!(
+                "No continuation token at {}, starting from the beginning",
+                path.display()
+            ),
+        }
+    }
+
+    // Pick up an interrupted run where its checkpoint left it
+    let resumed = match (&config.checkpoint, config.resume) {
+        (Some(path), true) => match Checkpoint::load(path)? {
+            Some(checkpoint) => {
+                let output = config.output.as_ref().expect("checked with --checkpoint");
+                if checkpoint.record_type != config.record_type {
+                    anyhow::bail!(
+                        "Checkpoint {} is for a run extracting {} records; refusing to resume it with --record-type {}",
+                        path.display(),
+                        checkpoint.record_type.name(),
+                        config.record_type.name()
+                    );
+                }
+                match filter.drift_from(&checkpoint.filter) {
+                    Drift::None => {}
+                    Drift::IdFile if config.allow_filter_drift => warn!(
+                        "Id file {} has changed since checkpoint {} was saved; resuming anyway",
+                        config.id_file.as_ref().expect("in the filter").display(),
+                        path.display()
+                    ),
+                    Drift::IdFile => anyhow::bail!(
+                        "Id file {} has changed since checkpoint {} was saved; refusing to resume without --allow-filter-drift",
+                        config.id_file.as_ref().expect("in the filter").display(),
+                        path.display()
+                    ),
+                    Drift::Selection => anyhow::bail!(
+                        "Checkpoint {} was saved with a different record filter ({:?}); refusing to resume with {:?}",
+                        path.display(),
+                        checkpoint.filter,
+                        filter
+                    ),
+                }
+                if checkpoint.atomic == config.no_atomic {
+                    let (written, resume) = if checkpoint.atomic {
+                        (format!("{}.part", output.display()), "without --no-atomic")
+                    } else {
+                        (output.display().to_string(), "with --no-atomic")
+                    };
+                    anyhow::bail!(
+                        "Checkpoint {} is for a run writing {}; resume {}",
+                        path.display(),
+                        written,
+                        resume
+                    );
+                }
+                let columns = config.fields.as_ref().map(FieldSpec::text);
+                if checkpoint.columns != columns {
+                    anyhow::bail!(
+                        "Checkpoint {} is for a CSV with columns {:?}; resume with the same --fields",
+                        path.display(),
+                        checkpoint.columns.as_deref().unwrap_or_default()
+                    );
+                }
+                if &checkpoint.output != output
+                    || checkpoint.format != config.format
+                    || checkpoint.records_per_file != config.records_per_file
+                    || checkpoint.max_file_bytes != config.max_file_bytes
+                {
+                    let limits = [
+                        checkpoint.records_per_file.map(|n| format!("{} records", n)),
+                        checkpoint.max_file_bytes.map(|n| format!("{} bytes", n)),
+                    ];
+                    let limits: Vec<String> = limits.into_iter().flatten().collect();
+                    anyhow::bail!(
+                        "Checkpoint {} is for {} in format {:?}{}; resume with the same --output, --format, --records-per-file, and --max-file-bytes",
+                        path.display(),
+                        checkpoint.output.display(),
+                        checkpoint.format,
+                        if limits.is_empty() {
+                            String::new()
+                        } else {
+                            format!(" in files of at most {}", limits.join(" or "))
+                        }
+                    );
+                }
+                match checkpoint.after_id {
+                    Some(id) => info!(
+                        "Resuming after record {} ({} records already written to {})",
+                        id,
+                        checkpoint.records_written,
+                        output.display()
+                    ),
+                    None => info!(
+                        "Resuming from the beginning of the selection ({} records already written to {})",
+                        checkpoint.records_written,
+                        output.display()
+                    ),
+                }
+                db_config.after_id = checkpoint.after_id;
+                Some(checkpoint)
+            }
+            None => {
+                info!("No checkpoint at {}, starting from the beginning", path.display());
+                None
+            }
+        },
+        (Some(path), false) if path.exists() => anyhow::bail!(
+            "Checkpoint {} is left from an interrupted run; pass --resume to continue it, or remove it to start over",
+            path.display()
+        ),
+        _ => None,
+    };
+
+    Ok(Selection {
+        filter,
+        capabilities,
+        enrichments,
+        db_config,
+        resumed,
+    })
+}
+
+/// The --error-file and --scrub-report; an estimate or a dry run writes neither
+fn failure_reports(
+    config: &ExtractorConfig,
+    resuming: bool,
+) -> Result<(Arc<ErrorFile>, Arc<Scrubber>)> {
+    if config.error_file.is_some() && config.error_file == config.id_file {
+        anyhow::bail!(
+            "--error-file cannot also be the --id-file, which it would overwrite before it is read"
+        );
+    }
+    let error_file = match config
+        .error_file
+        .as_ref()
+        .filter(|_| config.estimate.is_none() && !config.counts_only())
+    {
+        Some(path) => Arc::new(ErrorFile::open(path, config.error_file_stubs, resuming)?),
+        None => Arc::new(ErrorFile::default()),
+    };
+    let scrub_report = config
+        .scrub_report
+        .as_deref()
+        .filter(|_| config.estimate.is_none() && !config.counts_only());
+    let scrubber = Arc::new(Scrubber::new(!config.no_scrub, scrub_report)?);
+    Ok((error_file, scrubber))
+}
+
+/// How much there is to extract
+struct Work {
+    /// The ids an --id-file lists, or those --fill-gaps-against found missing
+    listed_ids: Option<Vec<i64>>,
+    /// The count of each partition, when chunks are cut partition by partition
+    partitions: Option<Vec<Partition>>,
+    total_count: i64,
+    /// Where a --continue-from batch ends, and whether records remain after it
+    batch_last_id: Option<i64>,
+    more_remains: bool,
+}
+
+/// Count the records selected, closing a --continue-from batch where --batch-max is reached
+async fn count(
+    config: &ExtractorConfig,
+    pool: &PgPool,
+    capabilities: &Capabilities,
+    db_config: &mut DatabaseConfig,
+) -> Result<Work> {
+    // Close the batch at the id where --batch-max is reached
+    let mut batch_last_id = None;
+    let mut more_remains = false;
+    if config.continue_from.is_some() {
+        let (last_id, more) = db::batch_end(pool, db_config, config.batch_max).await?;
+        if more {
+            db_config.max_id = last_id;
+        }
+        batch_last_id = last_id;
+        more_remains = more;
+    }
+
+    // When filling gaps, the work is the set of ids missing from the previous export; with
+    // an id file, the ids it lists, fetched without counting the table
+    let listed_ids = match (&config.id_file, &config.fill_gaps_against) {
+        (Some(path), _) => {
+            let ids = idfile::read_id_file(path)?;
+            info!("Id file {} lists {} records", path.display(), ids.len());
+            Some(ids)
+        }
+        (None, Some(path)) => {
+            info!(
+                "Scanning previous export {} for record ids...",
+                path.display()
+            );
+            let scan_path = path.clone();
+            let present = tokio::task::spawn_blocking(move || gaps::read_present_ids(&scan_path))
+                .await
+                .context("Previous export scan panicked")??;
+            info!("Previous export contains {} records", present.len());
+
+            let missing = db::missing_ids(pool, db_config, &present).await?;
+            info!("Records missing from previous export: {}", missing.len());
+            Some(missing)
+        }
+        (None, None) => None,
+    };
+
+    // Chunks of a table range partitioned by id are cut partition by partition, which
+    // needs the count of each partition instead of the total
+    let partitions = if listed_ids.is_none()
+        && !config.stream
+        && capabilities.is_enabled(Feature::PartitionPlan)
+    {
+        match db::partitioning(pool, db_config.table()).await? {
+            Partitioning::None => None,
+            Partitioning::RangeById if config.order_by == OrderBy::Id => {
+                Some(db::partition_counts(pool, db_config).await?)
+            }
+            Partitioning::RangeById => {
+                info!(
+                    "{} is partitioned by id, but --order-by {} runs across partitions; planning chunks over the whole table",
+                    db_config.table(),
+                    config.order_by.column()
+                );
+                None
+            }
+            Partitioning::Other(keys) => {
+                info!(
+                    "{} is partitioned by {}; only range partitions on id are planned around, so chunks cover the whole table",
+                    db_config.table(),
+                    keys.join(", then ")
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // Get total record count
+    let total_count = match (&listed_ids, &partitions) {
+        (Some(ids), _) => ids.len() as i64,
+        (None, Some(partitions)) => partitions.iter().map(|p| p.records).sum(),
+        (None, None) => db::get_record_count(pool, db_config)
+            .await
+            .context("Failed to get record count")?,
+    };
+
+    info!("Total records to process: {}", total_count);
+
+    Ok(Work {
+        listed_ids,
+        partitions,
+        total_count,
+        batch_last_id,
+        more_remains,
+    })
+}
+
+/// Finish a run that extracts nothing: --histogram-only, --dry-run, or one with nothing
+/// selected; `None` when the extraction goes ahead
+async fn without_extracting(
+    config: &ExtractorConfig,
+    pool: &PgPool,
+    selection: &Selection,
+    work: &Work,
+    output_options: &OutputOptions,
+) -> Result<Option<Outcome>> {
+    let Selection {
+        capabilities,
+        db_config,
+        resumed,
+        ..
+    } = selection;
+    let Work {
+        listed_ids,
+        partitions,
+        ..
+    } = work;
+    let total_count = work.total_count;
+
+    // The counts are the whole output, so a role that cannot read the date fails the run
+    if let Some(spec) = config.histogram.filter(|_| config.histogram_only) {
+        if !capabilities.is_enabled(Feature::Histogram(spec.field)) {
+            anyhow::bail!(
+                "--histogram-only cannot count without reading {}",
+                spec.field.column()
+            );
+        }
+        info!("Counting records by {}...", spec);
+        let histogram = db::date_histogram(pool, db_config, spec).await?;
+        if let Some(path) = &config.histogram_json {
+            histogram.save_json(path)?;
+        }
+        for line in histogram.table() {
+            println!("{}", line);
+        }
+        return Ok(Some(Outcome::Complete));
+    }
+
+    if config.dry_run {
+        let reading = match (listed_ids, partitions) {
+            _ if config.stream => dryrun::Reading::Stream,
+            (Some(ids), _) => dryrun::Reading::Listed(ids),
+            (None, Some(partitions)) => dryrun::Reading::Partitioned(partitions),
+            (None, None) => dryrun::Reading::Chunked,
+        };
+        let records = config
+            .limit
+            .map(|l| l.min(total_count))
+            .unwrap_or(total_count);
+        dryrun::report(
+            pool,
+            db_config,
+            reading,
+            records,
+            config.workers.max(),
+            output_options.clone(),
+        )
+        .await?;
+        return Ok(Some(Outcome::Complete));
+    }
+
+    if total_count == 0 {
+        // Nothing was left when the run was cut short, but its output was never closed
+        if let (Some(checkpoint), Some(path)) = (resumed, &config.checkpoint) {
+            sink::resume(
+                checkpoint.output.clone(),
+                output_options.clone(),
+                checkpoint.point,
+            )
+            .await?
+            .finalize()
+            .await?;
+            if checkpoint.atomic {
+                for file in output_files(
+                    &checkpoint.output,
+                    checkpoint.point.file.map(|last| 1..=last),
+                ) {
+                    writer::publish(&file, config.durable)?;
+                }
+            }
+            if let (Some(quarantine), Some(offset)) =
+                (&config.quarantine_file, checkpoint.quarantine_offset)
+            {
+                Quarantine::resume(quarantine.clone(), offset, config.durable)
+                    .await?
+                    .finalize()
+                    .await?;
+            }
+            std::fs::remove_file(path)
+                .context(format!("Failed to remove checkpoint: {}", path.display()))?;
+            info!(
+                "Nothing was left to extract; closed {} and removed the checkpoint",
+                checkpoint.output.display()
+            );
+            return Ok(Some(Outcome::Complete));
+        }
+        warn!("No records found to extract");
+        return Ok(Some(Outcome::Complete));
+    }
+    Ok(None)
+}
+
+/// How the extraction is carried out
+struct RunPlan {
+    /// --histogram's counts, taken before the extraction
+    histogram: Option<(HistogramSpec, Histogram)>,
+    /// The records the run extracts, after --limit
+    records: i64,
+    channel_capacity: usize,
+    /// The chunks the records are fetched in, unless they are streamed
+    chunks: Option<Arc<ChunkPlan>>,
+    /// Which records the writer has dealt with, for --checkpoint
+    progress: Option<Arc<Progress>>,
+    estimator: Option<Arc<Estimator>>,
+    /// The records the writer is expected to see, for progress
+    expected_records: i64,
+}
+
+/// Plan the chunks, checking that the run fits in memory, and set up what keeps track
+/// of them; a run writing to a file holds each record's encoding as well
+async fn plan_run(
+    config: &ExtractorConfig,
+    pool: &PgPool,
+    selection: &Selection,
+    work: &mut Work,
+    file_output: bool,
+    counters: &Counters,
+) -> Result<RunPlan> {
+    let Selection {
+        capabilities,
+        db_config,
+        resumed,
+        ..
+    } = selection;
+    let total_count = work.total_count;
+
+    // Counting per bucket is a single grouped query, cheap next to the extraction
+    let histogram = match config
+        .histogram
+        .filter(|spec| capabilities.is_enabled(Feature::Histogram(spec.field)))
+    {
+        Some(spec) => {
+            info!("Counting records by {}...", spec);
+            let histogram = db::date_histogram(pool, db_config, spec).await?;
+            if let Some(path) = &config.histogram_json {
+                histogram.save_json(path)?;
+            }
+            Some((spec, histogram))
+        }
+        None => None,
+    };
+
+    // Apply limit if specified
+    let records_to_process = config
+        .limit
+        .map(|l| l.min(total_count))
+        .unwrap_or(total_count);
+
+    let channel_capacity = match (config.channel_capacity, config.low_impact) {
+        (Some(capacity), _) => capacity as usize,
+        (None, true) => LOW_IMPACT_CHANNEL_CAPACITY,
+        (None, false) => CHANNEL_CAPACITY,
+    };
+    // A spooler adds a second channel of the same capacity
+    let queued = channel_capacity * if config.spill_dir.is_some() { 2 } else { 1 };
+    // A record waiting to go to a file also holds its encoding, about as big again
+    let encodings = if file_output { 2 } else { 1 };
+
+    // Streaming runs are a single task whatever the chunk size
+    if !config.stream {
+        guards::check_chunk_count(records_to_process, config.chunk_size, config.force)?;
+        let average = guards::average_record_size(pool, db_config.table()).await?;
+        if let Some(average) = average {
+            guards::check_chunk_memory(
+                average,
+                config.chunk_size.min(records_to_process),
+                config.workers.max(),
+            );
+            guards::check_channel_memory(average * encodings, queued);
+        }
+        guards::check_order_index(pool, db_config).await?;
+    } else if config.channel_capacity.is_some() {
+        if let Some(average) = guards::average_record_size(pool, db_config.table()).await? {
+            guards::check_channel_memory(average * encodings, queued);
+        }
+    }
+
+    let plan = if config.stream {
+        None
+    } else {
+        let partitions = work.partitions.take();
+        let aligned = partitions.is_some();
+        let plan = ChunkPlan::build(pool, db_config, partitions, records_to_process).await?;
+        if aligned {
+            info!(
+                "{} is range partitioned by id; {} chunks are aligned to {} partitions",
+                db_config.table(),
+                plan.len(),
+                plan.partitions_used()
+            );
+        }
+        Some(Arc::new(plan))
+    };
+
+    // The manifest gives each file's ids as ranges of the chunks, which only hold while
+    // those come in id order; a resumed run's files also hold records it did not write
+    if config.order_by == OrderBy::Id && resumed.is_none() && work.listed_ids.is_none() {
+        let last_ids = plan.as_ref().map_or_else(Vec::new, |plan| {
+            plan.chunks
+                .iter()
+                .map(|chunk| chunk.range.last.id)
+                .collect()
+        });
+        counters.ranges.planned(last_ids, false);
+    }
+
+    // Which records the writer has dealt with, reduced to what a checkpoint can vouch for
+    let progress = config.checkpoint.as_ref().map(|_| {
+        Arc::new(match &plan {
+            Some(plan) => Progress::chunked(plan, db_config.after_id),
+            None => Progress::streamed(db_config.after_id),
+        })
+    });
+    // Records the run being resumed wrote ahead of its checkpoint's id are counted in the
+    // selection, but not written again
+    let already_written = resumed
+        .as_ref()
+        .map_or(0, |checkpoint| checkpoint.handled.len() as i64);
+
+    let estimator = config.estimate.map(|budget| {
+        info!(
+            "Estimating from a {}s sample; nothing will be written",
+            budget.as_secs()
+        );
+        let chunk_records = plan
+            .iter()
+            .flat_map(|plan| plan.chunks.iter().map(|chunk| chunk.records() as u64))
+            .collect();
+        Arc::new(Estimator::new(budget, chunk_records))
+    });
+
+    // --only-chunk runs one chunk of the plan the full run would use
+    let expected_records = match (config.only_chunk, &plan) {
+        (Some(chunk), Some(plan)) => {
+            let Some(planned) = plan.chunks.get(chunk as usize) else {
+                anyhow::bail!(
+                    "--only-chunk {} is out of range: this run has {} chunks (0-{})",
+                    chunk,
+                    plan.len(),
+                    plan.len() - 1
+                );
+            };
+            planned.records()
+        }
+        _ => records_to_process - already_written,
+    };
+
+    Ok(RunPlan {
+        histogram,
+        records: records_to_process,
+        channel_capacity,
+        chunks: plan,
+        progress,
+        estimator,
+        expected_records,
+    })
+}
+
+/// Put a spooler between the fetchers and the writer, for --spill-dir
+fn spool(
+    config: &ExtractorConfig,
+    rx: mpsc::Receiver<EncodedRecord>,
+    channel_capacity: usize,
+    watchdog: &Arc<Watchdog>,
+    resumed: Option<&Checkpoint>,
+) -> (
+    mpsc::Receiver<EncodedRecord>,
+    Option<JoinHandle<Result<SpillStats>>>,
+) {
+    match &config.spill_dir {
+        Some(dir) => {
+            let spill_config = SpillConfig {
+                dir: dir.clone(),
+                max_bytes: config.spill_max,
+                arrival: if !(config.ordered || config.stream) {
+                    Arrival::Any
+                } else if config.order_by == OrderBy::Id {
+                    Arrival::ById
+                } else {
+                    Arrival::Other
+                },
+                after_id: resumed.and_then(|checkpoint| checkpoint.after_id),
+            };
+            let (spill_tx, spill_rx) = mpsc::channel::<EncodedRecord>(channel_capacity);
+            let watchdog = Arc::clone(watchdog);
+            (
+                spill_rx,
+                Some(tokio::spawn(spill::run(
+                    spill_config,
+                    rx,
+                    spill_tx,
+                    watchdog,
+                ))),
+            )
+        }
+        None => (rx, None),
+    }
+}
+
+/// The --checkpoint the writer keeps, carrying on the resumed run's
+fn checkpointer(
+    config: &ExtractorConfig,
+    selection: &Selection,
+    progress: Option<&Arc<Progress>>,
+) -> Option<Checkpointer> {
+    match (&config.checkpoint, progress) {
+        (Some(path), Some(progress)) => {
+            let base = match &selection.resumed {
+                Some(checkpoint) => checkpoint.clone(),
+                None => Checkpoint {
+                    columns: config.fields.as_ref().map(FieldSpec::text),
+                    ..Checkpoint::start(
+                        config.output.clone().expect("checked with --checkpoint"),
+                        config.format,
+                        config.records_per_file,
+                        config.max_file_bytes,
+                        !config.no_atomic,
+                        config.record_type,
+                        selection.filter.clone(),
+                    )
+                },
+            };
+            Some(Checkpointer::new(path.clone(), Arc::clone(progress), base))
+        }
+        _ => None,
+    }
+}
+
+/// What the writer task opens when it starts, and what it reports to
+struct WriterTask {
+    output: Option<PathBuf>,
+    options: OutputOptions,
+    postgres: Option<PostgresSinkConfig>,
+    /// The application's sink, in place of the outputs the options name
+    custom: Option<Box<dyn DynSink>>,
+    resumed: Option<Checkpoint>,
+    also_output: Vec<AlsoOutput>,
+    fail_fast: bool,
+    quarantine_file: Option<PathBuf>,
+    display_file: Option<PathBuf>,
+    durable: bool,
+    checkpointer: Option<Checkpointer>,
+    provenance_tx: Option<mpsc::Sender<Entry>>,
+    estimator: Option<Arc<Estimator>>,
+    tracer: Arc<Tracer>,
+    review: Arc<Review>,
+    error_file: Arc<ErrorFile>,
+    events: Arc<ProgressEvents>,
+    on_progress: Reporter,
+    written: Option<ByteCounter>,
+    /// The records the writer is expected to see, for progress
+    total: u64,
+    counters: Counters,
+}
+
+impl WriterTask {
+    /// Open the outputs on a task of their own, and write what `rx` brings to them
+    fn spawn(self, rx: mpsc::Receiver<EncodedRecord>) -> JoinHandle<Result<WriterReport>> {
+        tokio::spawn(async move { self.open().await?.run(rx).await })
+    }
+
+    async fn open(self) -> Result<WriterState> {
+        let WriterTask {
+            output,
+            options,
+            postgres,
+            custom,
+            resumed,
+            also_output,
+            fail_fast,
+            quarantine_file,
+            display_file,
+            durable,
+            checkpointer,
+            provenance_tx,
+            estimator,
+            tracer,
+            review,
+            error_file,
+            events,
+            on_progress,
+            written,
+            total,
+            counters,
+        } = self;
+        let quarantine_target = provenance_tx
+            .as_ref()
+            .and(quarantine_file.as_ref())
+            .map(|path| path.display().to_string());
+        let fanout = Fanout::open(&also_output, &options, fail_fast).await?;
+        let writer = match (&estimator, &resumed) {
+            (Some(_), _) => sink::discard(&options).await?,
+            (None, Some(checkpoint)) => {
+                sink::resume(checkpoint.output.clone(), options, checkpoint.point).await?
+            }
+            (None, None) => match custom {
+                Some(custom) => sink::RecordSink::Custom(custom),
+                None => sink::open(output, options, postgres).await?,
+            },
+        };
+        let quarantine_offset = resumed
+            .as_ref()
+            .and_then(|checkpoint| checkpoint.quarantine_offset);
+        let quarantine = match (quarantine_file, quarantine_offset) {
+            (Some(path), Some(offset)) => Some(Quarantine::resume(path, offset, durable).await?),
+            (Some(path), None) => Some(Quarantine::new(path, durable).await?),
+            (None, _) => None,
+        };
+        let display = match display_file {
+            Some(path) => Some(DisplayCsv::new(&path, durable).await?),
+            None => None,
+        };
+        let files = writer.files();
+        counters
+            .files
+            .store(files.unwrap_or_default(), Ordering::Relaxed);
+        Ok(WriterState {
+            writer,
+            fanout,
+            quarantine,
+            display,
+            checkpointer,
+            files,
+            quarantine_target,
+            provenance_tx,
+            estimator,
+            tracer,
+            review,
+            error_file,
+            events,
+            on_progress,
+            written,
+            total,
+            counters,
+        })
+    }
+}
+
+/// The writer task's outputs, and what it keeps track of as it writes to them
+struct WriterState {
+    writer: sink::RecordSink,
+    fanout: Fanout,
+    quarantine: Option<Quarantine>,
+    display: Option<DisplayCsv>,
+    checkpointer: Option<Checkpointer>,
+    /// The file a split output has reached, to report each new one
+    files: Option<u64>,
+    /// Where rejected records went, for --provenance-db
+    quarantine_target: Option<String>,
+    provenance_tx: Option<mpsc::Sender<Entry>>,
+    estimator: Option<Arc<Estimator>>,
+    tracer: Arc<Tracer>,
+    review: Arc<Review>,
+    error_file: Arc<ErrorFile>,
+    events: Arc<ProgressEvents>,
+    on_progress: Reporter,
+    written: Option<ByteCounter>,
+    total: u64,
+    counters: Counters,
+}
+
+/// What the writer task reports once its outputs are closed
+struct WriterReport {
+    quarantined: u64,
+    display_rows: u64,
+    /// The last file a split output was written to
+    files: Option<u64>,
+    /// Bytes --output took, before any compression
+    bytes: Option<u64>,
+    /// How each --also-output went
+    outputs: Vec<fanout::Report>,
+}
+
+impl WriterState {
+    /// Write the records as they come until the workers are done, then close the outputs
+    async fn run(mut self, mut rx: mpsc::Receiver<EncodedRecord>) -> Result<WriterReport> {
+        // Saved before any record is written, so that a run the disk fills up on
+        // early still has a checkpoint to resume from once space is freed
+        self.checkpoint().await?;
+
+        // Records are taken off the channel as many at a time as are waiting, and written
+        // out together every WRITE_BATCH records, or sooner once the buffer fills
+        let mut batch = Vec::with_capacity(WRITE_BATCH);
+        let mut unflushed = 0;
+        let mut reported = 0;
+        while rx.recv_many(&mut batch, WRITE_BATCH).await > 0 {
+            unflushed += batch.len();
+            for record in batch.drain(..) {
+                self.handle(record).await?;
+            }
+
+            if unflushed >= WRITE_BATCH {
+                self.writer.flush().await?;
+                unflushed = 0;
+            }
+            let count = self.counters.processed.load(Ordering::Relaxed);
+            if count != reported {
+                reported = count;
+                self.on_progress.send(ExtractionProgress::Written {
+                    records: count,
+                    bytes: self
+                        .written
+                        .as_ref()
+                        .map(|counter| counter.load(Ordering::Relaxed)),
+                });
+                self.events.progress(count, self.total);
+            }
+        }
+
+        // The footer comes after the last checkpoint, so a resumed run finds none to cut
+        self.checkpoint().await?;
+        let bytes = self.writer.offset();
+        self.writer.finalize().await?;
+        let outputs = self.fanout.finish().await?;
+
+        let display_rows = match self.display {
+            Some(display) => {
+                let rows = display.rows();
+                display.finalize().await?;
+                rows
+            }
+            None => 0,
+        };
+        let quarantined = match self.quarantine {
+            Some(quarantine) => {
+                let count = quarantine.count();
+                quarantine.finalize().await?;
+                count
+            }
+            None => 0,
+        };
+        Ok(WriterReport {
+            quarantined,
+            display_rows,
+            files: self.files,
+            bytes,
+            outputs,
+        })
+    }
+
+    /// Write a record, seeing to it whatever came of the write
+    async fn handle(&mut self, record: EncodedRecord) -> Result<()> {
+        let EncodedRecord {
+            record,
+            mut encoded,
+        } = record;
+        if let Some(checkpointer) = &mut self.checkpointer {
+            if checkpointer.already_handled(record.id) {
+                self.counters.ranges.missed(record.id);
+                return Ok(());
+            }
+        }
+        self.writer.prepare(&record, &mut encoded).await?;
+        let position = self
+            .tracer
+            .is_traced(record.id)
+            .then(|| self.writer.position());
+        let offset = self.writer.offset();
+        // Only needed, and only built, for --provenance-db
+        let target = self.provenance_tx.as_ref().map(|_| self.writer.target());
+
+        let outcome = self.writer.write_record(&record, encoded).await;
+        let entry = self
+            .dispose(&record, outcome, position, target, offset)
+            .await?;
+
+        if self.writer.files() != self.files {
+            self.files = self.writer.files();
+            if let Some(count) = self.files {
+                self.counters.files.store(count, Ordering::Relaxed);
+                self.on_progress.send(ExtractionProgress::File(count));
+            }
+        }
+
+        if let Some(tx) = &self.provenance_tx {
+            let entry = Entry {
+                marc: Some(record.marc),
+                ..entry
+            };
+            tx.send(entry)
+                .await
+                .map_err(|_| anyhow!("Provenance recorder stopped"))?;
+        }
+
+        if let Some(checkpointer) = &mut self.checkpointer {
+            checkpointer.handled(record.id);
+            if checkpointer.due() {
+                save_checkpoint(
+                    checkpointer,
+                    &mut self.writer,
+                    &mut self.quarantine,
+                    self.counters.processed.load(Ordering::Relaxed),
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Count a record by how its write came out, quarantining or reporting one that was
+    /// not written; the entry says where it went, for --provenance-db
+    async fn dispose(
+        &mut self,
+        record: &MarcRecord,
+        outcome: Result<WriteOutcome>,
+        position: Option<String>,
+        target: Option<String>,
+        offset: Option<u64>,
+    ) -> Result<Entry> {
+        let counters = &self.counters;
+        let (disposition, reason, (output_file, byte_offset)) = match outcome {
+            Ok(WriteOutcome::Written) => {
+                self.tracer
+                    .note(record.id, "written", || position.unwrap_or_default());
+                if let (Some(estimator), Some(offset)) = (&self.estimator, offset) {
+                    estimator.written(record.id, self.writer.offset().unwrap_or(offset) - offset);
+                }
+                if let Some(display) = &mut self.display {
+                    display.write(record).await?;
+                }
+                counters.emitted.note(record.id);
+                counters
+                    .ranges
+                    .written(record.id, self.writer.files().unwrap_or(1));
+                counters.processed.fetch_add(1, Ordering::Relaxed);
+                // Only what --output took, so every output holds the same records
+                self.fanout.send(record).await?;
+                ("written", None, (target, offset))
+            }
+            Ok(WriteOutcome::Rejected(rejection)) => {
+                self.tracer
+                    .note(record.id, "rejected", || rejection.to_string());
+                counters.ranges.missed(record.id);
+                self.review.capture(record, &rejection);
+                self.error_file.rejected(record.id, &rejection);
+                if let Some(estimator) = &self.estimator {
+                    estimator.failed(record.id);
+                }
+                counters.rejected.fetch_add(1, Ordering::Relaxed);
+                match &mut self.quarantine {
+                    Some(quarantine) => {
+                        let message =
+                            format!("Quarantined record ID {} ({})", record.id, rejection);
+                        warn!(
+                            record_id = record.id,
+                            error_kind = Category::of(&rejection).name(),
+                            "{}",
+                            message
+                        );
+                        self.events.warning(message);
+                        quarantine.write(record, &rejection).await?;
+                        let location = (self.quarantine_target.clone(), None);
+                        ("quarantined", Some(rejection.to_string()), location)
+                    }
+                    None => {
+                        let message = format!("Skipped record ID {} ({})", record.id, rejection);
+                        error!(
+                            record_id = record.id,
+                            error_kind = Category::of(&rejection).name(),
+                            "{}",
+                            message
+                        );
+                        self.events.warning(message);
+                        counters.errors.fetch_add(1, Ordering::Relaxed);
+                        let subject = Some(Subject::Record(record.id));
+                        counters.digest.record(
+                            Category::of(&rejection),
+                            subject,
+                            &rejection.to_string(),
+                        );
+                        ("skipped", Some(rejection.to_string()), (None, None))
+                    }
+                }
+            }
+            Err(e) if sink::is_fatal(&e) => return Err(e),
+            Err(e) => {
+                self.tracer
+                    .note(record.id, "write failed", || format!("{:#}", e));
+                counters.ranges.missed(record.id);
+                self.review
+                    .capture(record, &Rejection::new("write", format!("{:#}", e)));
+                self.error_file
+                    .record(record.id, "write", &format!("{:#}", e));
+                if let Some(estimator) = &self.estimator {
+                    estimator.failed(record.id);
+                }
+                let message = format!("Failed to write record ID {}: {}", record.id, e);
+                error!(
+                    record_id = record.id,
+                    error_kind = Category::WriteFailure.name(),
+                    "{}",
+                    message
+                );
+                self.events.warning(message);
+                let subject = Some(Subject::Record(record.id));
+                counters
+                    .digest
+                    .record(Category::WriteFailure, subject, &format!("{:#}", e));
+                ("failed", Some(format!("{:#}", e)), (None, None))
+            }
+        };
+        Ok(Entry {
+            record_id: record.id,
+            disposition,
+            reason,
+            marc: None,
+            output_file,
+            byte_offset,
+        })
+    }
+
+    /// Save a --checkpoint, when the run keeps one
+    async fn checkpoint(&mut self) -> Result<()> {
+        if let Some(checkpointer) = &mut self.checkpointer {
+            save_checkpoint(
+                checkpointer,
+                &mut self.writer,
+                &mut self.quarantine,
+                self.counters.processed.load(Ordering::Relaxed),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+/// The checks and changes the workers put each record through, each keeping what it did
+/// for the summary
+#[derive(Clone)]
+struct Stages {
+    stubs: Arc<StubCheck>,
+    multi_record: Arc<MultiRecordCheck>,
+    transforms: Arc<Transforms>,
+    profile: Arc<ProfileBuilder>,
+    limits: Arc<LimitCheck>,
+    validator: Arc<Validator>,
+    schema: Arc<SchemaCheck>,
+}
+
+impl Stages {
+    /// Set up the stages the options ask for; what they leave out goes to `review` and
+    /// `error_file`
+    async fn build(
+        config: &ExtractorConfig,
+        pool: &PgPool,
+        target_limits: Option<TargetLimits>,
+        tracer: &Arc<Tracer>,
+        review: &Arc<Review>,
+        error_file: &Arc<ErrorFile>,
+        counters: &Counters,
+    ) -> Result<Self> {
+        let stubs = Arc::new(StubCheck {
+            policy: config.on_stub,
+            quarantine: config.quarantine_file.is_some(),
+            ids_only: config.ids_only,
+            oai: config.envelope == Envelope::Oai,
+            ids: Mutex::new(Vec::new()),
+            tracer: Arc::clone(tracer),
+            review: Arc::clone(review),
+            error_file: Arc::clone(error_file),
+        });
+        let multi_record = Arc::new(MultiRecordCheck {
+            policy: config.multi_record_rows,
+            quarantine: config.quarantine_file.is_some(),
+            ids_only: config.ids_only,
+            ids: Mutex::new(Vec::new()),
+            tracer: Arc::clone(tracer),
+            review: Arc::clone(review),
+            error_file: Arc::clone(error_file),
+        });
+        let limits = Arc::new(LimitCheck::new(
+            target_limits,
+            config.on_limit_violation,
+            config.quarantine_file.is_some(),
+            Arc::clone(tracer),
+            Arc::clone(review),
+            Arc::clone(error_file),
+        ));
+        let validation = if config.validate_strict {
+            Some(Validation::Strict)
+        } else if config.validate {
+            Some(Validation::Skip)
+        } else {
+            None
+        };
+        let validator = Arc::new(Validator::new(
+            validation,
+            config.quarantine_file.is_some(),
+            !config.no_scrub,
+            Arc::clone(&counters.invalid),
+            Arc::clone(tracer),
+            Arc::clone(review),
+            Arc::clone(error_file),
+        ));
+        let schema_validation = match (config.schema_strict, config.schema_validate) {
+            (true, _) => Some(Validation::Strict),
+            (false, true) => Some(Validation::Skip),
+            (false, false) => None,
+        };
+        let schema = Arc::new(SchemaCheck::new(
+            schema_validation,
+            config.quarantine_file.is_some(),
+            Arc::clone(tracer),
+            Arc::clone(review),
+            Arc::clone(error_file),
+        ));
+        let sources = if config.add_901 {
+            Some(db::bib_sources(pool).await?.into_iter().collect())
+        } else {
+            None
+        };
+        let selection = match (
+            config.strip_fields.is_empty(),
+            config.keep_only_fields.is_empty(),
+        ) {
+            (false, _) => Some(FieldSelection {
+                patterns: config.strip_fields.clone(),
+                keep: false,
+            }),
+            (_, false) => Some(FieldSelection {
+                patterns: config.keep_only_fields.clone(),
+                keep: true,
+            }),
+            (true, true) => None,
+        };
+        if let Some(selection) = &selection {
+            let patterns: Vec<&str> = selection.patterns.iter().map(TagPattern::as_str).collect();
+            info!(
+                "Fields selected with {}: {}",
+                selection.option(),
+                patterns.join(",")
+            );
+        }
+        let transforms = Arc::new(Transforms::new(
+            selection,
+            config.sort_fields,
+            config.add_fingerprint_field.clone(),
+            sources,
+            Arc::clone(tracer),
+        ));
+        let profile = Arc::new(ProfileBuilder::new(
+            config.profile,
+            config.holdings_action,
+            config.quarantine_file.is_some(),
+            Arc::clone(tracer),
+            Arc::clone(review),
+            Arc::clone(error_file),
+        ));
+        Ok(Stages {
+            stubs,
+            multi_record,
+            transforms,
+            profile,
+            limits,
+            validator,
+            schema,
+        })
+    }
+
+    /// The records the stages dropped without quarantining them, with why
+    fn dropped(&self, config: &ExtractorConfig) -> Vec<(i64, String)> {
+        let Stages {
+            stubs,
+            multi_record,
+            profile,
+            limits,
+            validator,
+            schema,
+            ..
+        } = self;
+
+        let mut dropped = Vec::new();
+        if config.on_stub == OnStub::Skip && config.quarantine_file.is_none() {
+            let ids = stubs.ids.lock().unwrap();
+            dropped.extend(ids.iter().map(|&id| (id, "stub".to_string())));
+        }
+        if config.multi_record_rows == MultiRecordRows::Skip && config.quarantine_file.is_none() {
+            let ids = multi_record.ids.lock().unwrap();
+            dropped.extend(
+                ids.iter()
+                    .map(|&id| (id, "row holds several records".to_string())),
+            );
+        }
+        if let Some(target) = limits.target() {
+            if config.on_limit_violation == OnLimitViolation::Skip
+                && config.quarantine_file.is_none()
+            {
+                let ids = limits.ids.lock().unwrap();
+                dropped.extend(
+                    ids.iter()
+                        .map(|&id| (id, format!("exceeds {} limits", target))),
+                );
+            }
+        }
+        if validator.validation() == Some(Validation::Skip) && config.quarantine_file.is_none() {
+            let ids = validator.ids.lock().unwrap();
+            dropped.extend(
+                ids.iter()
+                    .map(|&id| (id, "not well-formed XML".to_string())),
+            );
+        }
+        if schema.validation() == Some(Validation::Skip) && config.quarantine_file.is_none() {
+            let ids = schema.ids.lock().unwrap();
+            dropped.extend(ids.iter().map(|&id| (id, "breaks MARC21slim".to_string())));
+        }
+        if config.quarantine_file.is_none() {
+            let ids = profile.ids.lock().unwrap();
+            dropped.extend(
+                ids.iter()
+                    .map(|&id| (id, "no OCLC number in 035 or 001".to_string())),
+            );
+        }
+        dropped
+    }
+}
+
+/// Stream the whole selection over a single connection, for --stream
+fn spawn_stream(
+    context: WorkerContext,
+    records_to_process: i64,
+    mode: ReadMode,
+    progress: Option<Arc<Progress>>,
+) -> JoinHandle<Result<()>> {
+    info!(
+        "Streaming {} records over a single connection",
+        records_to_process
+    );
+
+    let WorkerContext {
+        pool,
+        tx,
+        db_config,
+        errors,
+        digest,
+        throttle,
+        stages:
+            Stages {
+                stubs,
+                multi_record,
+                transforms,
+                profile,
+                limits,
+                validator,
+                schema,
+            },
+        cleaner,
+        watchdog,
+        error_file,
+        ..
+    } = context;
+    tokio::spawn(async move {
+        let stream = if mode == ReadMode::Copy {
+            db::copy_records(&pool, &db_config, records_to_process)
+                .await?
+                .left_stream()
+        } else {
+            db::stream_records(&pool, &db_config)?.right_stream()
+        };
+        futures::pin_mut!(stream);
+
+        let mut sent: i64 = 0;
+        let mut last_id = db_config.after_id;
+        let mut exhausted = false;
+        // Records of the last row still to go through, more than one when it held several
+        let mut split = VecDeque::new();
+        while sent < records_to_process {
+            if watchdog.stopped() {
+                warn!(offset = sent, "Stopping the stream after {} records", sent);
+                break;
+            }
+            let Some(mut record) = split.pop_front() else {
+                let record = match stream.next().await {
+                    Some(Ok(record)) => record,
+                    Some(Err(e)) => {
+                        error!(
+                            offset = sent,
+                            record_id = last_id,
+                            error_kind = Category::FetchFailure.name(),
+                            "Failed to stream records after {} rows: {}",
+                            sent,
+                            e
+                        );
+                        errors.fetch_add(1, Ordering::Relaxed);
+                        digest.record(Category::FetchFailure, None, &e.to_string());
+                        error_file.stream(last_id, &format!("{:#}", e));
+                        break;
+                    }
+                    None => {
+                        exhausted = true;
+                        break;
+                    }
+                };
+                last_id = Some(record.id);
+                if let Some(record) = stubs.screen(record)? {
+                    split.extend(multi_record.split(record));
+                }
+                continue;
+            };
+            transforms.apply(&mut record);
+            let Some(record) = profile.screen(record) else {
+                continue;
+            };
+            let Some(record) = limits.screen(record)? else {
+                continue;
+            };
+            let Some(record) = validator.screen(record)? else {
+                continue;
+            };
+            let Some(record) = schema.screen(record)? else {
+                continue;
+            };
+            let record = cleaner.prepare(record);
+
+            if watchdog.send(&tx, record).await.is_err() {
+                error!(offset = sent, "Channel closed, stopping stream");
+                break;
+            }
+            sent += 1;
+
+            // Pace the stream one chunk's worth of rows at a time
+            if let Some(throttle) = &throttle {
+                if sent % db_config.chunk_size == 0 {
+                    throttle.wait().await;
+                }
+            }
+        }
+        if let (Some(progress), true) = (&progress, exhausted || sent >= records_to_process) {
+            progress.stream_ended();
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })
+}
+
+/// Fetch the listed ids in batches of --chunk-size, for --id-file and --fill-gaps-against
+fn spawn_batches(
+    context: WorkerContext,
+    config: &ExtractorConfig,
+    mut ids: Vec<i64>,
+    records_to_process: i64,
+    not_found: &Arc<Mutex<Vec<i64>>>,
+    ranges: &FileRanges,
+) -> Vec<JoinHandle<Result<()>>> {
+    ids.truncate(records_to_process as usize);
+    let batches: Vec<Vec<i64>> = ids
+        .chunks(config.chunk_size as usize)
+        .map(|batch| batch.to_vec())
+        .collect();
+    ranges.planned(
+        batches.iter().map(|batch| batch[batch.len() - 1]).collect(),
+        true,
+    );
+
+    let listed = if config.id_file.is_some() {
+        "listed"
+    } else {
+        "missing"
+    };
+    info!(
+        "Fetching {} {} records in {} batches",
+        ids.len(),
+        listed,
+        batches.len()
+    );
+    debug!("Batch plan:");
+    for (batch_id, batch) in batches.iter().enumerate() {
+        debug!(
+            "  batch {}: {} ids, {}..={}",
+            batch_id,
+            batch.len(),
+            batch[0],
+            batch[batch.len() - 1]
+        );
+    }
+
+    let batches: Vec<(usize, Vec<i64>)> = batches
+        .into_iter()
+        .enumerate()
+        .filter(|(batch_id, _)| {
+            config
+                .only_chunk
+                .is_none_or(|only| only == *batch_id as i64)
+        })
+        .collect();
+    let workers = config.workers.max() as usize;
+    info!(
+        "Dispatching {} batches with {} concurrent workers",
+        batches.len(),
+        workers.min(batches.len())
+    );
+
+    let not_found = Arc::clone(not_found);
+    let work = move |(batch_id, batch): (usize, Vec<i64>)| {
+        let WorkerContext {
+            pool,
+            tx,
+            db_config,
+            errors,
+            digest,
+            throttle,
+            stages:
+                Stages {
+                    stubs,
+                    multi_record,
+                    transforms,
+                    profile,
+                    limits,
+                    validator,
+                    schema,
+                },
+            monitor,
+            events,
+            enrichments,
+            cleaner,
+            watchdog,
+            scaler,
+            reorder,
+            error_file,
+            display_fields,
+        } = context.clone();
+        let not_found = Arc::clone(&not_found);
+
+        let worker = async move {
+            if let Some(reorder) = &reorder {
+                reorder.admit(batch_id).await;
+            }
+            if let Some(throttle) = &throttle {
+                throttle.wait().await;
+            }
+
+            // Held until the batch has handed on its records
+            let _slot = match &scaler {
+                Some(scaler) => Some(scaler.admit().await),
+                None => None,
+            };
+            // Admitted once it holds a connection, so chunks queued for the pool
+            // are not counted as running
+            let _admission;
+            let acquire_started = Instant::now();
+            let fetched = match monitor.acquire(&pool).await {
+                Ok(mut conn) => {
+                    let waited = acquire_started.elapsed();
+                    _admission = match watchdog.admit(batch_id as i64).await {
+                        Some(admission) => admission,
+                        None => {
+                            if let Some(reorder) = &reorder {
+                                let _ = reorder.deliver(batch_id, Vec::new()).await;
+                            }
+                            return Ok(());
+                        }
+                    };
+                    let fetch_started = Instant::now();
+                    match db::fetch_records_by_id(&mut conn, &db_config, &batch).await {
+                        Ok(records) => {
+                            if let Some(scaler) = &scaler {
+                                scaler.record(records.len(), fetch_started.elapsed(), waited);
+                            }
+                            // Records come back in id order
+                            let found: Vec<i64> = records.iter().map(|record| record.id).collect();
+                            let missing =
+                                batch.iter().filter(|id| found.binary_search(id).is_err());
+                            not_found.lock().unwrap().extend(missing);
+                            // Screen before enriching, so an added field never hides a stub
+                            let fetched = records.len();
+                            let mut screened = Vec::with_capacity(fetched);
+                            for record in records {
+                                if let Some(record) = stubs.screen(record)? {
+                                    screened.extend(multi_record.split(record));
+                                }
+                            }
+                            async {
+                                enrichments
+                                    .apply(&mut conn, &mut screened, &stubs.tracer)
+                                    .await?;
+                                if display_fields {
+                                    display::attach(&mut conn, &mut screened).await?;
+                                }
+                                Ok(())
+                            }
+                            .await
+                            .map(|()| (fetched, screened))
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+
+            match fetched {
+                Ok((fetched, records)) => {
+                    let mut ordered = reorder.as_ref().map(|_| Vec::with_capacity(records.len()));
+                    for mut record in records {
+                        transforms.apply(&mut record);
+                        let Some(record) = profile.screen(record) else {
+                            continue;
+                        };
+                        // Measured after enrichment and the profile, which change the record
+                        let Some(record) = limits.screen(record)? else {
+                            continue;
+                        };
+                        let Some(record) = validator.screen(record)? else {
+                            continue;
+                        };
+                        let Some(record) = schema.screen(record)? else {
+                            continue;
+                        };
+                        let record = cleaner.prepare(record);
+                        if let Some(ordered) = &mut ordered {
+                            ordered.push(record);
+                            continue;
+                        }
+                        if watchdog.send(&tx, record).await.is_err() {
+                            error!(batch_id, "Channel closed, stopping batch {}", batch_id);
+                            break;
+                        }
+                    }
+                    if let (Some(reorder), Some(ordered)) = (&reorder, ordered) {
+                        if reorder.deliver(batch_id, ordered).await.is_err() {
+                            error!(batch_id, "Channel closed, stopping batch {}", batch_id);
+                        }
+                    }
+                    events.emit(Event::ChunkCompleted {
+                        chunk: batch_id as i64,
+                        records: fetched,
+                    });
+                }
+                Err(e) => {
+                    if let Some(reorder) = &reorder {
+                        let _ = reorder.deliver(batch_id, Vec::new()).await;
+                    }
+                    let message = format!("Failed to fetch batch {}: {}", batch_id, e);
+                    error!(
+                        batch_id,
+                        error_kind = Category::FetchFailure.name(),
+                        "{}",
+                        message
+                    );
+                    events.warning(message);
+                    errors.fetch_add(1, Ordering::Relaxed);
+                    let subject = Some(Subject::Batch(batch_id as i64));
+                    digest.record(Category::FetchFailure, subject, &e.to_string());
+                    for &id in &batch {
+                        error_file.record(id, "fetch", &format!("{:#}", e));
+                    }
+                }
+            }
+
+            Ok::<_, anyhow::Error>(())
+        };
+
+        // Every log line from the batch carries its id; error spans are never filtered out
+        worker
+            .map(move |result| result.context(format!("Batch {} failed", batch_id)))
+            .instrument(tracing::error_span!("batch", id = batch_id))
+    };
+    dispatch(batches, workers, work)
+}
+
+/// Fetch the planned chunks, as many at once as there are workers
+fn spawn_chunks(
+    context: WorkerContext,
+    config: &ExtractorConfig,
+    plan: Arc<ChunkPlan>,
+    records_to_process: i64,
+    estimator: Option<Arc<Estimator>>,
+    progress: Option<Arc<Progress>>,
+) -> Vec<JoinHandle<Result<()>>> {
+    let num_chunks = plan.len();
+
+    info!(
+        "Processing {} records in {} chunks",
+        records_to_process, num_chunks
+    );
+
+    let place = |chunk: &plan::Chunk| match chunk.partition {
+        Some(_) => format!(
+            "offset {} of {}, {}",
+            chunk.range.offset,
+            plan.table(chunk),
+            chunk.range
+        ),
+        None => format!("offset {}, {}", chunk.range.offset, chunk.range),
+    };
+    if tracing::enabled!(tracing::Level::DEBUG) {
+        debug!("Chunk plan:");
+        for (chunk_id, chunk) in plan.chunks.iter().enumerate() {
+            debug!("  chunk {}: {}", chunk_id, place(chunk));
+        }
+    }
+    if let Some(chunk) = config.only_chunk {
+        info!(
+            "Running only chunk {} ({})",
+            chunk,
+            place(&plan.chunks[chunk as usize])
+        );
+    }
+
+    // An estimate samples the plan across its whole range whenever it is cut short
+    let chunk_ids: Vec<i64> = match &estimator {
+        Some(_) => estimate::sample_order(num_chunks),
+        None => (0..num_chunks).collect(),
+    };
+    let chunk_ids: Vec<i64> = chunk_ids
+        .into_iter()
+        .filter(|&chunk_id| config.only_chunk.is_none_or(|only| only == chunk_id))
+        .collect();
+    let workers = config.workers.max() as usize;
+    info!(
+        "Dispatching {} chunks with {} concurrent workers",
+        chunk_ids.len(),
+        workers.min(chunk_ids.len())
+    );
+
+    let work = move |chunk_id: i64| {
+        let WorkerContext {
+            pool,
+            tx,
+            db_config,
+            errors,
+            digest,
+            throttle,
+            stages:
+                Stages {
+                    stubs,
+                    multi_record,
+                    transforms,
+                    profile,
+                    limits,
+                    validator,
+                    schema,
+                },
+            monitor,
+            events,
+            enrichments,
+            cleaner,
+            watchdog,
+            scaler,
+            reorder,
+            error_file,
+            display_fields,
+        } = context.clone();
+        let estimator = estimator.clone();
+        let plan = Arc::clone(&plan);
+        let progress = progress.clone();
+
+        let worker = async move {
+            let chunk = &plan.chunks[chunk_id as usize];
+
+            if let Some(reorder) = &reorder {
+                reorder.admit(chunk_id as usize).await;
+            }
+            if let Some(throttle) = &throttle {
+                throttle.wait().await;
+            }
+
+            // Held until the chunk has handed on its records
+            let _slot = match &scaler {
+                Some(scaler) => Some(scaler.admit().await),
+                None => None,
+            };
+            // Admitted once it holds a connection, so chunks queued for the pool
+            // are not counted as running
+            let _admission;
+            let acquire_started = Instant::now();
+            let fetched = match monitor.acquire(&pool).await {
+                Ok(mut conn) => {
+                    let waited = acquire_started.elapsed();
+                    _admission = match watchdog.admit(chunk_id).await {
+                        Some(admission) => admission,
+                        None => {
+                            if let Some(reorder) = &reorder {
+                                let _ = reorder.deliver(chunk_id as usize, Vec::new()).await;
+                            }
+                            return Ok(());
+                        }
+                    };
+                    if let Some(estimator) = &estimator {
+                        estimator.begin(chunk_id);
+                    }
+                    let fetch_started = Instant::now();
+                    match db::fetch_records(&mut conn, &db_config, plan.table(chunk), &chunk.range)
+                        .await
+                    {
+                        Ok(records) => {
+                            if let Some(scaler) = &scaler {
+                                scaler.record(records.len(), fetch_started.elapsed(), waited);
+                            }
+                            // Screen before enriching, so an added field never hides a stub
+                            let fetched = records.len();
+                            let mut screened = Vec::with_capacity(fetched);
+                            for record in records {
+                                if let Some(record) = stubs.screen(record)? {
+                                    screened.extend(multi_record.split(record));
+                                }
+                            }
+                            async {
+                                enrichments
+                                    .apply(&mut conn, &mut screened, &stubs.tracer)
+                                    .await?;
+                                if display_fields {
+                                    display::attach(&mut conn, &mut screened).await?;
+                                }
+                                Ok(())
+                            }
+                            .await
+                            .map(|()| (fetched, screened))
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+
+            match fetched {
+                Ok((fetched, records)) => {
+                    let mut sent: u64 = 0;
+                    let mut closed = false;
+                    let mut ordered = reorder.as_ref().map(|_| Vec::with_capacity(records.len()));
+                    for mut record in records {
+                        transforms.apply(&mut record);
+                        let Some(record) = profile.screen(record) else {
+                            continue;
+                        };
+                        // Measured after enrichment and the profile, which change the record
+                        let Some(record) = limits.screen(record)? else {
+                            continue;
+                        };
+                        let Some(record) = validator.screen(record)? else {
+                            continue;
+                        };
+                        let Some(record) = schema.screen(record)? else {
+                            continue;
+                        };
+                        if let Some(estimator) = &estimator {
+                            estimator.assign(record.id, chunk_id);
+                        }
+                        let record = cleaner.prepare(record);
+                        if let Some(ordered) = &mut ordered {
+                            ordered.push(record);
+                            sent += 1;
+                            continue;
+                        }
+                        if watchdog.send(&tx, record).await.is_err() {
+                            error!(chunk_id, "Channel closed, stopping chunk {}", chunk_id);
+                            closed = true;
+                            break;
+                        }
+                        sent += 1;
+                    }
+                    if let (Some(reorder), Some(ordered)) = (&reorder, ordered) {
+                        if reorder.deliver(chunk_id as usize, ordered).await.is_err() {
+                            error!(chunk_id, "Channel closed, stopping chunk {}", chunk_id);
+                            closed = true;
+                        }
+                    }
+                    if let (Some(progress), false) = (&progress, closed) {
+                        progress.chunk_sent(chunk_id as usize, sent);
+                    }
+                    if let Some(estimator) = &estimator {
+                        estimator.finish(chunk_id, false);
+                    }
+                    events.emit(Event::ChunkCompleted {
+                        chunk: chunk_id,
+                        records: fetched,
+                    });
+                    if let Some(partition) = plan.finish(chunk) {
+                        info!(
+                            "Partition {} done ({} records)",
+                            partition.table, partition.records
+                        );
+                    }
+                }
+                Err(e) => {
+                    if let Some(reorder) = &reorder {
+                        let _ = reorder.deliver(chunk_id as usize, Vec::new()).await;
+                    }
+                    if let Some(estimator) = &estimator {
+                        estimator.finish(chunk_id, true);
+                    }
+                    let message = format!("Failed to fetch chunk {}: {}", chunk_id, e);
+                    error!(
+                        chunk_id,
+                        error_kind = Category::FetchFailure.name(),
+                        "{}",
+                        message
+                    );
+                    events.warning(message);
+                    errors.fetch_add(1, Ordering::Relaxed);
+                    let subject = Some(Subject::Chunk(chunk_id));
+                    digest.record(Category::FetchFailure, subject, &e.to_string());
+                    error_file.range(
+                        chunk.range.first.id,
+                        chunk.range.last.id,
+                        &format!("{:#}", e),
+                    );
+                }
+            }
+
+            Ok::<_, anyhow::Error>(())
+        };
+
+        // Every log line from the chunk carries its id; error spans are never filtered out
+        worker
+            .map(move |result| result.context(format!("Chunk {} failed", chunk_id)))
+            .instrument(tracing::error_span!("chunk", id = chunk_id))
+    };
+    dispatch(chunk_ids, workers, work)
+}
+
+/// What the writer task came to, if it finished before the workers did
+type WriterResult = std::result::Result<Result<WriterReport>, JoinError>;
+
+/// Wait for all workers to complete, cancelling the rest when one fails, the writer stops,
+/// or an estimate's sample time is up; the first worker error is returned, as it is fatal
+/// once the output is finalized as it stands
+async fn wait(
+    handles: Vec<JoinHandle<Result<()>>>,
+    writer_handle: &mut JoinHandle<Result<WriterReport>>,
+    estimator: Option<&Estimator>,
+    counters: &Counters,
+) -> (Option<anyhow::Error>, Option<WriterResult>) {
+    let abort_handles: Vec<_> = handles.iter().map(|h| h.abort_handle()).collect();
+    let mut pending: FuturesUnordered<_> = handles.into_iter().collect();
+    let mut fatal = None;
+
+    // The writer only finishes before the workers when it has failed
+    let mut writer_result = None;
+
+    // An estimate cancels the chunks still running once its time is up
+    let deadline = async {
+        match estimator {
+            Some(estimator) => tokio::time::sleep(estimator.budget()).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::pin!(deadline);
+    let mut sampled = false;
+
+    loop {
+        let result = tokio::select! {
+            result = pending.next() => match result {
+                Some(result) => result,
+                None => break,
+            },
+            _ = &mut deadline, if !sampled => {
+                info!("Estimate sample time is up, cancelling the chunks still running");
+                abort_handles.iter().for_each(|h| h.abort());
+                sampled = true;
+                continue;
+            }
+            result = &mut *writer_handle, if writer_result.is_none() => {
+                if !matches!(result, Ok(Ok(_))) {
+                    error!("Writer stopped, cancelling workers");
+                    abort_handles.iter().for_each(|h| h.abort());
+                }
+                writer_result = Some(result);
+                continue;
+            }
+        };
+
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                error!(
+                    error_kind = Category::Worker.name(),
+                    "Worker failed, stopping extraction: {:#}", e
+                );
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+                counters
+                    .digest
+                    .record(Category::Worker, None, &format!("{:#}", e));
+                abort_handles.iter().for_each(|h| h.abort());
+                fatal.get_or_insert(e);
+            }
+            Err(e) if e.is_cancelled() => {}
+            Err(e) => error!("Worker task failed: {}", e),
+        }
+    }
+    (fatal, writer_result)
+}
+
+/// What the run's summary and its outcome are drawn from
+struct Summary<'a> {
+    selection: &'a Selection,
+    work: &'a Work,
+    plan: &'a RunPlan,
+    stages: &'a Stages,
+    counters: &'a Counters,
+    writer: WriterReport,
+    processed: u64,
+    rejected: u64,
+    errors: u64,
+    written: Option<ByteCounter>,
+    /// When the writer started, for the rate it wrote at
+    writing_started: Instant,
+    /// The --consistent snapshot, and when it was taken
+    snapshot: Option<(String, DateTime<Utc>)>,
+    review: &'a Review,
+    reviewed: Option<u64>,
+    error_lines: Option<u64>,
+    scrubber: &'a Scrubber,
+    /// Listed ids the fetches did not return, in order
+    not_found: Vec<i64>,
+    spill: Option<SpillStats>,
+    scaler: Option<&'a Scaler>,
+    provenance: Option<&'a Provenance>,
+    recorded: Option<u64>,
+    watchdog: &'a Watchdog,
+    /// Whether listed ids were fetched in batches, rather than the plan's chunks
+    listed: bool,
+    tracer: &'a Tracer,
+    first_file: u64,
+    /// The first worker error, which fails the run once it is reported on
+    fatal: Option<anyhow::Error>,
+}
+
+/// Log the summary of the run, with the trace reports and the error digest
+fn report(config: &ExtractorConfig, summary: &Summary) {
+    let Summary {
+        processed: final_processed,
+        rejected: final_rejected,
+        errors: final_errors,
+        reviewed,
+        error_lines,
+        recorded,
+        ..
+    } = *summary;
+    let WriterReport {
+        quarantined,
+        display_rows,
+        bytes,
+        ..
+    } = summary.writer;
+    let Stages {
+        stubs,
+        multi_record,
+        transforms,
+        profile,
+        limits,
+        validator,
+        schema,
+    } = summary.stages;
+    let Selection {
+        capabilities,
+        enrichments,
+        ..
+    } = summary.selection;
+
+    info!(
+        records_processed = final_processed,
+        records_rejected = final_rejected,
+        records_quarantined = quarantined,
+        errors = final_errors,
+        "\nExtraction completed:"
+    );
+    info!("  Records processed: {}", final_processed);
+    if let Some(counter) = &summary.written {
+        let bytes = counter.load(Ordering::Relaxed);
+        let elapsed = summary.writing_started.elapsed();
+        info!(
+            "  Bytes written: {} ({:.1} MB/s)",
+            HumanBytes(bytes),
+            bytes as f64 / 1_000_000.0 / elapsed.as_secs_f64().max(0.001)
+        );
+    }
+    if config.record_type != RecordType::Bib {
+        info!("  Record type: {}", config.record_type.name());
+    }
+    if let Some((id, taken)) = &summary.snapshot {
+        info!(
+            "  Snapshot: {} (the database as of {})",
+            id,
+            taken.to_rfc3339()
+        );
+    }
+    if final_rejected > 0 {
+        warn!("  Records rejected: {}", final_rejected);
+    }
+    if let Some(path) = config.quarantine_file.as_ref().filter(|_| quarantined > 0) {
+        warn!(
+            "  Records quarantined: {} (see {})",
+            quarantined,
+            path.display()
+        );
+    }
+    if let (Some(path), Some(reviewed)) = (&config.review_file, reviewed) {
+        let dropped = summary.review.dropped();
+        if dropped > reviewed {
+            warn!(
+                "  Records for review: {} of {} dropped or rejected (see {}; --review-max {})",
+                reviewed,
+                dropped,
+                path.display(),
+                config.review_max
+            );
+        } else if dropped > 0 {
+            warn!(
+                "  Records for review: {} (see {})",
+                reviewed,
+                path.display()
+            );
+        }
+    }
+    if let (Some(path), Some(lines)) = (&config.error_file, error_lines) {
+        if lines > 0 {
+            warn!(
+                "  Failures listed: {} (see {}; retry with --id-file)",
+                lines,
+                path.display()
+            );
+        } else {
+            info!("  Failures listed: none in {}", path.display());
+        }
+    }
+    if summary.scrubber.touched() > 0 {
+        match &config.scrub_report {
+            Some(path) => warn!(
+                "  Records scrubbed of characters XML does not allow: {} (see {})",
+                summary.scrubber.touched(),
+                path.display()
+            ),
+            None => warn!(
+                "  Records scrubbed of characters XML does not allow: {}",
+                summary.scrubber.touched()
+            ),
+        }
+    }
+    let not_found = &summary.not_found;
+    if !not_found.is_empty() {
+        warn!(
+            "  Records not found: {} (ids: {})",
+            not_found.len(),
+            format_ids(not_found)
+        );
+    }
+    let stub_ids = stubs.ids.lock().unwrap();
+    if !stub_ids.is_empty() {
+        let verb = if config.on_stub == OnStub::Keep {
+            "kept"
+        } else {
+            "skipped"
+        };
+        warn!(
+            "  Stub records {}: {} (ids: {})",
+            verb,
+            stub_ids.len(),
+            format_ids(&stub_ids)
+        );
+    }
+    let multi_ids = multi_record.ids.lock().unwrap();
+    if !multi_ids.is_empty() {
+        let verb = match config.multi_record_rows {
+            MultiRecordRows::EmitAll => "written in full",
+            MultiRecordRows::First => "cut to their first record",
+            MultiRecordRows::Skip if config.quarantine_file.is_some() => "quarantined",
+            MultiRecordRows::Skip => "skipped",
+        };
+        warn!(
+            "  Rows holding several records {}: {} (ids: {})",
+            verb,
+            multi_ids.len(),
+            format_ids(&multi_ids)
+        );
+    }
+    let limit_ids = limits.ids.lock().unwrap();
+    if let Some(target) = limits.target().filter(|_| !limit_ids.is_empty()) {
+        let verb = match config.on_limit_violation {
+            OnLimitViolation::Skip => "skipped",
+            _ => "written",
+        };
+        warn!(
+            "  Records over {} limits {}: {} (ids: {})",
+            target,
+            verb,
+            limit_ids.len(),
+            format_ids(&limit_ids)
+        );
+    }
+    let invalid_ids = validator.ids.lock().unwrap();
+    if !invalid_ids.is_empty() {
+        let verb = if config.quarantine_file.is_some() {
+            "quarantined"
+        } else {
+            "skipped"
+        };
+        warn!(
+            "  Records not well-formed {}: {} (ids: {})",
+            verb,
+            invalid_ids.len(),
+            format_ids(&invalid_ids)
+        );
+    }
+    let schema_ids = schema.ids.lock().unwrap();
+    if !schema_ids.is_empty() {
+        let verb = if config.quarantine_file.is_some() {
+            "quarantined"
+        } else {
+            "skipped"
+        };
+        warn!(
+            "  Records breaking MARC21slim {}: {} (ids: {})",
+            verb,
+            schema_ids.len(),
+            format_ids(&schema_ids)
+        );
+        for (kind, records) in schema.kinds() {
+            warn!("    {}: {} records", kind, records);
+        }
+    }
+    if let Some((option, fields, records)) = transforms.stripped() {
+        info!(
+            "  Fields removed by {}: {} (from {} records)",
+            option, fields, records
+        );
+    }
+    if transforms.field_order() == FieldOrder::Canonical {
+        info!(
+            "  Records with fields put in canonical order: {}",
+            transforms.reordered()
+        );
+    }
+    if let Some(stamped) = transforms.stamped() {
+        info!("  Records given a 901: {}", stamped);
+    }
+    if let Some(tag) = transforms.fingerprint_tag() {
+        let (added, missing) = transforms.fingerprinted();
+        info!("  Records given a {} fingerprint field: {}", tag, added);
+        if missing > 0 {
+            warn!(
+                "  Records with no fingerprint or quality computed: {}",
+                missing
+            );
+        }
+    }
+    if profile.profile().is_some() {
+        info!("  OCLC holdings records built: {}", profile.built());
+        let missing = profile.ids.lock().unwrap();
+        if !missing.is_empty() {
+            let verb = if config.quarantine_file.is_some() {
+                "quarantined"
+            } else {
+                "skipped"
+            };
+            warn!(
+                "  Records without an OCLC number {}: {} (ids: {})",
+                verb,
+                missing.len(),
+                format_ids(&missing)
+            );
+        }
+    }
+    if final_errors > 0 {
+        warn!("  Errors encountered: {}", final_errors);
+    }
+    if let Some(stats) = &summary.spill {
+        if stats.records_adopted > 0 {
+            info!(
+                "  Records replayed from a previous run's spill files: {}",
+                stats.records_adopted
+            );
+        }
+        if stats.records_spilled > 0 {
+            info!(
+                "  Records spilled to disk: {} (peak {})",
+                stats.records_spilled,
+                HumanBytes(stats.peak_bytes)
+            );
+        }
+    }
+
+    if let Some((spec, histogram)) = &summary.plan.histogram {
+        info!("  Records by {}:", spec);
+        for line in histogram.table() {
+            info!("    {}", line);
+        }
+        if let Some(path) = &config.histogram_json {
+            info!("  Histogram written to: {}", path.display());
+        }
+    }
+
+    let (fields_added, stray_rows) = enrichments.summary();
+    if !enrichments.tags().is_empty() {
+        info!("  Enrichment fields added: {}", fields_added);
+    }
+    if stray_rows > 0 {
+        warn!(
+            "  Enrichment rows for records outside their chunk: {}",
+            stray_rows
+        );
+    }
+    if let Some(path) = &config.with_display_fields {
+        info!(
+            "  Display field values written: {} (see {})",
+            display_rows,
+            path.display()
+        );
+    }
+    let outputs = &summary.writer.outputs;
+    if !outputs.is_empty() {
+        let name = |format: OutputFormat| format!("{:?}", format).to_lowercase();
+        let output = config
+            .output
+            .as_ref()
+            .map_or("STDOUT".to_string(), |path| path.display().to_string());
+        info!("  Outputs:");
+        info!(
+            "    {} ({}): {} records, {}",
+            output,
+            name(config.format),
+            final_processed,
+            HumanBytes(bytes.unwrap_or_default())
+        );
+        for report in outputs {
+            let mut line = format!(
+                "{} ({}): {} records, {}",
+                report.output.path.display(),
+                name(report.output.format),
+                report.written,
+                HumanBytes(report.bytes)
+            );
+            if report.rejected > 0 {
+                line.push_str(&format!(", {} rejected", report.rejected));
+            }
+            if report.failed > 0 {
+                line.push_str(&format!(", {} failed", report.failed));
+            }
+            match &report.error {
+                Some(e) => warn!("    {}; stopped: {}", line, e),
+                None if report.rejected + report.failed > 0 => warn!("    {}", line),
+                None => info!("    {}", line),
+            }
+        }
+    }
+    for line in capabilities.summary() {
+        warn!("  Disabled: {}", line);
+    }
+
+    if let Some(scaler) = summary.scaler {
+        let (settled, lowest, highest) = scaler.settled();
+        info!(
+            "  Workers: auto settled on {} (ranged {}-{}); pin it with --workers {}",
+            settled, lowest, highest, settled
+        );
+    }
+
+    if let Some(pool_summary) = summary.counters.pool.summary() {
+        info!(
+            "  Pool acquires: {} (avg wait {}ms, max {}ms)",
+            pool_summary.acquires, pool_summary.avg_wait_ms, pool_summary.max_wait_ms
+        );
+    }
+
+    if let Some(peak) = memory::peak_rss() {
+        info!("  Peak memory: {}", HumanBytes(peak));
+    }
+    if let (Some(provenance), Some(recorded)) = (summary.provenance, recorded) {
+        info!(
+            "  Provenance: {} records recorded for run {}",
+            recorded,
+            provenance.run_id()
+        );
+    }
+    if summary.watchdog.interrupted() {
+        warn!(
+            "  Interrupted: {} records written before the run stopped; the output is closed properly",
+            final_processed
+        );
+    }
+    let memory_actions = summary.watchdog.actions();
+    if !memory_actions.is_empty() {
+        warn!("  Memory watchdog: {}", memory_actions.join(", "));
+    }
+    let not_run = summary.watchdog.not_run.lock().unwrap();
+    if !not_run.is_empty() {
+        let unit = if summary.listed { "Batches" } else { "Chunks" };
+        warn!(
+            "  {} not run: {} (numbers: {}); rerun each with --only-chunk",
+            unit,
+            not_run.len(),
+            format_ids(&not_run)
+        );
+    }
+
+    // Trace reports and the error digest are printed whatever the log level
+    for line in summary.tracer.report() {
+        eprintln!("{}", line);
+    }
+    let details = config
+        .summary_json
+        .as_ref()
+        .map(|p| p.display().to_string());
+    for line in summary.counters.digest.table(details.as_deref()) {
+        eprintln!("{}", line);
+    }
+}
+
+/// Close out the run once it is reported on: publish its outputs, remove a checkpoint it
+/// no longer needs, and advance a --continue-from chain if the batch came out clean
+fn conclude(config: &ExtractorConfig, token: Option<&Token>, summary: Summary) -> Result<Outcome> {
+    let Summary {
+        selection,
+        work,
+        plan,
+        processed: final_processed,
+        errors: final_errors,
+        writer: WriterReport { files, outputs, .. },
+        watchdog,
+        first_file,
+        fatal,
+        ..
+    } = summary;
+
+    if let Some(estimator) = &plan.estimator {
+        for line in estimator.report() {
+            eprintln!("{}", line);
+        }
+        return match fatal {
+            Some(e) => Err(e),
+            None => Ok(Outcome::Complete),
+        };
+    }
+
+    if let (Some(output), Some(files)) = (&config.output, files) {
+        info!(
+            "  Output written to {} files: {} to {}",
+            files + 1 - first_file,
+            split::numbered(output, first_file).display(),
+            split::numbered(output, files).display()
+        );
+    } else if let Some(output) = &config.output {
+        info!("  Output written to: {}", output.display());
+    } else {
+        info!("  Output written to STDOUT");
+    }
+    if let Some(since) = selection.filter.since {
+        info!(
+            "  Changed since: {} ({})",
+            since.time.to_rfc3339(),
+            since.field.column()
+        );
+    }
+    if let (Some(path), Some(progress)) = (&config.checkpoint, &plan.progress) {
+        if progress.complete() {
+            std::fs::remove_file(path)
+                .context(format!("Failed to remove checkpoint: {}", path.display()))?;
+            info!("  Checkpoint: removed, the run is complete");
+        } else {
+            match Checkpoint::load(path)?.and_then(|checkpoint| checkpoint.after_id) {
+                Some(id) => warn!(
+                    "  Checkpoint: {} kept; --resume continues after record {}",
+                    path.display(),
+                    id
+                ),
+                None => warn!(
+                    "  Checkpoint: {} kept; --resume continues from the start",
+                    path.display()
+                ),
+            }
+        }
+    }
+
+    if let Some(e) = fatal {
+        return Err(e);
+    }
+
+    // A stopped run is incomplete whatever else happened, so it never advances the chain
+    if watchdog.interrupted() {
+        return Ok(Outcome::Interrupted);
+    }
+    if watchdog.stopped() {
+        return Ok(Outcome::MemoryLimit);
+    }
+
+    // Only now is the output whole; until it is renamed it stays NAME.part
+    if let (Some(output), false) = (&config.output, config.no_atomic) {
+        for file in output_files(output, files.map(|last| first_file..=last)) {
+            writer::publish(&file, config.durable)?;
+        }
+    }
+    // An --also-output that stopped part way is left as NAME.part, as an interrupted run's is
+    for report in outputs
+        .iter()
+        .filter(|report| report.error.is_none() && !config.no_atomic)
+    {
+        writer::publish(&report.output.path, config.durable)?;
+    }
+
+    let output_errors = outputs
+        .iter()
+        .any(|report| report.error.is_some() || report.rejected + report.failed > 0);
+    if final_errors > 0 || output_errors {
+        return Ok(Outcome::Errors);
+    }
+
+    // Only a clean batch advances the chain
+    if let (Some(path), Some(last_id)) = (&config.continue_from, work.batch_last_id) {
+        let next = Token::next(
+            token,
+            last_id,
+            final_processed,
+            config.record_type,
+            &selection.filter,
+            files.map(|last| last + 1),
+            config.manifest.clone(),
+        );
+        next.save(path)?;
+        info!(
+            "  Continuation token: {} (after record {})",
+            path.display(),
+            last_id
+        );
+    }
+
+    if work.more_remains {
+        info!("  More records remain; run again with --continue-from to extract the next batch");
+        return Ok(Outcome::MoreRemains);
+    }
+
+    Ok(Outcome::Complete)
+}
+
+/// Run `work` on every item, on at most `workers` tasks at once
+///
+/// Each task takes the next item when it finishes one, so an item's future is only made
+/// when its turn comes, and no more connections are asked for than there are tasks.
+fn dispatch<T, F, Fut>(items: Vec<T>, workers: usize, work: F) -> Vec<JoinHandle<Result<()>>>
+where
+    T: Send + 'static,
+    F: Fn(T) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let tasks = workers.min(items.len());
+    let queue = Arc::new(Mutex::new(items.into_iter()));
+    let work = Arc::new(work);
+    (0..tasks)
+        .map(|_| {
+            let queue = Arc::clone(&queue);
+            let work = Arc::clone(&work);
+            tokio::spawn(async move {
+                loop {
+                    let next = queue.lock().unwrap().next();
+                    let Some(item) = next else {
+                        return Ok(());
+                    };
+                    work(item).await?;
+                }
+            })
+        })
+        .collect()
+}
+
+/// Sync the output and --quarantine-file to disk, then save a checkpoint vouching for
+/// what they hold
+async fn save_checkpoint(
+    checkpointer: &mut Checkpointer,
+    writer: &mut sink::RecordSink,
+    quarantine: &mut Option<Quarantine>,
+    written: u64,
+) -> Result<()> {
+    writer.sync().await?;
+    if let Some(quarantine) = quarantine {
+        quarantine.sync().await?;
+    }
+    let point = writer.point().expect("--checkpoint writes to a file");
+    checkpointer.save(point, quarantine.as_ref().map(Quarantine::offset), written)
+}
+
+/// Render a list of record ids for a summary line, eliding long lists
+fn format_ids(ids: &[i64]) -> String {
+    const SHOWN: usize = 20;
+    let mut sorted = ids.to_vec();
+    sorted.sort_unstable();
+    let shown: Vec<String> = sorted.iter().take(SHOWN).map(|id| id.to_string()).collect();
+    if sorted.len() > SHOWN {
+        format!("{}, ... {} more", shown.join(", "), sorted.len() - SHOWN)
+    } else {
+        shown.join(", ")
+    }
+}
+
+/// The files --output was written to: itself, or the numbered files `files` when split
+pub(crate) fn output_files(
+    output: &std::path::Path,
+    files: Option<RangeInclusive<u64>>,
+) -> Vec<PathBuf> {
+    match files {
+        Some(files) => files
+            .map(|number| split::numbered(output, number))
+            .collect(),
+        None => vec![output.to_path_buf()],
+    }
+}
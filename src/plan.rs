@@ -0,0 +1,146 @@
+use anyhow::Result;
+use sqlx::PgPool;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use tracing::info;
+
+use crate::db::{self, ChunkRange, DatabaseConfig, Partition};
+
+/// Where one chunk's records come from
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    /// Partition the chunk is read from, as an index into ChunkPlan::partitions
+    pub partition: Option<usize>,
+    /// The keys the chunk covers in its table
+    pub range: ChunkRange,
+}
+
+impl Chunk {
+    /// Records the chunk is planned to hold
+    pub fn records(&self) -> i64 {
+        self.range.records
+    }
+}
+
+/// The chunks a run is cut into, in the order they are numbered
+///
+/// Every chunk is a range of keys in the --order-by order, read up front, so that each
+/// fetch is an index range scan however deep into the table it is. A plain table is cut
+/// over the whole selection. A table range partitioned on id is cut partition by
+/// partition, so no chunk spans two partitions and each is read from its partition
+/// directly rather than through the parent.
+pub struct ChunkPlan {
+    pub chunks: Vec<Chunk>,
+    /// The record table, which chunks outside any partition are read from
+    table: &'static str,
+    pub partitions: Vec<Partition>,
+    /// Chunks of each partition that have not finished yet
+    remaining: Vec<AtomicUsize>,
+}
+
+impl ChunkPlan {
+    /// Chunks over the first `records` of the selection, aligned to `partitions` if given
+    pub async fn build(
+        pool: &PgPool,
+        config: &DatabaseConfig,
+        partitions: Option<Vec<Partition>>,
+        records: i64,
+    ) -> Result<Self> {
+        let started = Instant::now();
+        let plan = match partitions {
+            Some(partitions) => {
+                let mut ranges = Vec::with_capacity(partitions.len());
+                let mut left = records;
+                for partition in &partitions {
+                    let planned = match left {
+                        0 => Vec::new(),
+                        _ => db::chunk_ranges(pool, config, &partition.table, left).await?,
+                    };
+                    left -= planned.iter().map(|range| range.records).sum::<i64>();
+                    ranges.push(planned);
+                }
+                Self::partitioned(config.table(), partitions, ranges)
+            }
+            None => {
+                let chunks = db::chunk_ranges(pool, config, config.table(), records)
+                    .await?
+                    .into_iter()
+                    .map(|range| Chunk {
+                        partition: None,
+                        range,
+                    })
+                    .collect();
+                Self {
+                    chunks,
+                    table: config.table(),
+                    partitions: Vec::new(),
+                    remaining: Vec::new(),
+                }
+            }
+        };
+        info!(
+            "Planned {} chunks by key range in {}ms",
+            plan.len(),
+            started.elapsed().as_millis()
+        );
+        Ok(plan)
+    }
+
+    /// Chunks aligned to `partitions`, given the ranges planned in each
+    fn partitioned(
+        table: &'static str,
+        partitions: Vec<Partition>,
+        ranges: Vec<Vec<ChunkRange>>,
+    ) -> Self {
+        let chunks: Vec<Chunk> = ranges
+            .into_iter()
+            .enumerate()
+            .flat_map(|(index, ranges)| {
+                ranges.into_iter().map(move |range| Chunk {
+                    partition: Some(index),
+                    range,
+                })
+            })
+            .collect();
+
+        let remaining = (0..partitions.len())
+            .map(|index| {
+                let planned = chunks.iter().filter(|c| c.partition == Some(index)).count();
+                AtomicUsize::new(planned)
+            })
+            .collect();
+        Self {
+            chunks,
+            table,
+            partitions,
+            remaining,
+        }
+    }
+
+    pub fn len(&self) -> i64 {
+        self.chunks.len() as i64
+    }
+
+    /// Table a chunk is fetched from
+    pub fn table(&self, chunk: &Chunk) -> &str {
+        match chunk.partition {
+            Some(index) => &self.partitions[index].table,
+            None => self.table,
+        }
+    }
+
+    /// Partitions that have chunks in the plan
+    pub fn partitions_used(&self) -> usize {
+        self.remaining
+            .iter()
+            .filter(|chunks| chunks.load(Ordering::Relaxed) > 0)
+            .count()
+    }
+
+    /// A chunk is done; returns its partition when it was the last of that partition's chunks
+    pub fn finish(&self, chunk: &Chunk) -> Option<&Partition> {
+        let index = chunk.partition?;
+        let left = self.remaining[index].fetch_sub(1, Ordering::Relaxed) - 1;
+        (left == 0).then(|| &self.partitions[index])
+    }
+}
@@ -0,0 +1,178 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sqlx::pool::PoolConnection;
+use sqlx::{PgPool, Postgres};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::events::{Event, ProgressEvents};
+
+/// How often the pool is sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Samples per report window
+const SAMPLES_PER_REPORT: u32 = 10;
+
+/// Average acquire wait above which the pool is reported as saturated
+const SLOW_ACQUIRE: Duration = Duration::from_millis(500);
+
+/// Fraction of idle connections above which the pool is reported as mostly idle
+const IDLE_RATIO: f64 = 0.75;
+
+/// Times pool acquisitions made by the workers and reports on pool usage
+#[derive(Debug, Default)]
+pub struct PoolMonitor {
+    window_acquires: AtomicU64,
+    window_wait_us: AtomicU64,
+    total_acquires: AtomicU64,
+    total_wait_us: AtomicU64,
+    max_wait_us: AtomicU64,
+    /// Workers waiting for a connection right now
+    waiting: AtomicU64,
+}
+
+/// Pool usage over the whole run, in the --summary-json file
+#[derive(Debug, Serialize)]
+pub struct PoolSummary {
+    pub acquires: u64,
+    pub avg_wait_ms: u64,
+    pub max_wait_ms: u64,
+}
+
+impl PoolMonitor {
+    /// Acquire a connection, recording how long it took
+    pub async fn acquire(&self, pool: &PgPool) -> Result<PoolConnection<Postgres>> {
+        let started = Instant::now();
+        let others = self.waiting.fetch_add(1, Ordering::Relaxed);
+        let acquired = pool.acquire().await;
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+        let conn = match acquired {
+            Ok(conn) => conn,
+            Err(sqlx::Error::PoolTimedOut) => anyhow::bail!(
+                "No database connection came free within --acquire-timeout {}s; all {} were in use",
+                pool.options().get_acquire_timeout().as_secs(),
+                pool.options().get_max_connections()
+            ),
+            Err(e) => return Err(e).context("Failed to acquire a database connection"),
+        };
+        let elapsed = started.elapsed();
+        if elapsed > SLOW_ACQUIRE {
+            debug!(
+                "Waited {}ms for a database connection, {} other workers waiting",
+                elapsed.as_millis(),
+                others
+            );
+        }
+        let waited = elapsed.as_micros() as u64;
+
+        self.window_acquires.fetch_add(1, Ordering::Relaxed);
+        self.window_wait_us.fetch_add(waited, Ordering::Relaxed);
+        self.total_acquires.fetch_add(1, Ordering::Relaxed);
+        self.total_wait_us.fetch_add(waited, Ordering::Relaxed);
+        self.max_wait_us.fetch_max(waited, Ordering::Relaxed);
+
+        Ok(conn)
+    }
+
+    /// Periodically log pool size, idle connections, and acquire waits, and send them as a
+    /// pool event to `events`
+    ///
+    /// Warns once when workers queue for connections and once when connections sit idle
+    /// (the fetchers are held back by the writer). Queueing means the database is the
+    /// bottleneck, unless there are fewer connections than `workers`, when it is the
+    /// --connections cap. An idle pool is expected under --throttle, so `warn_idle` is off
+    /// then.
+    pub fn spawn_reporter(
+        self: Arc<Self>,
+        pool: PgPool,
+        workers: u32,
+        warn_idle: bool,
+        events: Arc<ProgressEvents>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(SAMPLE_INTERVAL);
+            ticker.tick().await;
+
+            let mut samples = 0u32;
+            let mut idle_sum = 0f64;
+            let (mut warned_slow, mut warned_idle) = (false, false);
+
+            loop {
+                ticker.tick().await;
+
+                let size = pool.size();
+                if size > 0 {
+                    idle_sum += pool.num_idle() as f64 / size as f64;
+                }
+                samples += 1;
+
+                if samples < SAMPLES_PER_REPORT {
+                    continue;
+                }
+
+                let acquires = self.window_acquires.swap(0, Ordering::Relaxed);
+                let wait_us = self.window_wait_us.swap(0, Ordering::Relaxed);
+                let avg_wait = Duration::from_micros(wait_us.checked_div(acquires).unwrap_or(0));
+                let idle_ratio = idle_sum / samples as f64;
+                samples = 0;
+                idle_sum = 0.0;
+
+                let (idle, waiting) =
+                    (pool.num_idle() as u32, self.waiting.load(Ordering::Relaxed));
+                info!(
+                    "Pool: {} connections, {} idle, {} workers waiting, {} acquires in the last {}s, avg wait {}ms",
+                    size,
+                    idle,
+                    waiting,
+                    acquires,
+                    (SAMPLE_INTERVAL * SAMPLES_PER_REPORT).as_secs(),
+                    avg_wait.as_millis()
+                );
+                events.emit(Event::Pool {
+                    connections: size,
+                    idle,
+                    waiting,
+                    acquires,
+                    avg_wait_ms: avg_wait.as_millis() as u64,
+                });
+
+                let connections = pool.options().get_max_connections();
+                if avg_wait > SLOW_ACQUIRE && !warned_slow && connections < workers {
+                    warned_slow = true;
+                    warn!(
+                        "Workers waited {}ms on average for a connection, {} workers sharing {}; the --connections cap is the limit, not the database",
+                        avg_wait.as_millis(),
+                        workers,
+                        connections
+                    );
+                } else if avg_wait > SLOW_ACQUIRE && !warned_slow {
+                    warned_slow = true;
+                    warn!(
+                        "Workers waited {}ms on average for a connection; the database is saturated and more --workers will not help",
+                        avg_wait.as_millis()
+                    );
+                } else if warn_idle && idle_ratio > IDLE_RATIO && !warned_idle {
+                    warned_idle = true;
+                    warn!(
+                        "{:.0}% of pooled connections were idle; the writer is the bottleneck, not the database",
+                        idle_ratio * 100.0
+                    );
+                }
+            }
+        })
+    }
+
+    /// Pool usage so far, or `None` before any connection was acquired through it
+    pub fn summary(&self) -> Option<PoolSummary> {
+        let acquires = self.total_acquires.load(Ordering::Relaxed);
+        let wait_us = self.total_wait_us.load(Ordering::Relaxed);
+        (acquires > 0).then(|| PoolSummary {
+            acquires,
+            avg_wait_ms: wait_us / acquires / 1000,
+            max_wait_ms: self.max_wait_us.load(Ordering::Relaxed) / 1000,
+        })
+    }
+}
@@ -0,0 +1,367 @@
+use clap::ValueEnum;
+use quick_xml::escape::escape;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::db::MarcRecord;
+use crate::errorfile::ErrorFile;
+use crate::quarantine::Rejection;
+use crate::review::Review;
+use crate::trace::Tracer;
+
+/// Leader used when a record has none of the standard 24 characters
+const DEFAULT_LEADER: &str = "00000nam a2200000   4500";
+
+/// What records are built from each source record
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Profile {
+    /// Minimal records for OCLC batchload holdings: leader, 001/003, the OCLC number in 035,
+    /// and the --holdings-action in 940
+    OclcHoldings,
+}
+
+/// The local holdings action an OCLC holdings record asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HoldingsAction {
+    Set,
+    Delete,
+}
+
+impl HoldingsAction {
+    /// The action as the holdings record gives it
+    pub fn code(self) -> &'static str {
+        match self {
+            HoldingsAction::Set => "set",
+            HoldingsAction::Delete => "delete",
+        }
+    }
+}
+
+/// Rebuilds records for the --profile in the workers and remembers the ids it cannot build
+pub struct ProfileBuilder {
+    profile: Option<(Profile, HoldingsAction)>,
+    quarantine: bool,
+    /// Records left out because they have no OCLC number
+    pub ids: Mutex<Vec<i64>>,
+    built: AtomicU64,
+    tracer: Arc<Tracer>,
+    review: Arc<Review>,
+    error_file: Arc<ErrorFile>,
+}
+
+impl ProfileBuilder {
+    pub fn new(
+        profile: Option<Profile>,
+        action: Option<HoldingsAction>,
+        quarantine: bool,
+        tracer: Arc<Tracer>,
+        review: Arc<Review>,
+        error_file: Arc<ErrorFile>,
+    ) -> Self {
+        Self {
+            profile: profile.map(|p| (p, action.unwrap_or(HoldingsAction::Set))),
+            quarantine,
+            ids: Mutex::new(Vec::new()),
+            built: AtomicU64::new(0),
+            tracer,
+            review,
+            error_file,
+        }
+    }
+
+    pub fn profile(&self) -> Option<Profile> {
+        self.profile.map(|(profile, _)| profile)
+    }
+
+    pub fn built(&self) -> u64 {
+        self.built.load(Ordering::Relaxed)
+    }
+
+    /// Replace the record with the profile's version of it; `None` means it is dropped
+    pub fn screen(&self, mut record: MarcRecord) -> Option<MarcRecord> {
+        let Some((Profile::OclcHoldings, action)) = self.profile else {
+            return Some(record);
+        };
+        if record.rejection.is_some() {
+            return Some(record);
+        }
+
+        match oclc_holdings(&record, action) {
+            Some((number, marc)) => {
+                self.tracer.note(record.id, "profile", || {
+                    format!("oclc-holdings record for OCLC number {}", number)
+                });
+                self.built.fetch_add(1, Ordering::Relaxed);
+                record.marc = marc;
+                Some(record)
+            }
+            None => {
+                self.tracer.note(record.id, "profile", || {
+                    "no OCLC number in 035 or 001; left out of oclc-holdings".to_string()
+                });
+                self.ids.lock().unwrap().push(record.id);
+                let rejection = Rejection::new("profile", "no OCLC number in 035 or 001");
+                if self.quarantine {
+                    record.rejection = Some(rejection);
+                    Some(record)
+                } else {
+                    self.review.capture(&record, &rejection);
+                    self.error_file.rejected(record.id, &rejection);
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// The fields of a source record an OCLC holdings record is built from
+#[derive(Default)]
+struct HoldingsSource {
+    leader: String,
+    control_number: Option<String>,
+    control_number_id: Option<String>,
+    /// 035 $a values, in record order
+    system_numbers: Vec<String>,
+}
+
+/// The record's OCLC number and the minimal holdings record carrying it
+fn oclc_holdings(record: &MarcRecord, action: HoldingsAction) -> Option<(String, String)> {
+    let source = read_source(&record.marc)?;
+
+    let number = source
+        .system_numbers
+        .iter()
+        .find_map(|value| normalize_oclc_number(value, false))
+        .or_else(|| {
+            let control = source.control_number.as_deref()?;
+            let from_oclc = source.control_number_id.as_deref() == Some("OCoLC");
+            normalize_oclc_number(control, from_oclc)
+        })?;
+
+    // Lengths and base address are filled in by whoever encodes the record
+    let leader = if source.leader.chars().count() == 24 {
+        let chars: Vec<char> = source.leader.chars().collect();
+        format!(
+            "00000{}a2200000{}4500",
+            chars[5..9].iter().collect::<String>(),
+            chars[17..20].iter().collect::<String>()
+        )
+    } else {
+        DEFAULT_LEADER.to_string()
+    };
+    let control_number = source
+        .control_number
+        .unwrap_or_else(|| record.id.to_string());
+
+    let mut marc = String::from("<record xmlns=\"http://www.loc.gov/MARC21/slim\">");
+    marc.push_str(&format!("<leader>{}</leader>", escape(&leader)));
+    marc.push_str(&format!(
+        "<controlfield tag=\"001\">{}</controlfield>",
+        escape(&control_number)
+    ));
+    if let Some(id) = &source.control_number_id {
+        marc.push_str(&format!(
+            "<controlfield tag=\"003\">{}</controlfield>",
+            escape(id)
+        ));
+    }
+    marc.push_str(&format!(
+        "<datafield tag=\"035\" ind1=\" \" ind2=\" \"><subfield code=\"a\">(OCoLC){}</subfield></datafield>",
+        number
+    ));
+    marc.push_str(&format!(
+        "<datafield tag=\"940\" ind1=\" \" ind2=\" \"><subfield code=\"a\">{}</subfield></datafield>",
+        action.code()
+    ));
+    marc.push_str("</record>");
+
+    Some((number, marc))
+}
+
+/// The OCLC number in a 035 $a or 001 value, without prefixes or leading zeros
+///
+/// A 035 value counts only with the `(OCoLC)` source code, e.g. `(OCoLC)ocm00012345`;
+/// a 001 counts if it carries one of OCLC's own prefixes (`ocm` for eight digits, `ocn`
+/// for nine, `on` for ten or more), or is bare digits in a record whose 003 is `OCoLC`
+/// (`bare_digits`). The number after the prefix must be all digits and not all zeros, so
+/// `(OCoLC)ocm00012345`, `ocn123456789`, and `(OCoLC)12345` give 12345, 123456789, and
+/// 12345, while `(OCoLC)M-ESTCN12345` (an ESTC number) and `(DLC)12345` give nothing.
+fn normalize_oclc_number(value: &str, bare_digits: bool) -> Option<String> {
+    let value = value.trim();
+    let (rest, mut is_oclc) = match strip_prefix_ignore_case(value, "(OCoLC)") {
+        Some(rest) => (rest.trim_start(), true),
+        None => (value, bare_digits),
+    };
+    let number = match ["ocm", "ocn", "on"]
+        .iter()
+        .find_map(|prefix| strip_prefix_ignore_case(rest, prefix))
+    {
+        Some(number) => {
+            is_oclc = true;
+            number
+        }
+        None => rest,
+    };
+
+    if !is_oclc || number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let number = number.trim_start_matches('0');
+    (!number.is_empty()).then(|| number.to_string())
+}
+
+fn strip_prefix_ignore_case<'a>(value: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = value.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &value[prefix.len()..])
+}
+
+/// Leader, 001, 003, and every 035 $a of a MARCXML record; `None` if it cannot be parsed
+fn read_source(xml: &str) -> Option<HoldingsSource> {
+    /// Where the text being read belongs
+    enum Target {
+        None,
+        Leader,
+        Control(String),
+        Subfield035a,
+    }
+
+    let mut reader = Reader::from_str(xml);
+    let mut source = HoldingsSource::default();
+    let mut target = Target::None;
+    let mut datafield = String::new();
+    let mut text = String::new();
+
+    loop {
+        match reader.read_event().ok()? {
+            Event::Start(e) => {
+                text.clear();
+                target = match e.local_name().as_ref() {
+                    b"leader" => Target::Leader,
+                    b"controlfield" => {
+                        let tag = e.try_get_attribute("tag").ok()??.unescape_value().ok()?;
+                        Target::Control(tag.into_owned())
+                    }
+                    b"datafield" => {
+                        let tag = e.try_get_attribute("tag").ok()??.unescape_value().ok()?;
+                        datafield = tag.into_owned();
+                        Target::None
+                    }
+                    b"subfield" if datafield == "035" => {
+                        let code = e.try_get_attribute("code").ok()??.unescape_value().ok()?;
+                        if code == "a" {
+                            Target::Subfield035a
+                        } else {
+                            Target::None
+                        }
+                    }
+                    _ => Target::None,
+                };
+            }
+            Event::Text(t) => text.push_str(&t.unescape().ok()?),
+            Event::CData(data) => text.push_str(std::str::from_utf8(&data).ok()?),
+            Event::End(e) => {
+                match std::mem::replace(&mut target, Target::None) {
+                    Target::Leader => source.leader = text.clone(),
+                    Target::Control(tag) if tag == "001" => {
+                        source.control_number = Some(text.trim().to_string())
+                    }
+                    Target::Control(tag) if tag == "003" => {
+                        source.control_number_id = Some(text.trim().to_string())
+                    }
+                    Target::Subfield035a => source.system_numbers.push(text.clone()),
+                    _ => {}
+                }
+                if e.local_name().as_ref() == b"datafield" {
+                    datafield.clear();
+                }
+                text.clear();
+            }
+            Event::Eof => return Some(source),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oclc_numbers_normalize_as_oclc_documents() {
+        let cases = [
+            ("(OCoLC)ocm00012345", Some("12345")),
+            ("(OCoLC)ocn123456789", Some("123456789")),
+            ("(OCoLC)on1234567890", Some("1234567890")),
+            ("(OCoLC)12345", Some("12345")),
+            ("(OCoLC)00012345", Some("12345")),
+            ("(ocolc)OCM00012345", Some("12345")),
+            ("(OCoLC) 12345", Some("12345")),
+            ("ocm00012345", Some("12345")),
+            ("ocn123456789", Some("123456789")),
+            ("on1234567890", Some("1234567890")),
+            ("(OCoLC)M-ESTCN12345", None),
+            ("(DLC)12345", None),
+            ("(OCoLC)00000000", None),
+            ("(OCoLC)ocm", None),
+            ("(OCoLC)1234 5", None),
+            ("12345", None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(
+                normalize_oclc_number(value, false).as_deref(),
+                expected,
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn bare_001_digits_count_only_with_an_ocolc_003() {
+        assert_eq!(
+            normalize_oclc_number("000012345", true).as_deref(),
+            Some("12345")
+        );
+        assert_eq!(normalize_oclc_number("000012345", false), None);
+        assert_eq!(
+            normalize_oclc_number("ocm12345", false).as_deref(),
+            Some("12345")
+        );
+    }
+
+    #[test]
+    fn holdings_record_takes_the_first_oclc_035() {
+        let record = MarcRecord {
+            id: 7,
+            marc: "<record xmlns=\"http://www.loc.gov/MARC21/slim\">\
+                   <leader>01234cam a2200289 i 4500</leader>\
+                   <controlfield tag=\"001\">7</controlfield>\
+                   <datafield tag=\"035\" ind1=\" \" ind2=\" \"><subfield code=\"a\">(DLC)99</subfield></datafield>\
+                   <datafield tag=\"035\" ind1=\" \" ind2=\" \"><subfield code=\"a\">(OCoLC)ocm00012345</subfield></datafield>\
+                   <datafield tag=\"245\" ind1=\"0\" ind2=\"0\"><subfield code=\"a\">Title</subfield></datafield>\
+                   </record>"
+                .to_string(),
+            rejection: None,
+            display: Vec::new(),
+            meta: None,
+            identifiers: None,
+            status: None,
+        };
+        let (number, marc) = oclc_holdings(&record, HoldingsAction::Set).unwrap();
+        assert_eq!(number, "12345");
+        assert!(
+            marc.contains("<subfield code=\"a\">(OCoLC)12345</subfield>"),
+            "{}",
+            marc
+        );
+        assert!(!marc.contains("tag=\"245\""), "{}", marc);
+        assert!(
+            marc.contains("<leader>00000cam a2200000 i 4500</leader>"),
+            "{}",
+            marc
+        );
+    }
+}
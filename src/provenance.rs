@@ -0,0 +1,233 @@
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use sqlx::Row;
+use std::path::Path;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+/// Schema version kept in SQLite's user_version; bumped on incompatible changes
+const SCHEMA_VERSION: i64 = 1;
+
+/// Rows written per transaction
+const BATCH_ROWS: usize = 5000;
+
+/// Rows queued for the recorder before the writer has to wait for it
+const QUEUE_ROWS: usize = 50_000;
+
+const SCHEMA: &[&str] = &[
+    "CREATE TABLE runs (
+        run_id TEXT PRIMARY KEY,
+        started_at TEXT NOT NULL,
+        finished_at TEXT,
+        status TEXT,
+        source TEXT NOT NULL,
+        records INTEGER
+    )",
+    "CREATE TABLE records (
+        run_id TEXT NOT NULL REFERENCES runs,
+        record_id INTEGER NOT NULL,
+        disposition TEXT NOT NULL,
+        reason TEXT,
+        content_hash TEXT,
+        output_file TEXT,
+        byte_offset INTEGER
+    )",
+    "CREATE INDEX records_record_id ON records (record_id)",
+];
+
+/// What happened to one record in a run
+#[derive(Debug)]
+pub struct Entry {
+    pub record_id: i64,
+    /// written, quarantined, skipped, or failed
+    pub disposition: &'static str,
+    pub reason: Option<String>,
+    /// The MARC as fetched, hashed on the recorder task
+    pub marc: Option<String>,
+    pub output_file: Option<String>,
+    pub byte_offset: Option<u64>,
+}
+
+/// A --provenance-db file with the current run registered in it
+pub struct Provenance {
+    pool: SqlitePool,
+    run_id: String,
+}
+
+impl Provenance {
+    /// Open (or create) the database and register run `run_id` reading from `source`
+    pub async fn open(path: &Path, source: &str, run_id: &str) -> Result<Self> {
+        let pool = connect(path, true).await?;
+
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+            .fetch_one(&pool)
+            .await?;
+        match version {
+            0 => {
+                let mut tx = pool.begin().await?;
+                for statement in SCHEMA {
+                    sqlx::query(statement).execute(&mut *tx).await?;
+                }
+                sqlx::query(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await?;
+            }
+            SCHEMA_VERSION => {}
+            other => bail!(
+                "Provenance database {} has schema version {}, expected {}",
+                path.display(),
+                other,
+                SCHEMA_VERSION
+            ),
+        }
+
+        sqlx::query(
+            "INSERT INTO runs (run_id, started_at, source) \
+             VALUES ($1, strftime('%Y-%m-%dT%H:%M:%SZ', 'now'), $2)",
+        )
+        .bind(run_id)
+        .bind(source)
+        .execute(&pool)
+        .await
+        .context(format!("Failed to register run in {}", path.display()))?;
+
+        Ok(Self {
+            pool,
+            run_id: run_id.to_string(),
+        })
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    /// Start the task that writes entries in batched transactions, off the writer task
+    pub fn recorder(&self) -> (mpsc::Sender<Entry>, JoinHandle<Result<u64>>) {
+        let (tx, mut rx) = mpsc::channel::<Entry>(QUEUE_ROWS);
+        let pool = self.pool.clone();
+        let run_id = self.run_id.clone();
+
+        let handle = tokio::spawn(async move {
+            let mut recorded = 0;
+            let mut batch = Vec::with_capacity(BATCH_ROWS);
+            while rx.recv_many(&mut batch, BATCH_ROWS).await > 0 {
+                let mut tx = pool.begin().await?;
+                for entry in batch.drain(..) {
+                    let hash = entry
+                        .marc
+                        .map(|marc| format!("sha256:{:x}", Sha256::digest(marc.as_bytes())));
+                    sqlx::query(
+                        "INSERT INTO records \
+                         (run_id, record_id, disposition, reason, content_hash, output_file, byte_offset) \
+                         VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                    )
+                    .bind(&run_id)
+                    .bind(entry.record_id)
+                    .bind(entry.disposition)
+                    .bind(entry.reason)
+                    .bind(hash)
+                    .bind(entry.output_file)
+                    .bind(entry.byte_offset.map(|offset| offset as i64))
+                    .execute(&mut *tx)
+                    .await?;
+                    recorded += 1;
+                }
+                tx.commit()
+                    .await
+                    .context("Failed to write provenance records")?;
+                debug!("Recorded provenance for {} records", recorded);
+            }
+            Ok(recorded)
+        });
+
+        (tx, handle)
+    }
+
+    /// Record how the run ended
+    pub async fn finish(&self, status: &str, records: u64) -> Result<()> {
+        sqlx::query(
+            "UPDATE runs SET finished_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now'), status = $1, records = $2 \
+             WHERE run_id = $3",
+        )
+        .bind(status)
+        .bind(records as i64)
+        .bind(&self.run_id)
+        .execute(&self.pool)
+        .await
+        .context("Failed to finish the provenance run")?;
+        self.pool.close().await;
+        Ok(())
+    }
+}
+
+/// Print every run's entry for each record id, tab-separated with a header
+pub async fn query(path: &Path, record_ids: &[i64]) -> Result<()> {
+    let pool = connect(path, false).await?;
+    let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+        .fetch_one(&pool)
+        .await?;
+    if version != SCHEMA_VERSION {
+        bail!(
+            "Provenance database {} has schema version {}, expected {}",
+            path.display(),
+            version,
+            SCHEMA_VERSION
+        );
+    }
+
+    println!("record_id\trun_id\tstarted_at\tstatus\tdisposition\treason\tcontent_hash\toutput_file\tbyte_offset");
+    for &record_id in record_ids {
+        let rows = sqlx::query(
+            "SELECT r.run_id, runs.started_at, coalesce(runs.status, 'running'), r.disposition, \
+                    r.reason, r.content_hash, r.output_file, r.byte_offset \
+             FROM records r JOIN runs USING (run_id) \
+             WHERE r.record_id = $1 ORDER BY runs.started_at, r.rowid",
+        )
+        .bind(record_id)
+        .fetch_all(&pool)
+        .await?;
+
+        if rows.is_empty() {
+            eprintln!("Record {} is not in any recorded run", record_id);
+        }
+        for row in rows {
+            let text = |i: usize| -> Result<String> {
+                Ok(row.try_get::<Option<String>, _>(i)?.unwrap_or_default())
+            };
+            let offset: Option<i64> = row.try_get(7)?;
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                record_id,
+                text(0)?,
+                text(1)?,
+                text(2)?,
+                text(3)?,
+                text(4)?,
+                text(5)?,
+                text(6)?,
+                offset.map(|o| o.to_string()).unwrap_or_default()
+            );
+        }
+    }
+
+    pool.close().await;
+    Ok(())
+}
+
+async fn connect(path: &Path, create: bool) -> Result<SqlitePool> {
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(create)
+        .foreign_keys(true);
+    SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await
+        .context(format!(
+            "Failed to open provenance database: {}",
+            path.display()
+        ))
+}
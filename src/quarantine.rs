@@ -0,0 +1,159 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::compression::Compression;
+use crate::db::MarcRecord;
+use crate::marc;
+use crate::writer::{Destination, FormatWriter, Metadata, XmlEncoder, DEFAULT_BUFFER_SIZE};
+
+/// Why a pipeline stage kept a record out of the main output
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rejection {
+    /// The stage that turned the record away, e.g. `stub` or `validate`
+    pub stage: String,
+    /// What was wrong with it
+    pub reason: String,
+}
+
+impl Rejection {
+    /// A rejection by `stage`
+    pub fn new(stage: &str, reason: impl Into<String>) -> Self {
+        Self {
+            stage: stage.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.stage, self.reason)
+    }
+}
+
+/// Collection of rejected records, written as they were fetched
+///
+/// MARC that is well-formed XML goes in as its record elements, without the declaration
+/// or collection wrapper it was stored with; anything else goes in whole as character
+/// data, so the file always parses and nothing of the row is lost.
+pub struct Quarantine {
+    writer: FormatWriter,
+    count: u64,
+}
+
+impl Quarantine {
+    pub async fn new(path: PathBuf, durable: bool) -> Result<Self> {
+        Ok(Self {
+            writer: FormatWriter::start(
+                Destination::open(Some(path), Compression::NONE, false, DEFAULT_BUFFER_SIZE)
+                    .await?
+                    .durable(durable),
+                Arc::new(XmlEncoder::new(None, Metadata::Marc)),
+            )
+            .await?,
+            count: 0,
+        })
+    }
+
+    /// Write on after the first `offset` bytes of the file a checkpointed run was writing
+    pub async fn resume(path: PathBuf, offset: u64, durable: bool) -> Result<Self> {
+        Ok(Self {
+            writer: FormatWriter::resume(
+                Destination::resume(path, offset, false, DEFAULT_BUFFER_SIZE)
+                    .await?
+                    .durable(durable),
+                Arc::new(XmlEncoder::new(None, Metadata::Marc)),
+            ),
+            count: 0,
+        })
+    }
+
+    /// Write the record's MARC, preceded by a comment naming the rejection
+    pub async fn write(&mut self, record: &MarcRecord, rejection: &Rejection) -> Result<()> {
+        let note = format!("record {} quarantined by {}", record.id, rejection);
+        self.writer.write_comment(&note).await?;
+        match marc::record_elements(&record.marc).filter(|_| marc::is_well_formed(&record.marc)) {
+            Some(records) => {
+                for element in records {
+                    self.writer.write_raw(&element).await?;
+                }
+            }
+            None => self.writer.write_raw(&cdata(record.marc.trim())).await?,
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Make everything written so far survive a crash
+    pub async fn sync(&mut self) -> Result<()> {
+        self.writer.sync().await
+    }
+
+    /// Bytes written so far
+    pub fn offset(&self) -> u64 {
+        self.writer.offset()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub async fn finalize(self) -> Result<()> {
+        self.writer.finalize().await
+    }
+}
+
+/// `text` as a CDATA section, split where it holds the `]]>` that would end one
+fn cdata(text: &str) -> String {
+    format!("<![CDATA[{}]]>", text.replace("]]>", "]]]]><![CDATA[>"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: i64, marc: &str) -> MarcRecord {
+        MarcRecord {
+            id,
+            marc: marc.to_string(),
+            rejection: None,
+            display: Vec::new(),
+            meta: None,
+            identifiers: None,
+            status: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn quarantine_file_parses_whatever_the_rows_hold() {
+        let path = std::env::temp_dir().join(format!("quarantine-{}.xml", std::process::id()));
+        let mut quarantine = Quarantine::new(path.clone(), false).await.unwrap();
+        let rejection = Rejection::new("clean", "no <record> element found");
+        let wrapped =
+            "<?xml version=\"1.0\"?>\n<collection xmlns=\"http://www.loc.gov/MARC21/slim\">\
+                       <record><leader>00000nam a2200000 a 4500</leader></record></collection>";
+        quarantine
+            .write(&record(1, wrapped), &rejection)
+            .await
+            .unwrap();
+        quarantine
+            .write(&record(2, "<record><leader>unclosed"), &rejection)
+            .await
+            .unwrap();
+        quarantine
+            .write(&record(3, "<a>]]></a"), &rejection)
+            .await
+            .unwrap();
+        quarantine.finalize().await.unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(marc::is_well_formed(&written), "{}", written);
+        assert_eq!(written.matches("<?xml").count(), 1);
+        assert_eq!(written.matches("<collection").count(), 1);
+        assert!(written.contains("<![CDATA[<record><leader>unclosed]]>"));
+        assert!(written.contains("<![CDATA[<a>]]]]><![CDATA[></a]]>"));
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{mpsc, watch, Mutex};
+
+use crate::watchdog::Watchdog;
+use crate::writer::EncodedRecord;
+
+/// Buffer in front of the writer that hands it chunks in the order they are numbered,
+/// for --ordered
+///
+/// Chunks are planned and numbered in --order-by order, but finish in whatever order the
+/// workers complete them. A finished chunk is held here until every chunk numbered before
+/// it has been handed on. A chunk only starts once it is within `window` of the oldest one
+/// not handed on yet, which caps how many are held at once.
+pub struct Reorder {
+    window: usize,
+    tx: mpsc::Sender<EncodedRecord>,
+    watchdog: Arc<Watchdog>,
+    held: Mutex<Held>,
+    /// The oldest chunk not handed on yet
+    next: watch::Sender<usize>,
+}
+
+struct Held {
+    next: usize,
+    chunks: BTreeMap<usize, Vec<EncodedRecord>>,
+}
+
+impl Reorder {
+    pub fn new(window: usize, tx: mpsc::Sender<EncodedRecord>, watchdog: Arc<Watchdog>) -> Self {
+        Self {
+            window,
+            tx,
+            watchdog,
+            held: Mutex::new(Held {
+                next: 0,
+                chunks: BTreeMap::new(),
+            }),
+            next: watch::Sender::new(0),
+        }
+    }
+
+    /// Wait until chunk `index` is close enough to the oldest chunk not handed on to start
+    pub async fn admit(&self, index: usize) {
+        let mut next = self.next.subscribe();
+        // The sender lives as long as self, so this only returns once the chunk may start
+        let _ = next.wait_for(|&next| index < next + self.window).await;
+    }
+
+    /// Hand on the records of chunk `index`, then those of every held chunk that follows
+    /// on from it; fails once the writer has stopped taking records
+    ///
+    /// A chunk that failed or was not run must still be handed on, empty, or every chunk
+    /// after it is held for good.
+    pub async fn deliver(
+        &self,
+        index: usize,
+        records: Vec<EncodedRecord>,
+    ) -> Result<(), SendError<()>> {
+        let mut held = self.held.lock().await;
+        held.chunks.insert(index, records);
+        loop {
+            let next = held.next;
+            let Some(records) = held.chunks.remove(&next) else {
+                return Ok(());
+            };
+            for record in records {
+                self.watchdog
+                    .send(&self.tx, record)
+                    .await
+                    .map_err(|_| SendError(()))?;
+            }
+            held.next += 1;
+            self.next.send_replace(held.next);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::compression::Compression;
+use crate::db::MarcRecord;
+use crate::marc;
+use crate::quarantine::Rejection;
+use crate::writer::{self, Destination, FormatWriter, Metadata, XmlEncoder, DEFAULT_BUFFER_SIZE};
+
+/// A dropped record on its way to the review file
+struct Entry {
+    id: i64,
+    reason: String,
+    /// The cleaned MARC, or `None` when it is not well-formed enough to include
+    marc: Option<String>,
+}
+
+/// Collects the records stages drop or reject into a MARCXML file catalogers can open
+///
+/// Only the first `max` records are written; later ones are only counted. Capturing is
+/// cheap when there is no --review-file.
+#[derive(Default)]
+pub struct Review {
+    tx: Mutex<Option<mpsc::UnboundedSender<Entry>>>,
+    max: u64,
+    dropped: AtomicU64,
+}
+
+impl Review {
+    /// Open the review file, returning the collector and the task writing the file
+    pub async fn open(path: PathBuf, max: u64) -> Result<(Self, JoinHandle<Result<u64>>)> {
+        let mut writer = FormatWriter::start(
+            Destination::open(
+                Some(path.clone()),
+                Compression::NONE,
+                false,
+                DEFAULT_BUFFER_SIZE,
+            )
+            .await?,
+            Arc::new(XmlEncoder::new(None, Metadata::Marc)),
+        )
+        .await?;
+        let (tx, mut rx) = mpsc::unbounded_channel::<Entry>();
+
+        let handle = tokio::spawn(async move {
+            let mut written = 0;
+            let result = async {
+                while let Some(entry) = rx.recv().await {
+                    match &entry.marc {
+                        Some(marc) => {
+                            writer
+                                .write_comment(&format!("record {} dropped by {}", entry.id, entry.reason))
+                                .await?;
+                            writer.write_raw(marc).await?;
+                        }
+                        None => {
+                            let note = format!(
+                                "record {} dropped by {}; not included, its MARC is not well-formed XML",
+                                entry.id, entry.reason
+                            );
+                            writer.write_comment(&note).await?;
+                        }
+                    }
+                    written += 1;
+                }
+                Ok::<_, anyhow::Error>(())
+            }
+            .await;
+
+            // Closed whether or not a write failed, so what was written can be opened
+            let finalized = writer.finalize().await;
+            result
+                .and(finalized)
+                .context(format!("Failed to write review file: {}", path.display()))?;
+            Ok(written)
+        });
+
+        let review = Self {
+            tx: Mutex::new(Some(tx)),
+            max,
+            dropped: AtomicU64::new(0),
+        };
+        Ok((review, handle))
+    }
+
+    /// Keep a record some stage is leaving out of the output, with the reason
+    ///
+    /// `record` is the record as the stage saw it, so a record dropped late in the pipeline
+    /// carries the changes of the stages before.
+    pub fn capture(&self, record: &MarcRecord, rejection: &Rejection) {
+        let tx = self.tx.lock().unwrap();
+        let Some(tx) = tx.as_ref() else {
+            return;
+        };
+        if self.dropped.fetch_add(1, Ordering::Relaxed) >= self.max {
+            return;
+        }
+
+        let cleaned = writer::clean_marc_xml(&record.marc);
+        let entry = Entry {
+            id: record.id,
+            reason: rejection.to_string(),
+            marc: marc::is_well_formed(&cleaned).then_some(cleaned),
+        };
+        // The writing task only stops early when the file cannot be written; it reports that
+        let _ = tx.send(entry);
+    }
+
+    /// Stop taking records, so the writing task can finish the file
+    pub fn close(&self) {
+        self.tx.lock().unwrap().take();
+    }
+
+    /// Records dropped or rejected, whether or not they fit in the file
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,286 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::info;
+
+use crate::events::{Event, ProgressEvents};
+
+/// Upper bound of `--workers auto` without an explicit one
+const AUTO_MAX: u32 = 32;
+
+/// Concurrent chunks an automatic run starts with
+const START: u32 = 2;
+
+/// How often the controller looks at the chunks finished since it last did
+const ADJUST_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Mean chunk latency over the baseline by this factor counts as the database degrading
+const DEGRADATION: f64 = 2.0;
+
+/// How much the baseline may rise in one window, so that chunks slowly getting dearer
+/// through the run (deeper offsets) are not taken for overload
+const BASELINE_DRIFT: f64 = 1.1;
+
+/// Average acquire wait that counts as the database degrading, whatever the latency
+const SLOW_ACQUIRE: Duration = Duration::from_millis(500);
+
+/// The --workers setting: a fixed count, or `auto[:max]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workers {
+    /// Always this many chunks at once
+    Fixed(u32),
+    /// Scaled by the controller between 1 and `max`
+    Auto {
+        /// The most chunks the controller may run at once
+        max: u32,
+    },
+}
+
+impl Workers {
+    /// Connections the pool needs: the count, or the most the controller may use
+    pub fn max(self) -> u32 {
+        match self {
+            Workers::Fixed(n) => n,
+            Workers::Auto { max } => max,
+        }
+    }
+
+    /// The same setting with at most `max` workers
+    pub fn capped(self, max: u32) -> Self {
+        match self {
+            Workers::Fixed(n) => Workers::Fixed(n.min(max)),
+            Workers::Auto { max: current } => Workers::Auto {
+                max: current.min(max),
+            },
+        }
+    }
+}
+
+impl FromStr for Workers {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let count = |value: &str| match value.parse::<u32>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!(
+                "expected a worker count above 0, auto, or auto:MAX, got {:?}",
+                s
+            )),
+        };
+        match s.trim().split_once(':') {
+            Some(("auto", max)) => Ok(Workers::Auto { max: count(max)? }),
+            None if s.trim() == "auto" => Ok(Workers::Auto { max: AUTO_MAX }),
+            _ => Ok(Workers::Fixed(count(s.trim())?)),
+        }
+    }
+}
+
+impl fmt::Display for Workers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Workers::Fixed(n) => write!(f, "{}", n),
+            Workers::Auto { max } => write!(f, "auto (1-{})", max),
+        }
+    }
+}
+
+/// Chunks finished since the controller last looked
+#[derive(Default)]
+struct Window {
+    chunks: u32,
+    records: u64,
+    latency: Duration,
+    wait: Duration,
+    /// Most chunks running at once
+    peak: u32,
+}
+
+struct State {
+    limit: u32,
+    running: u32,
+    /// Permits to take out of circulation as running chunks finish, after a decrease
+    debt: u32,
+    window: Window,
+    /// Typical chunk latency when the database keeps up
+    baseline: Option<f64>,
+    /// Time spent at each limit, to report where the run settled
+    time_at: Vec<Duration>,
+    since: Instant,
+}
+
+/// Additive-increase, multiplicative-decrease control of how many chunks run at once
+///
+/// Every ADJUST_INTERVAL the controller compares the mean latency of the chunks fetched
+/// in the window with a baseline, the quickest windows seen so far. While the database
+/// keeps up and the chunks use every slot, the limit goes up by one; once latency passes
+/// DEGRADATION times the baseline, or connections are slow to come, it is halved.
+pub struct Scaler {
+    max: u32,
+    semaphore: Arc<Semaphore>,
+    state: Arc<Mutex<State>>,
+    events: Arc<ProgressEvents>,
+}
+
+/// A slot for one running chunk; it goes back to the controller when dropped
+pub struct Slot {
+    permit: Option<OwnedSemaphorePermit>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.running -= 1;
+        if state.debt > 0 {
+            state.debt -= 1;
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+impl Scaler {
+    pub fn new(max: u32, events: Arc<ProgressEvents>) -> Self {
+        let limit = START.min(max);
+        info!(
+            "Workers: starting automatic scaling at {} (up to {})",
+            limit, max
+        );
+        Self {
+            max,
+            semaphore: Arc::new(Semaphore::new(limit as usize)),
+            state: Arc::new(Mutex::new(State {
+                limit,
+                running: 0,
+                debt: 0,
+                window: Window::default(),
+                baseline: None,
+                time_at: vec![Duration::ZERO; max as usize + 1],
+                since: Instant::now(),
+            })),
+            events,
+        }
+    }
+
+    /// Wait for a free slot
+    pub async fn admit(&self) -> Slot {
+        let permit = Arc::clone(&self.semaphore)
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let mut state = self.state.lock().unwrap();
+        state.running += 1;
+        state.window.peak = state.window.peak.max(state.running);
+        Slot {
+            permit: Some(permit),
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    /// A chunk of `records` was fetched in `latency` after waiting `wait` for its connection
+    pub fn record(&self, records: usize, latency: Duration, wait: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let window = &mut state.window;
+        window.chunks += 1;
+        window.records += records as u64;
+        window.latency += latency;
+        window.wait += wait;
+    }
+
+    /// Start adjusting the limit
+    pub fn spawn(self: &Arc<Self>) -> JoinHandle<()> {
+        let scaler = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(ADJUST_INTERVAL);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                scaler.adjust();
+            }
+        })
+    }
+
+    fn adjust(&self) {
+        let mut state = self.state.lock().unwrap();
+        let window = std::mem::take(&mut state.window);
+        // Chunks still running count towards the next window's peak
+        state.window.peak = state.running;
+        if window.chunks == 0 {
+            return;
+        }
+
+        let latency = window.latency.as_secs_f64() / f64::from(window.chunks);
+        let wait = window.wait / window.chunks;
+        let rate = window.records as f64 / ADJUST_INTERVAL.as_secs_f64();
+        let baseline = match state.baseline {
+            Some(baseline) => latency.min(baseline * BASELINE_DRIFT),
+            None => latency,
+        };
+        state.baseline = Some(baseline);
+
+        let limit = state.limit;
+        let measured = format!(
+            "chunk latency {}ms against a baseline of {}ms, acquire wait {}ms, {:.0} records/s",
+            (latency * 1000.0).round(),
+            (baseline * 1000.0).round(),
+            wait.as_millis(),
+            rate
+        );
+        let (next, reason) = if latency > baseline * DEGRADATION || wait > SLOW_ACQUIRE {
+            (
+                (limit / 2).max(1),
+                format!("the database is degrading ({})", measured),
+            )
+        } else if window.peak >= limit && limit < self.max {
+            (limit + 1, format!("the database keeps up ({})", measured))
+        } else {
+            (limit, measured)
+        };
+        if next == limit {
+            return;
+        }
+
+        let now = Instant::now();
+        let elapsed = now - state.since;
+        state.time_at[limit as usize] += elapsed;
+        state.since = now;
+        state.limit = next;
+        if next > limit {
+            self.semaphore.add_permits((next - limit) as usize);
+        } else {
+            // Free permits go at once; the rest as the chunks holding them finish
+            let forgotten = self.semaphore.forget_permits((limit - next) as usize) as u32;
+            state.debt += limit - next - forgotten;
+        }
+
+        let verb = if next > limit { "raising" } else { "lowering" };
+        info!("Workers: {} to {}; {}", verb, next, reason);
+        self.events.emit(Event::Workers {
+            workers: next,
+            reason,
+        });
+    }
+
+    /// The limit the run spent longest at, and the lowest and highest it reached
+    pub fn settled(&self) -> (u32, u32, u32) {
+        let state = self.state.lock().unwrap();
+        let mut time_at = state.time_at.clone();
+        time_at[state.limit as usize] += state.since.elapsed();
+
+        let used: Vec<u32> = (1..=self.max)
+            .filter(|&n| time_at[n as usize] > Duration::ZERO)
+            .collect();
+        let longest = used
+            .iter()
+            .copied()
+            .max_by_key(|&n| time_at[n as usize])
+            .unwrap_or(state.limit);
+        let lowest = used.first().copied().unwrap_or(state.limit);
+        let highest = used.last().copied().unwrap_or(state.limit);
+        (longest, lowest, highest)
+    }
+}
@@ -0,0 +1,409 @@
+use anyhow::{bail, Result};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::name::{Namespace, ResolveResult};
+use quick_xml::NsReader;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+use crate::db::MarcRecord;
+use crate::errorfile::ErrorFile;
+use crate::quarantine::Rejection;
+use crate::review::Review;
+use crate::trace::Tracer;
+use crate::validate::Validation;
+use crate::writer;
+
+/// Namespace the schema puts every element in
+const MARC_NAMESPACE: &[u8] = b"http://www.loc.gov/MARC21/slim";
+
+/// Values the schema allows for a record's type attribute
+const RECORD_TYPES: [&str; 5] = [
+    "Bibliographic",
+    "Authority",
+    "Holdings",
+    "Classification",
+    "Community",
+];
+
+/// Violations named in a record's report; the rest are counted
+const REPORTED: usize = 3;
+
+/// One way a record breaks the MARC21slim schema
+#[derive(Debug, Clone)]
+pub struct Violation {
+    /// What is wrong, worded the same for every record (e.g. missing ind2), so that
+    /// records can be counted by it
+    pub kind: &'static str,
+    /// The offending element's start tag
+    pub element: String,
+}
+
+/// Where the checker is in the record
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Inside {
+    Nothing,
+    Record,
+    Leader,
+    Control,
+    /// A data field, with the subfields seen so far
+    Data(usize),
+    Subfield,
+    /// An element the schema does not allow, whose content is not checked
+    Unknown,
+}
+
+/// Every way a cleaned MARCXML record breaks the MARC21slim schema (LoC's MARC21slim.xsd)
+///
+/// A structural check rather than a full XSD validation: element names and nesting,
+/// the namespace, the attributes each element allows and requires, and the patterns the
+/// schema gives tags, indicators, subfield codes, and the leader.
+pub fn violations(xml: &str) -> Vec<Violation> {
+    let mut reader = NsReader::from_str(xml);
+    let mut violations = Vec::new();
+    let mut open: Vec<Inside> = Vec::new();
+    let mut leader = String::new();
+    // Start tag of the open leader or data field, for reporting what is wrong with it
+    let mut field = String::new();
+
+    loop {
+        let (namespace, event) = match reader.read_resolved_event() {
+            Ok(resolved) => resolved,
+            Err(_) => {
+                violations.push(Violation {
+                    kind: "not well-formed XML",
+                    element: "<record>".to_string(),
+                });
+                return violations;
+            }
+        };
+        let parent = open.last().copied().unwrap_or(Inside::Nothing);
+        match &event {
+            Event::Start(e) | Event::Empty(e) => {
+                let element = start_tag(e);
+                let inside = if parent == Inside::Unknown {
+                    Inside::Unknown
+                } else {
+                    match (parent, e.local_name().as_ref()) {
+                        (Inside::Nothing, b"record") => Inside::Record,
+                        (Inside::Record, b"leader") => Inside::Leader,
+                        (Inside::Record, b"controlfield") => Inside::Control,
+                        (Inside::Record, b"datafield") => Inside::Data(0),
+                        (Inside::Data(_), b"subfield") => Inside::Subfield,
+                        _ => {
+                            violations.push(Violation {
+                                kind: "unexpected element",
+                                element: element.clone(),
+                            });
+                            Inside::Unknown
+                        }
+                    }
+                };
+                if inside != Inside::Unknown {
+                    if !matches!(namespace, ResolveResult::Bound(Namespace(MARC_NAMESPACE))) {
+                        violations.push(Violation {
+                            kind: "wrong namespace",
+                            element: element.clone(),
+                        });
+                    }
+                    check_attributes(e, inside, &element, &mut violations);
+                }
+                if let (Inside::Data(subfields), Inside::Subfield) = (parent, inside) {
+                    *open.last_mut().unwrap() = Inside::Data(subfields + 1);
+                }
+                if matches!(inside, Inside::Leader | Inside::Data(_)) {
+                    leader.clear();
+                    field = element;
+                }
+
+                if matches!(event, Event::Start(_)) {
+                    open.push(inside);
+                } else {
+                    close(inside, &leader, &field, &mut violations);
+                }
+            }
+            Event::End(_) => {
+                if let Some(inside) = open.pop() {
+                    close(inside, &leader, &field, &mut violations);
+                }
+            }
+            Event::Text(text) => {
+                let Ok(text) = text.unescape() else {
+                    violations.push(Violation {
+                        kind: "not well-formed XML",
+                        element: "<record>".to_string(),
+                    });
+                    return violations;
+                };
+                match parent {
+                    Inside::Leader => leader.push_str(&text),
+                    Inside::Record | Inside::Data(_) if !text.trim().is_empty() => {
+                        let element = match parent {
+                            Inside::Data(_) => field.clone(),
+                            _ => "<record>".to_string(),
+                        };
+                        violations.push(Violation {
+                            kind: "text outside a field",
+                            element,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            Event::CData(data) if parent == Inside::Leader => {
+                leader.push_str(&String::from_utf8_lossy(data));
+            }
+            Event::Eof => return violations,
+            _ => {}
+        }
+    }
+}
+
+/// Check what can only be known once an element is closed
+fn close(inside: Inside, leader: &str, field: &str, violations: &mut Vec<Violation>) {
+    match inside {
+        Inside::Leader if !is_leader(leader) => violations.push(Violation {
+            kind: "invalid leader",
+            element: field.to_string(),
+        }),
+        Inside::Data(0) => violations.push(Violation {
+            kind: "datafield without subfields",
+            element: field.to_string(),
+        }),
+        _ => {}
+    }
+}
+
+/// Check the attributes of an element the schema allows where it is
+fn check_attributes(
+    e: &BytesStart,
+    inside: Inside,
+    element: &str,
+    violations: &mut Vec<Violation>,
+) {
+    let mut found = |kind: &'static str| {
+        violations.push(Violation {
+            kind,
+            element: element.to_string(),
+        })
+    };
+    let attribute = |name: &str| -> Option<String> {
+        let value = e.try_get_attribute(name).ok()??;
+        Some(
+            value
+                .unescape_value()
+                .map(|v| v.into_owned())
+                .unwrap_or_default(),
+        )
+    };
+
+    let allowed: &[&[u8]] = match inside {
+        Inside::Record => &[b"id", b"type"],
+        Inside::Leader => &[b"id"],
+        Inside::Control => &[b"id", b"tag"],
+        Inside::Data(_) => &[b"id", b"tag", b"ind1", b"ind2"],
+        Inside::Subfield => &[b"id", b"code"],
+        Inside::Nothing | Inside::Unknown => return,
+    };
+    // Namespace declarations and namespaced attributes such as xsi:schemaLocation are left
+    // to the schemas that define them
+    let unexpected = e.attributes().flatten().any(|a| {
+        a.key.prefix().is_none() && a.key.as_ref() != b"xmlns" && !allowed.contains(&a.key.as_ref())
+    });
+    if unexpected {
+        found("unexpected attribute");
+    }
+
+    match inside {
+        Inside::Record
+            if attribute("type").is_some_and(|t| !RECORD_TYPES.contains(&t.as_str())) =>
+        {
+            found("invalid record type");
+        }
+        Inside::Control => match attribute("tag") {
+            None => found("missing tag"),
+            Some(tag) if !is_control_tag(&tag) => found("invalid controlfield tag"),
+            Some(_) => {}
+        },
+        Inside::Data(_) => {
+            match attribute("tag") {
+                None => found("missing tag"),
+                Some(tag) if !is_data_tag(&tag) => found("invalid datafield tag"),
+                Some(_) => {}
+            }
+            for (name, missing, invalid) in [
+                ("ind1", "missing ind1", "invalid ind1"),
+                ("ind2", "missing ind2", "invalid ind2"),
+            ] {
+                match attribute(name) {
+                    None => found(missing),
+                    Some(value) if !is_indicator(&value) => found(invalid),
+                    Some(_) => {}
+                }
+            }
+        }
+        Inside::Subfield => match attribute("code") {
+            None => found("missing code"),
+            Some(code) if !is_subfield_code(&code) => found("invalid code"),
+            Some(_) => {}
+        },
+        _ => {}
+    }
+}
+
+/// An element's start tag as the record has it
+fn start_tag(e: &BytesStart) -> String {
+    format!("<{}>", String::from_utf8_lossy(e))
+}
+
+/// 00 followed by a digit other than 0 or a letter
+fn is_control_tag(tag: &str) -> bool {
+    matches!(tag.as_bytes(), [b'0', b'0', c] if *c != b'0' && c.is_ascii_alphanumeric())
+}
+
+/// Three digits or letters, all upper case or all lower, not starting 00
+fn is_data_tag(tag: &str) -> bool {
+    let [a, b, c] = tag.as_bytes() else {
+        return false;
+    };
+    let upper = [a, b, c]
+        .iter()
+        .all(|ch| ch.is_ascii_digit() || ch.is_ascii_uppercase());
+    let lower = [a, b, c]
+        .iter()
+        .all(|ch| ch.is_ascii_digit() || ch.is_ascii_lowercase());
+    (upper || lower) && !(*a == b'0' && *b == b'0')
+}
+
+/// One digit, lower-case letter, or blank
+fn is_indicator(value: &str) -> bool {
+    matches!(value.as_bytes(), [c] if c.is_ascii_digit() || c.is_ascii_lowercase() || *c == b' ')
+}
+
+/// One letter, digit, or one of the punctuation marks the schema lists
+fn is_subfield_code(code: &str) -> bool {
+    matches!(code.as_bytes(), [c] if c.is_ascii_alphanumeric() || b"!\"#$%&'()*+,-./:;<=>?{}_^`~[]\\".contains(c))
+}
+
+/// 24 characters, with the digits, blanks, and fixed values the schema's pattern asks for
+fn is_leader(leader: &str) -> bool {
+    let bytes = leader.as_bytes();
+    if bytes.len() != 24 {
+        return false;
+    }
+    let digit_or_blank = |c: &u8| c.is_ascii_digit() || *c == b' ';
+    let code_or_blank = |c: &u8| c.is_ascii_alphanumeric() || *c == b' ';
+    bytes[0..5].iter().all(digit_or_blank)
+        && code_or_blank(&bytes[5])
+        && bytes[6].is_ascii_alphanumeric()
+        && bytes[7..10].iter().all(code_or_blank)
+        && matches!(bytes[10], b'2' | b' ')
+        && matches!(bytes[11], b'2' | b' ')
+        && bytes[12..17].iter().all(digit_or_blank)
+        && bytes[17..20].iter().all(code_or_blank)
+        && matches!(&bytes[20..24], b"4500" | b"    ")
+}
+
+/// Checks in the workers that each record fits the MARC21slim schema once cleaned, and
+/// counts the records that do not by what is wrong with them
+pub struct SchemaCheck {
+    validation: Option<Validation>,
+    quarantine: bool,
+    pub ids: Mutex<Vec<i64>>,
+    /// Records with each kind of violation
+    kinds: Mutex<BTreeMap<&'static str, u64>>,
+    tracer: Arc<Tracer>,
+    review: Arc<Review>,
+    error_file: Arc<ErrorFile>,
+}
+
+impl SchemaCheck {
+    pub fn new(
+        validation: Option<Validation>,
+        quarantine: bool,
+        tracer: Arc<Tracer>,
+        review: Arc<Review>,
+        error_file: Arc<ErrorFile>,
+    ) -> Self {
+        Self {
+            validation,
+            quarantine,
+            ids: Mutex::new(Vec::new()),
+            kinds: Mutex::new(BTreeMap::new()),
+            tracer,
+            review,
+            error_file,
+        }
+    }
+
+    /// Decide what happens to a record; `None` means it is dropped
+    pub fn screen(&self, mut record: MarcRecord) -> Result<Option<MarcRecord>> {
+        let Some(validation) = self.validation else {
+            return Ok(Some(record));
+        };
+        if record.rejection.is_some() {
+            return Ok(Some(record));
+        }
+        let violations = violations(&writer::clean_marc_xml(&record.marc));
+        if violations.is_empty() {
+            return Ok(Some(record));
+        }
+
+        let kinds: BTreeSet<&'static str> = violations.iter().map(|v| v.kind).collect();
+        {
+            let mut counts = self.kinds.lock().unwrap();
+            for kind in kinds {
+                *counts.entry(kind).or_default() += 1;
+            }
+        }
+        let mut reason: Vec<String> = violations
+            .iter()
+            .take(REPORTED)
+            .map(|v| format!("{} on {}", v.kind, v.element))
+            .collect();
+        if violations.len() > REPORTED {
+            reason.push(format!("and {} more", violations.len() - REPORTED));
+        }
+        let reason = format!("breaks MARC21slim: {}", reason.join("; "));
+        self.tracer.note(record.id, "schema", || reason.clone());
+        self.ids.lock().unwrap().push(record.id);
+
+        match validation {
+            Validation::Strict => bail!("Record {} {} (--schema-strict)", record.id, reason),
+            Validation::Skip if self.quarantine => {
+                record.rejection = Some(Rejection::new("schema", reason));
+                Ok(Some(record))
+            }
+            Validation::Skip => {
+                warn!(
+                    record_id = record.id,
+                    error_kind = "schema",
+                    "Skipping record {}: {}",
+                    record.id,
+                    reason
+                );
+                let rejection = Rejection::new("schema", reason);
+                self.review.capture(&record, &rejection);
+                self.error_file.rejected(record.id, &rejection);
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn validation(&self) -> Option<Validation> {
+        self.validation
+    }
+
+    /// Records with each kind of violation, the most common first
+    pub fn kinds(&self) -> Vec<(&'static str, u64)> {
+        let mut kinds: Vec<_> = self
+            .kinds
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&k, &n)| (k, n))
+            .collect();
+        kinds.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        kinds
+    }
+}
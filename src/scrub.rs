@@ -0,0 +1,120 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::db::MarcRecord;
+use crate::marc;
+use crate::writer;
+
+/// First line of every --scrub-report
+const HEADER: &str = "id,removed,codepoints";
+/// Takes the characters XML 1.0 does not allow out of each record the workers hand on,
+/// counting the records it changes, and lists them in the --scrub-report if there is one
+///
+/// Stored records converted long ago can hold raw control characters (0x01, 0x1F) that
+/// would leave the whole output unparseable. --no-scrub turns this off, for output that
+/// keeps the records byte for byte. A failed write to the report stops it there and fails
+/// the run once it ends, as for the --error-file.
+pub struct Scrubber {
+    enabled: bool,
+    touched: AtomicU64,
+    report: Option<Mutex<Report>>,
+}
+
+struct Report {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    /// The write that failed, after which nothing more is written
+    failed: Option<std::io::Error>,
+}
+
+impl Scrubber {
+    /// A scrubber that does nothing when not `enabled`, and lists what it removes in
+    /// `report`
+    pub fn new(enabled: bool, report: Option<&Path>) -> Result<Self> {
+        let report = match report {
+            Some(path) => {
+                let context = || format!("Failed to create scrub report: {}", path.display());
+                let mut writer = BufWriter::new(File::create(path).with_context(context)?);
+                writeln!(writer, "{}", HEADER).with_context(context)?;
+                Some(Mutex::new(Report {
+                    writer,
+                    path: path.to_path_buf(),
+                    failed: None,
+                }))
+            }
+            None => None,
+        };
+        Ok(Self {
+            enabled,
+            touched: AtomicU64::new(0),
+            report,
+        })
+    }
+
+    /// Scrub a record about to be written; the code points removed, if any
+    pub fn scrub(&self, record: &mut MarcRecord) -> Option<Vec<u32>> {
+        if !self.enabled || record.rejection.is_some() {
+            return None;
+        }
+        let (scrubbed, removed) = marc::scrub_illegal_chars(&record.marc)?;
+        record.marc = scrubbed;
+        self.touched.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(report) = &self.report {
+            let mut report = report.lock().unwrap();
+            if report.failed.is_none() {
+                if let Err(e) = writeln!(
+                    report.writer,
+                    "{},{},{}",
+                    record.id,
+                    removed.len(),
+                    codepoints(&removed)
+                ) {
+                    report.failed = Some(e);
+                }
+            }
+        }
+        Some(removed)
+    }
+
+    /// Records that had characters removed
+    pub fn touched(&self) -> u64 {
+        self.touched.load(Ordering::Relaxed)
+    }
+
+    /// Flush the report, and sync it to disk when `durable`
+    pub fn finish(&self, durable: bool) -> Result<()> {
+        let Some(report) = &self.report else {
+            return Ok(());
+        };
+        let mut report = report.lock().unwrap();
+        let context = format!("Failed to write scrub report: {}", report.path.display());
+        if let Some(e) = report.failed.take() {
+            return Err(e).context(context);
+        }
+        report.writer.flush().context(context.clone())?;
+        if durable {
+            report.writer.get_ref().sync_all().context(context)?;
+            writer::sync_directory_of(&report.path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Each distinct code point, as U+001F, in the order first removed
+pub fn codepoints(removed: &[u32]) -> String {
+    let mut seen = Vec::new();
+    for &code in removed {
+        if !seen.contains(&code) {
+            seen.push(code);
+        }
+    }
+    seen.iter()
+        .map(|code| format!("U+{:04X}", code))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,90 @@
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::{ConnectOptions, Connection, Executor, PgConnection};
+use std::sync::Arc;
+
+/// Setup run on a freshly opened connection
+pub type SessionHook = Arc<
+    dyn for<'c> Fn(&'c mut PgConnection) -> BoxFuture<'c, Result<(), sqlx::Error>> + Send + Sync,
+>;
+
+/// Per-connection setup applied to every pooled connection before its first use
+///
+/// Options that need a session setting are added here as hooks, so that they run in
+/// the order given and compose with --session-sql.
+#[derive(Clone, Default)]
+pub struct SessionSetup {
+    hooks: Vec<SessionHook>,
+    descriptions: Vec<String>,
+}
+
+impl SessionSetup {
+    /// Run `sql`, which may hold several statements, on each connection
+    pub fn sql(self, sql: &str) -> Self {
+        let sql = Arc::new(sql.to_string());
+        let description = sql.to_string();
+        self.hook(description, move |conn| {
+            let sql = Arc::clone(&sql);
+            Box::pin(async move {
+                conn.execute(sqlx::raw_sql(&sql)).await?;
+                Ok(())
+            })
+        })
+    }
+
+    /// Run `hook` on each connection; `description` is what the log shows for it
+    pub fn hook<F>(mut self, description: impl Into<String>, hook: F) -> Self
+    where
+        F: for<'c> Fn(&'c mut PgConnection) -> BoxFuture<'c, Result<(), sqlx::Error>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.hooks.push(Arc::new(hook));
+        self.descriptions.push(description.into());
+        self
+    }
+
+    pub fn descriptions(&self) -> &[String] {
+        &self.descriptions
+    }
+
+    /// Run the setup once on a connection of its own, so a mistake fails startup with
+    /// the database's error rather than as a pool timeout
+    pub async fn check(&self, options: &PgConnectOptions) -> Result<()> {
+        if self.hooks.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = options
+            .connect()
+            .await
+            .context("Failed to connect to database")?;
+        for (hook, description) in self.hooks.iter().zip(&self.descriptions) {
+            hook(&mut conn)
+                .await
+                .context(format!("Session setup failed: {}", description))?;
+        }
+        conn.close().await?;
+        Ok(())
+    }
+
+    /// Install the setup as the pool's after_connect callback
+    pub fn apply(self, options: PgPoolOptions) -> PgPoolOptions {
+        if self.hooks.is_empty() {
+            return options;
+        }
+
+        let hooks = Arc::new(self.hooks);
+        options.after_connect(move |conn, _meta| {
+            let hooks = Arc::clone(&hooks);
+            Box::pin(async move {
+                for hook in hooks.iter() {
+                    hook(&mut *conn).await?;
+                }
+                Ok(())
+            })
+        })
+    }
+}
@@ -0,0 +1,582 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, PgConnection, Row};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+use crate::checkpoint::OutputPoint;
+use crate::compression::Compression;
+use crate::csv::CsvEncoder;
+use crate::db::MarcRecord;
+use crate::extractor::DynSink;
+use crate::fields::FieldSpec;
+use crate::idfile::IdEncoder;
+use crate::iso2709::Iso2709Encoder;
+use crate::marcjson::MarcJsonEncoder;
+use crate::mods::ModsEncoder;
+use crate::mrk::MrkEncoder;
+use crate::ndjson::{NdjsonBody, NdjsonEncoder};
+use crate::split::SplitWriter;
+use crate::writer::{
+    prepare_record, ByteCounter, Destination, Encoded, Encoder, FormatWriter, Metadata, OutputFull,
+    TornBatch, WriteOutcome, XmlEncoder,
+};
+
+/// How records are encoded on --output or stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// A MARCXML collection
+    Xml,
+    /// Binary MARC 21 (ISO 2709), for MarcEdit, yaz-marcdump, and other MARC tools
+    Marc21,
+    /// MARC-in-JSON: an array of records with leader and fields, for Elasticsearch and
+    /// other JSON loaders
+    Json,
+    /// MODS 3.7: a modsCollection mapped from the main MARC fields, for digital
+    /// repositories that ingest MODS
+    Mods,
+    /// Simple Dublin Core: an oai_dc record mapped from each, for OAI harvesters that only
+    /// take oai_dc; a flat collection, or an OAI-PMH response with --envelope oai
+    Dc,
+    /// CSV with a header row: each record's id, then the columns of --fields, for
+    /// spreadsheets and reports
+    Csv,
+    /// Newline-delimited JSON: one object per line with the record's id, edit date, and
+    /// MARC (see --ndjson-body), for bulk loading into search engines
+    Ndjson,
+    /// MarcEdit's mnemonic text (.mrk), for catalogers to read, or open in MarcEdit
+    Mrk,
+    /// Record ids alone, one per line; chosen with --ids-only rather than --format
+    #[value(skip)]
+    Ids,
+}
+
+/// What --format xml wraps its records in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Envelope {
+    /// A MARC collection element
+    Collection,
+    /// An OAI-PMH ListRecords response, each record under a header with its identifier
+    /// and datestamp
+    Oai,
+}
+
+/// How records are written to --output or stdout
+#[derive(Debug, Clone)]
+pub struct OutputOptions {
+    pub format: OutputFormat,
+    pub compression: Compression,
+    pub escape_non_ascii: bool,
+    /// Write XML records one element per line, indented
+    pub pretty: bool,
+    pub durable: bool,
+    /// Write each file as NAME.part, given its name once the run succeeds
+    pub atomic: bool,
+    /// Bytes gathered before each write to an output file
+    pub buffer_size: usize,
+    /// Start a new numbered file after this many records
+    pub records_per_file: Option<u64>,
+    /// Start a new numbered file before a record that would take the current one past
+    /// this many bytes
+    pub max_file_bytes: Option<u64>,
+    /// Number of the first file of a split output; a --continue-from batch carries on
+    /// from the one before
+    pub first_file: u64,
+    /// Write an OAI-PMH response instead of a collection, identifying records by this
+    /// prefix and their id
+    pub oai_prefix: Option<String>,
+    /// Columns of --format csv, after the record id
+    pub fields: Option<FieldSpec>,
+    /// What --format csv joins the values of repeated fields with
+    pub field_separator: String,
+    /// What --format ndjson carries each record as
+    pub ndjson_body: NdjsonBody,
+    /// Counts the bytes written out, after any compression, across every file
+    pub written: Option<ByteCounter>,
+}
+
+impl OutputFormat {
+    /// What an XML format holds for each record
+    fn metadata(self) -> Metadata {
+        match self {
+            OutputFormat::Dc => Metadata::DublinCore,
+            _ => Metadata::Marc,
+        }
+    }
+}
+
+impl OutputOptions {
+    /// Whether --output is written as numbered files
+    pub fn split(&self) -> bool {
+        self.records_per_file.is_some() || self.max_file_bytes.is_some()
+    }
+
+    /// The --fields spec, which --format csv cannot do without
+    fn csv_spec(&self) -> Result<FieldSpec> {
+        self.fields.clone().context("--format csv needs --fields")
+    }
+}
+
+/// Where extracted records are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SinkKind {
+    /// Each --format, written to --output or stdout
+    #[value(name = "xml")]
+    File,
+    /// Rows in a PostgreSQL table (--sink-url, --sink-table)
+    Postgres,
+}
+
+/// What the postgres sink does with a record whose id is already in the table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OnConflict {
+    /// Overwrite the existing row
+    Replace,
+    /// Keep the existing row
+    Skip,
+    /// Fail the batch, which stops the run
+    Fail,
+}
+
+/// Settings for writing into a PostgreSQL table
+#[derive(Debug, Clone)]
+pub struct PostgresSinkConfig {
+    pub url: String,
+    pub table: String,
+    pub on_conflict: OnConflict,
+    pub batch_size: usize,
+}
+
+/// A batch that could not be written; the run stops rather than leave a hole in the table
+#[derive(Debug)]
+pub struct BatchFailed {
+    pub first_id: i64,
+    pub last_id: i64,
+    pub table: String,
+}
+
+impl std::fmt::Display for BatchFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Failed to write records {}..{} to {}",
+            self.first_id, self.last_id, self.table
+        )
+    }
+}
+
+/// Whether a write error means no further record can be written
+pub fn is_fatal(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<BatchFailed>().is_some()
+        || e.downcast_ref::<OutputFull>().is_some()
+        || e.downcast_ref::<TornBatch>().is_some()
+}
+
+/// Destination the writer task hands records to
+pub enum RecordSink {
+    Format(FormatWriter),
+    Split(SplitWriter),
+    Postgres(PostgresSink),
+    /// The application's own sink, given to [`crate::Extractor::run`]
+    Custom(Box<dyn DynSink>),
+}
+
+impl RecordSink {
+    /// Get ready for `record`, opening the next file of a split output if it is due; a
+    /// split output takes the `encoded` bytes here
+    pub async fn prepare(
+        &mut self,
+        record: &MarcRecord,
+        encoded: &mut Option<Encoded>,
+    ) -> Result<()> {
+        match self {
+            RecordSink::Split(writer) => writer.prepare(record, encoded.take()).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Write a record, as `encoded` when a worker has encoded it for a file output
+    pub async fn write_record(
+        &mut self,
+        record: &MarcRecord,
+        encoded: Option<Encoded>,
+    ) -> Result<WriteOutcome> {
+        match self {
+            RecordSink::Format(writer) => match encoded {
+                Some(encoded) => writer.write(encoded).await,
+                None => writer.write_record(record).await,
+            },
+            RecordSink::Split(writer) => writer.write_record(record, encoded).await,
+            RecordSink::Postgres(sink) => sink.write_record(record).await,
+            RecordSink::Custom(sink) => sink.write_record(record).await,
+        }
+    }
+
+    /// Where the next record will be written, for --trace-record
+    pub fn position(&self) -> String {
+        match self {
+            RecordSink::Format(writer) => writer.position(),
+            RecordSink::Split(writer) => writer.position(),
+            RecordSink::Postgres(sink) => {
+                format!(
+                    "{} in a batch of {}",
+                    sink.config.table,
+                    sink.batch.len() + 1
+                )
+            }
+            RecordSink::Custom(_) => CUSTOM_TARGET.to_string(),
+        }
+    }
+
+    /// Where records end up, for --provenance-db
+    pub fn target(&self) -> String {
+        match self {
+            RecordSink::Format(writer) => writer.target(),
+            RecordSink::Split(writer) => writer.target(),
+            RecordSink::Postgres(sink) => sink.config.table.clone(),
+            RecordSink::Custom(_) => CUSTOM_TARGET.to_string(),
+        }
+    }
+
+    /// Byte offset the next record lands at, where the destination has one
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            RecordSink::Format(writer) => Some(writer.offset()),
+            RecordSink::Split(writer) => Some(writer.offset()),
+            RecordSink::Postgres(_) | RecordSink::Custom(_) => None,
+        }
+    }
+
+    /// Make every record written so far survive a crash, for --checkpoint
+    pub async fn sync(&mut self) -> Result<()> {
+        match self {
+            RecordSink::Format(writer) => writer.sync().await,
+            RecordSink::Split(writer) => writer.sync().await,
+            RecordSink::Postgres(_) | RecordSink::Custom(_) => Ok(()),
+        }
+    }
+
+    /// Write out the records gathered so far, at the end of a batch; the Postgres sink
+    /// keeps to its own --sink-batch-size
+    pub async fn flush(&mut self) -> Result<()> {
+        match self {
+            RecordSink::Format(writer) => writer.flush().await,
+            RecordSink::Split(writer) => writer.flush().await,
+            RecordSink::Postgres(_) | RecordSink::Custom(_) => Ok(()),
+        }
+    }
+
+    /// Where the writer has got to in a file output, for --checkpoint
+    pub fn point(&self) -> Option<OutputPoint> {
+        match self {
+            RecordSink::Format(writer) => Some(OutputPoint {
+                file: None,
+                records_in_file: 0,
+                offset: writer.offset(),
+            }),
+            RecordSink::Split(writer) => Some(writer.point()),
+            RecordSink::Postgres(_) | RecordSink::Custom(_) => None,
+        }
+    }
+
+    pub async fn finalize(self) -> Result<()> {
+        match self {
+            RecordSink::Format(writer) => writer.finalize().await,
+            RecordSink::Split(writer) => writer.finalize().await,
+            RecordSink::Postgres(sink) => sink.finalize().await,
+            RecordSink::Custom(sink) => sink.finish().await,
+        }
+    }
+
+    /// Files written so far, when the output is split by --records-per-file or
+    /// --max-file-bytes
+    pub fn files(&self) -> Option<u64> {
+        match self {
+            RecordSink::Split(writer) => Some(writer.files()),
+            _ => None,
+        }
+    }
+}
+
+/// What the trace and provenance give as the destination of records written to
+/// [`RecordSink::Custom`]
+pub(crate) const CUSTOM_TARGET: &str = "the application's sink";
+
+/// Staging table each batch is copied into before being merged into the target
+const STAGE_TABLE: &str = "marc_extractor_stage";
+
+/// Writes records into a table with binary COPY, one transaction per batch
+pub struct PostgresSink {
+    conn: PgConnection,
+    config: PostgresSinkConfig,
+    table: String,
+    batch: Vec<(i64, String)>,
+}
+
+impl PostgresSink {
+    /// Connect, then create the target table or check that an existing one fits
+    pub async fn new(config: PostgresSinkConfig) -> Result<Self> {
+        let table = quote_table(&config.table)?;
+        let mut conn = PgConnection::connect(&config.url)
+            .await
+            .context("Failed to connect to sink database")?;
+
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (id bigint PRIMARY KEY, marc xml NOT NULL, exported_at timestamptz NOT NULL DEFAULT now())",
+            table
+        ))
+        .execute(&mut conn)
+        .await
+        .context(format!("Failed to create sink table {}", config.table))?;
+
+        let columns = sqlx::query(
+            "SELECT attname::text, format_type(atttypid, atttypmod) FROM pg_attribute \
+             WHERE attrelid = $1::regclass AND attnum > 0 AND NOT attisdropped",
+        )
+        .bind(&table)
+        .fetch_all(&mut conn)
+        .await
+        .context(format!("Failed to inspect sink table {}", config.table))?;
+
+        for (name, kind) in [
+            ("id", "bigint"),
+            ("marc", "xml"),
+            ("exported_at", "timestamp with time zone"),
+        ] {
+            let found = columns
+                .iter()
+                .any(|row| row.get::<String, _>(0) == name && row.get::<String, _>(1) == kind);
+            if !found {
+                bail!(
+                    "Sink table {} has no {} column of type {}",
+                    config.table,
+                    name,
+                    kind
+                );
+            }
+        }
+
+        sqlx::query(&format!(
+            "CREATE TEMP TABLE {} (id bigint, marc text, seq bigserial) ON COMMIT DELETE ROWS",
+            STAGE_TABLE
+        ))
+        .execute(&mut conn)
+        .await
+        .context("Failed to create sink staging table")?;
+
+        info!("Writing records to table {}", config.table);
+
+        Ok(Self {
+            conn,
+            table,
+            batch: Vec::with_capacity(config.batch_size),
+            config,
+        })
+    }
+
+    /// Queue a record, writing the batch once it is full
+    ///
+    /// A queued record counts as written; if its batch later fails the run stops.
+    pub async fn write_record(&mut self, record: &MarcRecord) -> Result<WriteOutcome> {
+        let cleaned_marc = match prepare_record(record) {
+            Ok(cleaned) => cleaned,
+            Err(rejection) => return Ok(WriteOutcome::Rejected(rejection)),
+        };
+
+        self.batch.push((record.id, cleaned_marc.into_owned()));
+        if self.batch.len() >= self.config.batch_size {
+            self.flush().await?;
+        }
+
+        Ok(WriteOutcome::Written)
+    }
+
+    pub async fn finalize(mut self) -> Result<()> {
+        self.flush().await?;
+        self.conn.close().await?;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        // Records arrive from several workers, so the batch is not in id order
+        let ids = self.batch.iter().map(|(id, _)| *id);
+        let (Some(first_id), Some(last_id)) = (ids.clone().min(), ids.max()) else {
+            return Ok(());
+        };
+        let failed = BatchFailed {
+            first_id,
+            last_id,
+            table: self.config.table.clone(),
+        };
+        debug!(
+            "Writing {} records ({}..{}) to {}",
+            self.batch.len(),
+            failed.first_id,
+            failed.last_id,
+            failed.table
+        );
+
+        let merge = match self.config.on_conflict {
+            OnConflict::Replace => {
+                " ON CONFLICT (id) DO UPDATE SET marc = EXCLUDED.marc, exported_at = EXCLUDED.exported_at"
+            }
+            OnConflict::Skip => " ON CONFLICT (id) DO NOTHING",
+            OnConflict::Fail => "",
+        };
+        // One row per id, the last staged, since ON CONFLICT cannot touch a row twice in one
+        // statement; --multi-record-rows emit-all gives an id a record for each in its row
+        let insert = format!(
+            "INSERT INTO {} (id, marc, exported_at) \
+             SELECT DISTINCT ON (id) id, marc::xml, now() FROM {} ORDER BY id, seq DESC{}",
+            self.table, STAGE_TABLE, merge
+        );
+        let data = encode_copy_binary(&self.batch);
+
+        let result: Result<()> = async {
+            let mut tx = self.conn.begin().await?;
+            let mut copy = tx
+                .copy_in_raw(&format!(
+                    "COPY {} (id, marc) FROM STDIN (FORMAT binary)",
+                    STAGE_TABLE
+                ))
+                .await?;
+            copy.send(data).await?;
+            copy.finish().await?;
+            sqlx::query(&insert).execute(&mut *tx).await?;
+            tx.commit().await?;
+            Ok(())
+        }
+        .await;
+
+        self.batch.clear();
+        result.context(failed)
+    }
+}
+
+/// Quote a `table` or `schema.table` name as SQL identifiers
+fn quote_table(name: &str) -> Result<String> {
+    let parts: Vec<&str> = name.split('.').collect();
+    if parts.len() > 2 || parts.iter().any(|p| p.is_empty()) {
+        bail!(
+            "Invalid sink table name: {:?} (expected table or schema.table)",
+            name
+        );
+    }
+
+    Ok(parts
+        .iter()
+        .map(|p| format!("\"{}\"", p.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join("."))
+}
+
+/// Encode `(id bigint, marc text)` rows in PostgreSQL's binary COPY format
+fn encode_copy_binary(rows: &[(i64, String)]) -> Vec<u8> {
+    let size: usize = rows.iter().map(|(_, marc)| 18 + marc.len()).sum();
+    let mut buf = Vec::with_capacity(21 + size);
+
+    // Signature, flags, header extension length
+    buf.extend_from_slice(b"PGCOPY\n\xff\r\n\0");
+    buf.extend_from_slice(&0i32.to_be_bytes());
+    buf.extend_from_slice(&0i32.to_be_bytes());
+
+    for (id, marc) in rows {
+        buf.extend_from_slice(&2i16.to_be_bytes());
+        buf.extend_from_slice(&8i32.to_be_bytes());
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(&(marc.len() as i32).to_be_bytes());
+        buf.extend_from_slice(marc.as_bytes());
+    }
+
+    // Trailer
+    buf.extend_from_slice(&(-1i16).to_be_bytes());
+    buf
+}
+
+/// Open the postgres sink if one is configured, otherwise the output in `options`
+pub async fn open(
+    output: Option<PathBuf>,
+    options: OutputOptions,
+    postgres: Option<PostgresSinkConfig>,
+) -> Result<RecordSink> {
+    match (postgres, options.split(), output) {
+        (Some(config), _, _) => Ok(RecordSink::Postgres(PostgresSink::new(config).await?)),
+        (None, true, Some(template)) => Ok(RecordSink::Split(
+            SplitWriter::open(template, options).await?,
+        )),
+        (None, _, output) => Ok(RecordSink::Format(open_writer(output, options).await?)),
+    }
+}
+
+/// Reopen the file output a checkpointed run was writing, at the point it recorded
+pub async fn resume(
+    output: PathBuf,
+    options: OutputOptions,
+    point: OutputPoint,
+) -> Result<RecordSink> {
+    match (options.split(), point.file) {
+        (true, Some(_)) => Ok(RecordSink::Split(
+            SplitWriter::resume(output, options, point).await?,
+        )),
+        _ => Ok(RecordSink::Format(
+            resume_writer(output, options, point.offset).await?,
+        )),
+    }
+}
+
+/// The encoder for `options.format`
+pub fn encoder(options: &OutputOptions) -> Result<Arc<dyn Encoder>> {
+    Ok(match options.format {
+        OutputFormat::Xml | OutputFormat::Dc => Arc::new(
+            XmlEncoder::new(options.oai_prefix.clone(), options.format.metadata())
+                .escape_non_ascii(options.escape_non_ascii)
+                .pretty(options.pretty),
+        ),
+        OutputFormat::Marc21 => Arc::new(Iso2709Encoder),
+        OutputFormat::Json => Arc::new(MarcJsonEncoder),
+        OutputFormat::Mods => Arc::new(ModsEncoder::new().pretty(options.pretty)),
+        OutputFormat::Csv => Arc::new(CsvEncoder::new(
+            options.csv_spec()?,
+            options.field_separator.clone(),
+        )),
+        OutputFormat::Ndjson => Arc::new(NdjsonEncoder::new(options.ndjson_body)),
+        OutputFormat::Mrk => Arc::new(MrkEncoder),
+        OutputFormat::Ids => Arc::new(IdEncoder),
+    })
+}
+
+/// The writer for `options.format` on one file, or stdout
+pub async fn open_writer(output: Option<PathBuf>, options: OutputOptions) -> Result<FormatWriter> {
+    let encoder = encoder(&options)?;
+    let out = Destination::open(
+        output,
+        options.compression,
+        options.atomic,
+        options.buffer_size,
+    )
+    .await?;
+    let out = out.durable(options.durable).count_bytes(options.written);
+    FormatWriter::start(out, encoder).await
+}
+
+/// The writer for `options.format` on a file a checkpointed run was writing, picking up
+/// after its first `offset` bytes
+pub async fn resume_writer(
+    path: PathBuf,
+    options: OutputOptions,
+    offset: u64,
+) -> Result<FormatWriter> {
+    let encoder = encoder(&options)?;
+    let out = Destination::resume(path, offset, options.atomic, options.buffer_size).await?;
+    let out = out.durable(options.durable).count_bytes(options.written);
+    Ok(FormatWriter::resume(out, encoder))
+}
+
+/// A sink that measures the output it is given and keeps none of it, for --estimate
+pub async fn discard(options: &OutputOptions) -> Result<RecordSink> {
+    let writer = FormatWriter::start(Destination::discard(), encoder(options)?).await?;
+    Ok(RecordSink::Format(writer))
+}
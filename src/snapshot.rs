@@ -0,0 +1,105 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::{ConnectOptions, Connection, Executor, PgConnection};
+use std::sync::Arc;
+
+use crate::session::SessionSetup;
+
+/// Setting that marks a connection's transaction as reading in the snapshot
+const MARKER: &str = "marc_extractor.snapshot";
+
+/// The database state a --consistent run reads, exported by a transaction of its own
+///
+/// Every connection the pool hands out starts a REPEATABLE READ transaction on the
+/// snapshot, and rolls it back when returned, so the count, the chunk plan, and every
+/// fetch see the same records whichever connection runs them. The exporting transaction
+/// has to stay open for as long as connections may still import the snapshot, which is
+/// the whole run.
+pub struct Snapshot {
+    id: String,
+    /// When the exporting transaction started, which is the moment the snapshot shows
+    taken: DateTime<Utc>,
+    /// The connection holding the exporting transaction open
+    conn: PgConnection,
+}
+
+impl Snapshot {
+    /// Open a connection and export the snapshot of a read-only REPEATABLE READ
+    /// transaction on it
+    pub async fn export(options: &PgConnectOptions) -> Result<Self> {
+        let mut conn = options
+            .connect()
+            .await
+            .context("Failed to connect to database to export a snapshot")?;
+        conn.execute("BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY")
+            .await?;
+        let (id, taken): (String, DateTime<Utc>) =
+            sqlx::query_as("SELECT pg_export_snapshot(), now()")
+                .fetch_one(&mut conn)
+                .await
+                .context("Failed to export a snapshot")?;
+        Ok(Self { id, taken, conn })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn taken(&self) -> DateTime<Utc> {
+        self.taken
+    }
+
+    /// The statements that start a transaction reading in the snapshot, marked with a
+    /// setting that goes when the transaction ends
+    fn begin(&self) -> String {
+        format!(
+            "BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY; SET TRANSACTION SNAPSHOT '{0}'; \
+             SET LOCAL {1} = '{0}'",
+            self.id, MARKER
+        )
+    }
+
+    /// Start a new connection in the snapshot, after the rest of its setup
+    pub fn session(&self, session: SessionSetup) -> SessionSetup {
+        session.sql(&self.begin())
+    }
+
+    /// Start every connection going back out in the snapshot, and end its transaction
+    /// when it is returned; a new connection starts in it through `session`
+    ///
+    /// The pool puts some new connections straight back without the release callback,
+    /// so an idle connection may be in the snapshot already, which the marker tells.
+    pub fn apply(&self, options: PgPoolOptions) -> PgPoolOptions {
+        let begin = Arc::new(self.begin());
+        let id = Arc::new(self.id.clone());
+        options
+            .before_acquire(move |conn, _meta| {
+                let begin = Arc::clone(&begin);
+                let id = Arc::clone(&id);
+                Box::pin(async move {
+                    let marked: Option<String> =
+                        sqlx::query_scalar(&format!("SELECT current_setting('{}', true)", MARKER))
+                            .fetch_one(&mut *conn)
+                            .await?;
+                    if marked.as_deref() != Some(id.as_str()) {
+                        conn.execute(sqlx::raw_sql(&begin)).await?;
+                    }
+                    Ok(true)
+                })
+            })
+            .after_release(|conn, _meta| {
+                Box::pin(async move {
+                    conn.execute("ROLLBACK").await?;
+                    Ok(true)
+                })
+            })
+    }
+
+    /// End the exporting transaction once nothing reads in the snapshot any more
+    pub async fn release(mut self) -> Result<()> {
+        self.conn.execute("ROLLBACK").await?;
+        self.conn.close().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,571 @@
+use anyhow::{anyhow, bail, Context, Result};
+use indicatif::HumanBytes;
+use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Lines};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::db::MarcRecord;
+use crate::watchdog::{Pressure, Watchdog};
+use crate::writer::EncodedRecord;
+
+/// How long the writer channel must stay full before records are spilled
+const STALL_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// Size at which a spill segment is closed and a new one started
+const SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Spill configuration
+#[derive(Debug, Clone)]
+pub struct SpillConfig {
+    pub dir: PathBuf,
+    pub max_bytes: u64,
+    /// Order records reach the spooler in
+    pub arrival: Arrival,
+    /// Records at or below this id were written by the run being resumed
+    pub after_id: Option<i64>,
+}
+
+/// Order records reach the spooler in, which a failed run's leftover records must keep
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    /// No order to keep: leftover records are replayed first
+    Any,
+    /// Ascending id: leftover records are merged in by id
+    ById,
+    /// Another order to keep, which leftover records cannot be placed in
+    Other,
+}
+
+/// Spill statistics reported at the end of a run
+#[derive(Debug, Default)]
+pub struct SpillStats {
+    pub records_spilled: u64,
+    pub peak_bytes: u64,
+    /// Records replayed from spill files a failed run left behind
+    pub records_adopted: u64,
+}
+
+/// Sequential on-disk FIFO of records, split into segment files
+struct Spool {
+    dir: PathBuf,
+    next_segment: u64,
+    /// Closed segments waiting to be replayed, oldest first
+    closed: VecDeque<(PathBuf, u64)>,
+    /// Segment currently receiving spilled records
+    writing: Option<(PathBuf, BufWriter<File>, u64)>,
+    /// Segment currently being replayed
+    reading: Option<(PathBuf, u64, Lines<BufReader<File>>)>,
+    /// Records on disk that have not been replayed yet
+    pending: u64,
+    /// Bytes currently held on disk
+    bytes: u64,
+}
+
+impl Spool {
+    /// Prepare the spill directory, queueing any segments a failed run left in it for replay
+    async fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).await.context(format!(
+            "Failed to create spill directory: {}",
+            dir.display()
+        ))?;
+
+        let mut segments = Vec::new();
+        let mut entries = fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if let Some(number) = segment_number(&path) {
+                segments.push((number, path));
+            }
+        }
+        segments.sort_unstable();
+
+        let mut spool = Self {
+            dir: dir.to_path_buf(),
+            next_segment: segments.last().map_or(1, |(number, _)| number + 1),
+            closed: VecDeque::new(),
+            writing: None,
+            reading: None,
+            pending: 0,
+            bytes: 0,
+        };
+        for (_, path) in segments {
+            let contents = fs::read(&path)
+                .await
+                .context(format!("Failed to read spill file: {}", path.display()))?;
+            // A record cut short by the failure is dropped; it is fetched again
+            let size = contents
+                .iter()
+                .rposition(|&byte| byte == b'\n')
+                .map_or(0, |end| end + 1);
+            if size == 0 {
+                fs::remove_file(&path).await?;
+                continue;
+            }
+            if size < contents.len() {
+                fs::write(&path, &contents[..size]).await?;
+            }
+            spool.pending += contents[..size]
+                .iter()
+                .filter(|&&byte| byte == b'\n')
+                .count() as u64;
+            spool.bytes += size as u64;
+            spool.closed.push_back((path, size as u64));
+        }
+        if spool.pending > 0 {
+            info!(
+                "Found {} records ({}) left in {} by a previous run",
+                spool.pending,
+                HumanBytes(spool.bytes),
+                dir.display()
+            );
+        }
+
+        Ok(spool)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending == 0
+    }
+
+    /// Append a record to the current segment, without its encoding, which the writer
+    /// redoes once the record is replayed
+    async fn push(&mut self, record: &EncodedRecord) -> Result<()> {
+        if self.writing.is_none() {
+            let path = self
+                .dir
+                .join(format!("spill-{:06}.ndjson", self.next_segment));
+            self.next_segment += 1;
+            let file = File::create(&path)
+                .await
+                .context(format!("Failed to create spill file: {}", path.display()))?;
+            debug!("Opened spill segment {}", path.display());
+            self.writing = Some((path, BufWriter::new(file), 0));
+        }
+
+        let mut line = serde_json::to_vec(&record.record)?;
+        line.push(b'\n');
+
+        let (_, writer, size) = self.writing.as_mut().expect("segment opened above");
+        writer.write_all(&line).await?;
+        *size += line.len() as u64;
+        self.bytes += line.len() as u64;
+        self.pending += 1;
+
+        if *size >= SEGMENT_BYTES {
+            self.rotate().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Take the oldest spilled record
+    async fn pop(&mut self) -> Result<Option<EncodedRecord>> {
+        while self.pending > 0 {
+            if self.reading.is_none() {
+                if self.closed.is_empty() {
+                    // Only the active segment is left, so close it to make it readable
+                    self.rotate().await?;
+                }
+                let (path, size) = self.closed.pop_front().expect("segment closed above");
+                let file = File::open(&path)
+                    .await
+                    .context(format!("Failed to open spill file: {}", path.display()))?;
+                self.reading = Some((path, size, BufReader::new(file).lines()));
+            }
+
+            let (_, _, lines) = self.reading.as_mut().expect("segment opened above");
+            if let Some(line) = lines.next_line().await? {
+                self.pending -= 1;
+                if self.pending == 0 {
+                    // Nothing else is on disk, so the segment is exhausted
+                    self.discard_reading().await?;
+                }
+                let record: MarcRecord = serde_json::from_str(&line)?;
+                return Ok(Some(record.into()));
+            }
+
+            self.discard_reading().await?;
+        }
+
+        Ok(None)
+    }
+
+    /// Remove the fully replayed segment
+    async fn discard_reading(&mut self) -> Result<()> {
+        if let Some((path, size, _)) = self.reading.take() {
+            fs::remove_file(&path).await?;
+            self.bytes -= size;
+            debug!("Replayed and removed spill segment {}", path.display());
+        }
+        Ok(())
+    }
+
+    /// Close the active segment and queue it for replay
+    async fn rotate(&mut self) -> Result<()> {
+        if let Some((path, mut writer, size)) = self.writing.take() {
+            writer.flush().await?;
+            self.closed.push_back((path, size));
+        }
+        Ok(())
+    }
+}
+
+/// Number of a spill-NNNNNN.ndjson segment
+fn segment_number(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("spill-")?
+        .strip_suffix(".ndjson")?
+        .parse()
+        .ok()
+}
+
+/// Replay the records a failed run left on disk before any new ones are spilled
+///
+/// In id order they are merged with the incoming records, and an incoming record a
+/// leftover one already delivered is dropped; otherwise they all go first, and the ids
+/// to drop are returned. Leftover records the resumed run wrote are passed over.
+async fn adopt(
+    spool: &mut Spool,
+    config: &SpillConfig,
+    input: &mut mpsc::Receiver<EncodedRecord>,
+    output: &mpsc::Sender<EncodedRecord>,
+    stats: &mut SpillStats,
+) -> Result<HashSet<i64>> {
+    if config.arrival == Arrival::Other {
+        bail!(
+            "Spill directory {} holds records from a previous run, which cannot be replayed in the order this run writes; remove them or choose another directory",
+            config.dir.display()
+        );
+    }
+
+    let mut delivered = HashSet::new();
+    let mut last_id = None;
+    // An incoming record waiting for the leftover ones before it
+    let mut held = None;
+    while let Some(record) = spool.pop().await? {
+        if config
+            .after_id
+            .is_some_and(|after_id| record.record.id <= after_id)
+        {
+            continue;
+        }
+
+        if config.arrival == Arrival::ById {
+            if last_id.is_some_and(|last| record.record.id <= last) {
+                bail!(
+                    "Spill files in {} are not in id order, so cannot be merged into this run; remove them or choose another directory",
+                    config.dir.display()
+                );
+            }
+            last_id = Some(record.record.id);
+
+            loop {
+                let incoming = match held.take() {
+                    Some(incoming) => Some(incoming),
+                    None => input.recv().await,
+                };
+                match incoming {
+                    Some(incoming) if incoming.record.id < record.record.id => {
+                        output
+                            .send(incoming)
+                            .await
+                            .map_err(|_| writer_gone(config))?;
+                    }
+                    // Fetched again, but the leftover copy is delivered instead
+                    Some(incoming) if incoming.record.id == record.record.id => {}
+                    Some(incoming) => {
+                        held = Some(incoming);
+                        break;
+                    }
+                    None => break,
+                }
+            }
+        } else {
+            delivered.insert(record.record.id);
+        }
+
+        output.send(record).await.map_err(|_| writer_gone(config))?;
+        stats.records_adopted += 1;
+    }
+    if let Some(incoming) = held {
+        output
+            .send(incoming)
+            .await
+            .map_err(|_| writer_gone(config))?;
+    }
+
+    Ok(delivered)
+}
+
+/// Forward records from the workers to the writer, spilling to disk while the writer stalls
+/// or the watchdog asks for it
+///
+/// Once spilling starts, every new record goes through the spool until it has been
+/// fully replayed, so delivery order is the same as arrival order. Records a failed run
+/// left in the spill directory are replayed before anything new is spilled.
+pub async fn run(
+    config: SpillConfig,
+    mut input: mpsc::Receiver<EncodedRecord>,
+    output: mpsc::Sender<EncodedRecord>,
+    watchdog: Arc<Watchdog>,
+) -> Result<SpillStats> {
+    let mut spool = Spool::open(&config.dir).await?;
+    let mut stats = SpillStats::default();
+    let mut input_open = true;
+
+    let mut delivered = if spool.is_empty() {
+        HashSet::new()
+    } else {
+        adopt(&mut spool, &config, &mut input, &output, &mut stats).await?
+    };
+
+    loop {
+        if spool.is_empty() {
+            let Some(record) = input.recv().await else {
+                break;
+            };
+            if delivered.remove(&record.record.id) {
+                continue;
+            }
+
+            // Under memory pressure records go straight to disk rather than queueing
+            if watchdog.pressure() >= Pressure::Spill {
+                spool.push(&record).await?;
+                stats.records_spilled += 1;
+                stats.peak_bytes = stats.peak_bytes.max(spool.bytes);
+                continue;
+            }
+
+            match tokio::time::timeout(STALL_THRESHOLD, output.reserve()).await {
+                Ok(Ok(permit)) => permit.send(record),
+                Ok(Err(_)) => return Err(writer_gone(&config)),
+                Err(_) => {
+                    warn!(
+                        "Writer stalled for {}s, spilling records to {}",
+                        STALL_THRESHOLD.as_secs(),
+                        config.dir.display()
+                    );
+                    spool.push(&record).await?;
+                    stats.records_spilled += 1;
+                    stats.peak_bytes = stats.peak_bytes.max(spool.bytes);
+                }
+            }
+            continue;
+        }
+
+        if !input_open {
+            // Workers are done; replay whatever is left
+            let permit = output.reserve().await.map_err(|_| writer_gone(&config))?;
+            if let Some(record) = spool.pop().await? {
+                permit.send(record);
+            }
+        } else {
+            // Keep draining the workers to disk while replaying into the writer.
+            // Past the size cap, stop accepting input so backpressure reaches the workers.
+            let has_room = spool.bytes < config.max_bytes;
+
+            tokio::select! {
+                permit = output.reserve() => {
+                    let permit = permit.map_err(|_| writer_gone(&config))?;
+                    if let Some(record) = spool.pop().await? {
+                        permit.send(record);
+                    }
+                }
+                record = input.recv(), if has_room => {
+                    match record {
+                        Some(record) if delivered.remove(&record.record.id) => {}
+                        Some(record) => {
+                            spool.push(&record).await?;
+                            stats.records_spilled += 1;
+                            stats.peak_bytes = stats.peak_bytes.max(spool.bytes);
+                        }
+                        None => input_open = false,
+                    }
+                }
+            }
+        }
+
+        if spool.is_empty() {
+            info!(
+                "Spill drained ({} on disk at peak), resuming direct delivery",
+                HumanBytes(stats.peak_bytes)
+            );
+        }
+    }
+
+    Ok(stats)
+}
+
+fn writer_gone(config: &SpillConfig) -> anyhow::Error {
+    anyhow!(
+        "Writer stopped; undelivered spill files left in {}",
+        config.dir.display()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: i64) -> MarcRecord {
+        MarcRecord {
+            id,
+            marc: format!(
+                "<record><controlfield tag=\"001\">{}</controlfield></record>",
+                id
+            ),
+            rejection: None,
+            display: Vec::new(),
+            meta: None,
+            identifiers: None,
+            status: None,
+        }
+    }
+
+    /// A spill directory holding the segments a failed run left, one list of ids each
+    fn leftovers(name: &str, segments: &[&[i64]]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("spill-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (number, ids) in segments.iter().enumerate() {
+            let mut contents = Vec::new();
+            for &id in *ids {
+                contents.extend(serde_json::to_vec(&record(id)).unwrap());
+                contents.push(b'\n');
+            }
+            let path = dir.join(format!("spill-{:06}.ndjson", number + 1));
+            std::fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    fn config(dir: &Path, arrival: Arrival, after_id: Option<i64>) -> SpillConfig {
+        SpillConfig {
+            dir: dir.to_path_buf(),
+            max_bytes: u64::MAX,
+            arrival,
+            after_id,
+        }
+    }
+
+    /// Ids the writer receives when `incoming` is fetched, and how many were adopted
+    async fn deliver(config: SpillConfig, incoming: &[i64]) -> Result<(Vec<i64>, u64)> {
+        let (tx, input) = mpsc::channel(incoming.len() + 1);
+        for &id in incoming {
+            tx.send(record(id).into()).await.unwrap();
+        }
+        drop(tx);
+
+        let (output, mut rx) = mpsc::channel(1024);
+        let watchdog = Arc::new(Watchdog::new(None, true));
+        let stats = run(config, input, output, watchdog).await?;
+
+        let mut delivered = Vec::new();
+        while let Some(record) = rx.recv().await {
+            delivered.push(record.record.id);
+        }
+        Ok((delivered, stats.records_adopted))
+    }
+
+    fn segments(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| segment_number(&entry.as_ref().unwrap().path()).is_some())
+            .count()
+    }
+
+    #[tokio::test]
+    async fn leftover_records_go_first_and_are_not_delivered_twice() {
+        let dir = leftovers("any", &[&[7, 3], &[9]]);
+
+        let (delivered, adopted) = deliver(config(&dir, Arrival::Any, None), &[1, 3, 5, 7, 9, 11])
+            .await
+            .unwrap();
+
+        assert_eq!(delivered, vec![7, 3, 9, 1, 5, 11]);
+        assert_eq!(adopted, 3);
+        assert_eq!(segments(&dir), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn leftover_records_are_merged_in_by_id() {
+        let dir = leftovers("by-id", &[&[3, 4], &[6]]);
+
+        let (delivered, adopted) =
+            deliver(config(&dir, Arrival::ById, None), &[1, 2, 3, 4, 5, 6, 7])
+                .await
+                .unwrap();
+
+        assert_eq!(delivered, vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(adopted, 3);
+        assert_eq!(segments(&dir), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn leftover_records_the_resumed_run_wrote_are_passed_over() {
+        let dir = leftovers("resumed", &[&[4, 5, 6]]);
+
+        // The checkpoint vouches for everything up to 5; the fetch starts after it
+        let (delivered, adopted) = deliver(config(&dir, Arrival::ById, Some(5)), &[6, 7])
+            .await
+            .unwrap();
+
+        assert_eq!(delivered, vec![6, 7]);
+        assert_eq!(adopted, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_record_cut_short_is_dropped_and_fetched_again() {
+        let dir = leftovers("cut", &[&[2]]);
+        let path = dir.join("spill-000001.ndjson");
+        let mut contents = std::fs::read(&path).unwrap();
+        contents.extend(b"{\"id\":3,\"ma");
+        std::fs::write(&path, contents).unwrap();
+
+        let (delivered, adopted) = deliver(config(&dir, Arrival::Any, None), &[2, 3])
+            .await
+            .unwrap();
+
+        assert_eq!(delivered, vec![2, 3]);
+        assert_eq!(adopted, 1);
+        assert_eq!(segments(&dir), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn leftover_records_that_cannot_keep_the_order_are_refused() {
+        let other = leftovers("other", &[&[2]]);
+        let error = deliver(config(&other, Arrival::Other, None), &[1])
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("cannot be replayed in the order"));
+
+        let dir = leftovers("unsorted", &[&[5], &[2]]);
+        let error = deliver(config(&dir, Arrival::ById, None), &[1])
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("not in id order"));
+        std::fs::remove_dir_all(&other).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn segments_are_numbered_after_the_leftover_ones() {
+        let dir = leftovers("numbering", &[&[1], &[2]]);
+        let spool = Spool::open(&dir).await.unwrap();
+        assert_eq!(spool.next_segment, 3);
+        assert_eq!(spool.pending, 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::checkpoint::OutputPoint;
+use crate::db::MarcRecord;
+use crate::sink::{self, OutputOptions};
+use crate::writer::{Encoded, FormatWriter, WriteOutcome};
+
+/// Writes --output as a series of numbered files of at most --records-per-file records,
+/// or --max-file-bytes bytes, whichever a file reaches first
+///
+/// Each file is a complete document of its own, opened and finalized by the writer for
+/// the format. The next file is opened when a record arrives for it, so a run whose
+/// count divides evenly does not end with an empty file. Sizes are counted before any
+/// compression, so a compressed file comes out smaller than its limit.
+pub struct SplitWriter {
+    template: PathBuf,
+    per_file: Option<u64>,
+    max_bytes: Option<u64>,
+    options: OutputOptions,
+    current: Box<FormatWriter>,
+    /// Records written to the current file
+    in_file: u64,
+    files: u64,
+    /// The record prepare encoded to see which file it goes in, kept for write_record
+    pending: Option<(i64, Encoded)>,
+}
+
+impl SplitWriter {
+    /// Open the first file named after `template`, numbered `options.first_file`
+    pub async fn open(template: PathBuf, options: OutputOptions) -> Result<Self> {
+        let first = numbered(&template, options.first_file);
+        let current = sink::open_writer(Some(first), options.clone()).await?;
+        Ok(Self {
+            template,
+            per_file: options.records_per_file,
+            max_bytes: options.max_file_bytes,
+            files: options.first_file,
+            options,
+            current: Box::new(current),
+            in_file: 0,
+            pending: None,
+        })
+    }
+
+    /// Reopen the numbered file a checkpointed run was writing, at the point it recorded
+    pub async fn resume(
+        template: PathBuf,
+        options: OutputOptions,
+        point: OutputPoint,
+    ) -> Result<Self> {
+        let number = point.file.unwrap_or(1);
+        let current =
+            sink::resume_writer(numbered(&template, number), options.clone(), point.offset).await?;
+        Ok(Self {
+            template,
+            per_file: options.records_per_file,
+            max_bytes: options.max_file_bytes,
+            options,
+            current: Box::new(current),
+            in_file: point.records_in_file,
+            files: number,
+            pending: None,
+        })
+    }
+
+    /// Move on to the next file if `record` does not fit in the current one, so that the
+    /// position and offset reported for it are where it lands
+    ///
+    /// A record a worker has not `encoded` is encoded here to learn its size, once:
+    /// write_record takes the encoding. One that takes a file past --max-file-bytes on its
+    /// own still goes in whole, in a file of its own.
+    pub async fn prepare(&mut self, record: &MarcRecord, encoded: Option<Encoded>) -> Result<()> {
+        if self
+            .pending
+            .as_ref()
+            .is_some_and(|(id, _)| *id == record.id)
+        {
+            return Ok(());
+        }
+        let encoded = match encoded {
+            Some(encoded) => encoded,
+            None => self.current.encode(record)?,
+        };
+        let full = self
+            .per_file
+            .is_some_and(|per_file| self.in_file >= per_file);
+        let size = encoded.as_ref().map_or(0, |bytes| bytes.len() as u64) + self.current.overhead();
+        let over = self
+            .max_bytes
+            .filter(|&max| self.current.offset() + size > max);
+        if full || (over.is_some() && self.in_file > 0) {
+            self.roll().await?;
+        }
+        if let Some(max) = over.filter(|&max| self.current.offset() + size > max) {
+            warn!(
+                record_id = record.id,
+                "Record {} takes {} bytes, more than --max-file-bytes {} allows; writing it whole to {}",
+                record.id,
+                size,
+                max,
+                self.current.target()
+            );
+        }
+        self.pending = Some((record.id, encoded));
+        Ok(())
+    }
+
+    pub async fn write_record(
+        &mut self,
+        record: &MarcRecord,
+        encoded: Option<Encoded>,
+    ) -> Result<WriteOutcome> {
+        self.prepare(record, encoded).await?;
+        let (_, encoded) = self.pending.take().expect("prepared just now");
+        match encoded {
+            Ok(bytes) => {
+                self.current.write_encoded(&bytes).await?;
+                self.in_file += 1;
+                Ok(WriteOutcome::Written)
+            }
+            Err(rejection) => Ok(WriteOutcome::Rejected(rejection)),
+        }
+    }
+
+    /// Finalize the current file and open the next
+    async fn roll(&mut self) -> Result<()> {
+        let path = numbered(&self.template, self.files + 1);
+        let next = sink::open_writer(Some(path), self.options.clone()).await?;
+        let done = std::mem::replace(&mut self.current, Box::new(next));
+        let finished = done.target();
+        done.finalize().await?;
+        info!("Wrote {} records to {}", self.in_file, finished);
+        self.files += 1;
+        self.in_file = 0;
+        Ok(())
+    }
+
+    pub fn position(&self) -> String {
+        self.current.position()
+    }
+
+    /// The file being written
+    pub fn target(&self) -> String {
+        self.current.target()
+    }
+
+    /// Byte offset the next record lands at in the file being written
+    pub fn offset(&self) -> u64 {
+        self.current.offset()
+    }
+
+    /// Number of the file being written, the last one opened
+    pub fn files(&self) -> u64 {
+        self.files
+    }
+
+    /// Make everything written to the current file so far survive a crash; earlier files
+    /// were finalized when the output moved on from them
+    pub async fn sync(&mut self) -> Result<()> {
+        self.current.sync().await
+    }
+
+    /// Write out the records gathered so far in the current file
+    pub async fn flush(&mut self) -> Result<()> {
+        self.current.flush().await
+    }
+
+    /// The file being written, the records in it, and how much of it they fill
+    pub fn point(&self) -> OutputPoint {
+        OutputPoint {
+            file: Some(self.files),
+            records_in_file: self.in_file,
+            offset: self.current.offset(),
+        }
+    }
+
+    pub async fn finalize(self) -> Result<()> {
+        self.current.finalize().await
+    }
+}
+
+/// File `number` of a split output: export.xml.gz becomes export_00001.xml.gz
+pub fn numbered(template: &Path, number: u64) -> PathBuf {
+    let name = template
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let numbered = match name.split_once('.') {
+        Some((stem, extensions)) => format!("{}_{:05}.{}", stem, number, extensions),
+        None => format!("{}_{:05}", name, number),
+    };
+    template.with_file_name(numbered)
+}
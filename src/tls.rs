@@ -0,0 +1,85 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use sqlx::postgres::{PgConnectOptions, PgSslMode};
+use std::path::{Path, PathBuf};
+
+/// How the connection is encrypted, as libpq's sslmode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SslMode {
+    /// Never use TLS
+    Disable,
+    /// Try without TLS first, then with it
+    Allow,
+    /// Try TLS first, then without it
+    Prefer,
+    /// Use TLS, without checking the server's certificate
+    Require,
+    /// Use TLS, checking the server's certificate against the root certificate
+    VerifyCa,
+    /// As verify-ca, and check that the certificate names the host
+    VerifyFull,
+}
+
+impl From<SslMode> for PgSslMode {
+    fn from(mode: SslMode) -> Self {
+        match mode {
+            SslMode::Disable => PgSslMode::Disable,
+            SslMode::Allow => PgSslMode::Allow,
+            SslMode::Prefer => PgSslMode::Prefer,
+            SslMode::Require => PgSslMode::Require,
+            SslMode::VerifyCa => PgSslMode::VerifyCa,
+            SslMode::VerifyFull => PgSslMode::VerifyFull,
+        }
+    }
+}
+
+/// The --ssl-* options, applied over whatever the URL says
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    pub mode: Option<SslMode>,
+    pub root_cert: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+impl TlsOptions {
+    /// Set the mode and certificates on `options`, reading each file now so a missing or
+    /// unreadable one fails startup naming the option it came from
+    pub fn apply(&self, mut options: PgConnectOptions) -> Result<PgConnectOptions> {
+        if let Some(mode) = self.mode {
+            options = options.ssl_mode(mode.into());
+        }
+        if let Some(path) = &self.root_cert {
+            options = options.ssl_root_cert_from_pem(read_pem(path, "--ssl-root-cert")?);
+        }
+        if let Some(path) = &self.client_cert {
+            options = options.ssl_client_cert_from_pem(read_pem(path, "--ssl-client-cert")?);
+        }
+        if let Some(path) = &self.client_key {
+            options = options.ssl_client_key_from_pem(read_pem(path, "--ssl-client-key")?);
+        }
+        Ok(options)
+    }
+}
+
+/// The sslmode name of `mode`, for the log
+pub fn mode_name(mode: PgSslMode) -> &'static str {
+    match mode {
+        PgSslMode::Disable => "disable",
+        PgSslMode::Allow => "allow",
+        PgSslMode::Prefer => "prefer",
+        PgSslMode::Require => "require",
+        PgSslMode::VerifyCa => "verify-ca",
+        PgSslMode::VerifyFull => "verify-full",
+    }
+}
+
+/// Read a PEM file given to `option`
+fn read_pem(path: &Path, option: &str) -> Result<Vec<u8>> {
+    let pem = std::fs::read(path)
+        .with_context(|| format!("Failed to read {} {}", option, path.display()))?;
+    if !pem.windows(11).any(|window| window == b"-----BEGIN ") {
+        bail!("{} {} is not a PEM file", option, path.display());
+    }
+    Ok(pem)
+}
@@ -0,0 +1,85 @@
+use std::collections::{BTreeMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Mutex;
+
+use crate::db::MarcRecord;
+
+/// What one pipeline stage did with a traced record
+struct Note {
+    stage: &'static str,
+    detail: String,
+}
+
+/// Notes on the records named by --trace-record, kept apart from the records themselves
+/// so that a record dropped by a stage still has its history
+#[derive(Default)]
+pub struct Tracer {
+    ids: HashSet<i64>,
+    notes: Mutex<BTreeMap<i64, Vec<Note>>>,
+}
+
+impl Tracer {
+    pub fn new(ids: &[i64]) -> Self {
+        Self {
+            ids: ids.iter().copied().collect(),
+            notes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn is_traced(&self, id: i64) -> bool {
+        !self.ids.is_empty() && self.ids.contains(&id)
+    }
+
+    /// Record a note; `detail` is only built for traced ids
+    pub fn note(&self, id: i64, stage: &'static str, detail: impl FnOnce() -> String) {
+        if !self.is_traced(id) {
+            return;
+        }
+        self.notes
+            .lock()
+            .unwrap()
+            .entry(id)
+            .or_default()
+            .push(Note {
+                stage,
+                detail: detail(),
+            });
+    }
+
+    /// Note a record as it comes back from the database
+    pub fn fetched(&self, record: &MarcRecord) {
+        self.note(record.id, "fetched", || {
+            let mut hasher = DefaultHasher::new();
+            record.marc.hash(&mut hasher);
+            let mut detail = format!("{} bytes, hash {:016x}", record.marc.len(), hasher.finish());
+            if let Some(rejection) = &record.rejection {
+                detail.push_str(&format!(", already rejected ({})", rejection));
+            }
+            detail
+        });
+    }
+
+    /// The per-record report printed at the end of the run
+    pub fn report(&self) -> Vec<String> {
+        let notes = self.notes.lock().unwrap();
+        let mut ids: Vec<i64> = self.ids.iter().copied().collect();
+        ids.sort_unstable();
+
+        let mut lines = Vec::new();
+        for id in ids {
+            lines.push(format!("Trace of record {}:", id));
+            match notes.get(&id) {
+                Some(notes) => {
+                    for note in notes {
+                        lines.push(format!("  {}: {}", note.stage, note.detail));
+                    }
+                }
+                None => lines.push(
+                    "  never fetched: not selected by the filter, outside the batch or limit, or empty MARC"
+                        .to_string(),
+                ),
+            }
+        }
+        lines
+    }
+}
@@ -0,0 +1,311 @@
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::db::MarcRecord;
+use crate::marc;
+use crate::quarantine::Rejection;
+use crate::trace::Tracer;
+
+/// Tag of the field --add-901 writes, as Evergreen's own export does
+const STAMP_TAG: &str = "901";
+
+/// Field order written to the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum FieldOrder {
+    /// As stored in the database
+    #[default]
+    None,
+    /// Control fields ascending by tag, then data fields by tag; repeated tags keep their order
+    Canonical,
+}
+
+/// A tag, or a pattern of tags, given to --strip-fields or --keep-only-fields: three
+/// characters, each a digit or a wildcard (`.` or `x`), as in `938`, `9..`, or `59x`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagPattern(String);
+
+impl TagPattern {
+    /// Read a tag or pattern such as `938`, `9..`, or `59x`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let pattern = s.trim().to_ascii_lowercase();
+        if pattern.len() != 3
+            || !pattern
+                .bytes()
+                .all(|b| b.is_ascii_digit() || b == b'.' || b == b'x')
+        {
+            return Err(format!(
+                "must be a tag or a pattern like 9.. or 59x, got {:?}",
+                s
+            ));
+        }
+        Ok(Self(pattern.replace('x', ".")))
+    }
+
+    /// The pattern, with `.` for each wildcard
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// A plain tag rather than a pattern, which is all that picks out a control field
+    fn is_tag(&self) -> bool {
+        !self.0.contains('.')
+    }
+
+    fn matches(&self, tag: &str) -> bool {
+        tag.len() == 3
+            && self
+                .0
+                .bytes()
+                .zip(tag.bytes())
+                .all(|(p, t)| p == b'.' || p == t)
+    }
+}
+
+/// Fields --strip-fields removes, or --keep-only-fields keeps
+pub struct FieldSelection {
+    pub patterns: Vec<TagPattern>,
+    /// Keep the data fields the patterns match and remove the rest, rather than the reverse
+    pub keep: bool,
+}
+
+impl FieldSelection {
+    /// Whether a field goes; control fields only do when stripped by their own tag
+    fn removes(&self, tag: &str, data: bool) -> bool {
+        if !data {
+            return !self.keep && self.patterns.iter().any(|p| p.is_tag() && p.matches(tag));
+        }
+        self.patterns.iter().any(|p| p.matches(tag)) != self.keep
+    }
+
+    pub fn option(&self) -> &'static str {
+        if self.keep {
+            "--keep-only-fields"
+        } else {
+            "--strip-fields"
+        }
+    }
+}
+
+/// Changes made to each record's MARC in the workers, after enrichment
+pub struct Transforms {
+    /// Fields removed first, before any are added
+    selection: Option<FieldSelection>,
+    field_order: FieldOrder,
+    fingerprint_tag: Option<String>,
+    /// The name of every bib source by id, for the 901 $d; set when --add-901 asked for a 901
+    sources: Option<HashMap<i64, String>>,
+    reordered: AtomicU64,
+    fingerprinted: AtomicU64,
+    /// Records Evergreen never computed a fingerprint or quality for
+    unfingerprinted: AtomicU64,
+    stamped: AtomicU64,
+    /// Fields removed by the selection, and records that lost any
+    stripped_fields: AtomicU64,
+    stripped_records: AtomicU64,
+    tracer: Arc<Tracer>,
+}
+
+impl Transforms {
+    pub fn new(
+        selection: Option<FieldSelection>,
+        field_order: FieldOrder,
+        fingerprint_tag: Option<String>,
+        sources: Option<HashMap<i64, String>>,
+        tracer: Arc<Tracer>,
+    ) -> Self {
+        Self {
+            selection,
+            field_order,
+            fingerprint_tag,
+            sources,
+            reordered: AtomicU64::new(0),
+            fingerprinted: AtomicU64::new(0),
+            unfingerprinted: AtomicU64::new(0),
+            stamped: AtomicU64::new(0),
+            stripped_fields: AtomicU64::new(0),
+            stripped_records: AtomicU64::new(0),
+            tracer,
+        }
+    }
+
+    pub fn apply(&self, record: &mut MarcRecord) {
+        if record.rejection.is_some() {
+            return;
+        }
+
+        if let Some(selection) = &self.selection {
+            self.select_fields(record, selection);
+            if record.rejection.is_some() {
+                return;
+            }
+        }
+        // Added before sorting, so the field lands in its place in canonical order
+        if let Some(tag) = &self.fingerprint_tag {
+            self.add_fingerprint_field(record, tag);
+        }
+        if let Some(sources) = &self.sources {
+            self.add_901(record, sources);
+            if record.rejection.is_some() {
+                return;
+            }
+        }
+
+        if self.field_order == FieldOrder::Canonical {
+            if let Some(sorted) = marc::sort_fields(&record.marc) {
+                record.marc = sorted;
+                self.reordered.fetch_add(1, Ordering::Relaxed);
+                self.tracer.note(record.id, "transformed", || {
+                    "fields put in canonical order".to_string()
+                });
+            }
+        }
+    }
+
+    /// Remove the fields the selection does not want from a record
+    ///
+    /// A record that cannot be parsed is rejected rather than written with the fields it
+    /// was meant to lose.
+    fn select_fields(&self, record: &mut MarcRecord, selection: &FieldSelection) {
+        match marc::remove_fields(&record.marc, |tag, data| selection.removes(tag, data)) {
+            Some((_, 0)) => {}
+            Some((selected, removed)) => {
+                record.marc = selected;
+                self.stripped_fields
+                    .fetch_add(removed as u64, Ordering::Relaxed);
+                self.stripped_records.fetch_add(1, Ordering::Relaxed);
+                self.tracer.note(record.id, "transformed", || {
+                    format!(
+                        "{} field{} removed by {}",
+                        removed,
+                        if removed == 1 { "" } else { "s" },
+                        selection.option()
+                    )
+                });
+            }
+            None => {
+                let reason = format!(
+                    "cannot be parsed to remove fields for {}",
+                    selection.option()
+                );
+                let rejection = Rejection::new("strip", reason);
+                self.tracer
+                    .note(record.id, "transformed", || rejection.to_string());
+                record.rejection = Some(rejection);
+            }
+        }
+    }
+
+    /// Add the fingerprint as $a and the quality as $b of a `tag` field
+    ///
+    /// A null fingerprint or quality leaves its subfield out; with neither there is no field.
+    fn add_fingerprint_field(&self, record: &mut MarcRecord, tag: &str) {
+        let Some(meta) = &record.meta else {
+            return;
+        };
+        let quality = meta.quality.map(|q| q.to_string());
+        let mut subfields = Vec::new();
+        if let Some(fingerprint) = &meta.fingerprint {
+            subfields.push(('a', fingerprint.as_str()));
+        }
+        if let Some(quality) = &quality {
+            subfields.push(('b', quality.as_str()));
+        }
+        if subfields.is_empty() {
+            self.unfingerprinted.fetch_add(1, Ordering::Relaxed);
+            self.tracer.note(record.id, "transformed", || {
+                format!("no fingerprint or quality computed; no {} field added", tag)
+            });
+            return;
+        }
+
+        if marc::append_datafield(&mut record.marc, tag, (' ', ' '), &subfields) {
+            self.fingerprinted.fetch_add(1, Ordering::Relaxed);
+            self.tracer.note(record.id, "transformed", || {
+                format!("fingerprint field {} added", tag)
+            });
+        }
+    }
+
+    /// Replace any 901 with one holding the TCN value in $a, the TCN source in $b, the
+    /// record id in $c, and the bib source name in $d
+    ///
+    /// Null columns leave their subfield out. A record that cannot be parsed is rejected,
+    /// since the 901 it already has could not be told apart from the one to add.
+    fn add_901(&self, record: &mut MarcRecord, sources: &HashMap<i64, String>) {
+        let Some(identifiers) = &record.identifiers else {
+            return;
+        };
+        let id = record.id.to_string();
+        let source = identifiers
+            .source
+            .and_then(|source| sources.get(&i64::from(source)));
+        let mut subfields = Vec::new();
+        if let Some(tcn) = &identifiers.tcn_value {
+            subfields.push(('a', tcn.as_str()));
+        }
+        if let Some(tcn_source) = &identifiers.tcn_source {
+            subfields.push(('b', tcn_source.as_str()));
+        }
+        subfields.push(('c', id.as_str()));
+        if let Some(source) = source {
+            subfields.push(('d', source.as_str()));
+        }
+
+        match marc::replace_datafield(&record.marc, STAMP_TAG, (' ', ' '), &subfields) {
+            Some(stamped) => {
+                record.marc = stamped;
+                self.stamped.fetch_add(1, Ordering::Relaxed);
+                self.tracer
+                    .note(record.id, "transformed", || format!("{} added", STAMP_TAG));
+            }
+            None => {
+                let rejection = Rejection::new(STAMP_TAG, "cannot be parsed to replace its 901");
+                self.tracer
+                    .note(record.id, "transformed", || rejection.to_string());
+                record.rejection = Some(rejection);
+            }
+        }
+    }
+
+    /// The option fields were selected with, with the fields it removed and the records
+    /// that lost any, or `None` without one
+    pub fn stripped(&self) -> Option<(&'static str, u64, u64)> {
+        self.selection.as_ref().map(|selection| {
+            (
+                selection.option(),
+                self.stripped_fields.load(Ordering::Relaxed),
+                self.stripped_records.load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    pub fn field_order(&self) -> FieldOrder {
+        self.field_order
+    }
+
+    /// Records whose fields were reordered
+    pub fn reordered(&self) -> u64 {
+        self.reordered.load(Ordering::Relaxed)
+    }
+
+    pub fn fingerprint_tag(&self) -> Option<&str> {
+        self.fingerprint_tag.as_deref()
+    }
+
+    /// Records given a 901 by --add-901, or `None` without it
+    pub fn stamped(&self) -> Option<u64> {
+        self.sources
+            .as_ref()
+            .map(|_| self.stamped.load(Ordering::Relaxed))
+    }
+
+    /// Records given a fingerprint field, and records with nothing to put in one
+    pub fn fingerprinted(&self) -> (u64, u64) {
+        (
+            self.fingerprinted.load(Ordering::Relaxed),
+            self.unfingerprinted.load(Ordering::Relaxed),
+        )
+    }
+}
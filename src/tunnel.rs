@@ -0,0 +1,294 @@
+use anyhow::{bail, Context, Result};
+use sqlx::PgPool;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
+
+/// How long ssh gets to authenticate and open the forward
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the forwarded port is tried while ssh starts
+const READY_POLL: Duration = Duration::from_millis(100);
+
+/// Restarts tried, with doubling waits, before the tunnel is given up for lost
+const MAX_RESTARTS: u32 = 5;
+
+/// Keepalives ssh sends (every 15 seconds) without an answer before it exits
+const ALIVE_COUNT: u32 = 3;
+
+/// A --ssh-tunnel destination: `[user@]host[:port]`
+#[derive(Debug, Clone)]
+pub struct SshTarget {
+    user: Option<String>,
+    host: String,
+    port: u16,
+}
+
+impl FromStr for SshTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, rest) = match s.rsplit_once('@') {
+            Some((user, rest)) if !user.is_empty() => (Some(user.to_string()), rest),
+            Some(_) => return Err(format!("empty user in {:?}", s)),
+            None => (None, s),
+        };
+
+        // [v6::addr]:port, host:port, or a bare host
+        let (host, port) = if let Some(bracketed) = rest.strip_prefix('[') {
+            let (host, after) = bracketed
+                .split_once(']')
+                .ok_or_else(|| format!("unclosed [ in {:?}", s))?;
+            (host, after.strip_prefix(':'))
+        } else {
+            match rest.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (rest, None),
+            }
+        };
+        if host.is_empty() {
+            return Err(format!("expected user@host[:port], got {:?}", s));
+        }
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| format!("invalid port {:?}", port))?,
+            None => 22,
+        };
+
+        Ok(Self {
+            user,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl std::fmt::Display for SshTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TunnelConfig {
+    pub target: SshTarget,
+    /// Private key to use instead of the agent's
+    pub key: Option<PathBuf>,
+    /// Trust a bastion whose host key is not yet in known_hosts
+    pub accept_new_host_key: bool,
+}
+
+/// A local port forwarded to the database through a bastion host
+///
+/// The forward is an OpenSSH client (`ssh -N -L`) owned by this process: started
+/// before the pool connects, restarted if it dies mid-run, and killed when the tunnel
+/// is dropped. Host keys are checked against known_hosts, and no prompts are answered,
+/// so authentication has to come from the agent or --ssh-key.
+pub struct Tunnel {
+    local_port: u16,
+    pool: Arc<OnceLock<PgPool>>,
+    monitor: JoinHandle<()>,
+}
+
+impl Tunnel {
+    /// Start forwarding a local port to `remote_host:remote_port` as seen from the bastion
+    pub async fn open(config: TunnelConfig, remote_host: &str, remote_port: u16) -> Result<Self> {
+        // Ask the OS for a free port; ssh binds it again a moment later
+        let local_port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .and_then(|listener| listener.local_addr())
+            .context("Failed to find a free local port for the SSH tunnel")?
+            .port();
+
+        let forward = Forward {
+            config,
+            local_port,
+            remote: format!("{}:{}", remote_host, remote_port),
+        };
+        let child = forward.start().await?;
+        info!(
+            "SSH tunnel: 127.0.0.1:{} -> {} via {}",
+            local_port, forward.remote, forward.config.target
+        );
+
+        let pool = Arc::new(OnceLock::new());
+        let monitor = tokio::spawn(forward.supervise(child, Arc::clone(&pool)));
+        Ok(Self {
+            local_port,
+            pool,
+            monitor,
+        })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    /// The pool to close if the tunnel is lost for good, so that workers fail instead of waiting
+    pub fn attach(&self, pool: &PgPool) {
+        let _ = self.pool.set(pool.clone());
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        // Dropping the supervisor's child kills ssh
+        self.monitor.abort();
+    }
+}
+
+struct Forward {
+    config: TunnelConfig,
+    local_port: u16,
+    remote: String,
+}
+
+impl Forward {
+    /// Run ssh and wait until the forwarded port accepts connections
+    async fn start(&self) -> Result<Child> {
+        let target = &self.config.target;
+        let host_key_checking = if self.config.accept_new_host_key {
+            "accept-new"
+        } else {
+            "yes"
+        };
+
+        let mut command = Command::new("ssh");
+        command
+            .arg("-N")
+            .args(["-o", "BatchMode=yes"])
+            .args(["-o", "ExitOnForwardFailure=yes"])
+            .args([
+                "-o",
+                &format!("StrictHostKeyChecking={}", host_key_checking),
+            ])
+            .args(["-o", "ServerAliveInterval=15"])
+            .args(["-o", &format!("ServerAliveCountMax={}", ALIVE_COUNT)])
+            .args([
+                "-L",
+                &format!("127.0.0.1:{}:{}", self.local_port, self.remote),
+            ])
+            .args(["-p", &target.port.to_string()]);
+        if let Some(key) = &self.config.key {
+            command
+                .arg("-i")
+                .arg(key)
+                .args(["-o", "IdentitiesOnly=yes"]);
+        }
+        if let Some(user) = &target.user {
+            command.args(["-l", user]);
+        }
+        command
+            .arg(&target.host)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        debug!("Starting SSH tunnel: {:?}", command.as_std());
+        let mut child = command
+            .spawn()
+            .context("Failed to run ssh for --ssh-tunnel; is the OpenSSH client installed?")?;
+
+        // ssh explains its failures on stderr; they go to the log as they arrive
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    warn!("ssh: {}", line);
+                }
+            });
+        }
+
+        let deadline = Instant::now() + READY_TIMEOUT;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                bail!(
+                    "SSH tunnel via {} failed to start ({})",
+                    target,
+                    describe(status)
+                );
+            }
+            if TcpStream::connect(("127.0.0.1", self.local_port))
+                .await
+                .is_ok()
+            {
+                return Ok(child);
+            }
+            if Instant::now() >= deadline {
+                bail!(
+                    "SSH tunnel via {} was not ready after {}s",
+                    target,
+                    READY_TIMEOUT.as_secs()
+                );
+            }
+            tokio::time::sleep(READY_POLL).await;
+        }
+    }
+
+    /// Restart ssh whenever it exits, closing the pool once restarts stop working
+    async fn supervise(self, mut child: Child, pool: Arc<OnceLock<PgPool>>) {
+        loop {
+            let status = child.wait().await;
+            let reason = status.map_or_else(|e| e.to_string(), describe);
+            warn!(
+                "SSH tunnel via {} exited ({}); reconnecting",
+                self.config.target, reason
+            );
+
+            let mut restarted = None;
+            for attempt in 0..MAX_RESTARTS {
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                match self.start().await {
+                    Ok(child) => {
+                        restarted = Some(child);
+                        break;
+                    }
+                    Err(e) => warn!("{:#} (attempt {} of {})", e, attempt + 1, MAX_RESTARTS),
+                }
+            }
+
+            match restarted {
+                Some(next) => {
+                    info!("SSH tunnel via {} re-established", self.config.target);
+                    child = next;
+                }
+                None => {
+                    error!(
+                        "SSH tunnel via {} lost; closing the database pool",
+                        self.config.target
+                    );
+                    if let Some(pool) = pool.get() {
+                        pool.close().await;
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn describe(status: ExitStatus) -> String {
+    match status.code() {
+        // 255 is ssh's own failure, as opposed to the remote command's
+        Some(255) => "ssh exited with 255: connection or authentication failed".to_string(),
+        Some(code) => format!("exit code {}", code),
+        None => "killed by a signal".to_string(),
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::{bail, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+use crate::db::MarcRecord;
+use crate::digest::Category;
+use crate::errorfile::ErrorFile;
+use crate::marc;
+use crate::quarantine::Rejection;
+use crate::review::Review;
+use crate::trace::Tracer;
+use crate::writer;
+
+/// What happens to a record that is not well-formed XML, or breaks the schema for
+/// --schema-validate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Validation {
+    /// Leave it out of the output (quarantined if --quarantine-file is set)
+    Skip,
+    /// Abort the run at the first one
+    Strict,
+}
+
+/// Checks in the workers that each record is well-formed XML once cleaned, and
+/// remembers the ids of those that are not
+///
+/// A record is checked after every stage that changes it, as the writer will write it,
+/// so a mangled record never makes the whole output unparseable.
+pub struct Validator {
+    validation: Option<Validation>,
+    quarantine: bool,
+    /// The writer scrubs characters XML does not allow, so they are not held against a record
+    scrub: bool,
+    pub ids: Mutex<Vec<i64>>,
+    invalid: Arc<AtomicU64>,
+    tracer: Arc<Tracer>,
+    review: Arc<Review>,
+    error_file: Arc<ErrorFile>,
+}
+
+impl Validator {
+    pub fn new(
+        validation: Option<Validation>,
+        quarantine: bool,
+        scrub: bool,
+        invalid: Arc<AtomicU64>,
+        tracer: Arc<Tracer>,
+        review: Arc<Review>,
+        error_file: Arc<ErrorFile>,
+    ) -> Self {
+        Self {
+            validation,
+            quarantine,
+            scrub,
+            ids: Mutex::new(Vec::new()),
+            invalid,
+            tracer,
+            review,
+            error_file,
+        }
+    }
+
+    /// Decide what happens to a record; `None` means it is dropped
+    pub fn screen(&self, mut record: MarcRecord) -> Result<Option<MarcRecord>> {
+        let Some(validation) = self.validation else {
+            return Ok(Some(record));
+        };
+        if record.rejection.is_some() {
+            return Ok(Some(record));
+        }
+        let mut cleaned = writer::clean_marc_xml(&record.marc);
+        if self.scrub {
+            if let Some((scrubbed, _)) = marc::scrub_illegal_chars(&cleaned) {
+                cleaned = scrubbed;
+            }
+        }
+        let Some(error) = marc::well_formedness_error(&cleaned) else {
+            return Ok(Some(record));
+        };
+
+        self.tracer.note(record.id, "validate", || {
+            format!("not well-formed XML: {}", error)
+        });
+        self.ids.lock().unwrap().push(record.id);
+        self.invalid.fetch_add(1, Ordering::Relaxed);
+
+        let reason = format!("not well-formed XML: {}", error);
+        match validation {
+            Validation::Strict => bail!("Record {} is {} (--validate-strict)", record.id, reason),
+            Validation::Skip if self.quarantine => {
+                record.rejection = Some(Rejection::new("validate", reason));
+                Ok(Some(record))
+            }
+            Validation::Skip => {
+                warn!(
+                    record_id = record.id,
+                    error_kind = Category::InvalidXml.name(),
+                    "Skipping record {}: {}",
+                    record.id,
+                    reason
+                );
+                let rejection = Rejection::new("validate", reason);
+                self.review.capture(&record, &rejection);
+                self.error_file.rejected(record.id, &rejection);
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn validation(&self) -> Option<Validation> {
+        self.validation
+    }
+}
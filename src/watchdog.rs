@@ -0,0 +1,246 @@
+use indicatif::HumanBytes;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info, warn};
+
+use crate::memory;
+use crate::writer::EncodedRecord;
+
+/// How often the resident set size is sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Share of the limit memory must fall back under before scheduling returns to normal
+const RESUME_PERCENT: u64 = 90;
+
+/// Under pressure, senders only fill this fraction of the channel
+const SHRUNK_CHANNEL_DIVISOR: usize = 4;
+
+/// How often a sender held back by the shrunken channel looks again
+const SEND_POLL: Duration = Duration::from_millis(20);
+
+/// How hard the watchdog is pushing back on memory use, in escalating order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pressure {
+    Normal,
+    /// One chunk at a time, and a quarter of the channel
+    Throttle,
+    /// Records go to the spill directory instead of waiting for the writer
+    Spill,
+    /// No new chunks start; the running ones drain and the run stops
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct State {
+    pressure: Pressure,
+    /// Chunks admitted and not yet finished
+    running: usize,
+}
+
+/// Keeps the process under --max-memory by slowing, spilling, and finally stopping the run
+///
+/// Each sample over the limit that shows memory still growing moves one step up
+/// [`Pressure`]; falling back under the limit returns scheduling to normal. Without a
+/// limit nothing is sampled and every chunk is admitted at once. An interrupt (Ctrl-C)
+/// shuts the run down the same way, whatever memory it uses.
+pub struct Watchdog {
+    limit: Option<u64>,
+    spill: bool,
+    state: watch::Sender<State>,
+    /// Chunks (or batches) never started because of a shutdown
+    pub not_run: Mutex<Vec<i64>>,
+    /// What was done and at what memory use, for the summary
+    actions: Mutex<Vec<String>>,
+    /// The shutdown came from an interrupt rather than memory use
+    interrupted: AtomicBool,
+}
+
+/// A chunk let through by the watchdog; it counts as running until dropped
+pub struct Admission<'a>(&'a Watchdog);
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        self.0.state.send_modify(|state| state.running -= 1);
+    }
+}
+
+impl Watchdog {
+    /// `spill` says whether the run has a spill directory to divert records to
+    pub fn new(limit: Option<u64>, spill: bool) -> Self {
+        Self {
+            limit,
+            spill,
+            state: watch::Sender::new(State {
+                pressure: Pressure::Normal,
+                running: 0,
+            }),
+            not_run: Mutex::new(Vec::new()),
+            actions: Mutex::new(Vec::new()),
+            interrupted: AtomicBool::new(false),
+        }
+    }
+
+    pub fn pressure(&self) -> Pressure {
+        self.state.borrow().pressure
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.pressure() == Pressure::Shutdown
+    }
+
+    /// Stop the run as a shutdown does: no new chunks start, and the running ones drain
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+        self.state
+            .send_modify(|state| state.pressure = Pressure::Shutdown);
+    }
+
+    pub fn interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    pub fn actions(&self) -> Vec<String> {
+        self.actions.lock().unwrap().clone()
+    }
+
+    /// Wait until `chunk` may start; `None` means the run is shutting down and the chunk
+    /// is recorded as not run
+    pub async fn admit(&self, chunk: i64) -> Option<Admission<'_>> {
+        let mut changes = self.state.subscribe();
+        loop {
+            changes.borrow_and_update();
+            let mut admitted = false;
+            let mut shutdown = false;
+            self.state.send_if_modified(|state| {
+                match state.pressure {
+                    Pressure::Shutdown => shutdown = true,
+                    // Under pressure chunks still run, but only one at a time
+                    Pressure::Throttle | Pressure::Spill if state.running > 0 => {}
+                    _ => {
+                        state.running += 1;
+                        admitted = true;
+                    }
+                }
+                admitted
+            });
+
+            if admitted {
+                return Some(Admission(self));
+            }
+            if shutdown {
+                self.not_run.lock().unwrap().push(chunk);
+                return None;
+            }
+            // The sender lives in self, so this only returns on a change
+            let _ = changes.changed().await;
+        }
+    }
+
+    /// Send a record, keeping the channel to a fraction of its capacity under pressure
+    pub async fn send(
+        &self,
+        tx: &mpsc::Sender<EncodedRecord>,
+        record: EncodedRecord,
+    ) -> Result<(), mpsc::error::SendError<EncodedRecord>> {
+        let allowed = (tx.max_capacity() / SHRUNK_CHANNEL_DIVISOR).max(1);
+        while self.pressure() >= Pressure::Throttle
+            && tx.max_capacity() - tx.capacity() >= allowed
+            && !tx.is_closed()
+        {
+            tokio::time::sleep(SEND_POLL).await;
+        }
+        tx.send(record).await
+    }
+
+    /// Start sampling memory, if there is a limit to keep to
+    pub fn spawn(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        let limit = self.limit?;
+        let watchdog = Arc::clone(self);
+
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            // Memory use when the current step was taken
+            let mut step_rss = 0;
+
+            loop {
+                interval.tick().await;
+                // An interrupt shut the run down; nothing may bring it back
+                if watchdog.stopped() {
+                    return;
+                }
+                let Some(rss) = memory::current_rss().await else {
+                    warn!("Cannot read this process's memory use; --max-memory is not enforced");
+                    return;
+                };
+
+                let pressure = watchdog.pressure();
+                if rss > limit && (pressure == Pressure::Normal || rss > step_rss) {
+                    let next = watchdog.escalate(pressure, rss, limit);
+                    step_rss = rss;
+                    if next == Pressure::Shutdown {
+                        return;
+                    }
+                } else if pressure != Pressure::Normal && rss <= limit / 100 * RESUME_PERCENT {
+                    info!(
+                        "Memory use down to {}, resuming normal scheduling",
+                        HumanBytes(rss)
+                    );
+                    watchdog.record(format!("resumed at {}", HumanBytes(rss)));
+                    watchdog
+                        .state
+                        .send_modify(|state| state.pressure = Pressure::Normal);
+                }
+            }
+        }))
+    }
+
+    /// Take the next step up from `pressure` and report it
+    fn escalate(&self, pressure: Pressure, rss: u64, limit: u64) -> Pressure {
+        let next = match pressure {
+            Pressure::Normal => Pressure::Throttle,
+            Pressure::Throttle if self.spill => Pressure::Spill,
+            _ => Pressure::Shutdown,
+        };
+
+        let used = format!(
+            "Memory use {} is over --max-memory {}",
+            HumanBytes(rss),
+            HumanBytes(limit)
+        );
+        match next {
+            Pressure::Throttle => {
+                warn!(
+                    "{}: running one chunk at a time and shrinking the channel",
+                    used
+                );
+                self.record(format!("throttled at {}", HumanBytes(rss)));
+            }
+            Pressure::Spill => {
+                warn!(
+                    "{}: spilling records to disk instead of waiting for the writer",
+                    used
+                );
+                self.record(format!("spilled at {}", HumanBytes(rss)));
+            }
+            _ => {
+                error!(
+                    "{}: stopping; no new chunks will start, and the running ones finish before the output is closed",
+                    used
+                );
+                self.record(format!("stopped at {}", HumanBytes(rss)));
+            }
+        }
+
+        self.state.send_modify(|state| state.pressure = next);
+        next
+    }
+
+    fn record(&self, action: String) {
+        self.actions.lock().unwrap().push(action);
+    }
+}
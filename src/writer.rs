@@ -1,91 +1,1078 @@
-use anyhow::{Context, Result};
-use std::path::PathBuf;
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use futures::future::BoxFuture;
+use indicatif::HumanBytes;
+use quick_xml::escape::escape;
+use std::borrow::Cow;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs::File;
-use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::io::{AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 use tracing::debug;
 
-use crate::db::MarcRecord;
+use crate::compression::{self, Compression};
+use crate::db::{MarcRecord, RecordStatus};
+use crate::dc;
+use crate::marc;
+use crate::quarantine::Rejection;
 
-/// XML Writer for MARC records
-pub struct XmlWriter {
-    writer: Box<dyn AsyncWrite + Unpin + Send>,
+/// Result of handing a record to the writer
+#[derive(Debug)]
+pub enum WriteOutcome {
+    /// The record is in the output
+    Written,
+    /// The output cannot hold the record, for this reason
+    Rejected(Rejection),
 }
 
-impl XmlWriter {
-    /// Create a new XML writer
-    pub async fn new(output: Option<PathBuf>) -> Result<Self> {
-        let writer: Box<dyn AsyncWrite + Unpin + Send> = if let Some(path) = output {
+/// The bytes a record adds to the output in some format, or why it cannot be written
+pub type Encoded = std::result::Result<Vec<u8>, Rejection>;
+
+/// A record on its way to the writer, with what a worker encoded it as
+///
+/// Encoding is spread over the workers, so the writer is left to put the bytes in
+/// order. `encoded` is `None` when the output is not a file, or the record was replayed
+/// from a spill file, and the writer encodes the record itself.
+pub struct EncodedRecord {
+    pub record: MarcRecord,
+    pub encoded: Option<Encoded>,
+}
+
+impl From<MarcRecord> for EncodedRecord {
+    fn from(record: MarcRecord) -> Self {
+        Self {
+            record,
+            encoded: None,
+        }
+    }
+}
+
+/// The output ran out of space; writing more records would only fail the same way
+#[derive(Debug)]
+pub struct OutputFull {
+    /// The file being written
+    pub path: String,
+    /// Bytes written to it before it filled up
+    pub bytes_written: u64,
+    /// The filesystem it is on
+    pub filesystem: String,
+}
+
+impl std::fmt::Display for OutputFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "No space left for {} after writing {} (filesystem: {})",
+            self.path,
+            HumanBytes(self.bytes_written),
+            self.filesystem
+        )
+    }
+}
+
+/// A batch of records, already counted as written, could not be written out whole; the
+/// output ends in or before them, and records written after would hide the damage
+#[derive(Debug)]
+pub struct TornBatch {
+    pub target: String,
+    /// Where the batch started, in the uncompressed output
+    pub offset: u64,
+    /// Bytes of the batch that went out before the write failed
+    pub written: usize,
+}
+
+impl std::fmt::Display for TornBatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.written {
+            0 => write!(f, "Records from byte offset {} of {} could not be written", self.offset, self.target),
+            n => write!(
+                f,
+                "Records from byte offset {} of {} were cut off after {} bytes and could not be finished",
+                self.offset, self.target, n
+            ),
+        }
+    }
+}
+
+/// Bytes gathered before each write to an output file, unless --write-buffer-size says
+/// otherwise; tokio's own default
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Records the writer gathers before writing them out, if the buffer has not filled first;
+/// also the most it takes off the channel at a time
+pub const WRITE_BATCH: usize = 256;
+
+/// Times a write that stopped on a transient error is tried again from where it stopped
+const WRITE_RETRIES: u32 = 3;
+
+/// Where the writer's bytes go
+enum Output {
+    /// A file, kept as one so that finalize can sync it
+    File(Box<dyn OutputFile>),
+    Stream(Box<dyn AsyncWrite + Unpin + Send>),
+}
+
+impl Output {
+    fn as_write(&mut self) -> &mut (dyn AsyncWrite + Unpin + Send) {
+        match self {
+            Output::File(file) => file.as_mut(),
+            Output::Stream(stream) => stream.as_mut(),
+        }
+    }
+}
+
+/// A file output, as far as syncing it to disk goes
+pub trait OutputFile: AsyncWrite + Unpin + Send + Sync {
+    /// Sync the file's data, as [`File::sync_data`]
+    fn sync_data(&self) -> BoxFuture<'_, std::io::Result<()>>;
+
+    /// Sync the file's data and metadata, as [`File::sync_all`]
+    fn sync_all(&self) -> BoxFuture<'_, std::io::Result<()>>;
+
+    /// Sync the directory holding the file, which is at `path`
+    fn sync_directory(&self, path: &Path) -> Result<()> {
+        sync_directory_of(path)
+    }
+}
+
+impl OutputFile for File {
+    fn sync_data(&self) -> BoxFuture<'_, std::io::Result<()>> {
+        Box::pin(File::sync_data(self))
+    }
+
+    fn sync_all(&self) -> BoxFuture<'_, std::io::Result<()>> {
+        Box::pin(File::sync_all(self))
+    }
+}
+
+/// Bytes that have reached the output, after any compression, shared with whoever
+/// reports on them
+pub type ByteCounter = Arc<AtomicU64>;
+
+/// The output file or stdout a writer puts records on, whatever their format
+pub struct Destination {
+    writer: Output,
+    /// Compresses what is written before it reaches `writer`
+    encoder: Option<compression::Encoder>,
+    path: Option<PathBuf>,
+    /// File written instead of `path` until the run succeeds, for an atomic output
+    part: Option<PathBuf>,
+    /// Only counting bytes, for --estimate
+    discard: bool,
+    /// Sync a file output and its directory to disk before finish returns
+    durable: bool,
+    /// Bytes given to the destination, before any compression
+    bytes_written: u64,
+    /// Bytes gathered for the next write, after any compression
+    pending: Vec<u8>,
+    /// Size at which `pending` is written out without waiting for the batch to end
+    buffer_size: usize,
+    /// Offset in the uncompressed output where `pending` starts
+    pending_from: u64,
+    /// Counts each byte written out
+    counter: Option<ByteCounter>,
+}
+
+impl Destination {
+    /// Create `output`, or write to stdout without one; an `atomic` output is written to
+    /// its `.part` file, which [`publish`] gives its name. Writes are gathered until the
+    /// batch ends or `buffer_size` bytes are waiting
+    pub async fn open(
+        output: Option<PathBuf>,
+        compression: Compression,
+        atomic: bool,
+        buffer_size: usize,
+    ) -> Result<Self> {
+        let encoder = compression::Encoder::new(compression)
+            .context("Failed to start compressing the output")?;
+        let part = output.as_deref().filter(|_| atomic).map(part_path);
+        let writer = if let Some(path) = part.as_ref().or(output.as_ref()) {
             let file = File::create(&path)
                 .await
                 .context(format!("Failed to create output file: {}", path.display()))?;
-            Box::new(BufWriter::new(file))
+            Output::File(Box::new(file))
         } else {
-            Box::new(tokio::io::stdout())
+            Output::Stream(Box::new(tokio::io::stdout()))
         };
 
-        let mut xml_writer = Self { writer };
+        Ok(Self {
+            writer,
+            encoder,
+            path: output,
+            part,
+            discard: false,
+            durable: true,
+            bytes_written: 0,
+            pending: Vec::with_capacity(buffer_size),
+            buffer_size,
+            pending_from: 0,
+            counter: None,
+        })
+    }
 
-        // Write XML header and collection opening tag
-        xml_writer
-            .writer
-            .write_all(b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n")
-            .await?;
-        xml_writer
-            .writer
-            .write_all(b"<collection xmlns=\"http://www.loc.gov/MARC21/slim\">\n")
-            .await?;
+    /// Reopen a file output a checkpointed run was writing, cut back to the `offset`
+    /// bytes the checkpoint vouches for, to write on from there
+    pub async fn resume(
+        path: PathBuf,
+        offset: u64,
+        atomic: bool,
+        buffer_size: usize,
+    ) -> Result<Self> {
+        let part = atomic.then(|| part_path(&path));
+        let file = reopen(part.as_ref().unwrap_or(&path), offset).await?;
+        Ok(Self {
+            writer: Output::File(Box::new(file)),
+            encoder: None,
+            path: Some(path),
+            part,
+            discard: false,
+            durable: true,
+            bytes_written: offset,
+            pending: Vec::with_capacity(buffer_size),
+            buffer_size,
+            pending_from: offset,
+            counter: None,
+        })
+    }
 
-        Ok(xml_writer)
+    /// A destination that counts the bytes it is given and throws them away
+    pub fn discard() -> Self {
+        Self {
+            writer: Output::Stream(Box::new(tokio::io::sink())),
+            encoder: None,
+            path: None,
+            part: None,
+            discard: true,
+            durable: false,
+            bytes_written: 0,
+            pending: Vec::new(),
+            buffer_size: 0,
+            pending_from: 0,
+            counter: None,
+        }
     }
 
-    /// Write a single MARC record
-    pub async fn write_record(&mut self, record: &MarcRecord) -> Result<()> {
-        debug!("Writing record ID {}", record.id);
+    /// Whether finish syncs a file output to disk; stdout is never synced
+    pub fn durable(mut self, durable: bool) -> Self {
+        self.durable = durable;
+        self
+    }
+
+    /// Add the bytes written out, after any compression, to `counter`
+    pub fn count_bytes(mut self, counter: Option<ByteCounter>) -> Self {
+        self.counter = counter;
+        self
+    }
 
-        // Clean the MARC XML to remove any wrapper elements or declarations
-        let cleaned_marc = self.clean_marc_xml(&record.marc);
+    /// The output file, or STDOUT
+    pub fn target(&self) -> String {
+        match &self.path {
+            Some(path) => path.display().to_string(),
+            None if self.discard => "nowhere (estimating)".to_string(),
+            None => "STDOUT".to_string(),
+        }
+    }
 
-        // Write the record
-        self.writer.write_all(cleaned_marc.as_bytes()).await?;
-        self.writer.write_all(b"\n").await?;
+    /// Byte offset the next write lands at, in the uncompressed output
+    pub fn offset(&self) -> u64 {
+        self.bytes_written
+    }
 
+    /// Add `bytes` to the batch, writing the batch out once `buffer_size` bytes are waiting
+    pub async fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        if self.discard {
+            self.bytes_written += bytes.len() as u64;
+            return Ok(());
+        }
+        match &mut self.encoder {
+            Some(encoder) => match encoder.write(bytes) {
+                Ok(compressed) => self.pending.extend_from_slice(&compressed),
+                Err(e) => return Err(self.io_error(e)),
+            },
+            None => self.pending.extend_from_slice(bytes),
+        }
+        self.bytes_written += bytes.len() as u64;
+        if self.pending.len() >= self.buffer_size {
+            self.flush().await?;
+        }
         Ok(())
     }
 
-    /// Finalize and close the XML document
-    pub async fn finalize(mut self) -> Result<()> {
-        // Write closing collection tag
-        self.writer.write_all(b"</collection>\n").await?;
+    /// Write out the batch in one write, however many records it holds
+    ///
+    /// A write the OS interrupted or timed out is tried again from where it stopped. The
+    /// records in the batch were counted as written when they joined it, so a batch that
+    /// cannot be written out whole is [`TornBatch`], which stops the run.
+    pub async fn flush(&mut self) -> Result<()> {
+        let (mut done, mut retries) = (0, 0);
+        while done < self.pending.len() {
+            match self.writer.as_write().write(&self.pending[done..]).await {
+                Ok(0) => {
+                    return Err(self.torn(std::io::Error::from(std::io::ErrorKind::WriteZero), done))
+                }
+                Ok(n) => {
+                    done += n;
+                    retries = 0;
+                    if let Some(counter) = &self.counter {
+                        counter.fetch_add(n as u64, Ordering::Relaxed);
+                    }
+                }
+                Err(e) if is_transient(&e) && retries < WRITE_RETRIES => {
+                    retries += 1;
+                    tokio::time::sleep(Duration::from_millis(50 << retries)).await;
+                }
+                Err(e) => return Err(self.torn(e, done)),
+            }
+        }
+        self.pending.clear();
+        self.pending_from = self.bytes_written;
+        Ok(())
+    }
+
+    /// A failed write of the batch, `written` bytes into it; running out of space is
+    /// reported as that
+    fn torn(&self, e: std::io::Error, written: usize) -> anyhow::Error {
+        if is_out_of_space(&e) {
+            return self.io_error(e);
+        }
+        self.io_error(e).context(TornBatch {
+            target: self.target(),
+            offset: self.pending_from,
+            written,
+        })
+    }
+
+    /// Write out the batch and, for a durable file, sync it to disk, so that everything
+    /// written so far survives a crash; a compressed stream is not ended, so only what the
+    /// encoder has already let go of is written
+    pub async fn sync(&mut self) -> Result<()> {
+        self.flush().await?;
+        if let Err(e) = self.writer.as_write().flush().await {
+            return Err(self.io_error(e));
+        }
+        if let (true, Output::File(file)) = (self.durable, &self.writer) {
+            file.sync_data()
+                .await
+                .context(format!("Failed to sync output file: {}", self.target()))?;
+        }
+        Ok(())
+    }
 
-        // Flush any remaining buffered data
-        self.writer.flush().await?;
+    /// End any compressed stream and write out the batch, then sync it to disk for a
+    /// durable file
+    pub async fn finish(mut self) -> Result<()> {
+        if let Some(encoder) = self.encoder.take() {
+            match encoder.finish() {
+                Ok(rest) => self.pending.extend_from_slice(&rest),
+                Err(e) => return Err(self.io_error(e)),
+            }
+        }
+        self.flush().await?;
+        if let Err(e) = self.writer.as_write().flush().await {
+            return Err(self.io_error(e));
+        }
 
+        if let (true, Output::File(file), Some(path)) = (self.durable, &self.writer, self.file()) {
+            file.sync_all()
+                .await
+                .context(format!("Failed to sync {} to disk", path.display()))?;
+            file.sync_directory(path)?;
+        }
         Ok(())
     }
 
-    /// Clean MARC XML to remove wrapper elements and declarations
-    fn clean_marc_xml(&self, marc: &str) -> String {
-        let mut cleaned = marc.trim().to_string();
+    /// The file being written, which is the `.part` file of an atomic output
+    fn file(&self) -> Option<&Path> {
+        self.part.as_deref().or(self.path.as_deref())
+    }
+
+    /// Attach `OutputFull` to out-of-space errors so the writer task can stop the run
+    fn io_error(&self, e: std::io::Error) -> anyhow::Error {
+        if !is_out_of_space(&e) {
+            return e.into();
+        }
+
+        let (path, filesystem) = match self.file() {
+            Some(path) => (path.display().to_string(), filesystem_of(path)),
+            None => ("STDOUT".to_string(), "unknown".to_string()),
+        };
+        anyhow::Error::new(e).context(OutputFull {
+            path,
+            bytes_written: self.bytes_written,
+            filesystem,
+        })
+    }
+}
+
+/// Open `path` for writing at `offset`, dropping anything after it
+///
+/// A file that is missing, or shorter than `offset`, is not the file the checkpoint
+/// describes, and resuming it would lose records.
+pub async fn reopen(path: &Path, offset: u64) -> Result<File> {
+    let mut file = match tokio::fs::OpenOptions::new().write(true).open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!(
+                "Cannot resume: {} is missing; it should hold {} bytes",
+                path.display(),
+                offset
+            )
+        }
+        Err(e) => return Err(e).context(format!("Failed to reopen {}", path.display())),
+    };
+    let length = file
+        .metadata()
+        .await
+        .context(format!("Failed to reopen {}", path.display()))?
+        .len();
+    if length < offset {
+        bail!(
+            "Cannot resume: {} is {} bytes, shorter than the {} the checkpoint records",
+            path.display(),
+            length,
+            offset
+        );
+    }
+    file.set_len(offset).await.context(format!(
+        "Failed to cut {} back to the checkpoint",
+        path.display()
+    ))?;
+    file.seek(SeekFrom::Start(offset))
+        .await
+        .context(format!("Failed to reopen {}", path.display()))?;
+    Ok(file)
+}
+
+/// Root element of an OAI-PMH response
+const OAI_ROOT: &str = "<OAI-PMH xmlns=\"http://www.openarchives.org/OAI/2.0/\" \
+    xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" \
+    xsi:schemaLocation=\"http://www.openarchives.org/OAI/2.0/ http://www.openarchives.org/OAI/2.0/OAI-PMH.xsd\">";
+
+/// What an XML document holds for each record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metadata {
+    /// The MARCXML record itself
+    Marc,
+    /// An oai_dc:dc element mapped from it, for --format dc
+    DublinCore,
+}
+
+/// How one --format lays out its document: what opens it, each record, and what closes it
+///
+/// [`FormatWriter`] puts what it gives on a [`Destination`].
+pub trait Encoder: Send + Sync {
+    /// What the document opens with
+    fn header(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// The bytes a record adds to the document, or why it cannot be written; an error
+    /// stops the run
+    fn encode(&self, record: &MarcRecord) -> Result<Encoded>;
+
+    /// What goes before a record: the `first`, or one following another
+    fn separator(&self, _first: bool) -> &'static [u8] {
+        b""
+    }
+
+    /// What the document closes with, after no records when `empty`
+    fn footer(&self, _empty: bool) -> &'static [u8] {
+        b""
+    }
+
+    /// Bytes a record may add to the file besides its encoding: a separator before it,
+    /// or the close of the document after it
+    fn overhead(&self) -> u64 {
+        (self.separator(false).len() + self.footer(false).len()) as u64
+    }
+}
+
+/// Writes the records of one --format to one file, or stdout
+pub struct FormatWriter {
+    out: Destination,
+    encoder: Arc<dyn Encoder>,
+    /// Whether a record has been written, so the next takes the separator that follows one
+    written: bool,
+}
+
+impl FormatWriter {
+    /// Open the document on `out` with the encoder's header
+    pub async fn start(mut out: Destination, encoder: Arc<dyn Encoder>) -> Result<Self> {
+        out.write_bytes(&encoder.header()).await?;
+        Ok(Self {
+            out,
+            encoder,
+            written: false,
+        })
+    }
+
+    /// Write on after what a checkpointed run left on `out`, whose header is already
+    /// there; anything past it is a record
+    pub fn resume(out: Destination, encoder: Arc<dyn Encoder>) -> Self {
+        let written = out.offset() > encoder.header().len() as u64;
+        Self {
+            out,
+            encoder,
+            written,
+        }
+    }
+
+    /// Encode and write a single record; one that cannot be encoded is rejected whole,
+    /// so no partial record reaches the output
+    pub async fn write_record(&mut self, record: &MarcRecord) -> Result<WriteOutcome> {
+        let encoded = self.encode(record)?;
+        self.write(encoded).await
+    }
+
+    /// Write a record a worker has already encoded
+    pub async fn write(&mut self, encoded: Encoded) -> Result<WriteOutcome> {
+        match encoded {
+            Ok(bytes) => {
+                self.write_encoded(&bytes).await?;
+                Ok(WriteOutcome::Written)
+            }
+            Err(rejection) => Ok(WriteOutcome::Rejected(rejection)),
+        }
+    }
+
+    /// The bytes `record` adds to the output, or why it cannot be written
+    pub fn encode(&self, record: &MarcRecord) -> Result<Encoded> {
+        debug!("Writing record ID {}", record.id);
+        self.encoder.encode(record)
+    }
+
+    /// Write a record as [`encode`](Self::encode) gave it, after the separator it needs
+    pub async fn write_encoded(&mut self, bytes: &[u8]) -> Result<()> {
+        self.out
+            .write_bytes(self.encoder.separator(!self.written))
+            .await?;
+        self.out.write_bytes(bytes).await?;
+        self.written = true;
+        Ok(())
+    }
+
+    /// Bytes a record may add to the file besides its encoding
+    pub fn overhead(&self) -> u64 {
+        self.encoder.overhead()
+    }
+
+    /// Output and byte offset the next write lands at
+    pub fn position(&self) -> String {
+        format!("{} at byte offset {}", self.target(), self.offset())
+    }
+
+    /// The output file, or STDOUT
+    pub fn target(&self) -> String {
+        self.out.target()
+    }
+
+    /// Byte offset the next write lands at
+    pub fn offset(&self) -> u64 {
+        self.out.offset()
+    }
+
+    /// Make everything written so far survive a crash
+    pub async fn sync(&mut self) -> Result<()> {
+        self.out.sync().await
+    }
+
+    /// Write out the records gathered so far
+    pub async fn flush(&mut self) -> Result<()> {
+        self.out.flush().await
+    }
+
+    /// Write text verbatim as its own line
+    pub async fn write_raw(&mut self, text: &str) -> Result<()> {
+        self.out.write_bytes(text.as_bytes()).await?;
+        self.out.write_bytes(b"\n").await
+    }
+
+    /// Write an XML comment, defusing any `--` sequences in the text
+    pub async fn write_comment(&mut self, text: &str) -> Result<()> {
+        let mut safe = text.replace("--", "- -");
+        if safe.ends_with('-') {
+            safe.push(' ');
+        }
+        self.write_raw(&format!("<!-- {} -->", safe)).await
+    }
 
-        // Remove XML declaration if present
-        if let Some(pos) = cleaned.find("<?xml") {
-            if let Some(end_pos) = cleaned[pos..].find("?>") {
-                cleaned.replace_range(pos..pos + end_pos + 2, "");
+    /// Close the document and flush
+    pub async fn finalize(mut self) -> Result<()> {
+        self.out
+            .write_bytes(self.encoder.footer(!self.written))
+            .await?;
+        self.out.finish().await
+    }
+}
+
+/// Encoder for MARCXML, or Dublin Core, in a collection or an OAI-PMH response
+pub struct XmlEncoder {
+    metadata: Metadata,
+    /// Write non-ASCII characters as numeric character references
+    escape_non_ascii: bool,
+    /// Write each record one element per line, indented
+    pretty: bool,
+    /// Write an OAI-PMH ListRecords response rather than a collection, each record
+    /// identified by this prefix and its id
+    oai_prefix: Option<String>,
+}
+
+impl XmlEncoder {
+    pub fn new(oai_prefix: Option<String>, metadata: Metadata) -> Self {
+        Self {
+            metadata,
+            escape_non_ascii: false,
+            pretty: false,
+            oai_prefix,
+        }
+    }
+
+    /// Write records with non-ASCII characters as numeric character references
+    pub fn escape_non_ascii(mut self, escape: bool) -> Self {
+        self.escape_non_ascii = escape;
+        self
+    }
+
+    /// Write records one element per line, indented, rather than as stored
+    pub fn pretty(mut self, pretty: bool) -> Self {
+        self.pretty = pretty;
+        self
+    }
+
+    /// A record's text as the line or, pretty-printed, the lines it is written as
+    ///
+    /// Records sit a level inside the collection or ListRecords element. One that cannot be
+    /// pretty-printed is written as it is.
+    fn line(&self, text: String) -> Vec<u8> {
+        let mut text = if self.pretty {
+            marc::pretty(&text, 1).unwrap_or(text)
+        } else {
+            text
+        };
+        text.push('\n');
+        text.into_bytes()
+    }
+}
+
+impl Encoder for XmlEncoder {
+    fn header(&self) -> Vec<u8> {
+        let mut header = b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n".to_vec();
+        match self.oai_prefix {
+            Some(_) => {
+                let prefix = match self.metadata {
+                    Metadata::Marc => "marc21",
+                    Metadata::DublinCore => "oai_dc",
+                };
+                let open = format!(
+                    "{}\n<responseDate>{}</responseDate>\n\
+                     <request verb=\"ListRecords\" metadataPrefix=\"{}\"/>\n<ListRecords>\n",
+                    OAI_ROOT,
+                    Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+                    prefix
+                );
+                header.extend_from_slice(open.as_bytes());
+            }
+            // Dublin Core has no collection element of its own; each record declares its namespaces
+            None if self.metadata == Metadata::DublinCore => {
+                header.extend_from_slice(b"<dcCollection>\n");
+            }
+            None => {
+                header
+                    .extend_from_slice(b"<collection xmlns=\"http://www.loc.gov/MARC21/slim\">\n");
             }
         }
+        header
+    }
 
-        // Remove collection wrapper if present
-        cleaned = cleaned.replace("<collection xmlns=\"http://www.loc.gov/MARC21/slim\">", "");
-        cleaned = cleaned.replace("</collection>", "");
+    /// The lines a record adds to the document
+    fn encode(&self, record: &MarcRecord) -> Result<Encoded> {
+        // An OAI-PMH deletion is its header alone, whatever is left of its MARC
+        if let (Some(prefix), Some(status)) = (&self.oai_prefix, &record.status) {
+            if status.deleted {
+                if let Some(rejection) = &record.rejection {
+                    return Ok(Err(rejection.clone()));
+                }
+                let header = oai_header(prefix, record.id, status);
+                return Ok(Ok(self.line(format!("<record>{}</record>", header))));
+            }
+        }
 
-        // Remove any other common collection variations
-        if let Some(pos) = cleaned.find("<collection") {
-            if let Some(end_pos) = cleaned[pos..].find('>') {
-                cleaned.replace_range(pos..pos + end_pos + 1, "");
+        let mut cleaned_marc = match prepare_record(record) {
+            Ok(cleaned) => cleaned.into_owned(),
+            Err(rejection) => return Ok(Err(rejection)),
+        };
+        if self.metadata == Metadata::DublinCore {
+            cleaned_marc = match dc::to_dc(&cleaned_marc, record.id) {
+                Ok(dc) => dc,
+                Err(reason) => return Ok(Err(Rejection::new("encode", reason))),
+            };
+        }
+        if self.escape_non_ascii {
+            cleaned_marc = match marc::escape_non_ascii(&cleaned_marc) {
+                Some(escaped) => escaped,
+                None => {
+                    let reason =
+                        "not well-formed XML, so its non-ASCII characters cannot be escaped";
+                    return Ok(Err(Rejection::new("escape", reason)));
+                }
+            };
+        }
+
+        match &self.oai_prefix {
+            Some(prefix) => {
+                let Some(status) = &record.status else {
+                    bail!(
+                        "Record {} was fetched without the edit date its OAI-PMH header needs",
+                        record.id
+                    );
+                };
+                let header = oai_header(prefix, record.id, status);
+                let wrapped = format!(
+                    "<record>{}<metadata>\n{}\n</metadata></record>",
+                    header, cleaned_marc
+                );
+                Ok(Ok(self.line(wrapped)))
             }
+            None => Ok(Ok(self.line(cleaned_marc))),
+        }
+    }
+
+    fn footer(&self, _empty: bool) -> &'static [u8] {
+        match (&self.oai_prefix, self.metadata) {
+            (Some(_), _) => b"</ListRecords>\n</OAI-PMH>\n",
+            (None, Metadata::DublinCore) => b"</dcCollection>\n",
+            (None, Metadata::Marc) => b"</collection>\n",
+        }
+    }
+}
+
+/// The OAI-PMH header of a record: its identifier, and its edit date as the datestamp
+fn oai_header(prefix: &str, id: i64, status: &RecordStatus) -> String {
+    let deleted = if status.deleted {
+        " status=\"deleted\""
+    } else {
+        ""
+    };
+    format!(
+        "<header{}><identifier>{}{}</identifier><datestamp>{}</datestamp></header>",
+        deleted,
+        escape(prefix),
+        id,
+        status.edit_date.format("%Y-%m-%dT%H:%M:%SZ")
+    )
+}
+
+/// Sync a written file to disk, then the directory holding it
+///
+/// Without the second sync a power loss can leave the directory entry pointing at
+/// nothing, or not there at all, although the file's own data reached the disk.
+pub async fn sync_to_disk(file: &File, path: &Path) -> Result<()> {
+    file.sync_all()
+        .await
+        .context(format!("Failed to sync {} to disk", path.display()))?;
+    sync_directory_of(path)
+}
+
+/// Sync the directory holding `path`, so that its entry for the file survives a power loss
+pub fn sync_directory_of(path: &Path) -> Result<()> {
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let synced = std::fs::File::open(dir).and_then(|handle| handle.sync_all());
+    synced.context(format!(
+        "Failed to sync directory {} to disk",
+        dir.display()
+    ))
+}
+
+/// The file an atomic output is written to until the run succeeds: `NAME.part` beside `NAME`
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// Give a finished atomic output its name, replacing any file already there
+///
+/// The `.part` file was synced when it was finalized; for a durable output the directory
+/// is synced after the rename too, so the new name is what survives a power loss.
+pub fn publish(path: &Path, durable: bool) -> Result<()> {
+    let part = part_path(path);
+    std::fs::rename(&part, path).context(format!(
+        "Failed to rename {} to {}",
+        part.display(),
+        path.display()
+    ))?;
+    if durable {
+        sync_directory_of(path)?;
+    }
+    Ok(())
+}
+
+/// The cleaned MARC XML to write for a record, or why it cannot be written
+///
+/// The workers leave records cleaned, so those are taken as they are rather than cleaned
+/// a second time.
+pub fn prepare_record(record: &MarcRecord) -> std::result::Result<Cow<'_, str>, Rejection> {
+    if let Some(rejection) = &record.rejection {
+        return Err(rejection.clone());
+    }
+
+    // Clean the MARC XML to remove any wrapper elements or declarations
+    let cleaned_marc = if marc::is_bare_record(&record.marc) {
+        Cow::Borrowed(record.marc.as_str())
+    } else {
+        Cow::Owned(clean_marc_xml(&record.marc))
+    };
+
+    if !has_record_element(&cleaned_marc) {
+        return Err(Rejection::new("clean", "no <record> element found"));
+    }
+
+    Ok(cleaned_marc)
+}
+
+/// Clean MARC XML down to its record element, with the MARC21/slim namespace declared
+///
+/// Records that do not parse are cleaned as text instead, losing the declaration and
+/// any collection wrapper, and are otherwise written as they are.
+pub fn clean_marc_xml(marc: &str) -> String {
+    if let Some(record) = marc::record_element(marc) {
+        return record;
+    }
+
+    let mut cleaned = marc.trim().to_string();
+
+    // Remove XML declaration if present
+    if let Some(pos) = cleaned.find("<?xml") {
+        if let Some(end_pos) = cleaned[pos..].find("?>") {
+            cleaned.replace_range(pos..pos + end_pos + 2, "");
+        }
+    }
+
+    // Remove collection wrapper if present
+    cleaned = cleaned.replace("<collection xmlns=\"http://www.loc.gov/MARC21/slim\">", "");
+    cleaned = cleaned.replace("</collection>", "");
+
+    // Remove any other common collection variations
+    if let Some(pos) = cleaned.find("<collection") {
+        if let Some(end_pos) = cleaned[pos..].find('>') {
+            cleaned.replace_range(pos..pos + end_pos + 1, "");
+        }
+    }
+
+    // Trim any extra whitespace
+    cleaned.trim().to_string()
+}
+
+/// Whether a write that failed this way may go through when tried again
+fn is_transient(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        e.kind(),
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+    )
+}
+
+/// ENOSPC, or EDQUOT (Linux) when a disk quota is exhausted
+fn is_out_of_space(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(28) | Some(122))
+}
+
+/// Mount point, type, and device of the filesystem holding `path`, from /proc/self/mounts
+fn filesystem_of(path: &Path) -> String {
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let (Ok(dir), Ok(mounts)) = (
+        dir.canonicalize(),
+        std::fs::read_to_string("/proc/self/mounts"),
+    ) else {
+        return "unknown".to_string();
+    };
+
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (device, mount, fstype) = (fields.next()?, fields.next()?, fields.next()?);
+            dir.starts_with(mount).then_some((device, mount, fstype))
+        })
+        .max_by_key(|(_, mount, _)| mount.len())
+        .map(|(device, mount, fstype)| format!("{} ({} on {})", mount, fstype, device))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Whether the text contains a `record` start tag, with or without a namespace prefix
+fn has_record_element(xml: &str) -> bool {
+    xml.match_indices('<').any(|(pos, _)| {
+        let name = xml[pos + 1..]
+            .split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .next()
+            .unwrap_or("");
+        name.rsplit(':').next() == Some("record")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::sync::Mutex;
+    use std::task::{Context as TaskContext, Poll};
+
+    /// A file output that only records what was done to it
+    #[derive(Clone, Default)]
+    struct RecordingFile {
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RecordingFile {
+        fn record(&self, call: impl Into<String>) {
+            self.calls.lock().unwrap().push(call.into());
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
         }
+    }
+
+    impl AsyncWrite for RecordingFile {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut TaskContext<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.record("write");
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _: &mut TaskContext<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl OutputFile for RecordingFile {
+        fn sync_data(&self) -> BoxFuture<'_, std::io::Result<()>> {
+            self.record("sync_data");
+            Box::pin(async { Ok(()) })
+        }
+
+        fn sync_all(&self) -> BoxFuture<'_, std::io::Result<()>> {
+            self.record("sync_all");
+            Box::pin(async { Ok(()) })
+        }
+
+        fn sync_directory(&self, path: &Path) -> Result<()> {
+            self.record(format!("sync_directory {}", path.display()));
+            Ok(())
+        }
+    }
+
+    fn destination(file: &RecordingFile, durable: bool) -> Destination {
+        Destination {
+            writer: Output::File(Box::new(file.clone())),
+            encoder: None,
+            path: Some(PathBuf::from("out/records.xml")),
+            part: None,
+            discard: false,
+            durable,
+            bytes_written: 0,
+            pending: Vec::new(),
+            buffer_size: 1024,
+            pending_from: 0,
+            counter: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn finish_syncs_the_file_then_its_directory() {
+        let file = RecordingFile::default();
+        let mut out = destination(&file, true);
+        out.write_bytes(b"<collection/>").await.unwrap();
+        out.finish().await.unwrap();
+        assert_eq!(
+            file.calls(),
+            ["write", "sync_all", "sync_directory out/records.xml"]
+        );
+    }
+
+    #[tokio::test]
+    async fn an_atomic_output_syncs_its_part_file() {
+        let file = RecordingFile::default();
+        let mut out = destination(&file, true);
+        out.part = Some(part_path(Path::new("out/records.xml")));
+        out.write_bytes(b"<collection/>").await.unwrap();
+        out.finish().await.unwrap();
+        assert_eq!(
+            file.calls().last().unwrap(),
+            "sync_directory out/records.xml.part"
+        );
+    }
+
+    #[tokio::test]
+    async fn sync_writes_out_the_batch_before_syncing() {
+        let file = RecordingFile::default();
+        let mut out = destination(&file, true);
+        out.write_bytes(b"<record/>").await.unwrap();
+        assert!(file.calls().is_empty());
+        out.sync().await.unwrap();
+        assert_eq!(file.calls(), ["write", "sync_data"]);
+    }
+
+    #[tokio::test]
+    async fn an_output_that_is_not_durable_is_never_synced() {
+        let file = RecordingFile::default();
+        let mut out = destination(&file, false);
+        out.write_bytes(b"<record/>").await.unwrap();
+        out.sync().await.unwrap();
+        out.finish().await.unwrap();
+        assert_eq!(file.calls(), ["write"]);
+    }
+
+    #[tokio::test]
+    async fn finalize_syncs_after_the_footer() {
+        let file = RecordingFile::default();
+        let encoder = Arc::new(XmlEncoder::new(None, Metadata::Marc));
+        let writer = FormatWriter::start(destination(&file, true), encoder)
+            .await
+            .unwrap();
+        writer.finalize().await.unwrap();
+        // The header and footer go out in one batch, then the file and directory are synced
+        assert_eq!(
+            file.calls(),
+            ["write", "sync_all", "sync_directory out/records.xml"]
+        );
+    }
+
+    #[test]
+    fn a_record_the_workers_cleaned_is_not_cleaned_again() {
+        let mut record = MarcRecord {
+            id: 1,
+            marc: "<?xml version=\"1.0\"?><collection xmlns=\"http://www.loc.gov/MARC21/slim\">\
+                   <record><leader>00000nam a2200000 a 4500</leader></record></collection>"
+                .to_string(),
+            rejection: None,
+            display: Vec::new(),
+            meta: None,
+            identifiers: None,
+            status: None,
+        };
+        assert!(matches!(prepare_record(&record), Ok(Cow::Owned(_))));
 
-        // Trim any extra whitespace
-        cleaned.trim().to_string()
+        record.marc = clean_marc_xml(&record.marc);
+        assert!(matches!(prepare_record(&record), Ok(Cow::Borrowed(_))));
     }
 }
@@ -0,0 +1,45 @@
+use std::path::Path;
+use std::process::Command;
+
+/// What `marc_extractor_rs` prints for `args`, run without a database configured
+fn output(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_marc_extractor_rs"))
+        .args(args)
+        .env_remove("DATABASE_URL")
+        .output()
+        .expect("the binary runs");
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).expect("output is UTF-8")
+}
+
+/// The bash completion script lists every flag, so a renamed or dropped flag changes it
+///
+/// After changing the flags on purpose, regenerate the snapshot with
+/// `UPDATE_SNAPSHOTS=1 cargo test --test completions`.
+#[test]
+fn bash_completion_matches_the_snapshot() {
+    let script = output(&["completions", "bash"]);
+    let snapshot = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots/completions.bash");
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        std::fs::write(&snapshot, &script).unwrap();
+    }
+    let expected = std::fs::read_to_string(&snapshot).unwrap_or_default();
+    assert!(
+        script == expected,
+        "the bash completion script differs from {}; if the flags changed on purpose, \
+         regenerate it with UPDATE_SNAPSHOTS=1 cargo test --test completions",
+        snapshot.display()
+    );
+}
+
+#[test]
+fn man_page_needs_no_database() {
+    let page = output(&["man"]);
+    assert!(page.starts_with(".ie"), "{}", &page[..page.len().min(200)]);
+    assert!(page.contains("marc_extractor_rs"));
+}
@@ -0,0 +1,515 @@
+//! Runs the extractor against databases built by the fixture generator
+//!
+//! Each test creates its own scratch database on the server MARC_EXTRACTOR_TEST_DB
+//! connects to, e.g. `postgresql://postgres@localhost/postgres`; without it the tests
+//! are skipped.
+
+use marc_extractor_rs::{
+    ExtractionProgress, Extractor, ExtractorConfig, MarcRecord, Outcome, RecordSink, WriteOutcome,
+};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use serde_json::Value;
+use sqlx::postgres::PgPoolOptions;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use xtask::{test_database as database, Fixtures};
+
+/// One value per row of `sql`, which selects a single bigint or text column
+async fn query<T>(url: &str, sql: &str) -> T
+where
+    T: for<'r> sqlx::Decode<'r, sqlx::Postgres> + sqlx::Type<sqlx::Postgres> + Send + Unpin,
+{
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(url)
+        .await
+        .unwrap();
+    let value = sqlx::query_scalar(sql).fetch_one(&pool).await.unwrap();
+    pool.close().await;
+    value
+}
+
+/// Run an extraction into a temporary file, returning the summary and the output
+fn extract(url: &str, name: &str, args: &[&str]) -> (Value, String) {
+    let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let output: PathBuf = dir.join("out.xml");
+    let summary = dir.join("summary.json");
+    let run = Command::new(env!("CARGO_BIN_EXE_marc_extractor_rs"))
+        .arg("--db-url")
+        .arg(url)
+        .arg("--output")
+        .arg(&output)
+        .arg("--summary-json")
+        .arg(&summary)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "extraction failed: {}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    let summary: Value = serde_json::from_str(&std::fs::read_to_string(&summary).unwrap()).unwrap();
+    let xml = std::fs::read_to_string(&output).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    (summary, xml)
+}
+
+/// The `record` elements of a document, failing on anything that is not well-formed
+fn record_count(xml: &str) -> i64 {
+    let mut reader = Reader::from_str(xml);
+    let mut count = 0;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.local_name().as_ref() == b"record" => {
+                count += 1
+            }
+            Ok(Event::Eof) => return count,
+            Ok(_) => {}
+            Err(e) => panic!(
+                "output is not well-formed at {}: {}",
+                reader.error_position(),
+                e
+            ),
+        }
+    }
+}
+
+#[tokio::test]
+async fn every_active_record_with_marc_is_extracted() {
+    let Some(url) = database("fixtures_extract", &Fixtures::new(500)).await else {
+        return;
+    };
+    // Deleted rows are left out, NULL marc is skipped, and stubs are skipped
+    let expected: i64 = query(
+        &url,
+        "SELECT count(*) FROM biblio.record_entry
+         WHERE NOT deleted AND marc IS NOT NULL AND marc !~ '<record[^>]*></record>'",
+    )
+    .await;
+    assert!(expected > 400, "only {} records to extract", expected);
+
+    let (summary, xml) = extract(&url, "fixtures_extract", &[]);
+    assert_eq!(summary["status"], "complete");
+    assert_eq!(summary["processed"], expected);
+    assert!(summary["pool"]["acquires"].as_u64() > Some(0));
+    assert_eq!(record_count(&xml), expected);
+    // Prefixed records are written unprefixed, and control characters are scrubbed
+    assert!(!xml.contains("<marc:"));
+    assert!(!xml.contains('\u{0B}'));
+}
+
+#[tokio::test]
+async fn histogram_only_prints_the_counts_and_nothing_else() {
+    let Some(url) = database("fixtures_histogram", &Fixtures::new(200)).await else {
+        return;
+    };
+    // Counted like the selection, before NULL marc and stubs are skipped
+    let expected: i64 = query(
+        &url,
+        "SELECT count(*) FROM biblio.record_entry WHERE NOT deleted",
+    )
+    .await;
+    let run = Command::new(env!("CARGO_BIN_EXE_marc_extractor_rs"))
+        .args([
+            "--db-url",
+            &url,
+            "--histogram",
+            "edit_date:year",
+            "--histogram-only",
+        ])
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "histogram failed: {}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    let stdout = String::from_utf8(run.stdout).unwrap();
+    let counted: i64 = stdout
+        .lines()
+        .map(|line| {
+            line.split_whitespace()
+                .last()
+                .unwrap()
+                .parse::<i64>()
+                .unwrap()
+        })
+        .sum();
+    assert_eq!(counted, expected);
+}
+
+#[tokio::test]
+async fn the_same_seed_generates_the_same_rows() {
+    let digest = "SELECT md5(string_agg(id || ':' || deleted || ':' || coalesce(marc, '-'), '|' ORDER BY id))
+                  FROM biblio.record_entry";
+    let Some(first) = database("fixtures_seed_a", &Fixtures::new(300)).await else {
+        return;
+    };
+    let Some(again) = database("fixtures_seed_b", &Fixtures::new(300)).await else {
+        return;
+    };
+    let other_seed = Fixtures {
+        seed: 2,
+        ..Fixtures::new(300)
+    };
+    let Some(other) = database("fixtures_seed_c", &other_seed).await else {
+        return;
+    };
+
+    let first: String = query(&first, digest).await;
+    assert_eq!(first, query::<String>(&again, digest).await);
+    assert_ne!(first, query::<String>(&other, digest).await);
+}
+
+#[tokio::test]
+async fn the_edge_cases_are_all_generated() {
+    let Some(url) = database("fixtures_mix", &Fixtures::new(1000)).await else {
+        return;
+    };
+    for (case, sql) in [
+        (
+            "deleted",
+            "SELECT count(*) FROM biblio.record_entry WHERE deleted",
+        ),
+        (
+            "NULL marc",
+            "SELECT count(*) FROM biblio.record_entry WHERE marc IS NULL",
+        ),
+        (
+            "stub",
+            "SELECT count(*) FROM biblio.record_entry WHERE marc ~ '<record[^>]*></record>'",
+        ),
+        (
+            "prefixed",
+            "SELECT count(*) FROM biblio.record_entry WHERE marc LIKE '%<marc:record%'",
+        ),
+        (
+            "control character",
+            "SELECT count(*) FROM biblio.record_entry WHERE marc LIKE '%' || chr(11) || '%'",
+        ),
+        (
+            "duplicate 035",
+            "SELECT count(*) FROM (
+                 SELECT substring(marc FROM '\\(OCoLC\\)[0-9]+') AS oclc FROM biblio.record_entry
+             ) r WHERE oclc IS NOT NULL GROUP BY oclc HAVING count(*) > 1 LIMIT 1",
+        ),
+    ] {
+        let found: i64 = query(&url, sql).await;
+        assert!(found > 0, "no {} rows among the fixtures", case);
+    }
+}
+
+/// Fill a small tmpfs partway through a run, then free space and resume it
+///
+/// The tmpfs is mounted in a user and mount namespace of its own, so no root is needed;
+/// where `unshare` cannot make one the test is skipped.
+#[tokio::test]
+async fn a_run_the_disk_fills_up_on_resumes_from_its_checkpoint() {
+    let Some(url) = database("fixtures_enospc", &Fixtures::new(2000)).await else {
+        return;
+    };
+    let namespaces = Command::new("unshare").args(["-rm", "true"]).output();
+    if !namespaces.is_ok_and(|output| output.status.success()) {
+        eprintln!("unshare cannot make a mount namespace; skipping");
+        return;
+    }
+    let expected: i64 = query(
+        &url,
+        "SELECT count(*) FROM biblio.record_entry
+         WHERE NOT deleted AND marc IS NOT NULL AND marc !~ '<record[^>]*></record>'",
+    )
+    .await;
+
+    let dir = std::env::temp_dir().join(format!("fixtures_enospc-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("out")).unwrap();
+    // The checkpoint is kept outside the tmpfs, as it must be to be saved once it is full
+    let script = r#"
+        run() {
+            "$BIN" --db-url "$URL" --output "$DIR/out/records.xml" \
+                --checkpoint "$DIR/run.checkpoint" "$@" 2>>"$DIR/log"
+            echo $?
+        }
+        mount -t tmpfs -o size=128k tmpfs "$DIR/out" || exit 1
+        run > "$DIR/full.status"
+        cp "$DIR/run.checkpoint" "$DIR/saved.checkpoint"
+        mount -o remount,size=64m "$DIR/out" || exit 1
+        run --resume > "$DIR/resumed.status"
+        cp "$DIR/out/records.xml" "$DIR/records.xml"
+    "#;
+    let status = Command::new("unshare")
+        .args(["-rm", "sh", "-c", script])
+        .env("BIN", env!("CARGO_BIN_EXE_marc_extractor_rs"))
+        .env("URL", &url)
+        .env("DIR", &dir)
+        .status()
+        .unwrap();
+    let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap_or_default();
+    assert!(status.success(), "the script failed: {}", read("log"));
+
+    assert_eq!(read("full.status").trim(), "11", "{}", read("log"));
+    assert!(
+        read("saved.checkpoint").contains("\"offset\""),
+        "no checkpoint was left: {}",
+        read("log")
+    );
+    assert_eq!(read("resumed.status").trim(), "0", "{}", read("log"));
+
+    let xml = read("records.xml");
+    assert_eq!(record_count(&xml), expected);
+    let mut ids: Vec<&str> = xml
+        .split("<controlfield tag=\"001\">")
+        .skip(1)
+        .map(|rest| &rest[..rest.find('<').unwrap()])
+        .collect();
+    ids.sort_unstable();
+    ids.dedup();
+    assert_eq!(ids.len() as i64, expected, "records were written twice");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The "Peak memory" line of a --verbose summary, in bytes
+fn peak_memory(log: &str) -> Option<f64> {
+    let line = log.lines().find(|line| line.contains("Peak memory: "))?;
+    let text = line.split("Peak memory: ").nth(1)?.trim();
+    let (number, unit) = text.split_once(' ')?;
+    let scale = match unit {
+        "B" => 1.0,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    Some(number.parse::<f64>().ok()? * scale)
+}
+
+#[tokio::test]
+async fn low_impact_stays_under_its_memory_ceiling() {
+    let Some(url) = database("fixtures_low_impact", &Fixtures::new(20_000)).await else {
+        return;
+    };
+    let dir = std::env::temp_dir().join(format!("fixtures_low_impact-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let run = Command::new(env!("CARGO_BIN_EXE_marc_extractor_rs"))
+        .arg("--db-url")
+        .arg(&url)
+        .arg("--output")
+        .arg(dir.join("out.xml"))
+        .args(["--low-impact", "--verbose"])
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let log = String::from_utf8_lossy(&run.stderr);
+    // Exit code 12 would mean the watchdog had to stop the run
+    assert!(run.status.success(), "{}", log);
+
+    // The bound the README documents for --low-impact
+    let ceiling = 64.0 * 1024.0 * 1024.0;
+    let peak = peak_memory(&log).expect("the summary reports the peak");
+    assert!(
+        peak < ceiling,
+        "peak memory {:.1} MiB is over the 64 MiB ceiling",
+        peak / 1024.0 / 1024.0
+    );
+}
+
+#[tokio::test]
+async fn a_chain_of_batches_carries_on_its_files_and_manifest() {
+    let Some(url) = database("fixtures_chain", &Fixtures::new(300)).await else {
+        return;
+    };
+    let expected: i64 = query(
+        &url,
+        "SELECT count(*) FROM biblio.record_entry
+         WHERE NOT deleted AND marc IS NOT NULL AND marc !~ '<record[^>]*></record>'",
+    )
+    .await;
+    let dir = std::env::temp_dir().join(format!("fixtures_chain-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut batches = 0;
+    loop {
+        let run = Command::new(env!("CARGO_BIN_EXE_marc_extractor_rs"))
+            .arg("--db-url")
+            .arg(&url)
+            .arg("--output")
+            .arg(dir.join("out.xml"))
+            .arg("--continue-from")
+            .arg(dir.join("chain.token"))
+            .args(["--batch-max", "100", "--records-per-file", "40"])
+            // Only the first batch names the manifest; the token carries it on
+            .args(if batches == 0 {
+                vec!["--manifest".into(), dir.join("manifest.json")]
+            } else {
+                Vec::new()
+            })
+            .output()
+            .unwrap();
+        batches += 1;
+        match run.status.code() {
+            Some(10) if batches < 10 => {}
+            Some(0) => break,
+            _ => panic!(
+                "batch {} failed: {}",
+                batches,
+                String::from_utf8_lossy(&run.stderr)
+            ),
+        }
+    }
+    assert!(batches >= 3, "only {} batches", batches);
+
+    // Every batch's files follow on from the one before, none overwritten
+    let mut files = Vec::new();
+    while let Ok(xml) = std::fs::read_to_string(dir.join(format!("out_{:05}.xml", files.len() + 1)))
+    {
+        files.push(xml);
+    }
+    let written: i64 = files.iter().map(|xml| record_count(xml)).sum();
+    assert_eq!(written, expected);
+
+    let manifest: Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("manifest.json")).unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(manifest["status"], "complete");
+    assert_eq!(manifest["records_written"], expected);
+    assert_eq!(manifest["batches"].as_array().unwrap().len(), batches);
+    assert_eq!(manifest["outputs"].as_array().unwrap().len(), files.len());
+    assert_eq!(manifest["batches"][0]["batch"], 1);
+}
+
+/// Ids in the 001 of each record of a MARCXML document
+fn record_ids(xml: &str) -> Vec<i64> {
+    xml.split(r#"tag="001">"#)
+        .skip(1)
+        .map(|rest| rest[..rest.find('<').unwrap()].trim().parse().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn gaps_are_filled_from_the_id_ranges_of_a_manifest() {
+    let Some(url) = database("fixtures_gaps", &Fixtures::new(300)).await else {
+        return;
+    };
+    let dir = std::env::temp_dir().join(format!("fixtures_gaps-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let run = |args: &[&std::ffi::OsStr]| {
+        let run = Command::new(env!("CARGO_BIN_EXE_marc_extractor_rs"))
+            .arg("--db-url")
+            .arg(&url)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(
+            run.status.success(),
+            "{}",
+            String::from_utf8_lossy(&run.stderr)
+        );
+    };
+    let path = |name: &str| dir.join(name).into_os_string();
+
+    run(&[
+        "--output".as_ref(),
+        &path("out.xml"),
+        "--records-per-file".as_ref(),
+        "50".as_ref(),
+        "--chunk-size".as_ref(),
+        "30".as_ref(),
+        "--manifest".as_ref(),
+        &path("original.json"),
+    ]);
+
+    // The second file went missing, and with it its entry in the manifest
+    let original = std::fs::read_to_string(dir.join("original.json")).unwrap();
+    let mut manifest: Value = serde_json::from_str(&original).unwrap();
+    let lost = manifest["outputs"].as_array_mut().unwrap().remove(1);
+    assert!(lost["id_ranges"]
+        .as_array()
+        .is_some_and(|ranges| !ranges.is_empty()));
+    std::fs::write(dir.join("damaged.json"), manifest.to_string()).unwrap();
+    let mut lost_ids = record_ids(&std::fs::read_to_string(dir.join("out_00002.xml")).unwrap());
+    lost_ids.sort_unstable();
+
+    run(&[
+        "--fill-gaps-against".as_ref(),
+        &path("damaged.json"),
+        "--output".as_ref(),
+        &path("supplement.xml"),
+        "--manifest".as_ref(),
+        &path("supplement.json"),
+    ]);
+    let supplement = std::fs::read_to_string(dir.join("supplement.xml")).unwrap();
+    assert_eq!(record_ids(&supplement), lost_ids);
+
+    // The supplement names the export it completes, and together they leave no gaps
+    let chained: Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("supplement.json")).unwrap())
+            .unwrap();
+    assert_eq!(chained["parent"]["run_id"], manifest["run_id"]);
+    run(&[
+        "--fill-gaps-against".as_ref(),
+        &path("supplement.json"),
+        "--output".as_ref(),
+        &path("nothing.xml"),
+        "--summary-json".as_ref(),
+        &path("nothing.json"),
+    ]);
+    let summary: Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("nothing.json")).unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    // A stub the workers skip may sit between two ranges and be fetched again, but it is
+    // skipped again
+    assert_eq!(summary["processed"], 0);
+}
+
+/// Keeps the id of each record an Extractor hands it
+struct Ids(Arc<Mutex<Vec<i64>>>);
+
+impl RecordSink for Ids {
+    async fn write_record(&mut self, record: &MarcRecord) -> anyhow::Result<WriteOutcome> {
+        self.0.lock().unwrap().push(record.id);
+        Ok(WriteOutcome::Written)
+    }
+
+    async fn finish(self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn the_library_hands_a_sink_what_the_binary_writes() {
+    let Some(url) = database("fixtures_library", &Fixtures::new(300)).await else {
+        return;
+    };
+    let (summary, xml) = extract(&url, "fixtures_library", &[]);
+    let mut expected = record_ids(&xml);
+    expected.sort_unstable();
+
+    let ids = Arc::new(Mutex::new(Vec::new()));
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&progress);
+    let extracted = Extractor::new(ExtractorConfig::new(url.as_str()))
+        .on_progress(move |update| seen.lock().unwrap().push(update))
+        .run(Ids(Arc::clone(&ids)))
+        .await
+        .unwrap();
+    assert_eq!(extracted.outcome, Outcome::Complete);
+    assert_eq!(summary["processed"], extracted.records_written);
+    let mut ids = ids.lock().unwrap().clone();
+    ids.sort_unstable();
+    assert_eq!(ids, expected);
+
+    let progress = progress.lock().unwrap();
+    assert!(matches!(
+        progress.first(),
+        Some(ExtractionProgress::Started { .. })
+    ));
+    assert_eq!(
+        progress.last(),
+        Some(&ExtractionProgress::Finished { stopped: false })
+    );
+}
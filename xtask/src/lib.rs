@@ -0,0 +1,467 @@
+//! The fixture generator behind `cargo xtask generate-fixtures`, also used by the
+//! integration tests to build their databases
+
+use anyhow::{bail, Context, Result};
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// Marks tables created by generate-fixtures, so --reset never drops a real catalog
+const FIXTURE_COMMENT: &str = "marc_extractor_rs fixtures";
+
+/// Rows inserted per statement
+const INSERT_BATCH: usize = 1000;
+
+/// The records to generate, and how
+#[derive(Debug, Clone)]
+pub struct Fixtures {
+    pub count: i64,
+    /// The same seed and templates always produce the same records
+    pub seed: u64,
+    /// Directory of record templates (*.xml)
+    pub templates: PathBuf,
+    /// Drop fixture tables left by an earlier run first
+    pub reset: bool,
+    /// Range partition biblio.record_entry by id, this many ids to a partition
+    pub partition_size: Option<i64>,
+}
+
+impl Fixtures {
+    /// `count` records from the bundled templates, seed 1, unpartitioned
+    pub fn new(count: i64) -> Self {
+        Self {
+            count,
+            seed: 1,
+            templates: default_templates(),
+            reset: false,
+            partition_size: None,
+        }
+    }
+
+    /// Create the schema in the database at `db_url` and fill it
+    pub async fn generate(&self, db_url: &str) -> Result<()> {
+        let templates = load_templates(&self.templates)?;
+        let pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(db_url)
+            .await
+            .context("Failed to connect to database")?;
+
+        if self.reset {
+            drop_fixtures(&pool).await?;
+        }
+        create_schema(&pool, self.partition_size.map(|size| (size, self.count))).await?;
+
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+        let mut oclc_numbers: Vec<u64> = Vec::new();
+        let mut batch = Vec::with_capacity(INSERT_BATCH);
+
+        for id in 1..=self.count {
+            batch.push(generate_row(id, &templates, &mut oclc_numbers, &mut rng));
+            if batch.len() == INSERT_BATCH {
+                insert_rows(&pool, &batch).await?;
+                batch.clear();
+            }
+        }
+        insert_rows(&pool, &batch).await?;
+        pool.close().await;
+        Ok(())
+    }
+
+    /// How many templates the records are drawn from
+    pub fn template_count(&self) -> Result<usize> {
+        Ok(load_templates(&self.templates)?.len())
+    }
+}
+
+/// The templates bundled in xtask/fixtures
+pub fn default_templates() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures")
+}
+
+/// Create an empty database `name` on the server `server_url` connects to, dropping any
+/// left by an earlier run, and return the URL that connects to it
+///
+/// Only for scratch servers: whatever database of that name is there is lost.
+pub async fn scratch_database(server_url: &str, name: &str) -> Result<String> {
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        bail!(
+            "scratch database names are letters, digits, and underscores: {}",
+            name
+        );
+    }
+    let mut url = Url::parse(server_url).context("Failed to parse the server URL")?;
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(server_url)
+        .await
+        .context("Failed to connect to the scratch server")?;
+    sqlx::query(&format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", name))
+        .execute(&pool)
+        .await
+        .context(format!("Failed to drop database {}", name))?;
+    sqlx::query(&format!("CREATE DATABASE {}", name))
+        .execute(&pool)
+        .await
+        .context(format!("Failed to create database {}", name))?;
+    pool.close().await;
+    url.set_path(name);
+    Ok(url.to_string())
+}
+
+/// A scratch database named `name` holding `fixtures`, on the test server in
+/// MARC_EXTRACTOR_TEST_DB, or `None` when it is not set and the calling test is skipped
+pub async fn test_database(name: &str, fixtures: &Fixtures) -> Option<String> {
+    let Ok(server) = std::env::var("MARC_EXTRACTOR_TEST_DB") else {
+        eprintln!("MARC_EXTRACTOR_TEST_DB is not set; skipping {}", name);
+        return None;
+    };
+    let url = scratch_database(&server, name).await.unwrap();
+    fixtures.generate(&url).await.unwrap();
+    Some(url)
+}
+
+/// What a generated row looks like, beyond its template
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Shape {
+    Normal,
+    /// marc is NULL
+    NullMarc,
+    /// A leader-less, field-less `<record></record>`
+    Stub,
+    /// A control character in the title, which is not allowed in XML 1.0
+    InvalidChar,
+    /// Same 035 as an earlier record
+    Duplicate035,
+}
+
+/// One row of biblio.record_entry
+struct Row {
+    id: i64,
+    marc: Option<String>,
+    deleted: bool,
+    editor: i64,
+    /// Days after 2015-01-01
+    created: i32,
+    /// Days after creation
+    edited: i32,
+}
+
+/// Read every *.xml template in `dir`, in name order so the seed picks the same ones
+fn load_templates(dir: &Path) -> Result<Vec<String>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+        .context(format!(
+            "Failed to read template directory: {}",
+            dir.display()
+        ))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "xml"))
+        .collect();
+    paths.sort();
+
+    if paths.is_empty() {
+        bail!("No *.xml templates in {}", dir.display());
+    }
+
+    paths
+        .iter()
+        .map(|path| {
+            std::fs::read_to_string(path)
+                .context(format!("Failed to read template: {}", path.display()))
+        })
+        .collect()
+}
+
+async fn drop_fixtures(pool: &PgPool) -> Result<()> {
+    let exists: bool = sqlx::query_scalar("SELECT to_regclass('biblio.record_entry') IS NOT NULL")
+        .fetch_one(pool)
+        .await?;
+    if !exists {
+        return Ok(());
+    }
+
+    let comment: Option<String> =
+        sqlx::query_scalar("SELECT obj_description('biblio.record_entry'::regclass, 'pg_class')")
+            .fetch_one(pool)
+            .await?;
+    if comment.as_deref() != Some(FIXTURE_COMMENT) {
+        bail!("biblio.record_entry was not created by generate-fixtures; refusing to drop it");
+    }
+
+    for table in [
+        "vandelay.queued_bib_record",
+        "vandelay.bib_queue",
+        "config.upgrade_log",
+        "actor.usr",
+        "biblio.record_entry",
+    ] {
+        sqlx::query(&format!("DROP TABLE IF EXISTS {}", table))
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+/// The columns the extractor reads, and the tables its filters join
+///
+/// With `partitions` of (ids per partition, record count), biblio.record_entry is range
+/// partitioned by id into as many partitions as the records fill.
+async fn create_schema(pool: &PgPool, partitions: Option<(i64, i64)>) -> Result<()> {
+    let exists: bool = sqlx::query_scalar("SELECT to_regclass('biblio.record_entry') IS NOT NULL")
+        .fetch_one(pool)
+        .await?;
+    if exists {
+        bail!("biblio.record_entry already exists; use a scratch database, or --reset to replace earlier fixtures");
+    }
+
+    let partition_by = if partitions.is_some() {
+        " PARTITION BY RANGE (id)"
+    } else {
+        ""
+    };
+    let record_entry = format!(
+        "CREATE TABLE biblio.record_entry (
+            id bigint PRIMARY KEY,
+            marc text,
+            deleted boolean NOT NULL DEFAULT false,
+            editor bigint NOT NULL DEFAULT 1,
+            create_date timestamptz NOT NULL DEFAULT now(),
+            edit_date timestamptz NOT NULL DEFAULT now(),
+            tcn_value text NOT NULL,
+            fingerprint text,
+            quality int
+        ){}",
+        partition_by
+    );
+
+    for statement in [
+        "CREATE SCHEMA IF NOT EXISTS biblio",
+        "CREATE SCHEMA IF NOT EXISTS actor",
+        "CREATE SCHEMA IF NOT EXISTS vandelay",
+        "CREATE SCHEMA IF NOT EXISTS config",
+        &record_entry,
+        "CREATE INDEX ON biblio.record_entry (tcn_value)",
+        "CREATE TABLE actor.usr (id bigint PRIMARY KEY, usrname text NOT NULL UNIQUE)",
+        "INSERT INTO actor.usr VALUES (1, 'admin'), (2, 'cataloger'), (3, 'batchload')",
+        "CREATE TABLE vandelay.bib_queue (id bigint PRIMARY KEY)",
+        "INSERT INTO vandelay.bib_queue VALUES (1)",
+        // A release entry and the upgrade scripts after it, as an upgraded install has
+        "CREATE TABLE config.upgrade_log (
+            version text PRIMARY KEY,
+            install_date timestamptz NOT NULL DEFAULT now(),
+            applied_to text
+        )",
+        "INSERT INTO config.upgrade_log (version) VALUES ('3.11.1'), ('1400'), ('1401')",
+        "CREATE TABLE vandelay.queued_bib_record (
+            id serial PRIMARY KEY,
+            queue bigint NOT NULL REFERENCES vandelay.bib_queue,
+            imported_as bigint
+        )",
+    ] {
+        sqlx::query(statement).execute(pool).await?;
+    }
+
+    // Ids start at 1; partitions are dropped along with the parent
+    if let Some((size, count)) = partitions {
+        for (number, start) in (1..=count.max(1)).step_by(size as usize).enumerate() {
+            sqlx::query(&format!(
+                "CREATE TABLE biblio.record_entry_p{:04} PARTITION OF biblio.record_entry FOR VALUES FROM ({}) TO ({})",
+                number,
+                start,
+                start + size
+            ))
+            .execute(pool)
+            .await?;
+        }
+    }
+
+    sqlx::query(&format!(
+        "COMMENT ON TABLE biblio.record_entry IS '{}'",
+        FIXTURE_COMMENT
+    ))
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+fn generate_row(
+    id: i64,
+    templates: &[String],
+    oclc_numbers: &mut Vec<u64>,
+    rng: &mut ChaCha8Rng,
+) -> Row {
+    let shape = match rng.gen_range(0..100) {
+        0..=1 => Shape::NullMarc,
+        2 => Shape::Stub,
+        3..=4 => Shape::InvalidChar,
+        5 if !oclc_numbers.is_empty() => Shape::Duplicate035,
+        _ => Shape::Normal,
+    };
+
+    let oclc = match shape {
+        Shape::Duplicate035 => *oclc_numbers.choose(rng).expect("checked non-empty"),
+        _ => {
+            let number = rng.gen_range(10_000_000..2_000_000_000);
+            oclc_numbers.push(number);
+            number
+        }
+    };
+
+    let marc = match shape {
+        Shape::NullMarc => None,
+        Shape::Stub => {
+            Some("<record xmlns=\"http://www.loc.gov/MARC21/slim\"></record>".to_string())
+        }
+        _ => {
+            let template = templates.choose(rng).expect("at least one template");
+            let mut title = title(rng);
+            if shape == Shape::InvalidChar {
+                title.push('\u{0B}');
+            }
+            Some(fill_template(template, id, oclc, &title, rng))
+        }
+    };
+
+    Row {
+        id,
+        marc,
+        deleted: rng.gen_bool(0.05),
+        editor: *[1, 2, 2, 2, 3].choose(rng).expect("non-empty"),
+        created: rng.gen_range(0..3650),
+        edited: rng.gen_range(0..365),
+    }
+}
+
+fn fill_template(template: &str, id: i64, oclc: u64, title: &str, rng: &mut ChaCha8Rng) -> String {
+    const SURNAMES: &[&str] = &[
+        "Okafor",
+        "Lindqvist",
+        "Moreau",
+        "Nakamura",
+        "García",
+        "Brontë",
+        "Smith",
+        "O'Brien",
+    ];
+    const GIVEN: &[&str] = &[
+        "Ada", "Chidi", "Élise", "Kenji", "Maria", "Tomás", "Wen", "Zoë",
+    ];
+
+    let author = format!(
+        "{}, {}",
+        SURNAMES.choose(rng).expect("non-empty"),
+        GIVEN.choose(rng).expect("non-empty")
+    );
+    let year = rng.gen_range(1890..2025).to_string();
+
+    // Most records are small; a few carry enough notes to be tens of kilobytes
+    let note_count = match rng.gen_range(0..100) {
+        0 => rng.gen_range(100..400),
+        1..=10 => rng.gen_range(5..30),
+        _ => rng.gen_range(0..3),
+    };
+    let prefix = if template.contains("<marc:record") {
+        "marc:"
+    } else {
+        ""
+    };
+    let notes: String = (0..note_count)
+        .map(|n| {
+            format!(
+                "<{p}datafield tag=\"500\" ind1=\" \" ind2=\" \"><{p}subfield code=\"a\">Note {} on {}: includes index &amp; bibliography.</{p}subfield></{p}datafield>",
+                n + 1,
+                escape(title),
+                p = prefix
+            )
+        })
+        .collect();
+
+    template
+        .replace("{{id}}", &id.to_string())
+        .replace("{{oclc}}", &oclc.to_string())
+        .replace("{{title}}", &escape(title))
+        .replace("{{author}}", &escape(&author))
+        .replace("{{year}}", &year)
+        .replace("{{notes}}", &notes)
+}
+
+fn title(rng: &mut ChaCha8Rng) -> String {
+    const WORDS: &[&str] = &[
+        "river",
+        "atlas",
+        "gardens",
+        "history",
+        "winter",
+        "échos",
+        "machines",
+        "letters",
+        "salt",
+        "Arts & Crafts",
+        "night",
+        "über",
+        "harbor",
+        "<untitled>",
+        "stars",
+        "kitchen",
+    ];
+
+    let count = rng.gen_range(1..6);
+    let words: Vec<&str> = (0..count)
+        .map(|_| *WORDS.choose(rng).expect("non-empty"))
+        .collect();
+    let mut title = words.join(" ");
+    if let Some(first) = title.get(..1) {
+        title = first.to_uppercase() + &title[1..];
+    }
+    title
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+async fn insert_rows(pool: &PgPool, rows: &[Row]) -> Result<()> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+
+    // Every 25th record was loaded without ingest, so has no fingerprint or quality
+    sqlx::query(
+        "INSERT INTO biblio.record_entry
+             (id, marc, deleted, editor, create_date, edit_date, tcn_value, fingerprint, quality)
+         SELECT id, marc, deleted, editor,
+                timestamptz '2015-01-01' + make_interval(days => created),
+                timestamptz '2015-01-01' + make_interval(days => created + edited),
+                id::text,
+                CASE WHEN id % 25 <> 0 THEN md5(coalesce(marc, '')) END,
+                CASE WHEN id % 25 <> 0 THEN length(coalesce(marc, '')) % 100 END
+         FROM unnest($1::bigint[], $2::text[], $3::boolean[], $4::bigint[], $5::int[], $6::int[])
+             AS r(id, marc, deleted, editor, created, edited)",
+    )
+    .bind(rows.iter().map(|r| r.id).collect::<Vec<_>>())
+    .bind(rows.iter().map(|r| r.marc.clone()).collect::<Vec<_>>())
+    .bind(rows.iter().map(|r| r.deleted).collect::<Vec<_>>())
+    .bind(rows.iter().map(|r| r.editor).collect::<Vec<_>>())
+    .bind(rows.iter().map(|r| r.created).collect::<Vec<_>>())
+    .bind(rows.iter().map(|r| r.edited).collect::<Vec<_>>())
+    .execute(pool)
+    .await
+    .context("Failed to insert fixture records")?;
+
+    // Every tenth record came in through the import queue
+    sqlx::query(
+        "INSERT INTO vandelay.queued_bib_record (queue, imported_as)
+         SELECT 1, id FROM unnest($1::bigint[]) AS id WHERE id % 10 = 0",
+    )
+    .bind(rows.iter().map(|r| r.id).collect::<Vec<_>>())
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use xtask::Fixtures;
+
+/// Development tasks for marc_extractor_rs (run with `cargo xtask`)
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a minimal Evergreen schema and fill it with synthetic MARCXML records
+    GenerateFixtures(FixtureArgs),
+}
+
+#[derive(Parser)]
+struct FixtureArgs {
+    /// PostgreSQL connection URL of a scratch database
+    #[arg(long, env = "DATABASE_URL")]
+    db_url: String,
+
+    /// Number of records to generate
+    #[arg(long, default_value = "1000")]
+    count: i64,
+
+    /// RNG seed; the same seed and templates always produce the same records
+    #[arg(long, default_value = "1")]
+    seed: u64,
+
+    /// Directory of record templates (*.xml)
+    #[arg(long, default_value = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"))]
+    templates: PathBuf,
+
+    /// Drop fixture tables left by an earlier run first
+    #[arg(long)]
+    reset: bool,
+
+    /// Range partition biblio.record_entry by id, this many ids to a partition
+    #[arg(long, value_parser = clap::value_parser!(i64).range(1..))]
+    partition_size: Option<i64>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::GenerateFixtures(args) => generate_fixtures(args).await,
+    }
+}
+
+async fn generate_fixtures(args: FixtureArgs) -> Result<()> {
+    let fixtures = Fixtures {
+        count: args.count,
+        seed: args.seed,
+        templates: args.templates,
+        reset: args.reset,
+        partition_size: args.partition_size,
+    };
+    fixtures.generate(&args.db_url).await?;
+
+    println!(
+        "Generated {} records from {} templates (seed {})",
+        fixtures.count,
+        fixtures.template_count()?,
+        fixtures.seed
+    );
+    Ok(())
+}